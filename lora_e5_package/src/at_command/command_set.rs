@@ -24,6 +24,11 @@ const CONST_AT_COMMAND_STRINGS: &'static [&'static [&str]] =
     &["AT+RING", "+RING: ", "Command to enable/disable ring indicator.", "Y"],
     &["AT+RTABLE", "", "Command to print out routing table.", "N"],
     &["AT+ST", "+", "Command to get radio status.", "N"],
+    &["AT+NKEY", "", "Command to set the network encryption key.\n\rFormat: <32 hex chars>", "Y"],
+    &["AT+PING", "+PING: ", "Command to check a node is reachable.\n\rFormat: <dest id>,<ttl>\n\rReply: <dest id>,<rtt ms>,<hops out>,<hops back>,<last hop rssi out>,<last hop rssi back>", "Y"],
+    &["AT+TRACE", "+TRACE: ", "Command to list the relays on the way to a node.\n\rFormat: <dest id>,<ttl>\n\rReply: <dest id>,<hop count>,<id>:<rssi>,...", "Y"],
+    &["AT+MMODE", "", "Command to set the mesh routing mode.\n\rFormat: REACTIVE|PROACTIVE|HYBRID", "Y"],
+    &["AT+BEACON", "", "Command to set the neighbor beacon interval.\n\rFormat: <interval ms>, 0 turns beacons off", "Y"],
     &["AT?", "", "Command to get list of available commands.", "N"],
 ];
 
@@ -47,6 +52,11 @@ pub enum AtCommandSet {
     RingIndicator,
     RoutingTable,
     RadioStatus,
+    NetworkKey,
+    Ping,
+    Trace,
+    RoutingMode,
+    Beacon,
     AtList,
 
     // Below are not in CONST_AT_COMMAND_STRINGS
@@ -69,6 +79,11 @@ impl defmt::Format for AtCommandSet {
             AtCommandSet::RingIndicator => write!(fmt, "RingIndicator"),
             AtCommandSet::RoutingTable => write!(fmt, "RoutingTable"),
             AtCommandSet::RadioStatus => write!(fmt, "RadioStatus"),
+            AtCommandSet::NetworkKey => write!(fmt, "NetworkKey"),
+            AtCommandSet::Ping => write!(fmt, "Ping"),
+            AtCommandSet::Trace => write!(fmt, "Trace"),
+            AtCommandSet::RoutingMode => write!(fmt, "RoutingMode"),
+            AtCommandSet::Beacon => write!(fmt, "Beacon"),
 
             AtCommandSet::AtList => write!(fmt, "AtList"),
             AtCommandSet::NewLine => write!(fmt, "NewLine"),
@@ -95,8 +110,13 @@ impl AtCommandSet {
             9 => AtCommandSet::RingIndicator,
             10 => AtCommandSet::RoutingTable,
             11 => AtCommandSet::RadioStatus,
-            12 => AtCommandSet::AtList,
-            13 => AtCommandSet::NewLine,
+            12 => AtCommandSet::NetworkKey,
+            13 => AtCommandSet::Ping,
+            14 => AtCommandSet::Trace,
+            15 => AtCommandSet::RoutingMode,
+            16 => AtCommandSet::Beacon,
+            17 => AtCommandSet::AtList,
+            18 => AtCommandSet::NewLine,
             _ => AtCommandSet::Unknown,
         }
     }
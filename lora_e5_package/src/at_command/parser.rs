@@ -6,7 +6,8 @@ use crate::at_command::command_set::{
     AtCommandSet,
 };
 use bm_network::{
-    NetworkId, 
+    NetworkId, BmNetworkKey,
+    bm_network_engine::BmRoutingMode,
     bm_network_packet::bm_network_packet::BmNetworkPacketPayload
 };
 
@@ -162,4 +163,57 @@ pub fn cmd_arg_into_msg(argument_buffer: AtCmdStr) -> Option<MessageTuple> {
         defmt::error!("cmd_arg_into_msg: invalid args len={}", args.len());
     }
     None
-}
\ No newline at end of file
+}
+
+// Function to parse AT Cmd string of 32 hex characters into a network key.
+pub fn cmd_arg_into_key(argument_buffer: AtCmdStr) -> Option<BmNetworkKey> {
+    let hex_str = argument_buffer.trim();
+    if hex_str.len() != 2 * core::mem::size_of::<BmNetworkKey>() {
+        defmt::error!("cmd_arg_into_key: invalid key len={}", hex_str.len());
+        return None
+    }
+
+    let mut key: BmNetworkKey = [0; 16];
+    for (index, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex_str.get(index * 2..index * 2 + 2)?, 16).ok()?;
+    }
+    Some(key)
+}
+
+// Function to parse AT Cmd string into the destination and ttl of an echo or trace request.
+pub fn cmd_arg_into_dest_ttl(argument_buffer: AtCmdStr) -> Option<(NetworkId, u8)> {
+    // Expected format in the argument buffer: "dest,ttl"
+    let args: Vec<&str, 3> = argument_buffer.split(',').collect();
+
+    if args.len() == 2 {
+        let network_id = Some(args[0].trim().parse().ok()?);
+        let ttl = args[1].trim().parse().ok()?;
+        return Some((network_id, ttl))
+    }
+    else {
+        defmt::error!("cmd_arg_into_dest_ttl: invalid args len={}", args.len());
+    }
+    None
+}
+
+// Function to parse AT Cmd string into a beacon interval, 0 turns beacons off.
+pub fn cmd_arg_into_beacon_interval(argument_buffer: AtCmdStr) -> Option<Option<i64>> {
+    let Ok(interval) = argument_buffer.trim().parse::<u32>() else {
+        defmt::error!("cmd_arg_into_beacon_interval: invalid interval");
+        return None
+    };
+    Some(Some(interval as i64).filter(|&interval| interval > 0))
+}
+
+// Function to parse AT Cmd string into a mesh routing mode.
+pub fn cmd_arg_into_routing_mode(argument_buffer: AtCmdStr) -> Option<BmRoutingMode> {
+    match argument_buffer.trim() {
+        "REACTIVE" => Some(BmRoutingMode::Reactive),
+        "PROACTIVE" => Some(BmRoutingMode::Proactive),
+        "HYBRID" => Some(BmRoutingMode::Hybrid),
+        mode => {
+            defmt::error!("cmd_arg_into_routing_mode: unknown mode={}", mode);
+            None
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use core::fmt::Write;
 use heapless::String;
-use bm_network::{bm_network_node::bm_network_node::BmNodeEntry, bm_network_packet::bm_network_packet::BmNetworkPacket};
+use bm_network::{bm_network_node::bm_network_node::BmNodeEntry, bm_network_fragment::BmNetworkMessage};
 use defmt::unwrap;
 
 use crate::at_command::command_set::{
@@ -8,6 +8,9 @@ use crate::at_command::command_set::{
     AtCommandSet,
 };
 
+// Generic OK and prompt closing every response
+const RESP_OK_PROMPT: &str = "\n\rOK\n\r>";
+
 #[derive(Clone, PartialEq)]
 pub struct ResponseGenerator {
     // Buffer for at command responses
@@ -80,7 +83,7 @@ impl ResponseGenerator {
         self.resp_buffer.as_bytes()
     }
 
-    pub fn fmt_resp_packet_as_str_slice(&mut self, in_msg: &mut BmNetworkPacket) -> &[u8] {
+    pub fn fmt_resp_message_as_str_slice(&mut self, in_msg: &BmNetworkMessage) -> &[u8] {
         self.resp_buffer.clear();
         // Print out custom formatted packet to resp_buffer
         // +<originator>,<num hops>,<rssi>,<length>,<payload(optional)>
@@ -97,16 +100,20 @@ impl ResponseGenerator {
             )
         ).unwrap();
 
-        // If a payload is available append to resp buffer
-        if let Some(payload) = in_msg.get_payload() {
+        // If a payload is available append to resp buffer. Fragmented messages can be
+        // larger than the buffer, those are cut off to leave room for the OK.
+        if payload_len > 0 {
             unwrap!(self.resp_buffer.push_str(","));
-            for &ch in payload {
-                unwrap!(self.resp_buffer.push(char::try_from(ch).unwrap()));
+            for &ch in in_msg.get_payload() {
+                if self.resp_buffer.len() + RESP_OK_PROMPT.len() + 1 > self.resp_buffer.capacity() {
+                    break
+                }
+                unwrap!(self.resp_buffer.push(char::from(ch)));
             }
         }
 
         // Add generic OK and >
-        unwrap!(self.resp_buffer.push_str("\n\rOK\n\r>"));
+        unwrap!(self.resp_buffer.push_str(RESP_OK_PROMPT));
 
         self.resp_buffer.as_bytes()
     }
@@ -0,0 +1,75 @@
+use bm_network::bm_network_security::BmFrameCounterStore;
+use stm32wlxx_hal::{
+    flash::{Flash, Page},
+    pac,
+};
+
+// Last flash page, kept out of the program image by memory.x
+const COUNTER_PAGE_IDX: u8 = 127;
+
+// One record per double word
+const RECORD_SIZE: usize = core::mem::size_of::<u64>();
+const RECORD_SLOTS: usize = Page::SIZE / RECORD_SIZE;
+
+// Erased flash reads as all ones
+const ERASED_RECORD: u64 = u64::MAX;
+
+// Keeps the mesh frame counter in flash across reboots. Each store programs the next
+// erased double word of the page, so the page is only erased once every slot was used.
+// Records hold the counter and its complement, a record cut short by a reset is skipped.
+pub struct FlashCounterStore {
+    flash: pac::FLASH,
+    page: Page,
+    // First erased slot, RECORD_SLOTS when the page is full
+    next_slot: usize,
+}
+
+impl FlashCounterStore {
+    pub fn new(flash: pac::FLASH) -> Option<Self> {
+        let page = Page::from_index(COUNTER_PAGE_IDX)?;
+        let next_slot = (0..RECORD_SLOTS)
+            .find(|&slot| read_record(page, slot) == ERASED_RECORD)
+            .unwrap_or(RECORD_SLOTS);
+
+        Some(FlashCounterStore { flash, page, next_slot })
+    }
+}
+
+impl BmFrameCounterStore for FlashCounterStore {
+    fn load(&mut self) -> Option<u32> {
+        (0..self.next_slot).rev().find_map(|slot| decode_record(read_record(self.page, slot)))
+    }
+
+    fn store(&mut self, frame_counter: u32) {
+        let mut flash = Flash::unlock(&mut self.flash);
+
+        if self.next_slot >= RECORD_SLOTS {
+            // SAFETY: the page holds no code, memory.x ends the program image before it
+            if unsafe { flash.page_erase(self.page) }.is_err() {
+                defmt::error!("counter_store: page erase failed");
+                return
+            }
+            self.next_slot = 0;
+        }
+
+        let record = ((!frame_counter as u64) << 32) | frame_counter as u64;
+        let to = (self.page.addr() + self.next_slot * RECORD_SIZE) as *mut u64;
+        // SAFETY: an erased, aligned double word inside the counter page
+        if unsafe { flash.standard_program(&record, to) }.is_err() {
+            defmt::error!("counter_store: program failed");
+        }
+        // A failed write leaves the slot unusable either way
+        self.next_slot += 1;
+    }
+}
+
+fn read_record(page: Page, slot: usize) -> u64 {
+    let addr = page.addr() + slot * RECORD_SIZE;
+    // SAFETY: aligned read inside the flash page
+    unsafe { core::ptr::read_volatile(addr as *const u64) }
+}
+
+fn decode_record(record: u64) -> Option<u32> {
+    let frame_counter = record as u32;
+    ((record >> 32) as u32 == !frame_counter).then_some(frame_counter)
+}
@@ -0,0 +1 @@
+pub mod flash_counter_store;
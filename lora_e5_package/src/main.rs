@@ -14,7 +14,7 @@ use stm32wlxx_hal::{
     info::{self},
     pac,
     rcc,
-    //rng::{self, Rng},
+    rng::{self, Rng},
     rtc::{Clk, Rtc},
     subghz::SubGhz,
     uart::{self, Uart1},
@@ -24,8 +24,10 @@ use rtic_monotonics::systick::prelude::*;
 use heapless::String; // fixed capacity `std::Vec`
 
 use bm_network::{
+    bm_network_configs::BM_DEFAULT_PORT,
     bm_network_engine::BmNetworkEngine,
-    bm_network_engine::BmEngineStatus,
+    bm_network_event::BmEngineEvent,
+    bm_network_rng::BmRng,
 };
 mod at_command;
 use at_command::{
@@ -35,6 +37,8 @@ use at_command::{
     },
     parser::{CommandParser, MessageTuple},    
 };
+mod counter_store;
+use counter_store::flash_counter_store::FlashCounterStore;
 mod radio_control;
 use radio_control::{
     radio_control::RadioState,
@@ -47,7 +51,6 @@ systick_monotonic!(Mono, 1000);
 #[app(device = stm32wlxx_hal::pac, peripherals = true, dispatchers = [USART1])]
 mod app {
     use at_command::{parser, response::ResponseGenerator};
-    use bm_network::BmError;
 
     use super::*;
 
@@ -77,7 +80,6 @@ mod app {
 
         // mesh task
         outbound_buff_avail: bool,
-        status: BmEngineStatus,
     }
 
     #[init]
@@ -122,10 +124,10 @@ mod app {
         // Initialize the systick interrupt & obtain the token to prove that we did
         Mono::start(ctx.core.SYST, 48_000_000);
         
-        // TBD how to use RNG in mesh. 
-        // Need to add slop time on rebroadcasts?
+        // Setup RNG, the mesh uses it to spread out rebroadcasts
         // Check airspace before TX
-        //let rng: Rng = Rng::new(dp.RNG, rng::Clk::Msi, &mut dp.RCC);
+        let rng: Rng = Rng::new(dp.RNG, rng::Clk::Msi, &mut dp.RCC);
+        let mesh_rng: &'static mut MeshRng = unwrap!(hal::cortex_m::singleton!(: MeshRng = MeshRng(rng)));
 
         // Setup GPIO
         let gpioa: PortA = PortA::split(dp.GPIOA, &mut dp.RCC);
@@ -167,7 +169,14 @@ mod app {
         // Grab device number. Unique for each individual device.
         let devnum: u32 = info::Uid64::from_device().devnum();
         // Setup mesh stack
-        let mesh_inst = BmNetworkEngine::new(Some(devnum));
+        let mut mesh_inst = BmNetworkEngine::new(Some(devnum))
+            .with_radio_settings(radio_inst.get_radio_settings())
+            .with_rng(mesh_rng);
+        // Frame counters must never repeat after a reboot, keep them in flash
+        if let Some(counter_store) = FlashCounterStore::new(dp.FLASH) {
+            let counter_store: &'static mut FlashCounterStore = unwrap!(hal::cortex_m::singleton!(: FlashCounterStore = counter_store));
+            mesh_inst = mesh_inst.with_frame_counter_store(counter_store);
+        }
         defmt::info!("Mesh Stack Init Complete");
 
         // Start software tasks
@@ -197,7 +206,6 @@ mod app {
                 received_cmd: None,
                 buffer_available_to_parse: None,
                 outbound_buff_avail: false,
-                status: BmEngineStatus::default(),
             }
         )
     }
@@ -250,7 +258,7 @@ mod app {
     //
     #[task(
         shared = [uart1, rtc, radio_inst, mesh_inst],
-        local = [buffer_available_to_parse, outbound_buff_avail, status],
+        local = [buffer_available_to_parse, outbound_buff_avail],
         priority = 2,
     )]
     async fn mesh_stack_task(mut ctx: mesh_stack_task::Context) {
@@ -287,41 +295,41 @@ mod app {
             ).lock(|mesh_inst, rtc, uart1| {
                 // Run mesh engine
                 let current_millis: i64 = unwrap!(rtc.date_time()).and_utc().timestamp_millis();
-                let new_status = mesh_inst.run_engine(current_millis);
-                if *ctx.local.status != new_status {
-                    *ctx.local.status = new_status;
-
-                    // Update UI when we have updates from mesh engine
-                    match *ctx.local.status {
-                        BmEngineStatus::PerformingNetworkDiscovery => {
-                            write_str_uart1(uart1, "\n\r+Searching for route");
-                        }
-                        BmEngineStatus::RouteFound => {
-                            write_str_uart1(uart1, "\n\r+Found route");
-                        }
-                        BmEngineStatus::SendingPayload => {
-                            write_str_uart1(uart1, "\n\r+Sending payload");
-                        }
-                        BmEngineStatus::AckReceieved => {
-                            write_str_uart1(uart1, "\n\r+Ack Received");
-                        }
-                        BmEngineStatus::ErrorNoRoute => {
-                            write_str_uart1(uart1, "\n\r+Error no route");
+                mesh_inst.run_engine(current_millis);
+
+                // Update UI on engine events. Transfer outcomes are reported from their records below.
+                while let Some(event) = mesh_inst.get_next_event() {
+                    match event {
+                        BmEngineEvent::TransferSent { .. } |
+                        BmEngineEvent::TransferDelivered { .. } |
+                        BmEngineEvent::TransferFailed { .. } => { }
+                        _ => {
+                            uart1.write_fmt(format_args!("\n\r+EVENT: {}", event)).unwrap();
                         }
-                        BmEngineStatus::ErrorNoAck => {
-                            write_str_uart1(uart1, "\n\r+Error no ack");
-                        }
-                        BmEngineStatus::Complete => {
-                            write_str_uart1(uart1, "\n\rOk");
-                        }
-                        _ => { }
                     }
                 }
+
+                // Report how each transfer ended
+                while let Some(record) = mesh_inst.get_transfer_record() {
+                    uart1.write_fmt(format_args!("\n\r+TX: {},{}", record.id, record.state)).unwrap();
+                }
+
+                // Report echo results as they arrive
+                if let Some(echo_result) = mesh_inst.get_echo_result() {
+                    uart1.write_fmt(format_args!("\n\r+PING: {}", echo_result)).unwrap();
+                }
+                if let Some(trace_result) = mesh_inst.get_trace_result() {
+                    uart1.write_fmt(format_args!("\n\r+TRACE: {}", trace_result)).unwrap();
+                }
             });                
 
-            // Peek at outbound queue of mesh stack
-            ctx.shared.mesh_inst.lock(|mesh_inst| {
-                *ctx.local.outbound_buff_avail = mesh_inst.get_next_outbound_packet().is_some();
+            // Peek at outbound queue of mesh stack, relays wait out their jitter
+            (
+                &mut ctx.shared.mesh_inst,
+                &mut ctx.shared.rtc
+            ).lock(|mesh_inst, rtc| {
+                let current_millis: i64 = unwrap!(rtc.date_time()).and_utc().timestamp_millis();
+                *ctx.local.outbound_buff_avail = mesh_inst.get_next_outbound_packet(current_millis).is_some();
             });
 
             // If we have a packet to send in the mesh stack
@@ -339,8 +347,12 @@ mod app {
                 }
 
                 // Prepare and send packet to radio
-                ctx.shared.mesh_inst.lock(|mesh_inst| {
-                    if let Some(outbound_packet) = mesh_inst.get_next_outbound_packet() {
+                (
+                    &mut ctx.shared.mesh_inst,
+                    &mut ctx.shared.rtc
+                ).lock(|mesh_inst, rtc| {
+                    let current_millis: i64 = unwrap!(rtc.date_time()).and_utc().timestamp_millis();
+                    if let Some(outbound_packet) = mesh_inst.get_next_outbound_packet(current_millis) {
                         if let Some(outbound_packet_bytes) = outbound_packet.clone().to_bytes() {
                             let length_to_send = outbound_packet_bytes.len().try_into().unwrap();
                             defmt::info!("mesh_task: initiate tx len={}", length_to_send);
@@ -470,12 +482,12 @@ mod app {
                             }
                             AtCommandSet::AtMsgReceive => {
                                 ctx.shared.mesh_inst.lock(|mesh_inst| {
-                                    if let Some(mut in_msg) = mesh_inst.get_inbound_message() {
+                                    if let Some(in_msg) = mesh_inst.get_inbound_message() {
                                         defmt::info!("AtMsgReceive: in_msg:{}", defmt::Display2Format(&in_msg));
 
                                         // Format packet at response
                                         write_slice_uart1(uart1, 
-                                            ctx.local.at_resp_gen_inst.fmt_resp_packet_as_str_slice(&mut in_msg)
+                                            ctx.local.at_resp_gen_inst.fmt_resp_message_as_str_slice(&in_msg)
                                         );
                                     }
                                     else {
@@ -495,9 +507,15 @@ mod app {
 
                                     // Load new packet into engine
                                     ctx.shared.mesh_inst.lock(|mesh_inst| {
-                                        if mesh_inst.initiate_packet_transfer(network_id, ack_required, ttl, payload) != BmError::None {
-                                            defmt::error!("AtMsgSend: initiate_packet_transfer error");
-                                            write_str_uart1(uart1, "\n\rMesh Engine Error\n\r>");
+                                        match mesh_inst.initiate_packet_transfer(network_id, BM_DEFAULT_PORT, ack_required, ttl, payload) {
+                                            Ok(transfer_id) => {
+                                                // Outcome is reported as +TX with this id
+                                                uart1.write_fmt(format_args!("\n\r+SEND: {}", transfer_id)).unwrap();
+                                            }
+                                            Err(_) => {
+                                                defmt::error!("AtMsgSend: initiate_packet_transfer error");
+                                                write_str_uart1(uart1, "\n\rMesh Engine Error\n\r>");
+                                            }
                                         }
                                     });
                     
@@ -557,6 +575,90 @@ mod app {
                                     );
                                 });
                             }                
+                            AtCommandSet::NetworkKey => {
+                                if let Some(key) = parser::cmd_arg_into_key(ctx.local.at_cmd_parser_inst.get_cmd_arg()) {
+                                    // Payloads are encrypted from the next transfer on
+                                    ctx.shared.mesh_inst.lock(|mesh_inst| {
+                                        mesh_inst.set_network_key(Some(key));
+                                    });
+                                    write_slice_uart1(uart1, 
+                                        ctx.local.at_resp_gen_inst.fmt_resp_str_as_str_slice(rx_cmd_enum, "")
+                                    );
+                                }
+                                else {
+                                    defmt::error!("NetworkKey: Invalid key format");
+                                    write_str_uart1(uart1, "\n\rCmd Error\n\r>");
+                                }
+                            }
+                            AtCommandSet::Ping => {
+                                if let Some((network_id, ttl)) = parser::cmd_arg_into_dest_ttl(ctx.local.at_cmd_parser_inst.get_cmd_arg()) {
+                                    defmt::info!("Ping: id:{} ttl:{}", network_id, ttl);
+
+                                    (
+                                        &mut ctx.shared.mesh_inst,
+                                        &mut ctx.shared.rtc
+                                    ).lock(|mesh_inst, rtc| {
+                                        let current_millis: i64 = unwrap!(rtc.date_time()).and_utc().timestamp_millis();
+                                        if mesh_inst.initiate_echo_request(network_id, ttl, current_millis).is_err() {
+                                            defmt::error!("Ping: initiate_echo_request error");
+                                            write_str_uart1(uart1, "\n\rMesh Engine Error\n\r>");
+                                        }
+                                    });
+
+                                    // Result is printed by the mesh task when the reply arrives
+                                }
+                                else {
+                                    defmt::error!("Ping: Invalid command format");
+                                    write_str_uart1(uart1, "\n\rCmd Error\n\r>");
+                                }
+                            }
+                            AtCommandSet::Trace => {
+                                if let Some((network_id, ttl)) = parser::cmd_arg_into_dest_ttl(ctx.local.at_cmd_parser_inst.get_cmd_arg()) {
+                                    defmt::info!("Trace: id:{} ttl:{}", network_id, ttl);
+
+                                    ctx.shared.mesh_inst.lock(|mesh_inst| {
+                                        if mesh_inst.initiate_trace_request(network_id, ttl).is_err() {
+                                            defmt::error!("Trace: initiate_trace_request error");
+                                            write_str_uart1(uart1, "\n\rMesh Engine Error\n\r>");
+                                        }
+                                    });
+
+                                    // Path is printed by the mesh task when the reply arrives
+                                }
+                                else {
+                                    defmt::error!("Trace: Invalid command format");
+                                    write_str_uart1(uart1, "\n\rCmd Error\n\r>");
+                                }
+                            }
+                            AtCommandSet::RoutingMode => {
+                                if let Some(mode) = parser::cmd_arg_into_routing_mode(ctx.local.at_cmd_parser_inst.get_cmd_arg()) {
+                                    // Transfers already discovering a route finish their discovery
+                                    ctx.shared.mesh_inst.lock(|mesh_inst| {
+                                        mesh_inst.set_routing_mode(mode);
+                                    });
+                                    write_slice_uart1(uart1, 
+                                        ctx.local.at_resp_gen_inst.fmt_resp_str_as_str_slice(rx_cmd_enum, "")
+                                    );
+                                }
+                                else {
+                                    defmt::error!("RoutingMode: Invalid mode");
+                                    write_str_uart1(uart1, "\n\rCmd Error\n\r>");
+                                }
+                            }
+                            AtCommandSet::Beacon => {
+                                if let Some(interval) = parser::cmd_arg_into_beacon_interval(ctx.local.at_cmd_parser_inst.get_cmd_arg()) {
+                                    ctx.shared.mesh_inst.lock(|mesh_inst| {
+                                        mesh_inst.set_beacon_interval(interval);
+                                    });
+                                    write_slice_uart1(uart1, 
+                                        ctx.local.at_resp_gen_inst.fmt_resp_str_as_str_slice(rx_cmd_enum, "")
+                                    );
+                                }
+                                else {
+                                    defmt::error!("Beacon: Invalid interval");
+                                    write_str_uart1(uart1, "\n\rCmd Error\n\r>");
+                                }
+                            }
                             AtCommandSet::AtList => {                                
                                 write_slice_uart1(uart1, 
                                     ctx.local.at_resp_gen_inst.get_available_cmds()
@@ -623,6 +725,16 @@ mod app {
 }
 
 
+// Hardware RNG as the mesh random source
+struct MeshRng(Rng);
+
+impl BmRng for MeshRng {
+    fn next_u32(&mut self) -> u32 {
+        // On a seed or clock error relay without jitter
+        self.0.try_u32().unwrap_or(0)
+    }
+}
+
 fn write_str_uart1(uart1: &mut Uart1<pins::B7, pins::B6>, msg:&str)
 {
     uart1.write_str(msg).unwrap();
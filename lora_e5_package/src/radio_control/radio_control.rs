@@ -1,4 +1,4 @@
-use bm_network::TimeType;
+use bm_network::{bm_network_airtime::BmRadioSettings, TimeType};
 use defmt::{unwrap, write as defmt_write};
 use core::fmt::Write;
 use defmt_rtt as _; // global logger
@@ -126,6 +126,26 @@ impl RadioControl {
         }
     }
 
+    // Modulation for the mesh engine's airtime based timeouts, read back from the configured modulation
+    pub fn get_radio_settings(&self) -> BmRadioSettings {
+        // Set modulation params command: opcode, sf, bw, cr, ldro
+        let mod_params = self.config.mod_params.as_slice();
+        BmRadioSettings::new(mod_params[1], Self::get_bandwidth_hz(mod_params[2]), mod_params[3])
+            .with_preamble_len(PREAMBLE_LEN)
+            .with_low_data_rate_optimize(mod_params[4] != 0)
+    }
+
+    fn get_bandwidth_hz(bits: u8) -> u32 {
+        [
+            LoRaBandwidth::Bw7, LoRaBandwidth::Bw10, LoRaBandwidth::Bw15, LoRaBandwidth::Bw20,
+            LoRaBandwidth::Bw31, LoRaBandwidth::Bw41, LoRaBandwidth::Bw62, LoRaBandwidth::Bw125,
+            LoRaBandwidth::Bw250, LoRaBandwidth::Bw500,
+        ]
+        .iter()
+        .find(|bandwidth| **bandwidth as u8 == bits)
+        .map_or(0, |bandwidth| bandwidth.hertz())
+    }
+
     // LoRa-E5 radio clk requires power from GPIO
     pub fn power_on(&mut self) {        
         unsafe { subghz::wakeup() };
@@ -1,6 +1,7 @@
 MEMORY
 {
   /* See section 4.3.1 "Flash memory organization" in the reference manual */
-  FLASH : ORIGIN = 0x8000000, LENGTH = 256k
+  /* Last 2k page is left out, it holds the mesh frame counter */
+  FLASH : ORIGIN = 0x8000000, LENGTH = 254k
   RAM : ORIGIN = 0x20000000, LENGTH = 64K
 }
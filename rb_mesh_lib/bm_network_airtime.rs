@@ -0,0 +1,120 @@
+use super::{
+    bm_network_configs::*,
+    TimeType,
+};
+
+// LoRa modulation the mesh runs on, used to estimate time on air
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BmRadioSettings {
+    // 5 to 12
+    pub spreading_factor: u8,
+    pub bandwidth_hz: u32,
+    // 1 to 4 for 4/5 to 4/8
+    pub coding_rate: u8,
+    pub preamble_len: u16,
+    pub low_data_rate_optimize: bool,
+    pub crc: bool,
+    pub implicit_header: bool,
+}
+
+impl Default for BmRadioSettings {
+    fn default() -> Self {
+        BmRadioSettings::new(7, 125_000, 1)
+    }
+}
+
+impl BmRadioSettings {
+    pub fn new(spreading_factor: u8, bandwidth_hz: u32, coding_rate: u8) -> Self {
+        BmRadioSettings {
+            spreading_factor,
+            bandwidth_hz,
+            coding_rate,
+            preamble_len: 8,
+            low_data_rate_optimize: false,
+            crc: true,
+            implicit_header: false,
+        }
+    }
+
+    pub fn with_preamble_len(mut self, preamble_len: u16) -> Self {
+        self.preamble_len = preamble_len;
+        self
+    }
+
+    pub fn with_low_data_rate_optimize(mut self, enabled: bool) -> Self {
+        self.low_data_rate_optimize = enabled;
+        self
+    }
+
+    // Time on air of one frame, from the Semtech LoRa modem designer's guide. Rounded up.
+    pub fn get_time_on_air_millis(&self, packet_len: usize) -> TimeType {
+        let sf = self.spreading_factor.clamp(5, 12) as i64;
+        let cr = self.coding_rate.clamp(1, 4) as i64;
+        let de = if self.low_data_rate_optimize { 1 } else { 0 };
+        let crc = if self.crc { 1 } else { 0 };
+        let ih = if self.implicit_header { 1 } else { 0 };
+
+        // Payload symbols after the 8 symbols that always follow the preamble
+        let bits = 8 * packet_len as i64 - 4 * sf + 28 + 16 * crc - 20 * ih;
+        let bits_per_block = 4 * (sf - 2 * de);
+        let blocks = ((bits + bits_per_block - 1) / bits_per_block).max(0);
+        let payload_symbols = 8 + blocks * (cr + 4);
+
+        // Counted in quarter symbols, the preamble adds 4.25 symbols
+        let quarter_symbols = 4 * self.preamble_len as i64 + 17 + 4 * payload_symbols;
+        let micros = quarter_symbols * (1_000_000i64 << sf) / (4 * self.bandwidth_hz.max(1) as i64);
+        (micros + 999) / 1000
+    }
+
+    // Time to wait for the answer to a packet that crosses hops links, with the answer
+    // assumed to be as long as the packet. Every relay in between may also hold it back by
+    // up to BM_RELAY_JITTER_MS
+    pub fn get_round_trip_timeout_millis(&self, packet_len: usize, hops: u8) -> TimeType {
+        let hops = hops.max(1) as TimeType;
+        let hop_millis = self.get_time_on_air_millis(packet_len) + BM_HOP_DELAY_MS;
+        let one_way_millis = hops * hop_millis + (hops - 1) * BM_RELAY_JITTER_MS;
+        2 * one_way_millis * BM_TIMEOUT_MARGIN + BM_TIMEOUT_GUARD_MS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_time_on_air() {
+        // Values from the Semtech LoRa calculator: 41.2ms and 991.2ms
+        assert_eq!(BmRadioSettings::new(7, 125_000, 1).get_time_on_air_millis(10), 42);
+        let slow = BmRadioSettings::new(12, 125_000, 1).with_low_data_rate_optimize(true);
+        assert_eq!(slow.get_time_on_air_millis(10), 992);
+
+        // Wider bandwidth is faster, more coding is slower
+        assert!(BmRadioSettings::new(7, 250_000, 1).get_time_on_air_millis(50) < BmRadioSettings::new(7, 125_000, 1).get_time_on_air_millis(50));
+        assert!(BmRadioSettings::new(7, 125_000, 4).get_time_on_air_millis(50) > BmRadioSettings::new(7, 125_000, 1).get_time_on_air_millis(50));
+    }
+
+    #[test]
+    fn test_round_trip_timeout_scales() {
+        let fast = BmRadioSettings::new(7, 125_000, 1);
+        let slow = BmRadioSettings::new(12, 125_000, 1).with_low_data_rate_optimize(true);
+
+        assert!(fast.get_round_trip_timeout_millis(30, 1) < fast.get_round_trip_timeout_millis(30, 4));
+        assert!(fast.get_round_trip_timeout_millis(30, 1) < fast.get_round_trip_timeout_millis(200, 1));
+        assert!(fast.get_round_trip_timeout_millis(30, 4) < slow.get_round_trip_timeout_millis(30, 4));
+        assert!(slow.get_round_trip_timeout_millis(30, 4) > 10000);
+    }
+
+    #[test]
+    fn test_round_trip_timeout_covers_relay_jitter() {
+        let fast = BmRadioSettings::new(7, 125_000, 1);
+        let hop_millis = fast.get_time_on_air_millis(30) + BM_HOP_DELAY_MS;
+
+        // A direct neighbor has no relay to wait for
+        assert_eq!(fast.get_round_trip_timeout_millis(30, 1), 2 * hop_millis * BM_TIMEOUT_MARGIN + BM_TIMEOUT_GUARD_MS);
+        // Three hops pass two relays on the way out and two on the way back
+        assert_eq!(
+            fast.get_round_trip_timeout_millis(30, 3),
+            2 * (3 * hop_millis + 2 * BM_RELAY_JITTER_MS) * BM_TIMEOUT_MARGIN + BM_TIMEOUT_GUARD_MS
+        );
+    }
+}
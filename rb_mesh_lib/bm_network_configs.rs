@@ -5,14 +5,94 @@ pub const BM_MAX_NET_DEVICES: usize = 100;
 // Number of retries allowed on acknowledged data packets.
 pub const BM_PACKET_RETRY_COUNT: u8 = 2;
 
-// Pkt type + Sizeof(BmNetworkPacketHdr)
-pub const BM_PACKET_HDR_SIZE: usize = 18;
+// Route discovery retries after the first request. Every retry doubles the TTL, the last
+// one floods with the full TTL of the transfer.
+pub const BM_DISCOVERY_RETRY_COUNT: u8 = 2;
+
+// TTL of the first discovery request, so it stays in the local neighborhood
+pub const BM_DISCOVERY_INITIAL_TTL: u8 = 2;
+
+// Relays answer a discovery request for the destination when their route to it is at most
+// this old and this long, and has not failed
+pub const BM_INTERMEDIATE_REPLY_MAX_AGE_MS: i64 = 60_000;
+pub const BM_INTERMEDIATE_REPLY_MAX_DISTANCE: u8 = 3;
+
+// Relays are held back by a random delay of up to this long, so neighbors relaying
+// the same packet do not collide
+pub const BM_RELAY_JITTER_MS: i64 = 250;
+
+// Processing and radio turnaround per hop, on top of the time on air
+pub const BM_HOP_DELAY_MS: i64 = 100;
+
+// Round trip estimates are multiplied by this before they time out
+pub const BM_TIMEOUT_MARGIN: i64 = 2;
+
+// Fixed time added to every timeout
+pub const BM_TIMEOUT_GUARD_MS: i64 = 500;
+
+// Version/flags + Pkt type + Sizeof(BmNetworkPacketHdr)
+pub const BM_PACKET_HDR_SIZE: usize = 21;
+// Compact header with 16 bit short addresses, 2 bytes less on broadcasts without next hop
+pub const BM_COMPACT_PACKET_HDR_SIZE: usize = 13;
 
 // Max number of bytes paylaod can support. This should be 255 - sizeof(hdr).
 pub const BM_MAX_PAYLOAD_SIZE: usize = 200;
 
-// Max OTA size = hdr + payload
-pub const BM_MAX_OTA_SIZE: usize = BM_PACKET_HDR_SIZE + BM_MAX_PAYLOAD_SIZE;
+// Max OTA size = hdr + payload + hop MIC trailer
+pub const BM_MAX_OTA_SIZE: usize = BM_PACKET_HDR_SIZE + BM_MAX_PAYLOAD_SIZE + BM_SECURITY_OVERHEAD;
+
+// Frame counter carried at the start of a secured payload
+pub const BM_FRAME_COUNTER_SIZE: usize = 4;
+
+// Message integrity code carried at the end of a secured payload
+pub const BM_MIC_SIZE: usize = 8;
+
+// Payload bytes consumed by security, application data is limited to BM_MAX_PAYLOAD_SIZE minus this
+pub const BM_SECURITY_OVERHEAD: usize = BM_FRAME_COUNTER_SIZE + BM_MIC_SIZE;
+
+// Largest application message, including the port. Messages that do not fit one packet are fragmented.
+pub const BM_MAX_MESSAGE_SIZE: usize = 2048;
+
+// Application port carried as the first byte of every message
+pub const BM_PORT_SIZE: usize = 1;
+
+// Port used by applications that do not pick one, e.g. AT+MSEND
+pub const BM_DEFAULT_PORT: u8 = 0;
+
+// Max number of ports with a registered handler. Other ports go to the inbound queue.
+pub const BM_MAX_PORT_HANDLERS: usize = 4;
+
+// Fragment header: message id + fragment index + fragment count
+pub const BM_FRAGMENT_HDR_SIZE: usize = 4;
+
+// Message bytes per fragment, leaves room for payload encryption
+pub const BM_FRAGMENT_DATA_SIZE: usize = BM_MAX_PAYLOAD_SIZE - BM_SECURITY_OVERHEAD - BM_FRAGMENT_HDR_SIZE;
+
+// Fragments needed for the largest message
+pub const BM_MAX_FRAGMENTS: usize = BM_MAX_MESSAGE_SIZE.div_ceil(BM_FRAGMENT_DATA_SIZE);
+
+// Number of messages that can be reassembled at the same time
+pub const BM_REASSEMBLY_SLOTS: usize = 2;
+
+// Fragment buffers shared by the reassembly slots. Room for the largest message and a
+// few fragments of another, instead of a full message per slot.
+pub const BM_REASSEMBLY_BUFFERS: usize = BM_MAX_FRAGMENTS + 4;
+
+// Partial messages are dropped after this long without a new fragment
+pub const BM_REASSEMBLY_TIMEOUT_MS: i64 = 30000;
+
+// Rounds of sending missing fragments before a fragmented transfer gives up
+pub const BM_FRAGMENT_MAX_ROUNDS: u8 = 4;
+
+// Hop records a trace can collect. Hop count is 3 bits, so at most 7 relays plus the destination.
+pub const BM_MAX_TRACE_HOPS: usize = 8;
+
+// Relays a discovery request or response can name, hop count is 3 bits so at most 7
+pub const BM_MAX_DISCOVERY_PATH: usize = 7;
+
+// Frame counters reserved per write to the frame counter store. Bigger means fewer
+// flash writes, but more counters skipped after a reboot.
+pub const BM_FRAME_COUNTER_PERSIST_INTERVAL: u32 = 64;
 
 // Max routes stored per device
 pub const BM_MAX_DEVICE_ROUTES: usize = 5;
@@ -20,12 +100,48 @@ pub const BM_MAX_DEVICE_ROUTES: usize = 5;
 // Max rssi samples stored per route
 pub const BM_MAX_RSSI_SAMPLES: usize = 5;
 
-// Outbound queue size. Up this later? What if we hold packets for retries?
-pub const BM_OUTBOUND_QUEUE_SIZE: usize = 5;
+// Outbound queue size. Each transfer holds up to two packets for retries,
+// its discovery request and its payload.
+pub const BM_OUTBOUND_QUEUE_SIZE: usize = 8;
+
+// Max number of outbound transfers in flight, at most one per destination
+pub const BM_MAX_TRANSFERS: usize = 3;
+
+// Finished transfers kept until the application reads their outcome. New transfers are
+// refused while the records and the transfers in flight would not fit.
+pub const BM_MAX_TRANSFER_RECORDS: usize = 8;
+
+// Engine events kept until the application drains them. The oldest is dropped when full.
+pub const BM_EVENT_QUEUE_SIZE: usize = 16;
+
+// Routes not refreshed for this long are dropped
+pub const BM_ROUTE_TIMEOUT_MS: i64 = 600_000;
+
+// Time between neighbor table beacons. Half the route timeout, so neighbors heard only
+// through beacons stay in the table.
+pub const BM_NEIGHBOR_BEACON_INTERVAL_MS: i64 = BM_ROUTE_TIMEOUT_MS / 2;
+
+// Neighbors named per beacon, successive beacons go through the rest of the table
+pub const BM_NEIGHBOR_BEACON_ENTRIES: usize = 3;
+
+// Routes named per beacon in proactive and hybrid routing modes
+pub const BM_ROUTE_ADVERTISE_ENTRIES: usize = 8;
 
 // Inbound queue size. 
 pub const BM_INBOUND_QUEUE_SIZE: usize = 5;
 
+// Number of (originator, sequence) pairs remembered to suppress duplicate floods
+pub const BM_SEEN_CACHE_SIZE: usize = 16;
+
+// Retransmissions to a next hop whose LinkAck does not arrive, when link acks are on
+pub const BM_LINK_RETRY_COUNT: u8 = 2;
+
+// Alternate next hops a packet is tried on once its next hop stayed silent
+pub const BM_LINK_REROUTE_COUNT: u8 = 1;
+
+// Relayed unicast packets remembered to drop their link retransmissions
+pub const BM_LINK_SEEN_CACHE_SIZE: usize = 8;
+
 // NOTE: stack currently lives in ram, so it cannot be that large at the moment.
 // maybe can move some parts to flash some day?
 //
@@ -0,0 +1,170 @@
+use heapless::Vec; // fixed capacity `std::Vec`
+use super::{
+    bm_network_configs::*,
+    bm_network_payload::{BmPayloadCodec, BmPayloadWriter},
+    NetworkId,
+};
+
+// Flags byte in front of every non empty discovery payload
+const DISCOVERY_FLAGS_SIZE: usize = 1;
+const DISCOVERY_FLAG_REPLY: u8 = 0x01;
+const DISCOVERY_FLAG_SEQ: u8 = 0x02;
+
+// Relay reply: destination id + distance + route age
+const DISCOVERY_REPLY_SIZE: usize = 9;
+
+// Destination sequence number
+const DISCOVERY_SEQ_SIZE: usize = 2;
+
+// Network id of one relay on the path
+const DISCOVERY_HOP_SIZE: usize = 4;
+
+// Sent by a relay that answers a discovery on behalf of the destination
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct BmDiscoveryReply {
+    // Destination the relay answered for
+    pub dest: NetworkId,
+    // Distance of the relay's route to dest, 0 when dest is its neighbor
+    pub distance: u8,
+    // Milliseconds since the relay last refreshed the route
+    pub age_millis: u32,
+}
+
+impl BmDiscoveryReply {
+    fn from_bytes(bytes: &[u8]) -> Option<BmDiscoveryReply> {
+        Some(BmDiscoveryReply {
+            dest: Some(u32::from_le_bytes(bytes.get(0..4)?.try_into().ok()?)),
+            distance: *bytes.get(4)?,
+            age_millis: u32::from_le_bytes(bytes.get(5..9)?.try_into().ok()?),
+        })
+    }
+
+    fn to_bytes(self) -> [u8; DISCOVERY_REPLY_SIZE] {
+        let mut bytes = [0u8; DISCOVERY_REPLY_SIZE];
+        bytes[0..4].copy_from_slice(&self.dest.unwrap_or(0).to_le_bytes());
+        bytes[4] = self.distance;
+        bytes[5..9].copy_from_slice(&self.age_millis.to_le_bytes());
+        bytes
+    }
+}
+
+// Payload of RouteDiscoveryRequest and RouteDiscoveryResponse packets. Every relay
+// appends itself to the path, so the nodes handling the packet learn the whole chain.
+// The originator sends no payload, relay replies add a BmDiscoveryReply.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct BmDiscoveryPayload {
+    pub reply: Option<BmDiscoveryReply>,
+    // Destination sequence number. In a request the newest one the originator knows, relays
+    // only answer with a route at least as fresh. In a response the one the route was learned
+    // with.
+    pub dest_seq: Option<u16>,
+    // Relays in the order the packet went through them
+    path: Vec<NetworkId, BM_MAX_DISCOVERY_PATH>,
+}
+
+impl BmDiscoveryPayload {
+    pub fn new(reply: Option<BmDiscoveryReply>) -> Self {
+        BmDiscoveryPayload { reply, dest_seq: None, path: Vec::new() }
+    }
+
+    pub fn with_dest_seq(mut self, dest_seq: Option<u16>) -> Self {
+        self.dest_seq = dest_seq;
+        self
+    }
+
+    pub fn get_path(&self) -> &[NetworkId] {
+        &self.path
+    }
+
+    // Returns false when the path is full, the packet then travels on unchanged
+    pub fn push(&mut self, id: NetworkId) -> bool {
+        self.path.push(id).is_ok()
+    }
+}
+
+impl BmPayloadCodec for BmDiscoveryPayload {
+    const MAX_SIZE: usize = DISCOVERY_FLAGS_SIZE + DISCOVERY_REPLY_SIZE + DISCOVERY_SEQ_SIZE + BM_MAX_DISCOVERY_PATH * DISCOVERY_HOP_SIZE;
+
+    // Empty payloads carry neither reply nor path
+    fn from_payload(payload: &[u8]) -> Option<BmDiscoveryPayload> {
+        let Some((&flags, mut rest)) = payload.split_first() else {
+            return Some(BmDiscoveryPayload::default())
+        };
+
+        let mut discovery = BmDiscoveryPayload::default();
+        if flags & DISCOVERY_FLAG_REPLY != 0 {
+            discovery.reply = Some(BmDiscoveryReply::from_bytes(rest)?);
+            rest = &rest[DISCOVERY_REPLY_SIZE..];
+        }
+        if flags & DISCOVERY_FLAG_SEQ != 0 {
+            discovery.dest_seq = Some(u16::from_le_bytes(rest.get(0..DISCOVERY_SEQ_SIZE)?.try_into().ok()?));
+            rest = &rest[DISCOVERY_SEQ_SIZE..];
+        }
+
+        if !rest.len().is_multiple_of(DISCOVERY_HOP_SIZE) {
+            return None
+        }
+        for hop in rest.chunks_exact(DISCOVERY_HOP_SIZE) {
+            let id = u32::from_le_bytes([hop[0], hop[1], hop[2], hop[3]]);
+            if !discovery.push(Some(id)) {
+                return None
+            }
+        }
+        Some(discovery)
+    }
+
+    fn write_payload(&self, writer: &mut BmPayloadWriter) {
+        let mut flags = 0;
+        if self.reply.is_some() {
+            flags |= DISCOVERY_FLAG_REPLY;
+        }
+        if self.dest_seq.is_some() {
+            flags |= DISCOVERY_FLAG_SEQ;
+        }
+        writer.put_u8(flags);
+        if let Some(reply) = self.reply {
+            writer.put(&reply.to_bytes());
+        }
+        if let Some(dest_seq) = self.dest_seq {
+            writer.put(&dest_seq.to_le_bytes());
+        }
+        for &id in self.path.iter() {
+            writer.put_id(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::bm_network_payload::assert_round_trip;
+
+    #[test]
+    fn test_discovery_payload_round_trip() {
+        let reply = BmDiscoveryReply { dest: Some(0xABCD), distance: 2, age_millis: 45_000 };
+        let mut discovery = BmDiscoveryPayload::new(Some(reply)).with_dest_seq(Some(0x1234));
+        assert!(discovery.push(Some(7)));
+        assert!(discovery.push(Some(8)));
+        assert_round_trip(&discovery, DISCOVERY_FLAGS_SIZE + DISCOVERY_REPLY_SIZE + DISCOVERY_SEQ_SIZE + 2 * DISCOVERY_HOP_SIZE);
+
+        // Path only, sequence number only, and the originator's empty payload
+        let mut path_only = BmDiscoveryPayload::new(None);
+        assert!(path_only.push(Some(3)));
+        assert_round_trip(&path_only, DISCOVERY_FLAGS_SIZE + DISCOVERY_HOP_SIZE);
+        assert_round_trip(&BmDiscoveryPayload::new(None).with_dest_seq(Some(9)), DISCOVERY_FLAGS_SIZE + DISCOVERY_SEQ_SIZE);
+        assert_eq!(BmDiscoveryPayload::from_payload(&[]), Some(BmDiscoveryPayload::default()));
+
+        // Truncated reply is rejected
+        assert_eq!(BmDiscoveryPayload::from_payload(&discovery.to_payload()[..5]), None);
+    }
+
+    #[test]
+    fn test_discovery_path_full() {
+        let mut discovery = BmDiscoveryPayload::new(None);
+        for id in 0..BM_MAX_DISCOVERY_PATH as u32 {
+            assert!(discovery.push(Some(id)));
+        }
+        assert!(!discovery.push(Some(99)));
+        assert_eq!(discovery.get_path().len(), BM_MAX_DISCOVERY_PATH);
+    }
+}
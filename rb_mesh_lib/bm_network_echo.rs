@@ -0,0 +1,138 @@
+use core::fmt;
+use super::{
+    bm_network_payload::{BmPayloadCodec, BmPayloadWriter},
+    NetworkId, RssiType, TimeType,
+};
+
+// EchoRequest payload: sender timestamp
+const ECHO_REQUEST_SIZE: usize = 8;
+
+// EchoReply payload: echoed timestamp + request hop count + request rssi
+const ECHO_REPLY_SIZE: usize = 11;
+
+// Payload of an EchoRequest. The timestamp is only read back by the sender,
+// so nodes do not need synchronised clocks.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct BmEchoRequest {
+    pub timestamp: TimeType,
+}
+
+impl BmPayloadCodec for BmEchoRequest {
+    const MAX_SIZE: usize = ECHO_REQUEST_SIZE;
+
+    fn from_payload(payload: &[u8]) -> Option<BmEchoRequest> {
+        if payload.len() != ECHO_REQUEST_SIZE {
+            return None
+        }
+        Some(BmEchoRequest {
+            timestamp: TimeType::from_le_bytes(payload.try_into().ok()?),
+        })
+    }
+
+    fn write_payload(&self, writer: &mut BmPayloadWriter) {
+        writer.put(&self.timestamp.to_le_bytes());
+    }
+}
+
+// Payload of an EchoReply. Carries how the request arrived, the sender measures
+// the return direction itself.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct BmEchoReply {
+    // Timestamp copied from the request
+    pub timestamp: TimeType,
+    // Hops the request took
+    pub request_hop_count: u8,
+    // Rssi of the request at the replying node, last hop only
+    pub request_rssi: RssiType,
+}
+
+impl BmPayloadCodec for BmEchoReply {
+    const MAX_SIZE: usize = ECHO_REPLY_SIZE;
+
+    fn from_payload(payload: &[u8]) -> Option<BmEchoReply> {
+        if payload.len() != ECHO_REPLY_SIZE {
+            return None
+        }
+        Some(BmEchoReply {
+            timestamp: TimeType::from_le_bytes(payload[0..8].try_into().ok()?),
+            request_hop_count: payload[8],
+            request_rssi: RssiType::from_le_bytes([payload[9], payload[10]]),
+        })
+    }
+
+    fn write_payload(&self, writer: &mut BmPayloadWriter) {
+        writer.put(&self.timestamp.to_le_bytes());
+        writer.put_u8(self.request_hop_count);
+        writer.put(&self.request_rssi.to_le_bytes());
+    }
+}
+
+// Outcome of an echo exchange, as seen by the node that sent the request
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct BmEchoResult {
+    pub dest: NetworkId,
+    // Round trip time in milliseconds
+    pub rtt_millis: TimeType,
+    pub request_hop_count: u8,
+    pub reply_hop_count: u8,
+    // Rssi of the last hop of each direction, i.e. from the relay next to the target
+    // and from the relay next to us. Earlier hops are not measured.
+    pub request_rssi: RssiType,
+    pub reply_rssi: RssiType,
+}
+
+impl BmEchoResult {
+    // Combines a reply with how it reached us
+    pub fn new(dest: NetworkId, reply: &BmEchoReply, reply_hop_count: u8, reply_rssi: RssiType, millis: TimeType) -> Self {
+        BmEchoResult {
+            dest,
+            rtt_millis: millis.saturating_sub(reply.timestamp),
+            request_hop_count: reply.request_hop_count,
+            reply_hop_count,
+            request_rssi: reply.request_rssi,
+            reply_rssi,
+        }
+    }
+}
+
+impl fmt::Display for BmEchoResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{},{},{},{},{}",
+            self.dest.unwrap_or(0),
+            self.rtt_millis,
+            self.request_hop_count,
+            self.reply_hop_count,
+            self.request_rssi,
+            self.reply_rssi
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::bm_network_payload::assert_round_trip;
+
+    #[test]
+    fn test_echo_payload_round_trip() {
+        let request = BmEchoRequest { timestamp: 1_700_000_000_123 };
+        assert_round_trip(&request, ECHO_REQUEST_SIZE);
+
+        let reply = BmEchoReply { timestamp: request.timestamp, request_hop_count: 2, request_rssi: -97 };
+        assert_round_trip(&reply, ECHO_REPLY_SIZE);
+
+        // One is not taken for the other
+        assert_eq!(BmEchoRequest::from_payload(&reply.to_payload()), None);
+        assert_eq!(BmEchoReply::from_payload(&request.to_payload()), None);
+    }
+
+    #[test]
+    fn test_echo_result_rtt() {
+        let reply = BmEchoReply { timestamp: 1000, request_hop_count: 1, request_rssi: -80 };
+        let result = BmEchoResult::new(Some(7), &reply, 2, -90, 1450);
+        assert_eq!(result.rtt_millis, 450);
+        assert_eq!(result.request_hop_count, 1);
+        assert_eq!(result.reply_hop_count, 2);
+        assert_eq!(format!("{}", result), "7,450,1,2,-80,-90");
+    }
+}
@@ -1,10 +1,23 @@
-use heapless::Vec; use crate::BmError;
+use heapless::Vec; use crate::{BmError, BmNetworkKey};
 
 // fixed capacity `std::Vec`
 use super::{
     bm_network_configs::*, bm_network_packet::bm_network_packet::{
-        BmNetworkPacket, BmNetworkPacketPayload, BmPacketTypes, TransmitState
-    }, bm_network_routing_table::BmNetworkRoutingTable, NetworkId, RssiType, TimeType
+        BmNetworkPacket, BmNetworkPacketPayload, BmPacketDecodeError, BmPacketTypes, TransmitState
+    }, bm_network_packet::bm_packet_view::BmPacketView, bm_network_routing_table::BmNetworkRoutingTable, bm_network_seen_cache::BmSeenCache,
+    bm_network_fragment::{BmFragmentAck, BmFragmentHdr, BmFragmentTx, BmInboundMessage, BmNetworkMessage, BmPortHandler, BmReassemblyPool},
+    bm_network_echo::{BmEchoReply, BmEchoRequest, BmEchoResult},
+    bm_network_trace::{BmTracePath, BmTraceResult},
+    bm_network_event::BmEngineEvent,
+    bm_network_airtime::BmRadioSettings,
+    bm_network_rng::BmRng,
+    bm_network_route_error::BmRouteError,
+    bm_network_discovery::{BmDiscoveryPayload, BmDiscoveryReply},
+    bm_network_neighbor::{BmNeighborEntry, BmNeighborTable},
+    bm_network_link::{BmLinkAck, BmLinkSeenCache},
+    bm_network_payload::BmPayloadCodec,
+    bm_network_security::{BmFrameCounterStore, BmSecurityError},
+    NetworkId, RssiType, TimeType
 };
 use defmt::write;
 
@@ -40,20 +53,308 @@ impl defmt::Format for BmEngineStatus {
     }
 }
 
+// How routes are found. Reactive discovers them when a transfer needs one, proactive
+// only uses the routes advertised in beacons, hybrid advertises and still discovers
+// the routes the beacons did not bring.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum BmRoutingMode {
+    #[default]
+    Reactive,
+    Proactive,
+    Hybrid,
+}
+
+impl defmt::Format for BmRoutingMode {
+    fn format(&self, fmt: defmt::Formatter) {
+        match self {
+            BmRoutingMode::Reactive => write!(fmt, "Reactive"),
+            BmRoutingMode::Proactive => write!(fmt, "Proactive"),
+            BmRoutingMode::Hybrid => write!(fmt, "Hybrid"),
+        }
+    }
+}
+
+// Counters of received frames the engine dropped, by reason
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct BmRxDiagnostics {
+    pub too_short: u32,
+    pub unknown_type: u32,
+    pub oversize_payload: u32,
+    pub bad_info_bits: u32,
+    pub version_mismatch: u32,
+    pub duplicate_flood: u32,
+    // Retransmissions of packets we already relayed, whose LinkAck was lost
+    pub duplicate_link: u32,
+    // Unicast packets for another next hop, only learned from
+    pub overheard: u32,
+    pub auth_failed: u32,
+    pub unauthenticated: u32,
+    pub replayed: u32,
+}
+
+impl BmRxDiagnostics {
+    fn record_decode_error(&mut self, error: BmPacketDecodeError) {
+        let counter = match error {
+            BmPacketDecodeError::TooShort => &mut self.too_short,
+            BmPacketDecodeError::UnknownType(_) => &mut self.unknown_type,
+            BmPacketDecodeError::OversizePayload(_) => &mut self.oversize_payload,
+            BmPacketDecodeError::BadInfoBits(_) => &mut self.bad_info_bits,
+            BmPacketDecodeError::VersionMismatch(_) => &mut self.version_mismatch,
+        };
+        *counter = counter.saturating_add(1);
+    }
+
+    fn record_security_error(&mut self, error: BmSecurityError) {
+        let counter = match error {
+            BmSecurityError::Missing => &mut self.unauthenticated,
+            BmSecurityError::Replayed => &mut self.replayed,
+            _ => &mut self.auth_failed,
+        };
+        *counter = counter.saturating_add(1);
+    }
+}
+
+// Handle returned when a transfer is started
+pub type BmTransferId = u16;
+
+// Why a transfer failed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BmTransferError {
+    NoRoute,
+    NoAck,
+    // Its packet was lost from the outbound queue
+    Dropped,
+}
+
+impl core::fmt::Display for BmTransferError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            BmTransferError::NoRoute => "NO_ROUTE",
+            BmTransferError::NoAck => "NO_ACK",
+            BmTransferError::Dropped => "DROPPED",
+        })
+    }
+}
+
+// Application view of a transfer
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum BmTransferState {
+    // Waiting to be handed to the radio
+    #[default]
+    Queued,
+    Discovering,
+    // Sent, waiting for the ack or reply
+    InFlight,
+    // Sent without asking for an ack
+    Sent,
+    Delivered,
+    Failed(BmTransferError),
+}
+
+impl core::fmt::Display for BmTransferState {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            BmTransferState::Queued => f.write_str("QUEUED"),
+            BmTransferState::Discovering => f.write_str("DISCOVERING"),
+            BmTransferState::InFlight => f.write_str("IN_FLIGHT"),
+            BmTransferState::Sent => f.write_str("SENT"),
+            BmTransferState::Delivered => f.write_str("DELIVERED"),
+            BmTransferState::Failed(error) => core::write!(f, "FAILED_{}", error),
+        }
+    }
+}
+
+// Outcome of a finished transfer, kept until the application reads it
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct BmTransferRecord {
+    pub id: BmTransferId,
+    pub dest: NetworkId,
+    pub state: BmTransferState,
+}
+
+// State of one transfer we originate. Its packets are found in the outbound queue by
+// sequence number, so queue changes made for other transfers do not affect it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BmTransfer {
+    pub id: BmTransferId,
+    pub dest: NetworkId,
+    pub ttl: u8,
+    pub status: BmEngineStatus,
+    // Packet the state machine waits on: discovery request, payload or last fragment
+    working_seq: Option<u16>,
+    // Payload queued when the transfer started, None for fragmented messages
+    payload_seq: Option<u16>,
+    // Route discovery requests sent so far, minus one
+    discovery_attempt: u8,
+    // How long the working packet waits for its answer once sent
+    wait_timeout: TimeType,
+    // Set by the application to replace the airtime based timeouts
+    timeout_override: Option<TimeType>,
+    // Route errors received, each one restarted route discovery
+    route_errors: u8,
+    // Reported once the transfer is Complete
+    outcome: BmTransferState,
+}
+
+impl BmTransfer {
+    fn new(id: BmTransferId, dest: NetworkId, ttl: u8, payload_seq: Option<u16>) -> Self {
+        BmTransfer {
+            id,
+            dest,
+            ttl,
+            status: BmEngineStatus::Idle,
+            working_seq: None,
+            payload_seq,
+            discovery_attempt: 0,
+            wait_timeout: 0,
+            timeout_override: None,
+            route_errors: 0,
+            outcome: BmTransferState::default(),
+        }
+    }
+
+    // Expanding ring search. Early attempts stay close, the last one floods the whole mesh.
+    fn get_discovery_ttl(&self) -> u8 {
+        self.get_discovery_ttl_at(self.discovery_attempt)
+    }
+
+    fn get_discovery_ttl_at(&self, attempt: u8) -> u8 {
+        if attempt >= BM_DISCOVERY_RETRY_COUNT {
+            return self.ttl
+        }
+        BM_DISCOVERY_INITIAL_TTL
+            .saturating_mul(1 << attempt.min(7))
+            .min(self.ttl)
+    }
+
+    // Retries only pay off when they reach further than the last attempt. A TTL up to
+    // BM_DISCOVERY_INITIAL_TTL already floods the whole radius on the first one.
+    fn can_retry_discovery(&self) -> bool {
+        self.discovery_attempt < BM_DISCOVERY_RETRY_COUNT &&
+            self.get_discovery_ttl_at(self.discovery_attempt + 1) > self.get_discovery_ttl()
+    }
+
+    pub fn get_state(&self) -> BmTransferState {
+        match self.status {
+            BmEngineStatus::PerformingNetworkDiscovery | BmEngineStatus::RouteFound => BmTransferState::Discovering,
+            BmEngineStatus::WaitingForAck | BmEngineStatus::ErrorNoAck => BmTransferState::InFlight,
+            BmEngineStatus::AckReceieved => BmTransferState::Delivered,
+            BmEngineStatus::ErrorNoRoute => BmTransferState::Failed(BmTransferError::NoRoute),
+            BmEngineStatus::Complete => self.outcome,
+            _ => BmTransferState::Queued,
+        }
+    }
+
+    // Fragmented messages are sent from the engine's fragment buffer
+    fn is_fragmented(&self) -> bool {
+        self.payload_seq.is_none()
+    }
+}
+
+// Handler registered for one application port
+type BmPortHandlerEntry = (u8, &'static mut (dyn BmPortHandler + Send));
+
 pub struct BmNetworkEngine {
     pub table: BmNetworkRoutingTable,
 
-    // In packet buffer
-    inbound: Vec<BmNetworkPacket, BM_INBOUND_QUEUE_SIZE>,
+    // In message buffer
+    inbound: Vec<BmInboundMessage, BM_INBOUND_QUEUE_SIZE>,
 
     // Out packet buffer
     outbound: Vec<BmNetworkPacket, BM_OUTBOUND_QUEUE_SIZE>,
 
-    // Index of packet we are currently handling in the engine state machine
-    working_outbound_index: Option<usize>,
+    // Transfers in flight, each with its own state machine. At most one per destination.
+    transfers: Vec<BmTransfer, BM_MAX_TRANSFERS>,
+
+    // Id handed out to the last transfer started
+    transfer_id: BmTransferId,
+
+    // Finished transfers the application has not read yet, oldest first
+    transfer_records: Vec<BmTransferRecord, BM_MAX_TRANSFER_RECORDS>,
+
+    // Events the application has not drained yet, oldest first
+    events: Vec<BmEngineEvent, BM_EVENT_QUEUE_SIZE>,
+
+    // Rejected frame counters
+    rx_diagnostics: BmRxDiagnostics,
+
+    // Sequence number stamped on the next packet we originate. It is not persisted, the
+    // start is drawn from the RNG instead, so after a reboot our floods and message ids do
+    // not repeat pairs that neighbors still remember. Without an RNG it starts at 0.
+    sequence_number: u16,
+
+    // Floods already handled, so each is relayed at most once
+    seen_floods: BmSeenCache,
+
+    // Network key. When set, data payloads are encrypted and every other packet
+    // carries a hop MIC.
+    network_key: Option<BmNetworkKey>,
+
+    // Last frame counter stamped on a packet we encrypted or signed
+    frame_counter: u32,
+
+    // Highest frame counter already written to the store
+    frame_counter_reserved: u32,
+
+    // Modulation used to estimate time on air for timeouts
+    radio_settings: BmRadioSettings,
+
+    // Optional persistence for the frame counter
+    frame_counter_store: Option<&'static mut (dyn BmFrameCounterStore + Send)>,
+
+    // Random source for relay jitter. Without one relays go out right away.
+    rng: Option<&'static mut (dyn BmRng + Send)>,
 
-    // Enum state machine for status of mesh engine
-    engine_status: BmEngineStatus,
+    // Packet handed out by get_next_outbound_packet, by originator, sequence and type
+    next_outbound: Option<(NetworkId, u16, BmPacketTypes)>,
+
+    // Time between neighbor table beacons, None to send none
+    beacon_interval: Option<TimeType>,
+
+    // When the next beacon is due, scheduled on the first run of the engine
+    next_beacon_millis: Option<TimeType>,
+
+    // Routing table index the next beacon starts its entries at
+    beacon_cursor: usize,
+
+    // Route discovery, route advertisement or both
+    routing_mode: BmRoutingMode,
+
+    // Our destination sequence number, raised with every route advertisement. 0 until the first one.
+    dest_seq: u16,
+
+    // Ask the next hop of every unicast packet we send for a LinkAck
+    link_acks: bool,
+
+    // Take hearing the next hop forward a packet as its ack, LinkAcks are only asked for
+    // on the last hop and on retransmissions
+    passive_acks: bool,
+
+    // Unicast packets we relayed, so retransmissions asking for a LinkAck are not relayed twice
+    link_seen: BmLinkSeenCache,
+
+    // Originate packets with the compact header when our addresses allow it
+    compact_header: bool,
+
+    // Outgoing message that is sent in fragments. It holds the whole message,
+    // so only one fragmented transfer runs at a time.
+    tx_message: Option<BmFragmentTx>,
+
+    // Incoming fragmented messages
+    reassembly: BmReassemblyPool,
+
+    // Fragmented messages already delivered, so late retries are acked but not delivered again.
+    // Kept for BM_REASSEMBLY_TIMEOUT_MS, a message id seen after that is a new message.
+    completed_messages: BmLinkSeenCache,
+
+    // Result of the last echo exchange, until the application takes it
+    echo_result: Option<BmEchoResult>,
+
+    // Path reported by the last trace, until the application takes it
+    trace_result: Option<BmTraceResult>,
+
+    // Applications that handle their port themselves instead of using the inbound queue
+    port_handlers: Vec<BmPortHandlerEntry, BM_MAX_PORT_HANDLERS>,
 }
 
 impl BmNetworkEngine {
@@ -63,57 +364,275 @@ impl BmNetworkEngine {
             table: BmNetworkRoutingTable::new(local_network_id),
             inbound: Vec::new(),
             outbound: Vec::new(),
-            working_outbound_index: None,
-            engine_status: BmEngineStatus::default(),
+            transfers: Vec::new(),
+            transfer_id: 0,
+            transfer_records: Vec::new(),
+            events: Vec::new(),
+            rx_diagnostics: BmRxDiagnostics::default(),
+            sequence_number: 0,
+            seen_floods: BmSeenCache::new(),
+            network_key: None,
+            frame_counter: 0,
+            frame_counter_reserved: 0,
+            frame_counter_store: None,
+            rng: None,
+            next_outbound: None,
+            beacon_interval: None,
+            next_beacon_millis: None,
+            beacon_cursor: 0,
+            routing_mode: BmRoutingMode::default(),
+            dest_seq: 0,
+            link_acks: false,
+            passive_acks: false,
+            link_seen: BmLinkSeenCache::new(),
+            radio_settings: BmRadioSettings::default(),
+            compact_header: false,
+            tx_message: None,
+            reassembly: BmReassemblyPool::new(),
+            completed_messages: BmLinkSeenCache::new(),
+            echo_result: None,
+            trace_result: None,
+            port_handlers: Vec::new(),
+        }
+    }
+
+    pub fn with_network_key(mut self, key: BmNetworkKey) -> Self {
+        self.network_key = Some(key);
+        self
+    }
+
+    pub fn set_network_key(&mut self, key: Option<BmNetworkKey>) {
+        self.network_key = key;
+    }
+
+    pub fn with_rng(mut self, rng: &'static mut (dyn BmRng + Send)) -> Self {
+        self.sequence_number = rng.next_u32() as u16;
+        self.rng = Some(rng);
+        self
+    }
+
+    pub fn with_beacon_interval(mut self, interval: Option<TimeType>) -> Self {
+        self.beacon_interval = interval;
+        self
+    }
+
+    // The next beacon goes out an interval after the engine runs again
+    pub fn set_beacon_interval(&mut self, interval: Option<TimeType>) {
+        self.beacon_interval = interval;
+        self.next_beacon_millis = None;
+    }
+
+    pub fn with_routing_mode(mut self, mode: BmRoutingMode) -> Self {
+        self.routing_mode = mode;
+        self
+    }
+
+    // Transfers already discovering a route finish their discovery
+    pub fn set_routing_mode(&mut self, mode: BmRoutingMode) {
+        defmt::info!("rb_engine: routing mode {}", mode);
+        self.routing_mode = mode;
+    }
+
+    pub fn get_routing_mode(&self) -> BmRoutingMode {
+        self.routing_mode
+    }
+
+    pub fn with_link_acks(mut self, link_acks: bool) -> Self {
+        self.link_acks = link_acks;
+        self
+    }
+
+    // Packets already queued keep asking, or not asking, for LinkAcks
+    pub fn set_link_acks(&mut self, link_acks: bool) {
+        self.link_acks = link_acks;
+    }
+
+    pub fn with_passive_acks(mut self, passive_acks: bool) -> Self {
+        self.passive_acks = passive_acks;
+        self
+    }
+
+    // Packets already queued keep the kind of ack they wait for
+    pub fn set_passive_acks(&mut self, passive_acks: bool) {
+        self.passive_acks = passive_acks;
+    }
+
+    pub fn with_radio_settings(mut self, settings: BmRadioSettings) -> Self {
+        self.radio_settings = settings;
+        self
+    }
+
+    pub fn set_radio_settings(&mut self, settings: BmRadioSettings) {
+        self.radio_settings = settings;
+    }
+
+    pub fn with_compact_header(mut self, compact: bool) -> Self {
+        self.compact_header = compact;
+        self
+    }
+
+    pub fn set_compact_header(&mut self, compact: bool) {
+        self.compact_header = compact;
+    }
+
+    // Restores the frame counter from the store and keeps it updated from now on.
+    // Counters that may have been used before the reboot are never handed out again.
+    pub fn with_frame_counter_store(mut self, store: &'static mut (dyn BmFrameCounterStore + Send)) -> Self {
+        if let Some(stored) = store.load() {
+            self.frame_counter = stored;
+            self.frame_counter_reserved = stored;
         }
+        self.frame_counter_store = Some(store);
+        self
     }
 
-    pub fn process_packet(&mut self, length: usize, buffer: &mut [u8], millis: TimeType, rssi: RssiType) -> Option<BmNetworkPacket> {
-        // Parse packet into struct
-        // If we cannot successfully parse packet, return
-        let mut new_packet = BmNetworkPacket::from(length, buffer)?.with_rssi(rssi);
+    // Handles a received frame, returns its type if it was accepted. The header is read
+    // in place from the receive buffer, only packets addressed to us are copied out.
+    pub fn process_packet(&mut self, length: usize, buffer: &mut [u8], millis: TimeType, rssi: RssiType) -> Option<BmPacketTypes> {
+        // If we cannot successfully parse packet, count the reason and return
+        let mut view = match BmPacketView::new(length, buffer) {
+            Ok(view) => view,
+            Err(error) => {
+                defmt::warn!("rb_engine: dropped frame, {}", error);
+                self.rx_diagnostics.record_decode_error(error);
+                return None
+            }
+        };
 
         defmt::info!("process_packet len={}", length);
 
-        // Do not process our own packets
-        if new_packet.get_originator() == self.table.get_local_network_id() {
+        // Do not process our own packets, but a neighbor forwarding one acks that hop
+        if view.get_originator() == self.table.get_local_network_id() {
+            self.receive_forward(&view, false);
+            return None
+        }
+
+        // Check authentication before the routing table learns anything from the packet
+        if let Err(error) = self.authenticate_packet(&view) {
+            defmt::warn!("rb_engine: authentication failed, {}", error);
+            self.rx_diagnostics.record_security_error(error);
+
+            // An authentic copy of a packet we already had. It can be the forward of a packet
+            // we relayed, or a retransmission whose LinkAck was lost.
+            // Only copies inside the retransmission window of one we acked are acked again.
+            if error == BmSecurityError::Replayed {
+                self.receive_forward(&view, true);
+                let window = self.get_link_seen_window();
+                if self.is_link_ack_due(&view) &&
+                   self.link_seen.contains(view.get_originator(), view.get_seq(), millis, window) {
+                    self.send_link_ack(&view);
+                }
+            }
+            return None
+        }
+
+        // Unicast packets for another next hop are only overheard. Their source and hop
+        // count are only learned from when a verified hop MIC vouches for them, which every
+        // packet that passed authentication with a network key has.
+        let local_id = self.table.get_local_network_id();
+        let overheard = !view.get_packet_type().is_flood() &&
+            view.get_next_hop() != local_id && view.get_destination() != local_id;
+
+        if !overheard || self.network_key.is_some() {
+            // Update routing table. Even if the packet is direct and not relayed. We want 
+            // the neighbor node to show up as a route with distance 0.
+            self.learn_route(
+                view.get_originator(), 
+                view.get_source(),
+                view.get_hop_count(),
+                millis, rssi);
+
+            // The node we heard is a neighbor, whoever originated the packet
+            if view.get_source() != view.get_originator() {
+                self.learn_route(view.get_source(), view.get_source(), 0, millis, rssi);
+            }
+
+            // Discovery packets name the relays they went through, and relay replies the
+            // node they answered for
+            if matches!(view.get_packet_type(), BmPacketTypes::RouteDiscoveryRequest | BmPacketTypes::RouteDiscoveryResponse) {
+                self.learn_discovery_path(&view, millis, rssi);
+            }
+        }
+
+        // Our next hop forwarding a packet acks it
+        self.receive_forward(&view, true);
+
+        if overheard {
+            self.rx_diagnostics.overheard = self.rx_diagnostics.overheard.saturating_add(1);
             return None
         }
 
-        // Update routing table. Even if the packet is direct and not relayed. We want 
-        // the neighbor node to show up as a route with distance 0.
-        self.table.update_node_route(
-            new_packet.get_originator(), 
-            new_packet.get_source(),
-            new_packet.get_hop_count(),
-            millis, rssi);
+        // Ack the hop first. Retransmissions of packets we already relayed or received are
+        // acked again but not relayed or delivered twice. With passive acks only the
+        // retransmission asks for a LinkAck, so every packet we relay is remembered.
+        let link_ack_due = self.is_link_ack_due(&view);
+        if link_ack_due {
+            self.send_link_ack(&view);
+        }
+        if !view.get_packet_type().is_flood() {
+            let window = self.get_link_seen_window();
+            let first = self.link_seen.insert(view.get_originator(), view.get_seq(), millis, window);
+            if link_ack_due && !first {
+                defmt::info!("rb_engine: link retransmission, drop");
+                self.rx_diagnostics.duplicate_link = self.rx_diagnostics.duplicate_link.saturating_add(1);
+                return None
+            }
+        }
         
         // Check hop count against TTL of packet
         // TODO: move this logic into just the packet relay sections?
         //       i.e. if we are the destination at 3 of 3 hops, we should accept
-        if new_packet.get_info().hop_count() >= new_packet.get_info().ttl() {
+        if view.get_info().hop_count() >= view.get_info().ttl() {
             defmt::warn!("rb_engine: TTL expired, kill packet");
             return None
         }
 
+        // Floods are handled once per (originator, seq). Later copies have already
+        // taught us a route above, but are not answered or relayed again.
+        let packet_type = view.get_packet_type();
+        if packet_type.is_flood() &&
+           !self.seen_floods.insert(view.get_originator(), view.get_seq()) {
+            defmt::info!("rb_engine: duplicate flood, drop");
+            self.rx_diagnostics.duplicate_flood = self.rx_diagnostics.duplicate_flood.saturating_add(1);
+            return None
+        }
+
         // If dest is us, handle packet based off type
-        if new_packet.get_destination() == self.table.get_local_network_id() {
+        if view.get_destination() == self.table.get_local_network_id() {
+            // Copy into owned storage and decrypt, the payload MIC was already checked
+            let Ok(packet) = view.to_packet() else {
+                return None
+            };
+            let mut new_packet = packet.with_rssi(rssi);
+            if let (true, Some(key)) = (new_packet.get_info().encrypted(), self.network_key) {
+                if let Err(error) = new_packet.decrypt_payload(&key) {
+                    self.rx_diagnostics.record_security_error(error);
+                    return None
+                }
+            }
+
             match new_packet.packet_type {
                 BmPacketTypes::RouteDiscoveryRequest => {
                     defmt::info!("rb_engine: Rx Disc Req to us, Tx Disc Resp");
     
                     // Queue up discovery response. Addressed to the originator 
-                    // through the node we received this from. Same TTL and info bits.
-                    if self.outbound.push(
+                    // through the node we received this from. Same TTL, nothing acks a response.
+                    // Carries a new destination sequence number, fresher than any route to us
+                    // the nodes on the way hold.
+                    self.next_dest_seq();
+                    let discovery = BmDiscoveryPayload::new(None).with_dest_seq(Some(self.dest_seq));
+                    let seq = self.next_sequence_number();
+                    if self.queue_outbound(
                         BmNetworkPacket::new(
                             BmPacketTypes::RouteDiscoveryResponse, 
                             self.table.get_local_network_id(),
                             new_packet.get_source(),
                             new_packet.get_originator(),
                             new_packet.get_info().ttl(),
-                            new_packet.get_info().required_ack(),
-                            None
+                            false,
+                            Some(discovery.to_payload())
                         )
+                        .with_seq(seq)
                         .with_ok_to_transmit(),
                     ).is_err() {
                         defmt::error!("rb_engine: Error queue full");
@@ -121,9 +640,15 @@ impl BmNetworkEngine {
                 }
                 BmPacketTypes::RouteDiscoveryResponse => {    
                     // Discovery Response addressed to us. Theoretically our route is found.
-                    if self.engine_status == BmEngineStatus::PerformingNetworkDiscovery {
+                    // Relays answer for the destination named in the payload.
+                    let reply = new_packet.get_payload().as_deref()
+                        .and_then(BmDiscoveryPayload::from_payload)
+                        .and_then(|discovery| discovery.reply);
+                    let dest = reply.map_or(new_packet.get_originator(), |reply| reply.dest);
+                    if let Some(transfer) = self.transfers.iter_mut()
+                        .find(|transfer| transfer.dest == dest && transfer.status == BmEngineStatus::PerformingNetworkDiscovery) {
                         defmt::info!("rb_engine: Rx Disc Resp, route found");
-                        self.engine_status = BmEngineStatus::RouteFound;
+                        transfer.status = BmEngineStatus::RouteFound;
                     }
                     else {
                         defmt::error!("rb_engine: Rx Disc Resp, unexpected");
@@ -131,30 +656,42 @@ impl BmNetworkEngine {
                 }
                 BmPacketTypes::RouteDiscoveryError => {
                     defmt::info!("rb_engine: Rx Disc Error");
-    
-                    // What todo with disc error addressed to us??
+
+                    // Our route through the node we heard this from is broken, look for a new one
+                    if let Some(error) = new_packet.get_payload().as_deref().and_then(BmRouteError::from_payload) {
+                        self.invalidate_route(error.unreachable, new_packet.get_source());
+                        self.restart_discovery(error.unreachable);
+                    }
+                    else {
+                        defmt::warn!("rb_engine: malformed RouteDiscoveryError");
+                    }
                 }
                 BmPacketTypes::DataPayload => {
                     defmt::info!("rb_engine: Rx DataPayload");
 
-                    // Save packet to inbound queue
-                    if self.inbound.push(new_packet.clone()).is_err() {
-                        defmt::error!("rb_engine: Error in queue full");
-                    }
+                    // Hand message to its port
+                    let message = BmInboundMessage::from_received(
+                        new_packet.get_originator(),
+                        new_packet.get_hop_count(),
+                        rssi,
+                        new_packet.get_payload().as_deref().unwrap_or_default());
+                    self.deliver_message(message);
 
                     // Send ACK response if required
                     if new_packet.get_info().required_ack() {
                         defmt::info!("rb_engine: Rx DataPayload, sending ack");
-                        if self.outbound.push(
+                        let seq = self.next_sequence_number();
+                        if self.queue_outbound(
                             BmNetworkPacket::new(
                                 BmPacketTypes::DataPayloadAck, 
                                 self.table.get_local_network_id(),
                                 new_packet.get_source(),
                                 new_packet.get_originator(),
                                 new_packet.get_info().ttl(),
-                                new_packet.get_info().required_ack(),
+                                false,
                                 None
                             )
+                            .with_seq(seq)
                             .with_ok_to_transmit(),
                         ).is_err() {
                             defmt::error!("rb_engine: Error queue full");
@@ -162,68 +699,143 @@ impl BmNetworkEngine {
                     }
                 }
                 BmPacketTypes::DataPayloadAck => {
-                    if self.engine_status == BmEngineStatus::WaitingForAck {
+                    if let Some(index) = self.find_waiting_transfer(BmPacketTypes::DataPayload, new_packet.get_originator()) {
                         defmt::info!("rb_engine: Rx DataPayloadAck");
-                        self.engine_status = BmEngineStatus::AckReceieved;
+                        self.transfers[index].status = BmEngineStatus::AckReceieved;
                     }
                     else {
                         defmt::error!("rb_engine: Rx DataPayloadAck, unexpected");
                     }
                 }
+                BmPacketTypes::DataFragment => {
+                    defmt::info!("rb_engine: Rx DataFragment");
+                    self.receive_fragment(&mut new_packet, millis, rssi);
+                }
+                BmPacketTypes::DataFragmentAck => {
+                    defmt::info!("rb_engine: Rx DataFragmentAck");
+                    let ack = new_packet.get_payload().as_deref().and_then(BmFragmentAck::from_payload);
+                    self.receive_fragment_ack(ack);
+                }
+                BmPacketTypes::EchoRequest => {
+                    defmt::info!("rb_engine: Rx EchoRequest, Tx EchoReply");
+                    self.reply_to_echo(&mut new_packet, rssi);
+                }
+                BmPacketTypes::EchoReply => {
+                    self.receive_echo_reply(&mut new_packet, millis, rssi);
+                }
+                BmPacketTypes::TraceRequest => {
+                    defmt::info!("rb_engine: Rx TraceRequest, Tx TraceReply");
+                    self.reply_to_trace(&mut new_packet, rssi);
+                }
+                BmPacketTypes::TraceReply => {
+                    self.receive_trace_reply(&mut new_packet);
+                }
                 BmPacketTypes::BcastNeighborTable => {
                     defmt::info!("rb_engine: Rx Neighbor table");
                     // Should never receieve addressed neighbor table packet
                 }
+                BmPacketTypes::LinkAck => {
+                    self.receive_link_ack(&mut new_packet);
+                }
             }
         }
         else { // Route packet not addressed to us
-            match new_packet.packet_type {
-                BmPacketTypes::RouteDiscoveryRequest |
+            match packet_type {
+                BmPacketTypes::RouteDiscoveryRequest => {
+                    // A relay with a fresh route answers for the destination and stops the flood
+                    if self.reply_for_destination(&view, millis) {
+                        defmt::info!("rb_engine: Rx Disc Req, route known, Tx Disc Resp");
+                    }
+                    else {
+                        defmt::info!("rb_engine: rebroadcast packet");
+                        self.broadcast_packet(&mut view, millis, rssi);
+                    }
+                }
                 BmPacketTypes::BcastNeighborTable => {
-                    defmt::info!("rb_engine: rebroadcast packet");
-
-                    self.broadcast_packet(new_packet.clone());
+                    // Neighbor tables are meant for the nodes that hear them, never relayed
+                    defmt::info!("rb_engine: Rx Neighbor table");
+                    self.receive_neighbor_table(&view, millis, rssi);
+                }
+                BmPacketTypes::LinkAck => {
+                    // One hop only, never relayed
+                    defmt::warn!("rb_engine: Rx LinkAck for another node");
                 }
                 BmPacketTypes::RouteDiscoveryResponse |
                 BmPacketTypes::DataPayload |
-                BmPacketTypes::DataPayloadAck  => {
+                BmPacketTypes::DataPayloadAck |
+                BmPacketTypes::DataFragment |
+                BmPacketTypes::DataFragmentAck |
+                BmPacketTypes::EchoRequest |
+                BmPacketTypes::EchoReply |
+                BmPacketTypes::TraceRequest |
+                BmPacketTypes::TraceReply => {
                     defmt::info!("rb_engine: routing packet");
     
-                    if !self.route_packet(new_packet.clone()) {
-                        // Generate discovery error??
+                    if !self.route_packet(&mut view, millis, rssi) {
+                        defmt::warn!("rb_engine: no route, Tx Disc Error");
+                        self.send_route_error(view.get_originator(), view.get_destination(), view.get_info().ttl());
                     } 
                 }
-                _ => {}
+                BmPacketTypes::RouteDiscoveryError => {
+                    defmt::info!("rb_engine: routing Disc Error");
+
+                    // The route through the node we heard this from is broken for us as well.
+                    // Errors are not answered with errors, without a route they are dropped.
+                    if let Some(error) = BmRouteError::from_payload(view.get_payload()) {
+                        self.invalidate_route(error.unreachable, view.get_source());
+                    }
+                    self.route_packet(&mut view, millis, rssi);
+                }
             }
         }
 
-        Some(new_packet)
+        Some(packet_type)
     }
 
     // Function to search for next outbound packet that is available to transmit.
-    pub fn get_next_outbound_packet(&mut self) -> Option<&mut BmNetworkPacket> {
+    // Returns the next packet that is ok to transmit and due at current_time_millis.
+    // LinkAcks go first, the neighbor waiting for one retransmits otherwise.
+    pub fn get_next_outbound_packet(&mut self, current_time_millis: i64) -> Option<&mut BmNetworkPacket> {
         // Search for a packet that is ok to transmit
-        for pkt in self.outbound.iter_mut() {
-            if pkt.is_ok_to_transmit() {
-                return Some(pkt)
-            }
-        }
-        None
+        let ready = |pkt: &mut BmNetworkPacket| pkt.is_ok_to_transmit() && pkt.is_due(current_time_millis);
+        let index = self.outbound.iter_mut()
+            .position(|pkt| pkt.packet_type == BmPacketTypes::LinkAck && ready(pkt))
+            .or_else(|| self.outbound.iter_mut().position(ready))?;
+
+        // Latch it, other packets can become due before its transmit completes
+        let pkt = &mut self.outbound[index];
+        self.next_outbound = Some((pkt.get_originator(), pkt.get_seq(), pkt.packet_type.clone()));
+        Some(&mut self.outbound[index])
     }
 
+    // Completes the packet last returned by get_next_outbound_packet
     pub fn set_next_outbound_complete(&mut self, time_millis: i64) {
-        // Concern, will the iterator order change if the outbound queue is pushed mid event??
-        // Might need to latch an outbound packet here as it cannot be stored in the mesh_task loop.
+        let latched = self.next_outbound.take();
         for (index, pkt) in self.outbound.iter_mut().enumerate() {
-            if pkt.is_ok_to_transmit() {
+            let is_latched = latched.as_ref().is_none_or(|(orig, seq, packet_type)| {
+                pkt.get_originator() == *orig && pkt.get_seq() == *seq && pkt.packet_type == *packet_type
+            });
+            if pkt.is_ok_to_transmit() && is_latched {
+                // Kept until the next hop acks it. A forward comes after the relay jitter of the next hop.
+                if pkt.is_hop_ack_expected() {
+                    let packet_len = pkt.to_bytes().map_or(BM_MAX_OTA_SIZE, |bytes| bytes.len());
+                    let jitter = if pkt.passive_ack { BM_RELAY_JITTER_MS } else { 0 };
+                    pkt.link_ack_deadline = Some(time_millis + self.radio_settings.get_round_trip_timeout_millis(packet_len, 1) + jitter);
+                }
+
                 if pkt.is_waiting_for_reply() {
                     // Record timestamp of last tx
                     pkt.tx_complete_timestamp = Some(time_millis);
-                    // Increment tx counter
-                    pkt.tx_count += 1;
+                    // Increment tx counter, link retransmissions do not use up end to end retries
+                    if pkt.link_retries == 0 {
+                        pkt.tx_count += 1;
+                    }
                     // Remove from list of available packets to tx
                     pkt.tx_state = TransmitState::Complete;
                 }
+                else if pkt.is_hop_ack_expected() {
+                    pkt.tx_state = TransmitState::Complete;
+                }
                 else {
                     // If state machine is not waiting for a resp, remove successfully transmitted packet.
                     self.outbound.remove(index);
@@ -233,353 +845,3299 @@ impl BmNetworkEngine {
         }
     }
 
-    pub fn initiate_packet_transfer(&mut self, dest: NetworkId, ack: bool, ttl: u8, payload: BmNetworkPacketPayload) -> BmError {
-        let mut return_value = BmError::None;
+    // Registers the handler for messages sent to port. Replaces an earlier handler of that port.
+    pub fn register_port_handler(&mut self, port: u8, handler: &'static mut (dyn BmPortHandler + Send)) -> BmError {
+        if let Some(entry) = self.port_handlers.iter_mut().find(|entry| entry.0 == port) {
+            entry.1 = handler;
+            return BmError::None
+        }
+        if self.port_handlers.push((port, handler)).is_err() {
+            return BmError::QueueFull
+        }
+        BmError::None
+    }
+
+    // Removes the handler of port, its messages go to the inbound queue again
+    pub fn unregister_port_handler(&mut self, port: u8) -> Option<&'static mut (dyn BmPortHandler + Send)> {
+        let index = self.port_handlers.iter().position(|entry| entry.0 == port)?;
+        Some(self.port_handlers.swap_remove(index).1)
+    }
 
-        if self.engine_status == BmEngineStatus::Idle {
-            // Queue up data payload to send
-            if self.outbound.push(
-                BmNetworkPacket::new(
-                    BmPacketTypes::DataPayload, 
-                    self.table.get_local_network_id(),
-                    None,
-                    dest,
-                    ttl,
-                    ack,
-                    Some(payload)
-                ).with_wait_for_reply()
-            ).is_err() {
-                defmt::error!("Error queue full");
-                return BmError::QueueFull
-            }
+    // Sends one packet of application data to port on dest
+    pub fn initiate_packet_transfer(&mut self, dest: NetworkId, port: u8, ack: bool, ttl: u8, data: BmNetworkPacketPayload) -> Result<BmTransferId, BmError> {
+        let mut payload = BmNetworkPacketPayload::new();
+        if payload.push(port).is_err() || payload.extend_from_slice(&data).is_err() {
+            defmt::error!("initiate_packet_transfer: payload too large");
+            return Err(BmError::PayloadTooLarge)
+        }
+        self.queue_data_payload(dest, ack, ttl, payload)
+    }
 
-            // Check stack if we have route
-            if self.table.find_node_by_id(dest).is_none() {
-                // Start network discovery for destination node
-                self.start_network_discovery(dest, ttl);
-            }
-            else {
-                // Set data packet as working packet
-                self.select_data_packet();
-                // Jump right into sending payload
-                self.engine_status = BmEngineStatus::SendingPayload;
-            }           
+    // Sends an application message of up to BM_MAX_MESSAGE_SIZE bytes with its port.
+    // Messages that fit one packet go out as a DataPayload, larger ones are fragmented.
+    pub fn initiate_message_transfer(&mut self, dest: NetworkId, port: u8, ack: bool, ttl: u8, message: &[u8]) -> Result<BmTransferId, BmError> {
+        let max_single_payload = if self.network_key.is_some() {
+            BM_MAX_PAYLOAD_SIZE - BM_SECURITY_OVERHEAD
         }
         else {
-            defmt::warn!("initiate_packet_transfer: busy");
-            return_value = BmError::Busy;
+            BM_MAX_PAYLOAD_SIZE
+        };
+        if message.len() + BM_PORT_SIZE <= max_single_payload {
+            // Cannot fail, length checked above
+            let payload = BmNetworkPacketPayload::from_slice(message).unwrap_or_default();
+            return self.initiate_packet_transfer(dest, port, ack, ttl, payload)
+        }
+
+        if self.tx_message.is_some() {
+            defmt::warn!("initiate_message_transfer: busy");
+            return Err(BmError::Busy)
+        }
+        let error = self.check_transfer_slot(dest);
+        if error != BmError::None {
+            defmt::warn!("initiate_message_transfer: busy");
+            return Err(error)
         }
 
-        return_value       
+        let msg_id = self.next_sequence_number();
+        let Some(mut tx_message) = BmFragmentTx::new(dest, ttl, ack, msg_id, port, message) else {
+            defmt::error!("initiate_message_transfer: message too large");
+            return Err(BmError::PayloadTooLarge)
+        };
+        tx_message.start_round();
+        self.tx_message = Some(tx_message);
+
+        Ok(self.begin_transfer(dest, ttl, None))
+    }
+
+    // Checks that dest is reachable. The reply is timed and reported through get_echo_result.
+    pub fn initiate_echo_request(&mut self, dest: NetworkId, ttl: u8, millis: TimeType) -> Result<BmTransferId, BmError> {
+        let id = self.initiate_request(BmPacketTypes::EchoRequest, dest, ttl, Some(BmEchoRequest { timestamp: millis }.to_payload()))?;
+        self.echo_result = None;
+        Ok(id)
+    }
+
+    // Takes the result of the last echo exchange, None until a reply arrived
+    pub fn get_echo_result(&mut self) -> Option<BmEchoResult> {
+        self.echo_result.take()
+    }
+
+    // Records the relays on the way to dest. The path is reported through get_trace_result.
+    pub fn initiate_trace_request(&mut self, dest: NetworkId, ttl: u8) -> Result<BmTransferId, BmError> {
+        let id = self.initiate_request(BmPacketTypes::TraceRequest, dest, ttl, None)?;
+        self.trace_result = None;
+        Ok(id)
+    }
+
+    // Takes the path of the last trace, None until a reply arrived
+    pub fn get_trace_result(&mut self) -> Option<BmTraceResult> {
+        self.trace_result.take()
     }
 
     pub fn get_inbound_message_count(&mut self) -> usize {
         self.inbound.len()
     }
 
-    pub fn get_inbound_message(&mut self) -> Option<BmNetworkPacket> {
-        self.inbound.pop()
+    pub fn get_inbound_message(&mut self) -> Option<BmNetworkMessage> {
+        let entry = self.inbound.pop()?;
+        self.read_inbound(entry)
     }
 
-    pub fn run_engine(&mut self, current_time_millis: i64) -> BmEngineStatus {
-        let current_engine_status = self.engine_status.clone();
-        match current_engine_status {
-            BmEngineStatus::PerformingNetworkDiscovery => {
-                // TODO - Add some sort of time check to retry?
+    // Inbound queue for a single port, for applications without a handler
+    pub fn get_inbound_message_count_on_port(&mut self, port: u8) -> usize {
+        self.inbound.iter().filter(|entry| entry.port == port).count()
+    }
 
-                // Timeout on route discovery
-                // TODO - make timeout dynamic off the hop count and radio settings
-                // TODO - currently timeout includes tx time + rx time. Maybe change so timeout doesnt start until tx complete
-                if let Some(tx_comp_time) = self.outbound[self.working_outbound_index.unwrap()].tx_complete_timestamp {
-                    if current_time_millis - tx_comp_time > 10000 {    
-                        defmt::info!("run_engine: PerformingNetworkDiscovery - timeout");
-                        defmt::info!("current_time_millis={}", defmt::Display2Format(&current_time_millis));
-                        defmt::info!("tx_complete_timestamp={}", defmt::Display2Format(&tx_comp_time));  
-                        self.engine_status = BmEngineStatus::ErrorNoRoute;
-                    }
-                }                     
+    pub fn get_inbound_message_on_port(&mut self, port: u8) -> Option<BmNetworkMessage> {
+        // Newest first, the same order as get_inbound_message
+        let index = self.inbound.iter().rposition(|entry| entry.port == port)?;
+        let entry = self.inbound.remove(index);
+        self.read_inbound(entry)
+    }
+
+    pub fn get_rx_diagnostics(&self) -> &BmRxDiagnostics {
+        &self.rx_diagnostics
+    }
+
+    // Returns the number of transfers in flight
+    pub fn get_transfer_count(&self) -> usize {
+        self.transfers.len()
+    }
+
+    // State of a transfer in flight, or the outcome of a finished one the application has not read
+    pub fn get_transfer_state(&self, id: BmTransferId) -> Option<BmTransferState> {
+        if let Some(transfer) = self.transfers.iter().find(|transfer| transfer.id == id) {
+            return Some(transfer.get_state())
+        }
+        self.transfer_records.iter()
+            .find(|record| record.id == id)
+            .map(|record| record.state)
+    }
+
+    // Replaces the airtime based discovery and ack timeouts of a transfer, None goes back to them.
+    // Discovery retries still double it.
+    pub fn set_transfer_timeout(&mut self, id: BmTransferId, timeout_millis: Option<TimeType>) -> BmError {
+        let Some(index) = self.transfers.iter().position(|transfer| transfer.id == id) else {
+            return BmError::UnknownTransfer
+        };
+        self.transfers[index].timeout_override = timeout_millis;
+        if let Some(timeout_millis) = timeout_millis {
+            let transfer = &mut self.transfers[index];
+            transfer.wait_timeout = if transfer.status == BmEngineStatus::PerformingNetworkDiscovery {
+                timeout_millis << transfer.discovery_attempt.min(16)
             }
-            BmEngineStatus::RouteFound => {
-                // Remove node discovery packet from outbound
-                self.clear_working_packet();
+            else {
+                timeout_millis
+            };
+        }
+        BmError::None
+    }
 
-                // Find data payload and set working buffer index
-                if self.select_data_packet() {
-                    defmt::info!("run_engine: RouteFound -> SendingPayload");
+    // Takes the oldest engine event, None when all were drained
+    pub fn get_next_event(&mut self) -> Option<BmEngineEvent> {
+        if self.events.is_empty() {
+            return None
+        }
+        Some(self.events.remove(0))
+    }
 
-                    // Transition to send payload
-                    self.engine_status = BmEngineStatus::SendingPayload;
-                }                
-                else {
-                    defmt::warn!("run_engine: RouteFound -> Complete, could not find data pkt");
+    // Takes the oldest finished transfer, None when all outcomes were read
+    pub fn get_transfer_record(&mut self) -> Option<BmTransferRecord> {
+        if self.transfer_records.is_empty() {
+            return None
+        }
+        Some(self.transfer_records.remove(0))
+    }
 
-                    // Transition to complete
-                    self.engine_status = BmEngineStatus::Complete;
-                } 
+    // Steps every transfer. Returns the status of the oldest transfer before this step,
+    // Idle when there is none.
+    pub fn run_engine(&mut self, current_time_millis: i64) -> BmEngineStatus {
+        // Drop partial messages the originator gave up on
+        self.reassembly.expire(current_time_millis);
+
+        // Drop routes that were not refreshed
+        self.expire_routes(current_time_millis);
+
+        // Tell our neighbors who we hear
+        self.run_beacon(current_time_millis);
+
+        // Retransmit packets our next hops did not ack
+        self.run_link_acks(current_time_millis);
+
+        let current_engine_status = self.transfers.first()
+            .map(|transfer| transfer.status.clone())
+            .unwrap_or_default();
+
+        let mut index = 0;
+        while index < self.transfers.len() {
+            if self.run_transfer(index, current_time_millis) {
+                index += 1;
             }
-            BmEngineStatus::SendingPayload => {
-                self.send_data_payload();
+            else {
+                self.transfers.remove(index);
             }
-            BmEngineStatus::RetryingPayload => {
-                defmt::info!("run_engine: RetryingPayload -> SendingPayload");
-                
-                self.outbound[self.working_outbound_index.unwrap()].tx_count += 1;
+        }
 
-                // TODO - anything else to update?
+        // Return the current engine status, not the new status
+        current_engine_status
+    }
 
-                // Transition to send payload which will search for the best route
-                self.engine_status = BmEngineStatus::SendingPayload;
+    //-----------------------------------------------------------
+    // Private functions
+    //----------------------------------------------------------- 
+
+    // Queues a DataPayload whose payload already starts with the port and starts its transfer
+    fn queue_data_payload(&mut self, dest: NetworkId, ack: bool, ttl: u8, payload: BmNetworkPacketPayload) -> Result<BmTransferId, BmError> {
+        let error = self.check_transfer_slot(dest);
+        if error != BmError::None {
+            defmt::warn!("initiate_packet_transfer: busy");
+            return Err(error)
+        }
+
+        let seq = self.next_sequence_number();
+        let mut data_packet = BmNetworkPacket::new(
+            BmPacketTypes::DataPayload, 
+            self.table.get_local_network_id(),
+            None,
+            dest,
+            ttl,
+            ack,
+            Some(payload)
+        ).with_seq(seq)
+        .with_wait_for_reply();
+
+        // Payloads never go on air in plaintext once a network key is set
+        if let Some(key) = self.network_key {
+            let frame_counter = self.next_frame_counter();
+            if data_packet.encrypt_payload(&key, frame_counter).is_err() {
+                defmt::error!("initiate_packet_transfer: payload too large to encrypt");
+                return Err(BmError::PayloadTooLarge)
+            }
+        }
+
+        // Queue up data payload to send
+        if self.queue_outbound(data_packet).is_err() {
+            defmt::error!("Error queue full");
+            return Err(BmError::QueueFull)
+        }
+
+        Ok(self.begin_transfer(dest, ttl, Some(seq)))
+    }
+
+    // Starts a transfer of a request that is answered by dest, echo and trace
+    fn initiate_request(&mut self, packet_type: BmPacketTypes, dest: NetworkId, ttl: u8, payload: Option<BmNetworkPacketPayload>) -> Result<BmTransferId, BmError> {
+        let error = self.check_transfer_slot(dest);
+        if error != BmError::None {
+            defmt::warn!("initiate_request: busy");
+            return Err(error)
+        }
+
+        // Requests always wait for their reply, the ack bit drives the state machine
+        let seq = self.next_sequence_number();
+        let request_packet = BmNetworkPacket::new(
+            packet_type,
+            self.table.get_local_network_id(),
+            None,
+            dest,
+            ttl,
+            true,
+            payload
+        ).with_seq(seq)
+        .with_wait_for_reply();
+
+        if self.queue_outbound(request_packet).is_err() {
+            defmt::error!("Error queue full");
+            return Err(BmError::QueueFull)
+        }
+
+        Ok(self.begin_transfer(dest, ttl, Some(seq)))
+    }
+
+    // One transfer per destination, replies are matched on it. Every transfer leaves a
+    // record, so none starts while the records of the ones in flight would not fit.
+    fn check_transfer_slot(&self, dest: NetworkId) -> BmError {
+        if self.transfers.is_full() || self.transfers.iter().any(|transfer| transfer.dest == dest) {
+            return BmError::Busy
+        }
+        if self.transfers.len() + self.transfer_records.len() >= BM_MAX_TRANSFER_RECORDS {
+            return BmError::RecordsFull
+        }
+        BmError::None
+    }
+
+    // Adds a transfer for the packet just queued for dest, after a route discovery if there
+    // is no route yet. Fragmented messages pass no payload, they are queued once the route is known.
+    fn begin_transfer(&mut self, dest: NetworkId, ttl: u8, payload_seq: Option<u16>) -> BmTransferId {
+        self.transfer_id = self.transfer_id.wrapping_add(1);
+        let mut transfer = BmTransfer::new(self.transfer_id, dest, ttl, payload_seq);
+
+        // Check stack if we have route. Nodes can be known without one.
+        if self.table.get_next_hop(dest).is_none() && self.routing_mode == BmRoutingMode::Proactive {
+            // Only advertised routes are used, fail right away
+            defmt::warn!("begin_transfer: no advertised route");
+            transfer.working_seq = payload_seq;
+            transfer.status = BmEngineStatus::ErrorNoRoute;
+        }
+        else if self.table.get_next_hop(dest).is_none() {
+            // Start network discovery for destination node
+            let discovery_ttl = transfer.get_discovery_ttl();
+            transfer.working_seq = self.start_network_discovery(dest, discovery_ttl);
+            transfer.wait_timeout = self.get_reply_timeout(transfer.working_seq, discovery_ttl);
+            transfer.status = BmEngineStatus::PerformingNetworkDiscovery;
+        }
+        else {
+            // Set data packet as working packet and jump right into sending payload
+            transfer.working_seq = payload_seq;
+            transfer.status = BmEngineStatus::SendingPayload;
+        }
+
+        // Cannot fail, checked by check_transfer_slot
+        let _ = self.transfers.push(transfer);
+        self.transfer_id
+    }
+
+    // Queues a discovery request for dest and returns its sequence number
+    fn start_network_discovery(&mut self, dest: NetworkId, ttl: u8) -> Option<u16> {
+        defmt::info!("start_network_discovery: id={}", dest);
+
+        // Relays only answer with routes at least as fresh as the newest one we heard of
+        let dest_seq = self.table.find_node_by_id(dest).and_then(|node| node.get_dest_seq());
+        let payload = dest_seq.map(|dest_seq| BmDiscoveryPayload::new(None).with_dest_seq(Some(dest_seq)).to_payload());
+        let seq = self.next_sequence_number();
+        if self.queue_outbound(
+            BmNetworkPacket::new(
+                BmPacketTypes::RouteDiscoveryRequest, 
+                self.table.get_local_network_id(),
+                None,
+                dest,
+                ttl,
+                false,
+                payload
+            ).with_seq(seq)
+            .with_ok_to_transmit()
+            .with_wait_for_reply(),
+        ).is_err() {
+            defmt::error!("Error queue full");
+            return None
+        }
+        Some(seq)
+    }
+
+    // Replaces the discovery request of a transfer with one that reaches further
+    fn retry_network_discovery(&mut self, index: usize, working_index: usize) {
+        self.outbound.remove(working_index);
+
+        let transfer = &mut self.transfers[index];
+        transfer.discovery_attempt += 1;
+        let (dest, ttl) = (transfer.dest, transfer.get_discovery_ttl());
+        defmt::info!("run_engine: PerformingNetworkDiscovery - retry, ttl={}", ttl);
+
+        let working_seq = self.start_network_discovery(dest, ttl);
+        if working_seq.is_none() {
+            self.transfers[index].status = BmEngineStatus::ErrorNoRoute;
+        }
+
+        // Exponential backoff on top of the wider search
+        let timeout = match self.transfers[index].timeout_override {
+            Some(timeout) => timeout,
+            None => self.get_reply_timeout(working_seq, ttl),
+        };
+        let transfer = &mut self.transfers[index];
+        transfer.wait_timeout = timeout << transfer.discovery_attempt.min(16);
+        transfer.working_seq = working_seq;
+    }
+
+    // Airtime based time to wait for the answer to one of our packets, over hops links each way
+    fn get_reply_timeout(&mut self, seq: Option<u16>, hops: u8) -> TimeType {
+        let packet_len = self.find_outbound(seq)
+            .and_then(|index| self.outbound[index].to_bytes())
+            .map_or(BM_MAX_OTA_SIZE, |bytes| bytes.len());
+        self.radio_settings.get_round_trip_timeout_millis(packet_len, hops)
+    }
+
+    // Sets how long the working packet of a transfer waits for its ack, from the distance
+    // of the route it is sent on. Unknown distances assume the full TTL.
+    fn start_ack_timeout(&mut self, index: usize) {
+        let transfer = &self.transfers[index];
+        let (dest, ttl, working_seq) = (transfer.dest, transfer.ttl, transfer.working_seq);
+        let timeout = match transfer.timeout_override {
+            Some(timeout) => timeout,
+            None => {
+                let hops = self.table.get_distance(dest).map_or(ttl, |distance| distance.saturating_add(1).min(ttl));
+                self.get_reply_timeout(working_seq, hops)
+            }
+        };
+        self.transfers[index].wait_timeout = timeout;
+    }
+
+    // Steps the state machine of one transfer. Returns false once the transfer is done.
+    fn run_transfer(&mut self, index: usize, current_time_millis: i64) -> bool {
+        let working_index = self.find_outbound(self.transfers[index].working_seq);
+
+        match self.transfers[index].status {
+            BmEngineStatus::PerformingNetworkDiscovery => {
+                // Timeout on route discovery
+                // TODO - currently timeout includes tx time + rx time. Maybe change so timeout doesnt start until tx complete
+                let Some(working_index) = working_index else {
+                    defmt::error!("run_engine: PerformingNetworkDiscovery, no discovery packet");
+                    self.transfers[index].status = BmEngineStatus::ErrorNoRoute;
+                    return true
+                };
+                if let Some(tx_comp_time) = self.outbound[working_index].tx_complete_timestamp {
+                    if current_time_millis - tx_comp_time > self.transfers[index].wait_timeout {    
+                        defmt::info!("run_engine: PerformingNetworkDiscovery - timeout");
+                        defmt::info!("current_time_millis={}", defmt::Display2Format(&current_time_millis));
+                        defmt::info!("tx_complete_timestamp={}", defmt::Display2Format(&tx_comp_time));  
+
+                        if self.transfers[index].can_retry_discovery() {
+                            self.retry_network_discovery(index, working_index);
+                        }
+                        else {
+                            self.transfers[index].status = BmEngineStatus::ErrorNoRoute;
+                        }
+                    }
+                }                     
+            }
+            BmEngineStatus::RouteFound => {
+                // Remove node discovery packet from outbound
+                if let Some(working_index) = working_index {
+                    self.outbound.remove(working_index);
+                }
+                let payload_seq = self.transfers[index].payload_seq;
+                self.transfers[index].working_seq = None;
+
+                // Set the data payload as working packet
+                if self.transfers[index].is_fragmented() {
+                    defmt::info!("run_engine: RouteFound -> SendingPayload, fragmented");
+                    self.transfers[index].status = BmEngineStatus::SendingPayload;
+                }
+                else if self.find_outbound(payload_seq).is_some() {
+                    defmt::info!("run_engine: RouteFound -> SendingPayload");
+
+                    // Transition to send payload
+                    self.transfers[index].working_seq = payload_seq;
+                    self.transfers[index].status = BmEngineStatus::SendingPayload;
+                }                
+                else {
+                    defmt::warn!("run_engine: RouteFound -> Complete, could not find data pkt");
+
+                    // Transition to complete
+                    self.complete_transfer(index, BmTransferState::Failed(BmTransferError::Dropped));
+                } 
+            }
+            BmEngineStatus::SendingPayload => {
+                // Fragments are queued until the last one of the round becomes the working packet
+                if self.transfers[index].is_fragmented() && working_index.is_none() {
+                    self.send_fragments(index);
+                }
+                else {
+                    self.send_data_payload(index, current_time_millis);
+                }
+            }
+            BmEngineStatus::RetryingPayload => {
+                defmt::info!("run_engine: RetryingPayload -> SendingPayload");
+                
+                if let Some(working_index) = working_index {
+                    self.outbound[working_index].tx_count += 1;
+                    self.reencrypt_retry(working_index);
+                }
+
+                // Transition to send payload which will search for the best route
+                self.transfers[index].status = BmEngineStatus::SendingPayload;
             }
             BmEngineStatus::WaitingForAck => {
                 // Handle timeout on data payload
-                // TODO - currently timeout includes tx time + rx time. Maybe change so timeout doesnt start until tx complete
-                if let Some(tx_comp_time) = self.outbound[self.working_outbound_index.unwrap()].tx_complete_timestamp {
-                    if current_time_millis - tx_comp_time > 10000 {    
+                let Some(working_index) = working_index else {
+                    defmt::error!("run_engine: WaitingForAck, no working packet");
+                    self.complete_transfer(index, BmTransferState::Failed(BmTransferError::Dropped));
+                    return true
+                };
+                if let Some(tx_comp_time) = self.outbound[working_index].tx_complete_timestamp {
+                    if current_time_millis - tx_comp_time > self.transfers[index].wait_timeout {    
                         defmt::info!("run_engine: WaitingForAck -> ErrorNoAck");
                         defmt::info!("current_time_millis={}", defmt::Display2Format(&current_time_millis));
                         defmt::info!("tx_complete_timestamp={}", defmt::Display2Format(&tx_comp_time));  
     
-                        // Record error on that route
-                        self.table.set_node_error(
-                            self.outbound[self.working_outbound_index.unwrap()].get_next_hop(), 
-                            current_time_millis);
+                        // Record error on the route the packet took
+                        let pkt = &mut self.outbound[working_index];
+                        self.table.set_route_failure(pkt.get_destination(), pkt.get_next_hop());
 
-                        self.engine_status = BmEngineStatus::ErrorNoAck;
+                        self.transfers[index].status = BmEngineStatus::ErrorNoAck;
                     }
                 }                
             }
             BmEngineStatus::AckReceieved => {
                 defmt::info!("run_engine: AckReceieved -> Complete");
 
-                self.engine_status = BmEngineStatus::Complete;
+                self.complete_transfer(index, BmTransferState::Delivered);
             }
             BmEngineStatus::ErrorNoRoute => {
                 defmt::info!("run_engine: ErrorNoRoute -> Complete");
 
-                self.engine_status = BmEngineStatus::Complete;
+                self.complete_transfer(index, BmTransferState::Failed(BmTransferError::NoRoute));
             }
             BmEngineStatus::ErrorNoAck => {
                 // Check if tx count is below threshold
-                if self.outbound[self.working_outbound_index.unwrap()].tx_count < BM_PACKET_RETRY_COUNT {
+                if working_index.is_some_and(|working_index| self.outbound[working_index].tx_count < BM_PACKET_RETRY_COUNT) {
                     defmt::info!("run_engine: ErrorNoAck -> RetryingPayload");
 
-                    self.engine_status = BmEngineStatus::RetryingPayload;
+                    self.transfers[index].status = BmEngineStatus::RetryingPayload;
                 }
                 else {                    
                     defmt::info!("run_engine: ErrorNoAck -> Complete");
 
-                    self.engine_status = BmEngineStatus::Complete;
+                    self.complete_transfer(index, BmTransferState::Failed(BmTransferError::NoAck));
                 }
             }
             BmEngineStatus::Complete => {
                 // Wait for transmit to complete before erasing working packet
-                if !self.outbound[self.working_outbound_index.unwrap()].is_ok_to_transmit() {
-                    defmt::info!("run_engine: Complete");
-
-                    self.clear_working_packet();
+                let transmitting = working_index
+                    .is_some_and(|working_index| self.outbound[working_index].is_ok_to_transmit());
+                if transmitting {
+                    return true
+                }
+                defmt::info!("run_engine: Complete");
 
-                    self.engine_status = BmEngineStatus::Idle;
+                // The payload is still queued when its route was never found
+                let transfer = self.transfers[index].clone();
+                self.remove_outbound(transfer.working_seq);
+                self.remove_outbound(transfer.payload_seq);
+                if transfer.is_fragmented() {
+                    self.tx_message = None;
                 }
+                self.record_transfer(&transfer);
+                return false
             }
             _ => { }
         }
-        // Return the current engine status, not the new status
-        current_engine_status
+        true
     }
 
-    //-----------------------------------------------------------
-    // Private functions
-    //----------------------------------------------------------- 
+    // Re-encrypts the payload of a retry under a fresh frame counter, the destination may
+    // have seen the old one. Plaintext packets such as echo and trace requests have no
+    // payload MIC to renew, they only get a new hop MIC when they are sent again.
+    fn reencrypt_retry(&mut self, index: usize) {
+        let Some(key) = self.network_key else {
+            return
+        };
+        if !self.outbound[index].get_info().encrypted() {
+            return
+        }
 
-    fn start_network_discovery(&mut self, dest: NetworkId, ttl: u8) {
-        defmt::info!("start_network_discovery: id={}", dest);
+        let frame_counter = self.next_frame_counter();
+        let packet = &mut self.outbound[index];
+        if packet.decrypt_payload(&key).and_then(|_| packet.encrypt_payload(&key, frame_counter)).is_err() {
+            defmt::error!("rb_engine: unable to re-encrypt retry");
+        }
+    }
 
-        if self.outbound.push(
-            BmNetworkPacket::new(
-                BmPacketTypes::RouteDiscoveryRequest, 
-                self.table.get_local_network_id(),
-                None,
-                dest,
-                ttl,
-                false,
-                None
-            ).with_ok_to_transmit()
-            .with_wait_for_reply(),
-        ).is_err() {
-            defmt::error!("Error queue full");
+    // Verifies a received frame against the network key, in the receive buffer.
+    // Payloads are checked but left encrypted, so relays forward them as received.
+    fn authenticate_packet(&mut self, packet: &BmPacketView) -> Result<(), BmSecurityError> {
+        let local_id = self.table.get_local_network_id();
+        let for_us = packet.get_destination() == local_id;
+
+        let Some(key) = self.network_key else {
+            // Without a key we can still relay secured packets, just not read them
+            if for_us && packet.get_info().encrypted() {
+                return Err(BmSecurityError::AuthFailed)
+            }
+            return Ok(())
+        };
+
+        // Every packet is signed by the node we heard it from, so the transmitter and
+        // hop count the routing table learns from are authentic
+        let hop_counter = packet.verify_hop(&key)?;
+
+        let payload_counter = if packet.get_info().encrypted() {
+            Some(packet.verify_payload(&key)?)
         }
+        else if packet.get_packet_type().is_data() {
+            // Application data must be encrypted as well
+            return Err(BmSecurityError::Missing)
+        }
+        else {
+            None
+        };
 
-        self.working_outbound_index = Some(self.outbound.len() - 1);
-        self.engine_status = BmEngineStatus::PerformingNetworkDiscovery;
+        // Only authentic counters of frames we handle may move the replay windows. An
+        // overheard hop would otherwise make the copy relayed to us look like a replay.
+        // The hop counter belongs to the transmitter and is checked by its next hop, the
+        // payload counter belongs to the originator and is checked by the destination.
+        // Overheard frames are still checked, since routes are learned from them. A recorded
+        // frame replayed after its transmitter went away must not bring its route back.
+        let handled = for_us || packet.get_next_hop() == local_id || packet.get_packet_type().is_flood();
+        if handled && !self.table.check_frame_counter(packet.get_source(), hop_counter) {
+            return Err(BmSecurityError::Replayed)
+        }
+        if !handled && !self.table.is_frame_counter_fresh(packet.get_source(), hop_counter) {
+            return Err(BmSecurityError::Replayed)
+        }
+        if let (true, Some(frame_counter)) = (for_us, payload_counter) {
+            if !self.table.check_frame_counter(packet.get_originator(), frame_counter) {
+                return Err(BmSecurityError::Replayed)
+            }
+        }
+        Ok(())
     }
 
-    fn clear_working_packet(&mut self) {
-        if let Some(index) = self.working_outbound_index {
-            // Remove working buffer
-            self.outbound.remove(index);
-            // Invalidate working buff index
-            self.working_outbound_index = None;
+    // Pushes a packet to the outbound queue. Packets we originate take our header format,
+    // relayed ones keep the format they were received in. With a network key, every packet
+    // is signed with our next frame counter, encrypted ones included.
+    fn queue_outbound(&mut self, packet: BmNetworkPacket) -> Result<(), BmError> {
+        self.outbound.push(packet).map_err(|_| BmError::QueueFull)?;
+        self.prepare_outbound(self.outbound.len() - 1)
+    }
+
+    // Finishes a packet placed in the outbound queue, see queue_outbound. A packet that
+    // cannot be signed is removed again.
+    fn prepare_outbound(&mut self, index: usize) -> Result<(), BmError> {
+        let packet = &mut self.outbound[index];
+        if packet.get_originator() == self.table.get_local_network_id() {
+            packet.set_compact_header(self.compact_header);
         }
-        else {
-            defmt::error!("No working index");
+        Self::request_hop_ack(self.link_acks, self.passive_acks, packet);
+
+        if let Some(key) = self.network_key {
+            let frame_counter = self.next_frame_counter();
+            if self.outbound[index].sign_hop(&key, frame_counter).is_err() {
+                defmt::error!("rb_engine: unable to sign packet");
+                self.outbound.remove(index);
+                return Err(BmError::PayloadTooLarge)
+            }
         }
+        Ok(())
     }
 
-    fn broadcast_packet(&mut self, mut packet_to_broadcast: BmNetworkPacket) {
-        // Update source with our network id
-        packet_to_broadcast.set_source(self.table.get_local_network_id());
-        // Increment hop count
-        packet_to_broadcast.increment_hop_count();
-        // Set Ok to transmit
-        packet_to_broadcast.set_ok_to_transmit();
-        // Push updated packet to outbound queue
-        self.outbound.push(packet_to_broadcast).unwrap();
+    // Hands out the next frame counter, reserving a new block in the store when
+    // the previous one is used up
+    fn next_frame_counter(&mut self) -> u32 {
+        self.frame_counter = self.frame_counter.wrapping_add(1);
+        if self.frame_counter > self.frame_counter_reserved {
+            self.frame_counter_reserved = self.frame_counter.saturating_add(BM_FRAME_COUNTER_PERSIST_INTERVAL - 1);
+            if let Some(store) = self.frame_counter_store.as_mut() {
+                store.store(self.frame_counter_reserved);
+            }
+        }
+        self.frame_counter
     }
 
-    fn route_packet(&mut self, mut packet_to_route: BmNetworkPacket) -> bool {
-        // Check if we have route to destination
-        if let Some(next_hop) = self.table.get_next_hop(packet_to_route.get_destination()) {
-            // Update source with our network id
-            packet_to_route.set_source(self.table.get_local_network_id());
-            // Increment hop count
-            packet_to_route.increment_hop_count();
-            // Update next_hop from routing table
-            packet_to_route.set_next_hop(Some(next_hop));
-            // Set Ok to transmit
-            packet_to_route.set_ok_to_transmit();
-            // Push updated packet to outbound queue
-            self.outbound.push(packet_to_route).unwrap();
-            return true
+    fn next_sequence_number(&mut self) -> u16 {
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+        self.sequence_number
+    }
+
+    // Finishes a transfer once its working packet is sent, with the outcome reported to the application
+    fn complete_transfer(&mut self, index: usize, outcome: BmTransferState) {
+        self.transfers[index].outcome = outcome;
+        self.transfers[index].status = BmEngineStatus::Complete;
+    }
+
+    // Keeps the outcome of a finished transfer until the application reads it
+    fn record_transfer(&mut self, transfer: &BmTransfer) {
+        // Cannot fail, check_transfer_slot kept room for every transfer in flight
+        let _ = self.transfer_records.push(BmTransferRecord {
+            id: transfer.id,
+            dest: transfer.dest,
+            state: transfer.outcome,
+        });
+
+        let (id, dest) = (transfer.id, transfer.dest);
+        match transfer.outcome {
+            BmTransferState::Sent => self.push_event(BmEngineEvent::TransferSent { id, dest }),
+            BmTransferState::Delivered => self.push_event(BmEngineEvent::TransferDelivered { id, dest }),
+            BmTransferState::Failed(error) => self.push_event(BmEngineEvent::TransferFailed { id, dest, error }),
+            _ => { }
         }
-        false
     }
 
-    fn send_data_payload(&mut self) {
-        if let Some(working_index) = self.working_outbound_index {
-            let dest_id = self.outbound[working_index].get_destination();
+    // Queues an event for the application. The oldest is dropped when nobody drains them.
+    fn push_event(&mut self, event: BmEngineEvent) {
+        if self.events.is_full() {
+            defmt::warn!("rb_engine: event queue full, dropping oldest");
+            self.events.remove(0);
+        }
+        // Cannot fail, room made above
+        let _ = self.events.push(event);
+    }
 
-            // Check if we have route to destination
-            if let Some(next_hop) = self.table.get_next_hop(dest_id) {
-                // Update outbound packet with new next_hop
-                self.outbound[working_index].set_next_hop(Some(next_hop));
+    // Updates the routing table with a route to orig, reporting new routes and neighbors
+    fn learn_route(&mut self, orig: NetworkId, next_hop: NetworkId, distance: u8, millis: TimeType, rssi: RssiType) {
+        self.learn_route_with_seq(orig, next_hop, distance, None, millis, rssi);
+    }
 
-                // Mark packet as ok to transmit
-                self.outbound[working_index].set_wait_for_reply();
-                self.outbound[working_index].set_ok_to_transmit();
+    // Same as learn_route, routes advertised with a destination sequence number are checked
+    // against it first
+    fn learn_route_with_seq(&mut self, orig: NetworkId, next_hop: NetworkId, distance: u8, seq: Option<u16>, millis: TimeType, rssi: RssiType) {
+        let had_route = self.table.get_next_hop(orig).is_some();
+        let was_neighbor = self.table.is_neighbor(orig);
 
-                // Check if Ack is required and transition to next state
-                if self.outbound[working_index].get_info().required_ack() {
-                    defmt::info!("run_engine: SendingPayload -> WaitingForAck");
-                    self.engine_status = BmEngineStatus::WaitingForAck;
-                }
-                else {                    
-                    defmt::info!("run_engine: SendingPayload -> Complete");
-                    self.engine_status = BmEngineStatus::Complete;
-                }
-            }
-            else {
-                defmt::warn!("run_engine: SendingPayload -> ErrorNoRoute");
+        match seq {
+            Some(seq) => if !self.table.update_node_route_seq(orig, next_hop, distance, seq, millis, rssi) {
+                defmt::info!("rb_engine: stale advertised route, seq={}", seq);
+                return
+            },
+            None => self.table.update_node_route(orig, next_hop, distance, millis, rssi),
+        }
 
-                // Transition to complete
-                self.engine_status = BmEngineStatus::ErrorNoRoute; 
-            }
+        if !had_route && self.table.get_next_hop(orig).is_some() {
+            self.push_event(BmEngineEvent::RouteFound { dest: orig });
         }
-        else {
-            defmt::error!("run_engine: SendingPayload, no working index");
+        if !was_neighbor && self.table.is_neighbor(orig) {
+            self.push_event(BmEngineEvent::NeighborFound { id: orig });
         }
     }
 
-    // Function to find the data payload packet in the outbound queue. Save that index
-    fn select_data_packet(&mut self) -> bool {
-        for (index, pkt) in self.outbound.iter_mut().enumerate() {
-            if pkt.is_ok_to_transmit() == false && 
-               pkt.tx_count == 0 &&
-               pkt.packet_type == BmPacketTypes::DataPayload {
-                self.working_outbound_index = Some(index);
-                return true
+    // Learns routes to the relays a discovery packet went through, all via the node we heard
+    // it from. A relay reply also teaches the route to the node it answered for, at its end
+    // to end distance and age. Responses carry the destination sequence number of that route.
+    fn learn_discovery_path(&mut self, packet: &BmPacketView, millis: TimeType, rssi: RssiType) {
+        let Some(discovery) = BmDiscoveryPayload::from_payload(packet.get_payload()) else {
+            defmt::warn!("rb_engine: malformed discovery payload");
+            return
+        };
+        let local_id = self.table.get_local_network_id();
+        let source = packet.get_source();
+        let hop_count = packet.get_hop_count();
+
+        // The i-th relay is hop_count - i hops behind the node we heard
+        for (index, &relay) in discovery.get_path().iter().enumerate() {
+            if relay != local_id && relay != packet.get_originator() {
+                let distance = hop_count.saturating_sub(index as u8 + 1);
+                self.learn_route(relay, source, distance, millis, rssi);
             }
         }
-        false
+
+        // Requests carry the originator's number, not one of the route
+        let dest_seq = discovery.dest_seq.filter(|_| packet.get_packet_type() == BmPacketTypes::RouteDiscoveryResponse);
+        match discovery.reply {
+            Some(reply) if reply.dest != local_id => {
+                // Hops to the relay, the link from the relay, then the relay's own distance
+                let distance = hop_count.saturating_add(1).saturating_add(reply.distance);
+                self.learn_route_with_seq(reply.dest, source, distance, dest_seq, millis - reply.age_millis as TimeType, rssi);
+            }
+            Some(_) => { }
+            None => if dest_seq.is_some() {
+                self.learn_route_with_seq(packet.get_originator(), source, hop_count, dest_seq, millis, rssi);
+            },
+        }
     }
-    
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    // Answers a discovery request for its destination when we hold a fresh, short route
+    // that does not lead back to the requester and fits the TTL of the request. The route
+    // must come with a destination sequence number no older than the one in the request,
+    // routes without one may be left over from before a link broke and lead into a loop.
+    // Returns true if we answered.
+    fn reply_for_destination(&mut self, request: &BmPacketView, millis: TimeType) -> bool {
+        let dest = request.get_destination();
+        let Some(dest_seq) = self.table.find_node_by_id(dest).and_then(|node| node.get_dest_seq()) else {
+            return false
+        };
+        let requested_seq = BmDiscoveryPayload::from_payload(request.get_payload()).and_then(|discovery| discovery.dest_seq);
+        if requested_seq.is_some_and(|requested_seq| (dest_seq.wrapping_sub(requested_seq) as i16) < 0) {
+            return false
+        }
+        let Some(mut route) = self.table.get_best_route(dest) else {
+            return false
+        };
+        let next_hop = route.get_next_hop();
+        let distance = route.get_distance();
+        let age = millis - route.get_timestamp_millis();
 
-    // Satisfy defmt linker symbol for host unit tests
-    #[no_mangle]
-    fn _defmt_timestamp() -> u64 {
-        0
+        if age > BM_INTERMEDIATE_REPLY_MAX_AGE_MS ||
+           distance > BM_INTERMEDIATE_REPLY_MAX_DISTANCE ||
+           route.get_failures() > 0 ||
+           next_hop == request.get_source() ||
+           next_hop == request.get_originator() {
+            return false
+        }
+
+        // Links to us plus the links from us to dest
+        let total_hops = request.get_hop_count() as u16 + 2 + distance as u16;
+        if total_hops > request.get_info().ttl() as u16 {
+            return false
+        }
+
+        let reply = BmDiscoveryReply { dest, distance, age_millis: age.clamp(0, u32::MAX as TimeType) as u32 };
+        let seq = self.next_sequence_number();
+        self.queue_outbound(
+            BmNetworkPacket::new(
+                BmPacketTypes::RouteDiscoveryResponse,
+                self.table.get_local_network_id(),
+                request.get_source(),
+                request.get_originator(),
+                request.get_info().ttl(),
+                false,
+                Some(BmDiscoveryPayload::new(Some(reply)).with_dest_seq(Some(dest_seq)).to_payload())
+            )
+            .with_seq(seq)
+            .with_ok_to_transmit(),
+        ).is_ok()
     }
 
-    #[test]
-    fn test_engine_initialization() {
-        // Initialize engine with random network id 
-        let mut bm_engine = BmNetworkEngine::new(Some(5));
-        
-        // Initial state should be Idle and outbound/inbound buffers empty
-        assert_eq!(bm_engine.get_next_outbound_packet(), None);
-        assert_eq!(bm_engine.get_inbound_message_count(), 0);
-        assert_eq!(bm_engine.run_engine(0), BmEngineStatus::Idle);
+    // Sends a neighbor table every beacon interval. The first one goes out an interval
+    // after the engine first runs, once there was time to hear the neighbors.
+    fn run_beacon(&mut self, current_time_millis: TimeType) {
+        let Some(interval) = self.beacon_interval else {
+            return
+        };
+        match self.next_beacon_millis {
+            Some(next_beacon_millis) if current_time_millis >= next_beacon_millis => {
+                if self.routing_mode != BmRoutingMode::Reactive {
+                    self.next_dest_seq();
+                }
+                self.send_neighbor_table();
+                self.next_beacon_millis = Some(current_time_millis + interval);
+            }
+            Some(_) => { }
+            None => self.next_beacon_millis = Some(current_time_millis + interval),
+        }
     }
 
-    #[test]
-    fn test_initiate_packet_transfer_without_route_triggers_discovery() {
-        let mut bm_engine = BmNetworkEngine::new(Some(1));
-        let dest_id = Some(2);
-        let payload = BmNetworkPacketPayload::default();
+    // Raises our destination sequence number, skipping 0. The first one is drawn from the
+    // RNG when there is one, so nodes do not take us for stale after a reboot.
+    fn next_dest_seq(&mut self) {
+        if self.dest_seq == 0 {
+            self.dest_seq = self.rng.as_mut().map_or(0, |rng| rng.next_u32() as u16);
+        }
+        self.dest_seq = self.dest_seq.wrapping_add(1).max(1);
+    }
 
-        // Initiating transfer to an unknown route should start network discovery
-        let err = bm_engine.initiate_packet_transfer(dest_id, true, 5, payload);
-        assert_eq!(err, BmError::None);
+    // Broadcasts the next BM_NEIGHBOR_BEACON_ENTRIES of our neighbors, one hop only. In the
+    // proactive and hybrid modes it advertises the best route to the next
+    // BM_ROUTE_ADVERTISE_ENTRIES nodes instead, with their destination sequence numbers.
+    // Sent without entries as well, so nodes that hear it learn us as their neighbor.
+    fn send_neighbor_table(&mut self) {
+        let (advertise, max_entries) = match self.routing_mode {
+            BmRoutingMode::Reactive => (false, BM_NEIGHBOR_BEACON_ENTRIES),
+            _ => (true, BM_ROUTE_ADVERTISE_ENTRIES),
+        };
+        let mut neighbor_table = BmNeighborTable::new(self.dest_seq);
+        let num_nodes = self.table.get_num_nodes();
+        let mut visited = 0;
+        while visited < num_nodes && neighbor_table.get_entries().len() < max_entries {
+            let index = (self.beacon_cursor + visited) % num_nodes;
+            visited += 1;
 
-        // State machine should transition to PerformingNetworkDiscovery
-        assert_eq!(bm_engine.run_engine(0), BmEngineStatus::PerformingNetworkDiscovery);
+            let Some(node) = self.table.get_node_by_idx(index) else {
+                continue
+            };
+            let route = match advertise {
+                true => node.get_best_route(),
+                false => node.get_route_via(node.dest_id),
+            };
+            if let Some(route) = route {
+                let rssi = route.get_avg_rssi().clamp(RssiType::MIN as i32, RssiType::MAX as i32) as RssiType;
+                let seq = node.get_dest_seq().unwrap_or(0);
+                neighbor_table.push(BmNeighborEntry { id: node.dest_id, rssi, distance: route.get_distance(), seq });
+            }
+        }
+        self.beacon_cursor = if num_nodes > 0 { (self.beacon_cursor + visited) % num_nodes } else { 0 };
 
-        // A Route Discovery Request packet should be generated and ready for transmit
-        let next_pkt = bm_engine.get_next_outbound_packet();
-        assert!(next_pkt.is_some());
-        
-        let pkt = next_pkt.unwrap();
-        assert_eq!(pkt.packet_type, BmPacketTypes::RouteDiscoveryRequest);
-        assert_eq!(pkt.get_destination(), dest_id);
+        defmt::info!("rb_engine: Tx Neighbor table, entries={}", neighbor_table.get_entries().len());
+        let seq = self.next_sequence_number();
+        if self.queue_outbound(
+            BmNetworkPacket::new(
+                BmPacketTypes::BcastNeighborTable,
+                self.table.get_local_network_id(),
+                None,
+                None,
+                1,
+                false,
+                Some(neighbor_table.to_payload())
+            )
+            .with_seq(seq)
+            .with_ok_to_transmit(),
+        ).is_err() {
+            defmt::error!("rb_engine: Error queue full");
+        }
     }
 
-    #[test]
-    fn test_busy_when_initiating_transfer_twice() {
-        let mut bm_engine = BmNetworkEngine::new(Some(1));
-        let payload = BmNetworkPacketPayload::default();
+    // Learns the nodes listed by the sender of a neighbor table as routes through it, one hop
+    // further than the sender's own. The route is no better than the weaker of its links.
+    // Entries with a destination sequence number only replace routes that are older or longer.
+    fn receive_neighbor_table(&mut self, packet: &BmPacketView, millis: TimeType, rssi: RssiType) {
+        let Some(neighbor_table) = BmNeighborTable::from_payload(packet.get_payload()) else {
+            defmt::warn!("rb_engine: malformed neighbor table");
+            return
+        };
+        let local_id = self.table.get_local_network_id();
+        let source = packet.get_source();
 
-        // First transfer succeeds
-        let err1 = bm_engine.initiate_packet_transfer(Some(2), true, 5, payload.clone());
-        assert_eq!(err1, BmError::None);
+        // The direct route to the sender was learned with the packet, only its number is new.
+        // Heard from the sender itself, an older number means it restarted.
+        if neighbor_table.seq != 0 {
+            if let Some(node) = self.table.find_node_by_id(source) {
+                if !node.accept_seq(source, packet.get_hop_count(), neighbor_table.seq) {
+                    defmt::info!("rb_engine: neighbor restarted its sequence numbers, seq={}", neighbor_table.seq);
+                    node.restart_seq(neighbor_table.seq);
+                }
+            }
+        }
 
-        // Second transfer while engine is active should return Busy
-        let err2 = bm_engine.initiate_packet_transfer(Some(3), true, 5, payload);
-        assert_eq!(err2, BmError::Busy);
+        for entry in neighbor_table.get_entries() {
+            if entry.id == local_id || entry.id == source {
+                continue
+            }
+            let distance = packet.get_hop_count().saturating_add(1).saturating_add(entry.distance);
+            let seq = Some(entry.seq).filter(|&seq| seq != 0);
+            self.learn_route_with_seq(entry.id, source, distance, seq, millis, rssi.min(entry.rssi));
+        }
     }
 
-    #[test]
-    fn test_network_discovery_timeout() {
-        let mut bm_engine = BmNetworkEngine::new(Some(1));
-        let payload = BmNetworkPacketPayload::default();
-
-        // Initiate transfer
-        let _ = bm_engine.initiate_packet_transfer(Some(2), true, 5, payload);
-        bm_engine.run_engine(0); // Transition into PerformingNetworkDiscovery
+    // Drops routes older than BM_ROUTE_TIMEOUT_MS, reporting routes and neighbors that are gone.
+    // Nodes whose last route expired are dropped as well, so the table does not fill up
+    // with nodes we no longer hear of.
+    fn expire_routes(&mut self, current_time_millis: TimeType) {
+        let oldest_millis = current_time_millis - BM_ROUTE_TIMEOUT_MS;
+        let mut index = 0;
+        while let Some(node) = self.table.get_node_by_idx(index) {
+            let was_neighbor = node.is_neighbor();
+            if !node.expire_routes(oldest_millis) {
+                index += 1;
+                continue
+            }
 
-        // Simulate transmit finishing at t = 1000ms
+            let id = node.dest_id;
+            let is_neighbor = node.is_neighbor();
+            let has_route = node.get_best_route().is_some();
+            if was_neighbor && !is_neighbor {
+                self.push_event(BmEngineEvent::NeighborLost { id });
+            }
+            if !has_route {
+                self.push_event(BmEngineEvent::RouteLost { dest: id });
+                self.table.remove_node_by_idx(index);
+                continue
+            }
+            index += 1;
+        }
+    }
+
+    // Index of a packet we originated, by sequence number
+    fn find_outbound(&mut self, seq: Option<u16>) -> Option<usize> {
+        let seq = seq?;
+        let local_id = self.table.get_local_network_id();
+        self.outbound.iter_mut()
+            .position(|pkt| pkt.get_originator() == local_id && pkt.get_seq() == seq)
+    }
+
+    fn remove_outbound(&mut self, seq: Option<u16>) {
+        if let Some(index) = self.find_outbound(seq) {
+            self.outbound.remove(index);
+        }
+    }
+
+    fn broadcast_packet(&mut self, packet_to_broadcast: &mut BmPacketView, millis: TimeType, rssi: RssiType) {
+        self.relay_packet(packet_to_broadcast, None, millis, rssi);
+    }
+
+    fn route_packet(&mut self, packet_to_route: &mut BmPacketView, millis: TimeType, rssi: RssiType) -> bool {
+        // Check if we have route to destination
+        if let Some(next_hop) = self.table.get_next_hop(packet_to_route.get_destination()) {
+            self.relay_packet(packet_to_route, Some(next_hop), millis, rssi);
+            return true
+        }
+        false
+    }
+
+    // Rewrites the header in the receive buffer and copies the frame straight into a free
+    // outbound slot, the one copy that goes on air. Floods keep their next hop. Trace
+    // requests also get our hop record appended.
+    fn relay_packet(&mut self, packet_to_relay: &mut BmPacketView, next_hop: NetworkId, millis: TimeType, rssi: RssiType) {
+        let local_id = self.table.get_local_network_id();
+        let prev_hop = packet_to_relay.get_source();
+
+        // Update source with our network id and next_hop from routing table
+        let rewritten = packet_to_relay.set_source(local_id) &&
+            (next_hop.is_none() || packet_to_relay.set_next_hop(next_hop));
+        // Increment hop count
+        packet_to_relay.increment_hop_count();
+
+        if self.outbound.push(BmNetworkPacket::default()).is_err() {
+            defmt::error!("rb_engine: Error queue full");
+            return
+        }
+        let index = self.outbound.len() - 1;
+        if packet_to_relay.copy_to(&mut self.outbound[index]).is_err() {
+            self.outbound.pop();
+            return
+        }
+
+        let packet = &mut self.outbound[index];
+        if !rewritten {
+            // Our id does not fit the compact header, the queued packet falls back to the full one
+            packet.set_source(local_id);
+            if next_hop.is_some() {
+                packet.set_next_hop(next_hop);
+            }
+        }
+
+        match packet.packet_type {
+            BmPacketTypes::TraceRequest => Self::append_trace_hop(local_id, packet, rssi),
+            BmPacketTypes::RouteDiscoveryRequest |
+            BmPacketTypes::RouteDiscoveryResponse => Self::append_discovery_hop(local_id, packet),
+            _ => {}
+        }
+
+        packet.prev_hop = prev_hop;
+
+        // Hold the relay back by a random delay, neighbors that heard the same packet pick other delays
+        if let Some(rng) = self.rng.as_mut() {
+            let jitter = rng.next_u32() as i64 % (BM_RELAY_JITTER_MS + 1);
+            packet.tx_not_before = Some(millis + jitter);
+        }
+
+        // Set Ok to transmit
+        packet.set_ok_to_transmit();
+        // Sign the relay, an unsigned one would be dropped by keyed neighbors
+        let _ = self.prepare_outbound(index);
+    }
+
+    // Asks the next hop of a unicast packet for a LinkAck when link acks are on. With passive
+    // acks we listen for the next hop forwarding the packet instead, apart from the last hop.
+    fn request_hop_ack(link_acks: bool, passive_acks: bool, packet: &mut BmNetworkPacket) {
+        let unicast = !packet.packet_type.is_flood() &&
+            packet.packet_type != BmPacketTypes::LinkAck &&
+            packet.get_next_hop().is_some();
+        let passive = passive_acks && unicast && packet.get_next_hop() != packet.get_destination();
+        packet.set_link_ack((link_acks || passive_acks) && unicast && !passive);
+        packet.passive_ack = passive;
+    }
+
+    // True when the packet asks us, its next hop, for a LinkAck
+    fn is_link_ack_due(&self, packet: &BmPacketView) -> bool {
+        packet.is_link_ack_requested() &&
+        !packet.get_packet_type().is_flood() &&
+        packet.get_next_hop() == self.table.get_local_network_id()
+    }
+
+    // Time to wait for the LinkAck of a packet, one link each way
+    fn get_link_ack_timeout(&self, packet_len: usize) -> TimeType {
+        self.radio_settings.get_round_trip_timeout_millis(packet_len, 1)
+    }
+
+    // Time a sender keeps retransmitting a packet whose LinkAck it did not get
+    fn get_link_seen_window(&self) -> TimeType {
+        self.get_link_ack_timeout(BM_MAX_OTA_SIZE) * (BM_LINK_RETRY_COUNT as TimeType + 1)
+    }
+
+    // Acks a packet to the node we heard it from. One hop only.
+    fn send_link_ack(&mut self, packet: &BmPacketView) {
+        let source = packet.get_source();
+        let ack = BmLinkAck { orig: packet.get_originator(), seq: packet.get_seq() };
+
+        let seq = self.next_sequence_number();
+        if self.queue_outbound(
+            BmNetworkPacket::new(
+                BmPacketTypes::LinkAck,
+                self.table.get_local_network_id(),
+                source,
+                source,
+                1,
+                false,
+                Some(ack.to_payload())
+            )
+            .with_seq(seq)
+            .with_ok_to_transmit(),
+        ).is_err() {
+            defmt::error!("rb_engine: Error queue full");
+        }
+    }
+
+    // Our next hop acked a packet with a LinkAck
+    fn receive_link_ack(&mut self, packet: &mut BmNetworkPacket) {
+        let Some(ack) = packet.get_payload().as_deref().and_then(BmLinkAck::from_payload) else {
+            defmt::warn!("rb_engine: malformed LinkAck");
+            return
+        };
+        let Some(index) = self.find_hop_ack(ack.orig, ack.seq, packet.get_source()) else {
+            defmt::info!("rb_engine: Rx LinkAck, unexpected");
+            return
+        };
+        self.complete_hop(index);
+    }
+
+    // Hearing the next hop forward a packet we wait on acks it like a LinkAck. Our own
+    // packets come here before authentication and are checked first.
+    fn receive_forward(&mut self, packet: &BmPacketView, authenticated: bool) {
+        let Some(index) = self.find_hop_ack(packet.get_originator(), packet.get_seq(), packet.get_source())
+            .filter(|&index| self.outbound[index].packet_type == packet.get_packet_type()) else {
+            return
+        };
+
+        if let (false, Some(key)) = (authenticated, self.network_key) {
+            if packet.verify_hop(&key).is_err() {
+                defmt::warn!("rb_engine: forward not authentic");
+                return
+            }
+        }
+
+        defmt::info!("rb_engine: next hop forwarded, id={}", packet.get_source());
+        self.complete_hop(index);
+    }
+
+    // Outbound packet with orig and seq that waits for next_hop to ack it
+    fn find_hop_ack(&mut self, orig: NetworkId, seq: u16, next_hop: NetworkId) -> Option<usize> {
+        self.outbound.iter_mut().position(|pkt| {
+            pkt.is_hop_ack_expected() && pkt.get_originator() == orig &&
+            pkt.get_seq() == seq && pkt.get_next_hop() == next_hop
+        })
+    }
+
+    // Stops the retransmissions of the packet our next hop acked. Relayed packets are done,
+    // our own stay queued for their end to end reply.
+    fn complete_hop(&mut self, index: usize) {
+        let pkt = &mut self.outbound[index];
+        pkt.link_ack_deadline = None;
+        if pkt.is_waiting_for_reply() {
+            // An earlier copy arrived, drop the retransmission still queued
+            if pkt.link_retries > 0 && pkt.is_ok_to_transmit() {
+                pkt.tx_state = TransmitState::Complete;
+            }
+            pkt.link_retries = 0;
+        }
+        else {
+            self.outbound.remove(index);
+        }
+    }
+
+    // Retransmits packets whose next hop did not ack them, up to BM_LINK_RETRY_COUNT times.
+    // After that the route counts a failure and the packet tries an alternate route.
+    fn run_link_acks(&mut self, current_time_millis: TimeType) {
+        let mut index = 0;
+        while index < self.outbound.len() {
+            let pkt = &mut self.outbound[index];
+            if pkt.link_ack_deadline.is_none_or(|deadline| current_time_millis < deadline) {
+                index += 1;
+                continue
+            }
+
+            pkt.link_ack_deadline = None;
+            if pkt.link_retries < BM_LINK_RETRY_COUNT {
+                defmt::info!("rb_engine: no LinkAck, retransmit");
+                // The next hop may have forwarded it unheard, the retransmission asks for a LinkAck
+                if pkt.passive_ack {
+                    pkt.passive_ack = false;
+                    pkt.set_link_ack(true);
+                    self.sign_outbound(index);
+                }
+                let pkt = &mut self.outbound[index];
+                pkt.link_retries += 1;
+                pkt.set_ok_to_transmit();
+                index += 1;
+            }
+            else if self.reroute_packet(index) {
+                index += 1;
+            }
+        }
+    }
+
+    // Moves a packet whose next hop stayed silent to the best other route, avoiding the node it
+    // came from, up to BM_LINK_REROUTE_COUNT times. Without one, relayed packets are dropped with
+    // a route error to their originator and ours wait for their end to end retry.
+    // Returns false if the packet was dropped.
+    fn reroute_packet(&mut self, index: usize) -> bool {
+        let pkt = &mut self.outbound[index];
+        let (dest, failed_hop, prev_hop) = (pkt.get_destination(), pkt.get_next_hop(), pkt.prev_hop);
+        let alternate_allowed = pkt.link_reroutes < BM_LINK_REROUTE_COUNT;
+        defmt::warn!("rb_engine: next hop silent, id={}", failed_hop);
+        self.table.set_route_failure(dest, failed_hop);
+
+        let alternate = match alternate_allowed {
+            true => self.table.get_alternate_next_hop(dest, &[failed_hop, prev_hop]),
+            false => None,
+        };
+        if let Some(next_hop) = alternate {
+            defmt::info!("rb_engine: alternate next hop, id={}", next_hop);
+            let pkt = &mut self.outbound[index];
+            pkt.set_next_hop(Some(next_hop));
+            Self::request_hop_ack(true, self.passive_acks, pkt);
+            pkt.link_reroutes += 1;
+
+            // The header changed, sign again with a fresh frame counter
+            self.sign_outbound(index);
+            self.outbound[index].set_ok_to_transmit();
+            return true
+        }
+
+        let pkt = &mut self.outbound[index];
+        if pkt.is_waiting_for_reply() {
+            return true
+        }
+        let (orig, ttl) = (pkt.get_originator(), pkt.get_info().ttl());
+        self.outbound.remove(index);
+        if orig != self.table.get_local_network_id() {
+            defmt::warn!("rb_engine: no next hop left, Tx Disc Error");
+            self.send_route_error(orig, dest, ttl);
+        }
+        false
+    }
+
+    // Signs an outbound packet again after its header changed. Encrypted payloads need no hop MIC.
+    fn sign_outbound(&mut self, index: usize) {
+        if let Some(key) = self.network_key {
+            let frame_counter = self.next_frame_counter();
+            if self.outbound[index].sign_hop(&key, frame_counter).is_err() {
+                defmt::error!("rb_engine: unable to sign packet");
+            }
+        }
+    }
+
+    fn send_data_payload(&mut self, index: usize, current_time_millis: TimeType) {
+        if let Some(working_index) = self.find_outbound(self.transfers[index].working_seq) {
+            let dest_id = self.outbound[working_index].get_destination();
+
+            // Check if we have route to destination
+            if let Some(next_hop) = self.table.get_next_hop(dest_id) {
+                // Update outbound packet with new next_hop
+                self.outbound[working_index].set_next_hop(Some(next_hop));
+                Self::request_hop_ack(self.link_acks, self.passive_acks, &mut self.outbound[working_index]);
+
+                // Echo requests are timed from this attempt, not from discovery or earlier tries
+                if self.outbound[working_index].packet_type == BmPacketTypes::EchoRequest {
+                    self.outbound[working_index].set_payload(Some(BmEchoRequest { timestamp: current_time_millis }.to_payload()));
+                }
+
+                // The header changed, sign again with a fresh frame counter
+                self.sign_outbound(working_index);
+
+                // Mark packet as ok to transmit
+                self.outbound[working_index].set_wait_for_reply();
+                self.outbound[working_index].set_ok_to_transmit();
+
+                // Check if Ack is required and transition to next state
+                if self.outbound[working_index].get_info().required_ack() {
+                    defmt::info!("run_engine: SendingPayload -> WaitingForAck");
+                    self.start_ack_timeout(index);
+                    self.transfers[index].status = BmEngineStatus::WaitingForAck;
+                }
+                else {                    
+                    defmt::info!("run_engine: SendingPayload -> Complete");
+                    self.complete_transfer(index, BmTransferState::Sent);
+                }
+            }
+            else {
+                defmt::warn!("run_engine: SendingPayload -> ErrorNoRoute");
+
+                // Transition to complete
+                self.transfers[index].status = BmEngineStatus::ErrorNoRoute; 
+            }
+        }
+        else {
+            defmt::error!("run_engine: SendingPayload, no working packet");
+            self.complete_transfer(index, BmTransferState::Failed(BmTransferError::Dropped));
+        }
+    }
+
+    // Queues the fragments of the current round, as many as the outbound queue allows.
+    // The last fragment becomes the working packet and asks for a DataFragmentAck.
+    fn send_fragments(&mut self, index: usize) {
+        let Some(mut tx_message) = self.tx_message.take() else {
+            return
+        };
+
+        if let Some(next_hop) = self.table.get_next_hop(tx_message.dest) {
+            // Leave a slot free for acks and relayed packets
+            while self.outbound.len() < BM_OUTBOUND_QUEUE_SIZE - 1 {
+                let Some((payload, last)) = tx_message.next_fragment() else {
+                    break
+                };
+
+                let seq = self.next_sequence_number();
+                let mut fragment = BmNetworkPacket::new(
+                    BmPacketTypes::DataFragment,
+                    self.table.get_local_network_id(),
+                    Some(next_hop),
+                    tx_message.dest,
+                    tx_message.ttl,
+                    tx_message.ack && last,
+                    Some(payload)
+                ).with_seq(seq)
+                .with_ok_to_transmit();
+                if last {
+                    fragment = fragment.with_wait_for_reply();
+                }
+
+                if let Some(key) = self.network_key {
+                    let frame_counter = self.next_frame_counter();
+                    if fragment.encrypt_payload(&key, frame_counter).is_err() {
+                        defmt::error!("rb_engine: unable to encrypt fragment");
+                    }
+                }
+
+                if self.queue_outbound(fragment).is_err() {
+                    defmt::error!("rb_engine: Error queue full");
+                    break
+                }
+
+                if last {
+                    self.transfers[index].working_seq = Some(seq);
+                    if tx_message.ack {
+                        defmt::info!("run_engine: SendingPayload -> WaitingForAck, fragmented");
+                        self.start_ack_timeout(index);
+                        self.transfers[index].status = BmEngineStatus::WaitingForAck;
+                    }
+                    else {
+                        defmt::info!("run_engine: SendingPayload -> Complete, fragmented");
+                        self.complete_transfer(index, BmTransferState::Sent);
+                    }
+                }
+            }
+        }
+        else {
+            defmt::warn!("run_engine: SendingPayload -> ErrorNoRoute, fragmented");
+            self.transfers[index].status = BmEngineStatus::ErrorNoRoute;
+        }
+
+        self.tx_message = Some(tx_message);
+    }
+
+    // Adds a fragment addressed to us to reassembly. Complete messages go to the inbound
+    // queue, and the fragments held so far are reported back when the sender asks.
+    // Queued messages stay in reassembly until the application reads them.
+    fn receive_fragment(&mut self, packet: &mut BmNetworkPacket, millis: TimeType, rssi: RssiType) {
+        let orig = packet.get_originator();
+        let source = packet.get_source();
+        let hop_count = packet.get_hop_count();
+        let info = packet.get_info();
+
+        let Some((hdr, data)) = packet.get_payload().as_deref().and_then(BmFragmentHdr::from_payload) else {
+            defmt::warn!("rb_engine: malformed fragment");
+            return
+        };
+
+        let received = if self.completed_messages.contains(orig, hdr.msg_id, millis, BM_REASSEMBLY_TIMEOUT_MS) ||
+                          self.reassembly.is_complete(orig, hdr.msg_id) {
+            // Already delivered, our ack got lost
+            Some(u32::MAX)
+        }
+        else {
+            let received = self.reassembly.insert(orig, &hdr, data, millis);
+            if self.reassembly.is_complete(orig, hdr.msg_id) {
+                self.completed_messages.insert(orig, hdr.msg_id, millis, BM_REASSEMBLY_TIMEOUT_MS);
+                let message = self.reassembly.get_port(orig, hdr.msg_id).map(|port| BmInboundMessage {
+                    orig,
+                    port,
+                    hop_count,
+                    rx_rssi: rssi,
+                    data: Vec::new(),
+                    reassembled: Some(hdr.msg_id),
+                });
+                self.deliver_message(message);
+            }
+            received
+        };
+
+        // No reply while reassembly has no room, the sender retries
+        let Some(received) = received else {
+            defmt::warn!("rb_engine: fragment dropped");
+            return
+        };
+
+        if info.required_ack() {
+            let seq = self.next_sequence_number();
+            if self.queue_outbound(
+                BmNetworkPacket::new(
+                    BmPacketTypes::DataFragmentAck,
+                    self.table.get_local_network_id(),
+                    source,
+                    orig,
+                    info.ttl(),
+                    false,
+                    Some(BmFragmentAck { msg_id: hdr.msg_id, received }.to_payload())
+                )
+                .with_seq(seq)
+                .with_ok_to_transmit(),
+            ).is_err() {
+                defmt::error!("rb_engine: Error queue full");
+            }
+        }
+    }
+
+    // Records which fragments arrived. Completes the transfer, or starts a round
+    // resending only the missing fragments.
+    fn receive_fragment_ack(&mut self, ack: Option<BmFragmentAck>) {
+        let (Some(ack), Some(tx_message)) = (ack, self.tx_message.as_mut()) else {
+            defmt::error!("rb_engine: Rx DataFragmentAck, unexpected");
+            return
+        };
+        let index = self.transfers.iter()
+            .position(|transfer| transfer.is_fragmented() && transfer.status == BmEngineStatus::WaitingForAck);
+        let Some(index) = index.filter(|_| ack.msg_id == tx_message.msg_id) else {
+            defmt::error!("rb_engine: Rx DataFragmentAck, unexpected");
+            return
+        };
+
+        tx_message.set_acked(ack.received);
+        if tx_message.is_complete() {
+            self.transfers[index].status = BmEngineStatus::AckReceieved;
+        }
+        else if tx_message.start_round() {
+            defmt::info!("rb_engine: WaitingForAck -> SendingPayload, resending missing fragments");
+            let working_seq = self.transfers[index].working_seq.take();
+            self.remove_outbound(working_seq);
+            self.transfers[index].status = BmEngineStatus::SendingPayload;
+        }
+        else {
+            defmt::error!("rb_engine: fragments still missing, giving up");
+            self.complete_transfer(index, BmTransferState::Failed(BmTransferError::NoAck));
+        }
+    }
+
+    // Tells the originator of a packet we cannot route that dest is unreachable through us.
+    // Sent back the way the packet came.
+    fn send_route_error(&mut self, orig: NetworkId, unreachable: NetworkId, ttl: u8) {
+        let Some(next_hop) = self.table.get_next_hop(orig) else {
+            defmt::warn!("rb_engine: no route back to originator");
+            return
+        };
+
+        let seq = self.next_sequence_number();
+        if self.queue_outbound(
+            BmNetworkPacket::new(
+                BmPacketTypes::RouteDiscoveryError,
+                self.table.get_local_network_id(),
+                Some(next_hop),
+                orig,
+                ttl,
+                false,
+                Some(BmRouteError { unreachable }.to_payload())
+            )
+            .with_seq(seq)
+            .with_ok_to_transmit(),
+        ).is_err() {
+            defmt::error!("rb_engine: Error queue full");
+        }
+    }
+
+    // Drops a route reported broken, raising RouteLost when it was the last one
+    fn invalidate_route(&mut self, dest: NetworkId, next_hop: NetworkId) {
+        if self.table.remove_route(dest, next_hop) && self.table.get_next_hop(dest).is_none() {
+            self.push_event(BmEngineEvent::RouteLost { dest });
+        }
+    }
+
+    // Sends the transfer waiting for an ack from dest back to route discovery, instead of
+    // waiting out its ack timeout. Up to BM_PACKET_RETRY_COUNT times per transfer.
+    fn restart_discovery(&mut self, dest: NetworkId) {
+        let Some(index) = self.transfers.iter()
+            .position(|transfer| transfer.dest == dest && transfer.status == BmEngineStatus::WaitingForAck) else {
+            return
+        };
+
+        let transfer = &mut self.transfers[index];
+        transfer.route_errors += 1;
+        if transfer.route_errors > BM_PACKET_RETRY_COUNT {
+            defmt::info!("run_engine: WaitingForAck -> ErrorNoRoute, route errors");
+            transfer.status = BmEngineStatus::ErrorNoRoute;
+            return
+        }
+
+        // Without discovery the ack timeout retries on the next advertised route, if any is left
+        if self.routing_mode == BmRoutingMode::Proactive {
+            if self.table.get_next_hop(dest).is_none() {
+                defmt::info!("run_engine: WaitingForAck -> ErrorNoRoute, no advertised route");
+                self.transfers[index].status = BmEngineStatus::ErrorNoRoute;
+            }
+            return
+        }
+        let payload_seq = transfer.payload_seq;
+
+        if transfer.is_fragmented() {
+            // Drop the fragments still queued, the round is sent again on the new route
+            let local_id = self.table.get_local_network_id();
+            self.outbound.retain_mut(|pkt| pkt.get_originator() != local_id || pkt.packet_type != BmPacketTypes::DataFragment);
+            if !self.tx_message.as_mut().is_some_and(|tx_message| tx_message.start_round()) {
+                self.transfers[index].status = BmEngineStatus::ErrorNoRoute;
+                return
+            }
+        }
+        else if let Some(payload_index) = self.find_outbound(payload_seq) {
+            // Hold the payload until the route is found
+            let payload = &mut self.outbound[payload_index];
+            payload.tx_state = TransmitState::Waiting;
+            payload.tx_complete_timestamp = None;
+        }
+
+        defmt::info!("run_engine: WaitingForAck -> PerformingNetworkDiscovery, route error");
+        self.transfers[index].discovery_attempt = 0;
+        let discovery_ttl = self.transfers[index].get_discovery_ttl();
+        let working_seq = self.start_network_discovery(dest, discovery_ttl);
+        if working_seq.is_none() {
+            self.transfers[index].status = BmEngineStatus::ErrorNoRoute;
+            return
+        }
+
+        let timeout = match self.transfers[index].timeout_override {
+            Some(timeout) => timeout,
+            None => self.get_reply_timeout(working_seq, discovery_ttl),
+        };
+        let transfer = &mut self.transfers[index];
+        transfer.wait_timeout = timeout;
+        transfer.working_seq = working_seq;
+        transfer.status = BmEngineStatus::PerformingNetworkDiscovery;
+    }
+
+    // Answers an echo request with its hop count and the rssi of its last hop only.
+    // Sent back through the node we heard it from.
+    fn reply_to_echo(&mut self, packet: &mut BmNetworkPacket, rssi: RssiType) {
+        let Some(request) = packet.get_payload().as_deref().and_then(BmEchoRequest::from_payload) else {
+            defmt::warn!("rb_engine: malformed EchoRequest");
+            return
+        };
+        let reply = BmEchoReply {
+            timestamp: request.timestamp,
+            request_hop_count: packet.get_hop_count(),
+            request_rssi: rssi,
+        };
+
+        let seq = self.next_sequence_number();
+        if self.queue_outbound(
+            BmNetworkPacket::new(
+                BmPacketTypes::EchoReply,
+                self.table.get_local_network_id(),
+                packet.get_source(),
+                packet.get_originator(),
+                packet.get_info().ttl(),
+                false,
+                Some(reply.to_payload())
+            )
+            .with_seq(seq)
+            .with_ok_to_transmit(),
+        ).is_err() {
+            defmt::error!("rb_engine: Error queue full");
+        }
+    }
+
+    // Completes our echo request when the reply comes from the node we asked
+    fn receive_echo_reply(&mut self, packet: &mut BmNetworkPacket, millis: TimeType, rssi: RssiType) {
+        let reply = packet.get_payload().as_deref().and_then(BmEchoReply::from_payload);
+
+        match (reply, self.find_waiting_transfer(BmPacketTypes::EchoRequest, packet.get_originator())) {
+            (Some(reply), Some(index)) => {
+                defmt::info!("rb_engine: Rx EchoReply");
+                self.echo_result = Some(BmEchoResult::new(packet.get_originator(), &reply, packet.get_hop_count(), rssi, millis));
+                self.transfers[index].status = BmEngineStatus::AckReceieved;
+            }
+            _ => {
+                defmt::error!("rb_engine: Rx EchoReply, unexpected");
+            }
+        }
+    }
+
+    // Hands a received message to the handler of its port, or the inbound queue
+    fn deliver_message(&mut self, message: Option<BmInboundMessage>) {
+        let Some(message) = message else {
+            defmt::warn!("rb_engine: message without port, drop");
+            return
+        };
+        let event = BmEngineEvent::MessageReceived { orig: message.orig, port: message.port };
+        if self.port_handlers.iter().any(|entry| entry.0 == message.port) {
+            let port = message.port;
+            let Some(message) = self.read_inbound(message) else {
+                return
+            };
+            if let Some(entry) = self.port_handlers.iter_mut().find(|entry| entry.0 == port) {
+                entry.1.receive(&message);
+            }
+        }
+        else if let Err(message) = self.inbound.push(message) {
+            defmt::error!("rb_engine: Error in queue full");
+            if let Some(msg_id) = message.reassembled {
+                self.reassembly.remove(message.orig, msg_id);
+            }
+            return
+        }
+        self.push_event(event);
+    }
+
+    // Turns an inbound queue entry into the message handed to the application. Reassembled
+    // messages are taken out of reassembly, which frees their slot.
+    fn read_inbound(&mut self, entry: BmInboundMessage) -> Option<BmNetworkMessage> {
+        match entry.reassembled {
+            Some(msg_id) => {
+                let message = self.reassembly.take_complete(entry.orig, msg_id)?;
+                BmNetworkMessage::from_received(entry.orig, entry.hop_count, entry.rx_rssi, &message)
+            }
+            None => BmNetworkMessage::new(entry.orig, entry.port, entry.hop_count, entry.rx_rssi, &entry.data),
+        }
+    }
+
+    // Adds our network id to the path of a discovery packet. Full or malformed paths are
+    // forwarded unchanged.
+    fn append_discovery_hop(local_id: NetworkId, packet: &mut BmNetworkPacket) {
+        let Some(mut discovery) = BmDiscoveryPayload::from_payload(packet.get_payload().as_deref().unwrap_or_default()) else {
+            defmt::warn!("rb_engine: malformed discovery payload");
+            return
+        };
+        if discovery.push(local_id) {
+            packet.set_payload(Some(discovery.to_payload()));
+        }
+    }
+
+    // Adds our network id and the rssi we heard the request with to a trace payload.
+    // The originator sends no payload. Full or malformed paths are forwarded unchanged.
+    fn append_trace_hop(local_id: NetworkId, packet: &mut BmNetworkPacket, rssi: RssiType) {
+        let Some(mut path) = BmTracePath::from_payload(packet.get_payload().as_deref().unwrap_or_default()) else {
+            defmt::warn!("rb_engine: malformed TraceRequest");
+            return
+        };
+        if path.push(local_id, rssi) {
+            packet.set_payload(Some(path.to_payload()));
+        }
+        else {
+            defmt::warn!("rb_engine: trace path full");
+        }
+    }
+
+    // Returns the path a trace request took, with ourselves as the last hop
+    fn reply_to_trace(&mut self, packet: &mut BmNetworkPacket, rssi: RssiType) {
+        let Some(mut path) = BmTracePath::from_payload(packet.get_payload().as_deref().unwrap_or_default()) else {
+            defmt::warn!("rb_engine: malformed TraceRequest");
+            return
+        };
+        if !path.push(self.table.get_local_network_id(), rssi) {
+            defmt::warn!("rb_engine: trace path full");
+        }
+
+        let seq = self.next_sequence_number();
+        if self.queue_outbound(
+            BmNetworkPacket::new(
+                BmPacketTypes::TraceReply,
+                self.table.get_local_network_id(),
+                packet.get_source(),
+                packet.get_originator(),
+                packet.get_info().ttl(),
+                false,
+                Some(path.to_payload())
+            )
+            .with_seq(seq)
+            .with_ok_to_transmit(),
+        ).is_err() {
+            defmt::error!("rb_engine: Error queue full");
+        }
+    }
+
+    // Completes our trace request when the path comes back from the node we traced
+    fn receive_trace_reply(&mut self, packet: &mut BmNetworkPacket) {
+        let path = packet.get_payload().as_deref().and_then(BmTracePath::from_payload);
+        let dest = packet.get_originator();
+
+        match (path, self.find_waiting_transfer(BmPacketTypes::TraceRequest, dest)) {
+            (Some(path), Some(index)) => {
+                defmt::info!("rb_engine: Rx TraceReply");
+                self.trace_result = Some(BmTraceResult { dest, path });
+                self.transfers[index].status = BmEngineStatus::AckReceieved;
+            }
+            _ => {
+                defmt::error!("rb_engine: Rx TraceReply, unexpected");
+            }
+        }
+    }
+
+    // Transfer whose working packet is a request of this type to dest, waiting for its reply
+    fn find_waiting_transfer(&mut self, packet_type: BmPacketTypes, dest: NetworkId) -> Option<usize> {
+        let index = self.transfers.iter()
+            .position(|transfer| transfer.dest == dest && transfer.status == BmEngineStatus::WaitingForAck)?;
+        let working_index = self.find_outbound(self.transfers[index].working_seq)?;
+        (self.outbound[working_index].packet_type == packet_type).then_some(index)
+    }
+    
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicU32, Ordering};
+    use crate::bm_network_packet::bm_network_packet::BmNetworkOtaPacket;
+
+    // Satisfy defmt linker symbol for host unit tests
+    #[no_mangle]
+    fn _defmt_timestamp() -> u64 {
+        0
+    }
+
+    #[test]
+    fn test_engine_initialization() {
+        // Initialize engine with random network id 
+        let mut bm_engine = BmNetworkEngine::new(Some(5));
+        
+        // Initial state should be Idle and outbound/inbound buffers empty
+        assert_eq!(bm_engine.get_next_outbound_packet(0), None);
+        assert_eq!(bm_engine.get_inbound_message_count(), 0);
+        assert_eq!(bm_engine.run_engine(0), BmEngineStatus::Idle);
+    }
+
+    #[test]
+    fn test_initiate_packet_transfer_without_route_triggers_discovery() {
+        let mut bm_engine = BmNetworkEngine::new(Some(1));
+        let dest_id = Some(2);
+        let payload = BmNetworkPacketPayload::default();
+
+        // Initiating transfer to an unknown route should start network discovery
+        let err = bm_engine.initiate_packet_transfer(dest_id, BM_DEFAULT_PORT, true, 5, payload);
+        assert!(err.is_ok());
+
+        // State machine should transition to PerformingNetworkDiscovery
+        assert_eq!(bm_engine.run_engine(0), BmEngineStatus::PerformingNetworkDiscovery);
+
+        // A Route Discovery Request packet should be generated and ready for transmit
+        let next_pkt = bm_engine.get_next_outbound_packet(0);
+        assert!(next_pkt.is_some());
+        
+        let pkt = next_pkt.unwrap();
+        assert_eq!(pkt.packet_type, BmPacketTypes::RouteDiscoveryRequest);
+        assert_eq!(pkt.get_destination(), dest_id);
+    }
+
+    #[test]
+    fn test_busy_when_initiating_transfer_twice() {
+        let mut bm_engine = BmNetworkEngine::new(Some(1));
+        let payload = BmNetworkPacketPayload::default();
+
+        // First transfer succeeds
+        let err1 = bm_engine.initiate_packet_transfer(Some(2), BM_DEFAULT_PORT, true, 5, payload.clone());
+        assert!(err1.is_ok());
+
+        // Second transfer to the same destination should return Busy
+        let err2 = bm_engine.initiate_packet_transfer(Some(2), BM_DEFAULT_PORT, true, 5, payload.clone());
+        assert_eq!(err2, Err(BmError::Busy));
+
+        // Other destinations run in parallel, up to the transfer limit
+        for dest in 3..(2 + BM_MAX_TRANSFERS as u32) {
+            assert!(bm_engine.initiate_packet_transfer(Some(dest), BM_DEFAULT_PORT, true, 5, payload.clone()).is_ok());
+        }
+        assert_eq!(bm_engine.get_transfer_count(), BM_MAX_TRANSFERS);
+        let err3 = bm_engine.initiate_packet_transfer(Some(10), BM_DEFAULT_PORT, true, 5, payload);
+        assert_eq!(err3, Err(BmError::Busy));
+    }
+
+    #[test]
+    fn test_concurrent_transfers_complete_independently() {
+        let mut sender = BmNetworkEngine::new(Some(1));
+        let mut node2 = BmNetworkEngine::new(Some(2));
+        sender.table.update_node_route(Some(2), Some(2), 0, 0, -50);
+
+        // Node 3 needs a discovery that never gets answered, node 2 is a direct neighbor
+        let payload = BmNetworkPacketPayload::from_slice(&[1, 2, 3]).unwrap();
+        let discovering = sender.initiate_packet_transfer(Some(3), BM_DEFAULT_PORT, true, 5, payload.clone()).unwrap();
+        let direct = sender.initiate_packet_transfer(Some(2), BM_DEFAULT_PORT, true, 5, payload).unwrap();
+        assert_ne!(discovering, direct);
+        assert_eq!(sender.get_transfer_count(), 2);
+        assert_eq!(sender.get_transfer_state(discovering), Some(BmTransferState::Discovering));
+        assert_eq!(sender.get_transfer_state(direct), Some(BmTransferState::Queued));
+
+        // The payload to node 2 is acked while discovery for node 3 is still running
+        deliver(&mut sender, &mut node2, 100, &mut |pkt| pkt.get_destination() != Some(2));
+        deliver(&mut node2, &mut sender, 150, &mut |_| false);
+        assert_eq!(node2.get_inbound_message_count(), 1);
+        sender.run_engine(200);
+        sender.run_engine(200);
+        assert_eq!(sender.get_transfer_count(), 1);
+        assert_eq!(sender.get_transfer_state(direct), Some(BmTransferState::Delivered));
+        assert_eq!(sender.run_engine(300), BmEngineStatus::PerformingNetworkDiscovery);
+
+        // Discovery gives up after its retries and takes its queued payload with it
+        let mut millis = 300;
+        while sender.get_transfer_count() > 0 {
+            millis += 1000;
+            deliver(&mut sender, &mut node2, millis, &mut |_| true);
+            sender.run_engine(millis);
+        }
+        assert_eq!(sender.get_next_outbound_packet(millis), None);
+        assert_eq!(sender.run_engine(millis), BmEngineStatus::Idle);
+
+        // Outcomes are read oldest first
+        let record = sender.get_transfer_record().unwrap();
+        assert_eq!((record.id, record.dest, record.state), (direct, Some(2), BmTransferState::Delivered));
+        let record = sender.get_transfer_record().unwrap();
+        assert_eq!((record.id, record.state), (discovering, BmTransferState::Failed(BmTransferError::NoRoute)));
+        assert_eq!(sender.get_transfer_record(), None);
+    }
+
+    #[test]
+    fn test_transfer_state_lifecycle() {
+        let mut sender = BmNetworkEngine::new(Some(1));
+        let mut node2 = BmNetworkEngine::new(Some(2));
+        let payload = BmNetworkPacketPayload::from_slice(&[1, 2, 3]).unwrap();
+        let id = sender.initiate_packet_transfer(Some(2), BM_DEFAULT_PORT, true, 5, payload).unwrap();
+        assert_eq!(sender.get_transfer_state(id), Some(BmTransferState::Discovering));
+
+        // Node 2 answers the discovery, the payload waits for its turn
+        deliver(&mut sender, &mut node2, 0, &mut |_| false);
+        deliver(&mut node2, &mut sender, 50, &mut |_| false);
+        assert_eq!(sender.get_transfer_state(id), Some(BmTransferState::Discovering));
+        sender.run_engine(60);
+        assert_eq!(sender.get_transfer_state(id), Some(BmTransferState::Queued));
+        sender.run_engine(100);
+        assert_eq!(sender.get_transfer_state(id), Some(BmTransferState::InFlight));
+
+        // The ack delivers it, the record outlives the transfer
+        deliver(&mut sender, &mut node2, 150, &mut |_| false);
+        assert_eq!(sender.get_transfer_state(id), Some(BmTransferState::InFlight));
+        deliver(&mut node2, &mut sender, 200, &mut |_| false);
+        assert_eq!(sender.get_transfer_state(id), Some(BmTransferState::Delivered));
+        sender.run_engine(250);
+        sender.run_engine(250);
+        assert_eq!(sender.get_transfer_count(), 0);
+        assert_eq!(sender.get_transfer_state(id), Some(BmTransferState::Delivered));
+
+        // Records are read once
+        let record = sender.get_transfer_record().unwrap();
+        assert_eq!((record.id, record.dest, record.state), (id, Some(2), BmTransferState::Delivered));
+        assert_eq!(sender.get_transfer_state(id), None);
+        assert_eq!(sender.get_transfer_record(), None);
+        assert_eq!(sender.get_transfer_state(id + 1), None);
+    }
+
+    #[test]
+    fn test_transfers_refused_while_records_unread() {
+        let mut sender = BmNetworkEngine::new(Some(1));
+        let mut node2 = BmNetworkEngine::new(Some(2));
+        sender.table.update_node_route(Some(2), Some(2), 0, 0, -50);
+        let payload = BmNetworkPacketPayload::from_slice(&[1, 2, 3]).unwrap();
+
+        let mut millis = 0;
+        let mut send = |sender: &mut BmNetworkEngine| {
+            let id = sender.initiate_packet_transfer(Some(2), BM_DEFAULT_PORT, false, 5, payload.clone())?;
+            millis += 100;
+            sender.run_engine(millis);
+            deliver(sender, &mut node2, millis, &mut |_| false);
+            sender.run_engine(millis);
+            Ok::<BmTransferId, BmError>(id)
+        };
+        let first = send(&mut sender).unwrap();
+        assert_eq!(sender.get_transfer_state(first), Some(BmTransferState::Sent));
+        for _ in 1..BM_MAX_TRANSFER_RECORDS - 1 {
+            send(&mut sender).unwrap();
+        }
+        assert_eq!(sender.get_transfer_count(), 0);
+
+        // The last free record is kept for the transfer in flight
+        let payload = BmNetworkPacketPayload::from_slice(&[4]).unwrap();
+        assert!(sender.initiate_packet_transfer(Some(3), BM_DEFAULT_PORT, true, 5, payload.clone()).is_ok());
+        assert_eq!(sender.initiate_packet_transfer(Some(4), BM_DEFAULT_PORT, true, 5, payload.clone()), Err(BmError::RecordsFull));
+        assert_eq!(sender.initiate_trace_request(Some(4), 5), Err(BmError::RecordsFull));
+        assert_eq!(sender.initiate_message_transfer(Some(4), BM_DEFAULT_PORT, true, 5, &[0; 300]), Err(BmError::RecordsFull));
+
+        // Reading the oldest outcome makes room again, nothing was dropped
+        assert_eq!(sender.get_transfer_record().unwrap().id, first);
+        assert!(sender.initiate_packet_transfer(Some(4), BM_DEFAULT_PORT, true, 5, payload).is_ok());
+        let mut count = 0;
+        while sender.get_transfer_record().is_some() {
+            count += 1;
+        }
+        assert_eq!(count, BM_MAX_TRANSFER_RECORDS - 2);
+    }
+
+    #[test]
+    fn test_route_error_restarts_discovery() {
+        // 1 - 2 - 3, node 3 lost its link to node 4 but the others still route through it
+        let mut sender = BmNetworkEngine::new(Some(1));
+        let mut node2 = BmNetworkEngine::new(Some(2));
+        let mut node3 = BmNetworkEngine::new(Some(3));
+        sender.table.update_node_route(Some(4), Some(2), 2, 0, -50);
+        node2.table.update_node_route(Some(4), Some(3), 1, 0, -50);
+
+        let payload = BmNetworkPacketPayload::from_slice(&[1, 2, 3]).unwrap();
+        let id = sender.initiate_packet_transfer(Some(4), BM_DEFAULT_PORT, true, 5, payload).unwrap();
+        sender.run_engine(0);
+        assert_eq!(sender.get_transfer_state(id), Some(BmTransferState::InFlight));
+
+        // Node 3 has no route and answers with an error toward node 1
+        deliver(&mut sender, &mut node2, 100, &mut |_| false);
+        deliver(&mut node2, &mut node3, 200, &mut |_| false);
+        let error = node3.get_next_outbound_packet(300).unwrap();
+        assert_eq!(error.packet_type, BmPacketTypes::RouteDiscoveryError);
+        assert_eq!(error.get_destination(), Some(1));
+        assert_eq!(error.get_next_hop(), Some(2));
+
+        // Node 2 relays it and drops its route through node 3
+        deliver(&mut node3, &mut node2, 300, &mut |_| false);
+        assert_eq!(node2.table.get_next_hop(Some(4)), None);
+        assert!(core::iter::from_fn(|| node2.get_next_event()).any(|event| event == BmEngineEvent::RouteLost { dest: Some(4) }));
+
+        // Node 1 drops its route and looks for a new one without waiting for the ack timeout
+        deliver(&mut node2, &mut sender, 400, &mut |_| false);
+        assert_eq!(sender.table.get_next_hop(Some(4)), None);
+        assert_eq!(sender.get_transfer_state(id), Some(BmTransferState::Discovering));
+        let request = sender.get_next_outbound_packet(400).unwrap();
+        assert_eq!(request.packet_type, BmPacketTypes::RouteDiscoveryRequest);
+        assert_eq!(request.get_destination(), Some(4));
+        sender.set_next_outbound_complete(400);
+
+        // The payload waits for the new route
+        assert_eq!(sender.get_next_outbound_packet(500), None);
+    }
+
+    #[test]
+    fn test_relay_answers_discovery_with_fresh_route() {
+        let mut sender = BmNetworkEngine::new(Some(1));
+        let mut relay = BmNetworkEngine::new(Some(2));
+        relay.table.update_node_route_seq(Some(9), Some(9), 0, 4, 1000, -50);
+
+        let payload = BmNetworkPacketPayload::from_slice(&[1, 2, 3]).unwrap();
+        let id = sender.initiate_packet_transfer(Some(9), BM_DEFAULT_PORT, true, 5, payload).unwrap();
+        sender.run_engine(11_000);
+
+        // The relay answers for node 9 and does not rebroadcast the request
+        deliver(&mut sender, &mut relay, 11_000, &mut |_| false);
+        let response = relay.get_next_outbound_packet(11_000).unwrap();
+        assert_eq!(response.packet_type, BmPacketTypes::RouteDiscoveryResponse);
+        assert_eq!(response.get_destination(), Some(1));
+        assert_eq!(relay.outbound.len(), 1);
+
+        // The sender records the end to end distance and the age of the relay's route
+        deliver(&mut relay, &mut sender, 11_100, &mut |_| false);
+        let route = sender.table.get_best_route(Some(9)).unwrap();
+        assert_eq!(route.get_distance(), 1);
+        assert_eq!(route.get_timestamp_millis(), 1100);
+        assert_eq!(sender.table.find_node_by_id(Some(9)).unwrap().get_dest_seq(), Some(4));
+        assert_eq!(sender.run_engine(11_100), BmEngineStatus::RouteFound);
+        sender.run_engine(11_100);
+        assert_eq!(sender.get_transfer_state(id), Some(BmTransferState::InFlight));
+        let data = sender.get_next_outbound_packet(11_100).unwrap();
+        assert_eq!(data.packet_type, BmPacketTypes::DataPayload);
+        assert_eq!(data.get_next_hop(), Some(2));
+    }
+
+    #[test]
+    fn test_relay_floods_discovery_with_stale_route() {
+        let mut relay = BmNetworkEngine::new(Some(2));
+        relay.table.update_node_route(Some(9), Some(9), 0, 0, -50);
+
+        let mut request = BmNetworkPacket::new(
+            BmPacketTypes::RouteDiscoveryRequest, Some(1), None, Some(9), 5, false, None
+        ).with_seq(7);
+        let mut bytes = request.to_bytes().unwrap();
+        relay.process_packet(bytes.len(), &mut bytes, BM_INTERMEDIATE_REPLY_MAX_AGE_MS + 1, -50);
+
+        let forwarded = relay.get_next_outbound_packet(BM_INTERMEDIATE_REPLY_MAX_AGE_MS + 1).unwrap();
+        assert_eq!(forwarded.packet_type, BmPacketTypes::RouteDiscoveryRequest);
+        assert_eq!(forwarded.get_source(), Some(2));
+    }
+
+    #[test]
+    fn test_network_discovery_timeout() {
+        let mut bm_engine = BmNetworkEngine::new(Some(1));
+        let payload = BmNetworkPacketPayload::default();
+
+        // Initiate transfer, with a fixed timeout instead of the airtime based one
+        const DISCOVERY_TIMEOUT_MS: i64 = 5000;
+        let id = bm_engine.initiate_packet_transfer(Some(2), BM_DEFAULT_PORT, true, 5, payload).unwrap();
+        assert_eq!(bm_engine.set_transfer_timeout(id, Some(DISCOVERY_TIMEOUT_MS)), BmError::None);
+        assert_eq!(bm_engine.set_transfer_timeout(id + 1, None), BmError::UnknownTransfer);
+        bm_engine.run_engine(0); // Transition into PerformingNetworkDiscovery
+
+        // First request stays local. Simulate transmit finishing at t = 1000ms.
+        let request = bm_engine.get_next_outbound_packet(0).unwrap();
+        assert_eq!(request.get_info().ttl(), BM_DISCOVERY_INITIAL_TTL);
+        let first_seq = request.get_seq();
         bm_engine.set_next_outbound_complete(1000);
 
-        // Check state before timeout threshold (< 10000ms elapsed)
-        assert_eq!(bm_engine.run_engine(5000), BmEngineStatus::PerformingNetworkDiscovery);
+        // Check state before timeout threshold
+        assert_eq!(bm_engine.run_engine(1000 + DISCOVERY_TIMEOUT_MS), BmEngineStatus::PerformingNetworkDiscovery);
+        assert_eq!(bm_engine.get_next_outbound_packet(0), None);
+
+        // Timeout sends a new request further out, and waits twice as long for it
+        let mut millis = 1001 + DISCOVERY_TIMEOUT_MS;
+        assert_eq!(bm_engine.run_engine(millis), BmEngineStatus::PerformingNetworkDiscovery);
+        let request = bm_engine.get_next_outbound_packet(millis).unwrap();
+        assert_eq!(request.get_info().ttl(), 2 * BM_DISCOVERY_INITIAL_TTL);
+        assert_ne!(request.get_seq(), first_seq);
+        bm_engine.set_next_outbound_complete(millis);
+        assert_eq!(bm_engine.run_engine(millis + 2 * DISCOVERY_TIMEOUT_MS), BmEngineStatus::PerformingNetworkDiscovery);
+        assert_eq!(bm_engine.get_next_outbound_packet(millis), None);
+
+        // The last request floods with the full TTL
+        millis += 2 * DISCOVERY_TIMEOUT_MS + 1;
+        bm_engine.run_engine(millis);
+        let request = bm_engine.get_next_outbound_packet(millis).unwrap();
+        assert_eq!(request.get_info().ttl(), 5);
+        bm_engine.set_next_outbound_complete(millis);
+
+        // Check state after the last timeout
+        millis += 4 * DISCOVERY_TIMEOUT_MS + 1;
+        assert_eq!(bm_engine.run_engine(millis), BmEngineStatus::PerformingNetworkDiscovery);
+        
+        // Next engine iteration should reflect ErrorNoRoute transition
+        assert_eq!(bm_engine.run_engine(millis), BmEngineStatus::ErrorNoRoute);
+    }
+
+    #[test]
+    fn test_discovery_ttl_and_backoff_per_attempt() {
+        let mut bm_engine = BmNetworkEngine::new(Some(1));
+        const DISCOVERY_TIMEOUT_MS: i64 = 1000;
+        const TTL: u8 = 7;
+        let id = bm_engine.initiate_packet_transfer(Some(2), BM_DEFAULT_PORT, true, TTL, BmNetworkPacketPayload::default()).unwrap();
+        bm_engine.set_transfer_timeout(id, Some(DISCOVERY_TIMEOUT_MS));
+        bm_engine.run_engine(0);
+
+        // Each retry doubles the TTL and the wait, the last one floods the full TTL
+        let mut millis = 0;
+        for attempt in 0..=BM_DISCOVERY_RETRY_COUNT {
+            let expected_ttl = if attempt == BM_DISCOVERY_RETRY_COUNT {
+                TTL
+            }
+            else {
+                BM_DISCOVERY_INITIAL_TTL << attempt
+            };
+            let request = bm_engine.get_next_outbound_packet(millis).unwrap();
+            assert_eq!(request.packet_type, BmPacketTypes::RouteDiscoveryRequest);
+            assert_eq!(request.get_info().ttl(), expected_ttl);
+            bm_engine.set_next_outbound_complete(millis);
+
+            // Nothing happens up to the end of the wait
+            millis += DISCOVERY_TIMEOUT_MS << attempt;
+            assert_eq!(bm_engine.run_engine(millis), BmEngineStatus::PerformingNetworkDiscovery);
+            assert_eq!(bm_engine.get_next_outbound_packet(millis), None);
+            millis += 1;
+            assert_eq!(bm_engine.run_engine(millis), BmEngineStatus::PerformingNetworkDiscovery);
+        }
+
+        // No request is left after the flood timed out
+        assert_eq!(bm_engine.get_next_outbound_packet(millis), None);
+        assert_eq!(bm_engine.run_engine(millis), BmEngineStatus::ErrorNoRoute);
+    }
+
+    #[test]
+    fn test_short_ttl_discovery_not_retried() {
+        let mut bm_engine = BmNetworkEngine::new(Some(1));
+        const DISCOVERY_TIMEOUT_MS: i64 = 5000;
+        let id = bm_engine.initiate_packet_transfer(Some(2), BM_DEFAULT_PORT, true, BM_DISCOVERY_INITIAL_TTL, BmNetworkPacketPayload::default()).unwrap();
+        bm_engine.set_transfer_timeout(id, Some(DISCOVERY_TIMEOUT_MS));
+        bm_engine.run_engine(0);
+
+        // The first request already covers the whole TTL, a retry would flood the same nodes
+        assert_eq!(bm_engine.get_next_outbound_packet(0).unwrap().get_info().ttl(), BM_DISCOVERY_INITIAL_TTL);
+        bm_engine.set_next_outbound_complete(0);
+
+        let millis = DISCOVERY_TIMEOUT_MS + 1;
+        assert_eq!(bm_engine.run_engine(millis), BmEngineStatus::PerformingNetworkDiscovery);
+        assert_eq!(bm_engine.get_next_outbound_packet(millis), None);
+        assert_eq!(bm_engine.run_engine(millis), BmEngineStatus::ErrorNoRoute);
+    }
+
+    #[test]
+    fn test_ack_timeout_follows_airtime() {
+        // Time until a lost payload counts as not acked, for one hop
+        fn ack_timeout(settings: BmRadioSettings) -> i64 {
+            let mut bm_engine = BmNetworkEngine::new(Some(1)).with_radio_settings(settings);
+            bm_engine.table.update_node_route(Some(2), Some(2), 0, 0, -50);
+            let _ = bm_engine.initiate_packet_transfer(Some(2), BM_DEFAULT_PORT, true, 5, BmNetworkPacketPayload::default());
+            bm_engine.run_engine(0);
+            bm_engine.set_next_outbound_complete(0);
+
+            let mut millis = 0;
+            while bm_engine.run_engine(millis) != BmEngineStatus::ErrorNoAck {
+                millis += 10;
+            }
+            millis
+        }
+
+        let fast = ack_timeout(BmRadioSettings::new(7, 125_000, 1));
+        let slow = ack_timeout(BmRadioSettings::new(12, 125_000, 1).with_low_data_rate_optimize(true));
+        assert!(fast < 2000);
+        assert!(slow > 4 * fast);
+    }
+
+    #[test]
+    fn test_rejected_frames_counted_by_reason() {
+        let mut bm_engine = BmNetworkEngine::new(Some(1));
+
+        // Too short for a header
+        let mut short_frame = [0u8; 4];
+        assert!(bm_engine.process_packet(short_frame.len(), &mut short_frame, 0, -60).is_none());
+
+        // Valid length, pre-versioned layout
+        let mut legacy_frame = [0u8; BM_PACKET_HDR_SIZE];
+        legacy_frame[0] = BmPacketTypes::DataPayload as u8;
+        assert!(bm_engine.process_packet(legacy_frame.len(), &mut legacy_frame, 0, -60).is_none());
+        assert!(bm_engine.process_packet(legacy_frame.len(), &mut legacy_frame, 0, -60).is_none());
+
+        let diagnostics = bm_engine.get_rx_diagnostics();
+        assert_eq!(diagnostics.too_short, 1);
+        assert_eq!(diagnostics.version_mismatch, 2);
+        assert_eq!(diagnostics.unknown_type, 0);
+
+        // Routing table must not learn anything from rejected frames
+        assert_eq!(bm_engine.table.get_num_nodes(), 0);
+    }
+
+    #[test]
+    fn test_flood_relayed_once() {
+        let mut relay = BmNetworkEngine::new(Some(2));
+
+        // Node 1 floods a discovery request for node 9, heard directly and via node 3
+        let mut request = BmNetworkPacket::new(
+            BmPacketTypes::RouteDiscoveryRequest, Some(1), None, Some(9), 5, false, None
+        ).with_seq(7);
+        let mut direct_bytes = request.to_bytes().unwrap();
+        request.set_source(Some(3));
+        request.increment_hop_count();
+        let mut relayed_bytes = request.to_bytes().unwrap();
+
+        assert!(relay.process_packet(direct_bytes.len(), &mut direct_bytes, 100, -60).is_some());
+        assert!(relay.process_packet(relayed_bytes.len(), &mut relayed_bytes, 150, -70).is_none());
+
+        // Only the first copy is rebroadcast
+        assert!(relay.get_next_outbound_packet(150).is_some());
+        relay.set_next_outbound_complete(200);
+        assert!(relay.get_next_outbound_packet(200).is_none());
+        assert_eq!(relay.get_rx_diagnostics().duplicate_flood, 1);
+
+        // The duplicate still taught the relay a second route to node 1, and node 3 as a neighbor
+        assert_eq!(relay.table.get_num_nodes(), 2);
+        assert!(relay.table.is_neighbor(Some(3)));
+
+        // A new flood from the same originator is relayed again
+        let mut next_request = BmNetworkPacket::new(
+            BmPacketTypes::RouteDiscoveryRequest, Some(1), None, Some(9), 5, false, None
+        ).with_seq(8);
+        let mut next_bytes = next_request.to_bytes().unwrap();
+        assert!(relay.process_packet(next_bytes.len(), &mut next_bytes, 300, -60).is_some());
+        assert!(relay.get_next_outbound_packet(300).is_some());
+    }
+
+    // Hands out a fixed list of values
+    struct TestRng {
+        values: [u32; 2],
+        next: usize,
+    }
+
+    impl BmRng for TestRng {
+        fn next_u32(&mut self) -> u32 {
+            let value = self.values[self.next % self.values.len()];
+            self.next += 1;
+            value
+        }
+    }
+
+    #[test]
+    fn test_relay_waits_for_jitter() {
+        // The first value seeds the sequence number
+        let rng = std::boxed::Box::leak(std::boxed::Box::new(TestRng { values: [40, 120], next: 0 }));
+        let mut relay = BmNetworkEngine::new(Some(3)).with_rng(rng);
+
+        let mut request = BmNetworkPacket::new(
+            BmPacketTypes::RouteDiscoveryRequest, Some(1), None, Some(9), 5, false, None
+        ).with_seq(7);
+        let mut bytes = request.to_bytes().unwrap();
+        assert!(relay.process_packet(bytes.len(), &mut bytes, 1000, -60).is_some());
+
+        // Held back by 120ms
+        assert!(relay.get_next_outbound_packet(1000).is_none());
+        assert!(relay.get_next_outbound_packet(1119).is_none());
+        let forwarded = relay.get_next_outbound_packet(1120).unwrap();
+        assert_eq!(forwarded.get_source(), Some(3));
+
+        // A later relay that is due first goes out first, completing the latched one only
+        let mut second = BmNetworkPacket::new(
+            BmPacketTypes::RouteDiscoveryRequest, Some(2), None, Some(9), 5, false, None
+        ).with_seq(1);
+        let mut bytes = second.to_bytes().unwrap();
+        assert!(relay.process_packet(bytes.len(), &mut bytes, 1050, -60).is_some());
+        let first_due = relay.get_next_outbound_packet(1090).unwrap();
+        assert_eq!(first_due.get_originator(), Some(2));
+        relay.set_next_outbound_complete(1100);
+
+        let remaining = relay.get_next_outbound_packet(1120).unwrap();
+        assert_eq!(remaining.get_originator(), Some(1));
+        relay.set_next_outbound_complete(1200);
+        assert!(relay.get_next_outbound_packet(2000).is_none());
+    }
+
+    #[test]
+    fn test_sequence_number_seeded_from_rng() {
+        // A neighbor that relayed our first flood before we rebooted
+        let mut neighbor = BmNetworkEngine::new(Some(2));
+        let mut node = BmNetworkEngine::new(Some(1));
+        node.initiate_packet_transfer(Some(9), BM_DEFAULT_PORT, true, 5, BmNetworkPacketPayload::new()).unwrap();
+        node.run_engine(0);
+        let mut bytes = node.get_next_outbound_packet(0).unwrap().to_bytes().unwrap();
+        assert!(neighbor.process_packet(bytes.len(), &mut bytes, 0, -50).is_some());
+
+        // After the reboot the sequence starts elsewhere, so the next flood is relayed
+        let rng = std::boxed::Box::leak(std::boxed::Box::new(TestRng { values: [1000, 7], next: 0 }));
+        let mut node = BmNetworkEngine::new(Some(1)).with_rng(rng);
+        node.initiate_packet_transfer(Some(9), BM_DEFAULT_PORT, true, 5, BmNetworkPacketPayload::new()).unwrap();
+        node.run_engine(0);
+        let request = node.get_next_outbound_packet(0).unwrap();
+        assert_eq!(request.packet_type, BmPacketTypes::RouteDiscoveryRequest);
+        assert!(request.get_seq() > 1000);
+        let mut bytes = request.to_bytes().unwrap();
+        assert!(neighbor.process_packet(bytes.len(), &mut bytes, 10, -50).is_some());
+        assert_eq!(neighbor.get_rx_diagnostics().duplicate_flood, 0);
+    }
+
+    #[test]
+    fn test_originated_packets_get_new_sequence_numbers() {
+        let mut bm_engine = BmNetworkEngine::new(Some(1));
+        let _ = bm_engine.initiate_packet_transfer(Some(2), BM_DEFAULT_PORT, true, 5, BmNetworkPacketPayload::default());
+
+        // Data payload is queued first, discovery request second
+        let data_seq = bm_engine.outbound[0].get_seq();
+        let disc_seq = bm_engine.outbound[1].get_seq();
+        assert_ne!(data_seq, disc_seq);
+    }
+
+    const TEST_KEY: BmNetworkKey = [0x2B; 16];
+
+    fn build_encrypted_data(dest: NetworkId, key: &BmNetworkKey) -> BmNetworkPacket {
+        let mut payload = BmNetworkPacketPayload::new();
+        payload.push(BM_DEFAULT_PORT).unwrap();
+        payload.extend_from_slice(b"secret").unwrap();
+        let mut packet = BmNetworkPacket::new(BmPacketTypes::DataPayload, Some(1), dest, dest, 5, false, Some(payload))
+            .with_seq(3);
+        packet.encrypt_payload(key, 1).unwrap();
+        packet.sign_hop(key, 2).unwrap();
+        packet
+    }
+
+    #[test]
+    fn test_keyed_engine_encrypts_outbound_payload() {
+        let mut bm_engine = BmNetworkEngine::new(Some(1)).with_network_key(TEST_KEY);
+        bm_engine.table.update_node_route(Some(2), Some(2), 0, 0, -50);
+
+        let mut payload = BmNetworkPacketPayload::new();
+        payload.extend_from_slice(b"secret").unwrap();
+        assert!(bm_engine.initiate_packet_transfer(Some(2), BM_DEFAULT_PORT, false, 5, payload).is_ok());
+        bm_engine.run_engine(0);
 
-        // Check state after timeout (> 10000ms elapsed: 1000 + 10001 = 11001ms)
-        assert_eq!(bm_engine.run_engine(11001), BmEngineStatus::PerformingNetworkDiscovery);
-        
-        // Next engine iteration should reflect ErrorNoRoute transition
-        assert_eq!(bm_engine.run_engine(11002), BmEngineStatus::ErrorNoRoute);
+        let data_pkt = bm_engine.get_next_outbound_packet(0).unwrap();
+        assert!(data_pkt.get_info().encrypted());
+        assert!(data_pkt.is_authenticated());
+        let bytes = data_pkt.to_bytes().unwrap();
+        assert!(!bytes.windows(6).any(|w| w == b"secret"));
+
+        // No room left for frame counter and MIC
+        let mut oversize = BmNetworkPacketPayload::new();
+        oversize.resize(BM_MAX_PAYLOAD_SIZE, 0).unwrap();
+        let mut other_engine = BmNetworkEngine::new(Some(1)).with_network_key(TEST_KEY);
+        assert_eq!(other_engine.initiate_packet_transfer(Some(2), BM_DEFAULT_PORT, false, 5, oversize), Err(BmError::PayloadTooLarge));
+    }
+
+    #[test]
+    fn test_keyed_engine_delivers_decrypted_payload() {
+        let mut bm_engine = BmNetworkEngine::new(Some(2)).with_network_key(TEST_KEY);
+
+        let mut bytes = build_encrypted_data(Some(2), &TEST_KEY).to_bytes().unwrap();
+        assert!(bm_engine.process_packet(bytes.len(), &mut bytes, 0, -50).is_some());
+
+        let msg = bm_engine.get_inbound_message().unwrap();
+        assert_eq!(msg.get_payload(), b"secret");
+        assert_eq!(bm_engine.get_rx_diagnostics().auth_failed, 0);
+    }
+
+    #[test]
+    fn test_keyed_engine_drops_unauthenticated_payloads() {
+        let mut bm_engine = BmNetworkEngine::new(Some(2)).with_network_key(TEST_KEY);
+
+        // Encrypted under a different key
+        let mut forged = build_encrypted_data(Some(2), &[0u8; 16]).to_bytes().unwrap();
+        assert!(bm_engine.process_packet(forged.len(), &mut forged, 0, -50).is_none());
+
+        // Relayed packet with a flipped ciphertext byte
+        let mut corrupted = build_encrypted_data(Some(3), &TEST_KEY).to_bytes().unwrap();
+        corrupted[BM_PACKET_HDR_SIZE + BM_FRAME_COUNTER_SIZE] ^= 0x01;
+        assert!(bm_engine.process_packet(corrupted.len(), &mut corrupted, 0, -50).is_none());
+
+        // Plaintext application data
+        let mut plaintext = BmNetworkPacket::new(BmPacketTypes::DataPayload, Some(1), Some(2), Some(2), 5, false, None)
+            .to_bytes().unwrap();
+        assert!(bm_engine.process_packet(plaintext.len(), &mut plaintext, 0, -50).is_none());
+
+        // Valid payload, but a source and hop count nobody signed
+        let mut unsigned = build_encrypted_data(Some(2), &TEST_KEY);
+        unsigned.set_source(Some(9));
+        let mut unsigned_bytes = unsigned.to_bytes().unwrap();
+        assert!(bm_engine.process_packet(unsigned_bytes.len(), &mut unsigned_bytes, 0, -50).is_none());
+
+        assert_eq!(bm_engine.get_rx_diagnostics().auth_failed, 2);
+        assert_eq!(bm_engine.get_rx_diagnostics().unauthenticated, 2);
+        assert_eq!(bm_engine.get_inbound_message_count(), 0);
+        assert_eq!(bm_engine.table.get_num_nodes(), 0);
+    }
+
+    #[test]
+    fn test_keyed_engine_ignores_forged_control_packets() {
+        let mut bm_engine = BmNetworkEngine::new(Some(1)).with_network_key(TEST_KEY);
+        let _ = bm_engine.initiate_packet_transfer(Some(3), BM_DEFAULT_PORT, true, 5, BmNetworkPacketPayload::default());
+        bm_engine.run_engine(0);
+
+        // Unsigned discovery response claiming node 3 is one hop away via node 9
+        let mut unsigned = BmNetworkPacket::new(BmPacketTypes::RouteDiscoveryResponse, Some(3), Some(1), Some(1), 5, false, None)
+            .with_seq(1);
+        unsigned.set_source(Some(9));
+        let mut unsigned_bytes = unsigned.to_bytes().unwrap();
+        assert!(bm_engine.process_packet(unsigned_bytes.len(), &mut unsigned_bytes, 100, -50).is_none());
+
+        // Same response signed with the wrong key
+        let mut forged = unsigned.clone();
+        forged.sign_hop(&[0u8; 16], 1).unwrap();
+        let mut forged_bytes = forged.to_bytes().unwrap();
+        assert!(bm_engine.process_packet(forged_bytes.len(), &mut forged_bytes, 100, -50).is_none());
+
+        assert_eq!(bm_engine.get_rx_diagnostics().unauthenticated, 1);
+        assert_eq!(bm_engine.get_rx_diagnostics().auth_failed, 1);
+        assert_eq!(bm_engine.table.get_num_nodes(), 0);
+        assert_eq!(bm_engine.run_engine(200), BmEngineStatus::PerformingNetworkDiscovery);
+
+        // Correctly signed response is accepted
+        let mut genuine = unsigned.clone();
+        genuine.sign_hop(&TEST_KEY, 1).unwrap();
+        let mut genuine_bytes = genuine.to_bytes().unwrap();
+        assert!(bm_engine.process_packet(genuine_bytes.len(), &mut genuine_bytes, 300, -50).is_some());
+        assert_eq!(bm_engine.table.get_next_hop(Some(3)), Some(9));
+        assert_eq!(bm_engine.run_engine(400), BmEngineStatus::RouteFound);
+    }
+
+    #[test]
+    fn test_keyed_relay_resigns_forwarded_packets() {
+        let mut relay = BmNetworkEngine::new(Some(2)).with_network_key(TEST_KEY);
+
+        let mut request = BmNetworkPacket::new(BmPacketTypes::RouteDiscoveryRequest, Some(1), None, Some(3), 5, false, None)
+            .with_seq(1);
+        request.sign_hop(&TEST_KEY, 10).unwrap();
+        let mut bytes = request.to_bytes().unwrap();
+        assert!(relay.process_packet(bytes.len(), &mut bytes, 0, -50).is_some());
+
+        // Forwarded copy is signed by the relay itself
+        let forwarded = relay.get_next_outbound_packet(0).unwrap();
+        assert_eq!(forwarded.get_source(), Some(2));
+        assert!(forwarded.is_authenticated());
+        let forwarded_bytes = forwarded.to_bytes().unwrap();
+        let received = BmNetworkPacket::from(forwarded_bytes.len(), &forwarded_bytes).unwrap();
+        assert!(received.verify_hop(&TEST_KEY).is_ok());
+    }
+
+    #[test]
+    fn test_keyed_engine_rejects_replayed_frames() {
+        let mut bm_engine = BmNetworkEngine::new(Some(2)).with_network_key(TEST_KEY);
+
+        let bytes = build_encrypted_data(Some(2), &TEST_KEY).to_bytes().unwrap();
+        let mut first = bytes.clone();
+        assert!(bm_engine.process_packet(first.len(), &mut first, 0, -50).is_some());
+
+        // Same frame heard again
+        let mut replay = bytes.clone();
+        assert!(bm_engine.process_packet(replay.len(), &mut replay, 100, -50).is_none());
+
+        // Control packet signed with a counter node 1 already used
+        let mut ack = BmNetworkPacket::new(BmPacketTypes::DataPayloadAck, Some(1), Some(2), Some(2), 5, false, None);
+        ack.sign_hop(&TEST_KEY, 1).unwrap();
+        let mut ack_bytes = ack.to_bytes().unwrap();
+        assert!(bm_engine.process_packet(ack_bytes.len(), &mut ack_bytes, 200, -50).is_none());
+
+        assert_eq!(bm_engine.get_rx_diagnostics().replayed, 2);
+        assert_eq!(bm_engine.get_inbound_message_count(), 1);
+    }
+
+    #[test]
+    fn test_keyed_relay_forwards_packet_it_overheard_before() {
+        let mut bm_engine = BmNetworkEngine::new(Some(3)).with_network_key(TEST_KEY);
+        bm_engine.table.update_node_route(Some(4), Some(4), 0, 0, -50);
+
+        // First hop, 1 to relay 2, overheard on the way to 4
+        let mut packet = build_encrypted_data(Some(4), &TEST_KEY);
+        packet.set_next_hop(Some(2));
+        packet.sign_hop(&TEST_KEY, 2).unwrap();
+        let mut overheard = packet.to_bytes().unwrap();
+        assert!(bm_engine.process_packet(overheard.len(), &mut overheard, 0, -50).is_none());
+        assert!(bm_engine.get_next_outbound_packet(0).is_none());
+
+        // Relay 2 picked us as its next hop, the payload counter is unchanged
+        packet.set_source(Some(2));
+        packet.set_next_hop(Some(3));
+        packet.sign_hop(&TEST_KEY, 7).unwrap();
+        let mut relayed = packet.to_bytes().unwrap();
+        assert!(bm_engine.process_packet(relayed.len(), &mut relayed, 100, -50).is_some());
+
+        assert_eq!(bm_engine.get_rx_diagnostics().replayed, 0);
+        assert_eq!(bm_engine.get_next_outbound_packet(100).unwrap().get_next_hop(), Some(4));
+    }
+
+    #[test]
+    fn test_retry_uses_new_frame_counter() {
+        let mut bm_engine = BmNetworkEngine::new(Some(1)).with_network_key(TEST_KEY);
+        bm_engine.table.update_node_route(Some(2), Some(2), 0, 0, -50);
+
+        let mut payload = BmNetworkPacketPayload::new();
+        payload.extend_from_slice(b"secret").unwrap();
+        let _ = bm_engine.initiate_packet_transfer(Some(2), BM_DEFAULT_PORT, true, 5, payload);
+        bm_engine.run_engine(0);
+        let first_counter = bm_engine.get_next_outbound_packet(0).unwrap().get_frame_counter();
+        bm_engine.set_next_outbound_complete(0);
+
+        // Just past the ack timeout the transfer steps through its retry states once each.
+        // run_engine returns the status each step started from.
+        let millis = bm_engine.transfers[0].wait_timeout + 1;
+        assert_eq!(bm_engine.run_engine(millis), BmEngineStatus::WaitingForAck);
+        assert_eq!(bm_engine.run_engine(millis), BmEngineStatus::ErrorNoAck);
+        assert_eq!(bm_engine.run_engine(millis), BmEngineStatus::RetryingPayload);
+        assert_eq!(bm_engine.run_engine(millis), BmEngineStatus::SendingPayload);
+
+        let retry = bm_engine.get_next_outbound_packet(millis).unwrap();
+        assert_ne!(retry.get_frame_counter(), first_counter);
+        assert!(retry.clone().decrypt_payload(&TEST_KEY).is_ok());
+    }
+
+    #[test]
+    fn test_plaintext_retry_is_resigned() {
+        let mut bm_engine = BmNetworkEngine::new(Some(1)).with_network_key(TEST_KEY);
+        bm_engine.table.update_node_route(Some(2), Some(2), 0, 0, -50);
+
+        let _ = bm_engine.initiate_echo_request(Some(2), 5, 0);
+        bm_engine.run_engine(0);
+        let first = bm_engine.get_next_outbound_packet(0).unwrap();
+        assert!(!first.get_info().encrypted());
+        let first_counter = first.verify_hop(&TEST_KEY).unwrap();
+        bm_engine.set_next_outbound_complete(0);
+
+        let millis = bm_engine.transfers[0].wait_timeout + 1;
+        for _ in 0..4 {
+            bm_engine.run_engine(millis);
+        }
+
+        // Payload left as it was, header signed again under a new counter
+        let retry = bm_engine.get_next_outbound_packet(millis).unwrap();
+        assert!(!retry.get_info().encrypted());
+        assert!(BmEchoRequest::from_payload(retry.get_payload().as_deref().unwrap()).is_some());
+        assert!(retry.verify_hop(&TEST_KEY).unwrap() > first_counter);
+    }
+
+    static STORED_COUNTER: AtomicU32 = AtomicU32::new(500);
+    static STORE_WRITES: AtomicU32 = AtomicU32::new(0);
+
+    struct TestCounterStore;
+
+    impl BmFrameCounterStore for TestCounterStore {
+        fn load(&mut self) -> Option<u32> {
+            Some(STORED_COUNTER.load(Ordering::Relaxed))
+        }
+
+        fn store(&mut self, frame_counter: u32) {
+            STORED_COUNTER.store(frame_counter, Ordering::Relaxed);
+            STORE_WRITES.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_frame_counter_survives_reboot() {
+        let store = std::boxed::Box::leak(std::boxed::Box::new(TestCounterStore));
+        let mut bm_engine = BmNetworkEngine::new(Some(1))
+            .with_network_key(TEST_KEY)
+            .with_frame_counter_store(store);
+
+        // First counter after a reboot is above anything stored, and a block is reserved
+        assert_eq!(bm_engine.next_frame_counter(), 501);
+        for _ in 1..BM_FRAME_COUNTER_PERSIST_INTERVAL {
+            bm_engine.next_frame_counter();
+        }
+        assert_eq!(STORE_WRITES.load(Ordering::Relaxed), 1);
+        assert_eq!(STORED_COUNTER.load(Ordering::Relaxed), 500 + BM_FRAME_COUNTER_PERSIST_INTERVAL);
+
+        // Next block is reserved once the first one is used up
+        bm_engine.next_frame_counter();
+        assert_eq!(STORE_WRITES.load(Ordering::Relaxed), 2);
+        assert_eq!(STORED_COUNTER.load(Ordering::Relaxed), 500 + 2 * BM_FRAME_COUNTER_PERSIST_INTERVAL);
+    }
+
+    #[test]
+    fn test_compact_header_originated_and_relayed() {
+        let mut bm_engine = BmNetworkEngine::new(Some(1)).with_compact_header(true);
+        let _ = bm_engine.initiate_packet_transfer(Some(3), BM_DEFAULT_PORT, false, 5, BmNetworkPacketPayload::default());
+
+        // Discovery flood goes out compact, without next hop
+        let request = bm_engine.get_next_outbound_packet(0).unwrap();
+        assert!(request.is_compact_header());
+        let mut bytes = request.to_bytes().unwrap();
+        assert_eq!(bytes.len(), BM_COMPACT_PACKET_HDR_SIZE - 2);
+
+        // A full header relay forwards it compact as well
+        let mut relay = BmNetworkEngine::new(Some(2));
+        assert!(relay.process_packet(bytes.len(), &mut bytes, 0, -50).is_some());
+        let forwarded = relay.get_next_outbound_packet(0).unwrap();
+        assert!(forwarded.is_compact_header());
+        assert_eq!(forwarded.get_source(), Some(2));
+    }
+
+    // Transmits everything queued on `from` to `to`. Packets for which `drop` returns true are lost on air.
+    fn deliver(from: &mut BmNetworkEngine, to: &mut BmNetworkEngine, millis: i64, drop: &mut dyn FnMut(&mut BmNetworkPacket) -> bool) {
+        while let Some(packet) = from.get_next_outbound_packet(millis) {
+            let lost = drop(packet);
+            let mut bytes = packet.to_bytes().unwrap();
+            from.set_next_outbound_complete(millis);
+            if !lost {
+                to.process_packet(bytes.len(), &mut bytes, millis, -50);
+            }
+            from.run_engine(millis);
+        }
+    }
+
+    // Delivers the outbound packets of one node in a chain to another
+    fn deliver_in_chain(nodes: &mut [BmNetworkEngine], from: usize, to: usize, millis: i64) {
+        if from < to {
+            let (left, right) = nodes.split_at_mut(to);
+            deliver(&mut left[from], &mut right[0], millis, &mut |_| false);
+        }
+        else {
+            let (left, right) = nodes.split_at_mut(from);
+            deliver(&mut right[0], &mut left[to], millis, &mut |_| false);
+        }
+    }
+
+    #[test]
+    fn test_discovery_fills_routes_along_chain() {
+        // 1 - 2 - 3 - 4 - 5
+        let mut nodes: std::vec::Vec<BmNetworkEngine> = (1..=5).map(|id| BmNetworkEngine::new(Some(id))).collect();
+
+        // The first request does not reach far enough, the retry does
+        let payload = BmNetworkPacketPayload::from_slice(&[1]).unwrap();
+        let id = nodes[0].initiate_packet_transfer(Some(5), BM_DEFAULT_PORT, true, 5, payload).unwrap();
+        assert_eq!(nodes[0].set_transfer_timeout(id, Some(1000)), BmError::None);
+        nodes[0].run_engine(0);
+        deliver(&mut nodes[0], &mut BmNetworkEngine::new(Some(9)), 0, &mut |_| true);
+        nodes[0].run_engine(2000);
+
+        for index in 0..4 {
+            deliver_in_chain(&mut nodes, index, index + 1, 2000 + 100 * index as i64);
+        }
+        for index in (1..5).rev() {
+            deliver_in_chain(&mut nodes, index, index - 1, 3000 + 100 * index as i64);
+        }
+        assert_eq!(nodes[0].run_engine(4000), BmEngineStatus::RouteFound);
+
+        // Every node knows every other one, through its neighbor on that side
+        for (index, node) in nodes.iter_mut().enumerate() {
+            for other in 0..5usize {
+                if other == index {
+                    continue
+                }
+                let next_hop = if other < index { index } else { index + 2 };
+                let distance = other.abs_diff(index) as u8 - 1;
+                let mut route = node.table.get_best_route(Some(other as u32 + 1)).unwrap();
+                assert_eq!((route.get_next_hop(), route.get_distance()), (Some(next_hop as u32), distance));
+            }
+        }
+    }
+
+    #[test]
+    fn test_beacons_off_by_default() {
+        let mut engine = BmNetworkEngine::new(Some(1));
+        engine.run_engine(0);
+        engine.run_engine(BM_NEIGHBOR_BEACON_INTERVAL_MS);
+        assert_eq!(engine.get_next_outbound_packet(BM_NEIGHBOR_BEACON_INTERVAL_MS), None);
+
+        // Turned on at runtime, the first one is due an interval later
+        engine.set_beacon_interval(Some(1000));
+        engine.run_engine(BM_NEIGHBOR_BEACON_INTERVAL_MS);
+        engine.run_engine(BM_NEIGHBOR_BEACON_INTERVAL_MS + 999);
+        assert_eq!(engine.get_next_outbound_packet(BM_NEIGHBOR_BEACON_INTERVAL_MS + 999), None);
+        engine.run_engine(BM_NEIGHBOR_BEACON_INTERVAL_MS + 1000);
+        let beacon = engine.get_next_outbound_packet(BM_NEIGHBOR_BEACON_INTERVAL_MS + 1000).unwrap();
+        assert_eq!(beacon.packet_type, BmPacketTypes::BcastNeighborTable);
+    }
+
+    #[test]
+    fn test_neighbor_table_beacons() {
+        let mut sender = BmNetworkEngine::new(Some(1)).with_beacon_interval(Some(1000));
+        let mut listener = BmNetworkEngine::new(Some(9));
+        sender.table.update_node_route(Some(2), Some(2), 0, 0, -40);
+        sender.table.update_node_route(Some(3), Some(3), 0, 0, -90);
+        sender.table.update_node_route(Some(4), Some(2), 1, 0, -40);
+        sender.table.update_node_route(Some(5), Some(5), 0, 0, -50);
+        sender.table.update_node_route(Some(6), Some(6), 0, 0, -50);
+
+        // Due an interval after the first run
+        sender.run_engine(0);
+        sender.run_engine(999);
+        assert_eq!(sender.get_next_outbound_packet(999), None);
+        sender.run_engine(1000);
+
+        // Neighbors only, node 4 is two hops away
+        let beacon = sender.get_next_outbound_packet(1000).unwrap();
+        assert_eq!(beacon.packet_type, BmPacketTypes::BcastNeighborTable);
+        let neighbor_table = BmNeighborTable::from_payload(beacon.get_payload().as_deref().unwrap()).unwrap();
+        let ids: std::vec::Vec<NetworkId> = neighbor_table.get_entries().iter().map(|entry| entry.id).collect();
+        assert_eq!(ids, [Some(2), Some(3), Some(5)]);
+        assert_eq!(neighbor_table.get_entries()[1].rssi, -90);
+
+        // The listener learns the sender as neighbor and its neighbors two hops away,
+        // no better than the weaker link. Beacons are not relayed.
+        deliver(&mut sender, &mut listener, 1000, &mut |_| false);
+        assert!(listener.table.is_neighbor(Some(1)));
+        for id in [2, 3, 5] {
+            assert_eq!(listener.table.get_next_hop(Some(id)), Some(1));
+            assert_eq!(listener.table.get_distance(Some(id)), Some(1));
+        }
+        assert_eq!(listener.table.get_best_route(Some(3)).unwrap().get_avg_rssi(), -90);
+        assert_eq!(listener.table.get_next_hop(Some(4)), None);
+        assert_eq!(listener.get_next_outbound_packet(1000), None);
+
+        // The next beacon continues with the rest of the table
+        sender.run_engine(1500);
+        assert_eq!(sender.get_next_outbound_packet(1500), None);
+        sender.run_engine(2000);
+        let beacon = sender.get_next_outbound_packet(2000).unwrap();
+        let neighbor_table = BmNeighborTable::from_payload(beacon.get_payload().as_deref().unwrap()).unwrap();
+        assert_eq!(neighbor_table.get_entries()[0].id, Some(6));
+    }
+
+    #[test]
+    fn test_proactive_mode_uses_advertised_routes() {
+        // 1 - 2 - 3
+        let mut nodes: std::vec::Vec<BmNetworkEngine> = (1..=3)
+            .map(|id| BmNetworkEngine::new(Some(id))
+                .with_beacon_interval(Some(1000))
+                .with_routing_mode(BmRoutingMode::Proactive))
+            .collect();
+        for node in nodes.iter_mut() {
+            node.run_engine(0);
+        }
+
+        // Node 3 advertises itself, node 2 passes the route on with its sequence number
+        nodes[2].run_engine(1000);
+        deliver_in_chain(&mut nodes, 2, 1, 1000);
+        nodes[1].run_engine(1000);
+        deliver_in_chain(&mut nodes, 1, 0, 1000);
+        assert_eq!(nodes[0].table.get_next_hop(Some(3)), Some(2));
+        assert_eq!(nodes[0].table.get_distance(Some(3)), Some(1));
+        assert_eq!(nodes[0].table.find_node_by_id(Some(3)).unwrap().get_dest_seq(), Some(1));
+
+        // Node 1 advertises the route back, node 2 keeps only its shorter one
+        nodes[0].run_engine(1000);
+        deliver_in_chain(&mut nodes, 0, 1, 1000);
+        assert!(nodes[1].table.find_node_by_id(Some(3)).unwrap().get_route_via(Some(1)).is_none());
+        assert_eq!(nodes[1].table.get_next_hop(Some(3)), Some(3));
+
+        // Advertised destinations are sent to right away, unknown ones fail without discovery
+        let payload = BmNetworkPacketPayload::from_slice(&[1]).unwrap();
+        let routed = nodes[0].initiate_packet_transfer(Some(3), BM_DEFAULT_PORT, true, 5, payload.clone()).unwrap();
+        let unknown = nodes[0].initiate_packet_transfer(Some(7), BM_DEFAULT_PORT, true, 5, payload).unwrap();
+        nodes[0].run_engine(1000);
+        nodes[0].run_engine(1000);
+        assert_eq!(nodes[0].get_transfer_state(routed), Some(BmTransferState::InFlight));
+        assert_eq!(nodes[0].get_transfer_state(unknown), Some(BmTransferState::Failed(BmTransferError::NoRoute)));
+
+        let packet = nodes[0].get_next_outbound_packet(1000).unwrap();
+        assert_eq!(packet.packet_type, BmPacketTypes::DataPayload);
+        assert_eq!(packet.get_next_hop(), Some(2));
+        nodes[0].set_next_outbound_complete(1000);
+        assert_eq!(nodes[0].get_next_outbound_packet(1000), None);
+    }
+
+    #[test]
+    fn test_lost_route_not_learned_back_through_neighbor() {
+        // 1 - 2 - 3, node 1 routes to node 3 through node 2
+        let mut nodes: std::vec::Vec<BmNetworkEngine> = (1..=3)
+            .map(|id| BmNetworkEngine::new(Some(id))
+                .with_beacon_interval(Some(1000))
+                .with_routing_mode(BmRoutingMode::Proactive))
+            .collect();
+        for node in nodes.iter_mut() {
+            node.run_engine(0);
+        }
+        nodes[2].run_engine(1000);
+        deliver_in_chain(&mut nodes, 2, 1, 1000);
+        nodes[1].run_engine(1000);
+        deliver_in_chain(&mut nodes, 1, 0, 1000);
+        assert_eq!(nodes[0].table.get_next_hop(Some(3)), Some(2));
+
+        // The link from node 2 to node 3 breaks. Node 1 still advertises its route through
+        // node 2 under the same number, node 2 must not take it or the two loop.
+        nodes[1].invalidate_route(Some(3), Some(3));
+        nodes[0].run_engine(2000);
+        deliver_in_chain(&mut nodes, 0, 1, 2000);
+        assert_eq!(nodes[1].table.get_next_hop(Some(3)), None);
+
+        // A newer number from node 3 itself brings the route back
+        nodes[2].run_engine(2000);
+        deliver_in_chain(&mut nodes, 2, 1, 2000);
+        assert_eq!(nodes[1].table.get_next_hop(Some(3)), Some(3));
+        assert_eq!(nodes[1].table.find_node_by_id(Some(3)).unwrap().get_dest_seq(), Some(2));
+    }
+
+    #[test]
+    fn test_dest_seq_restarts() {
+        let mut listener = BmNetworkEngine::new(Some(2));
+        listener.table.update_node_route_seq(Some(3), Some(4), 1, 500, 0, -50);
+        listener.table.update_node_route_seq(Some(3), Some(3), 0, 500, 0, -50);
+
+        // Node 3 rebooted and counts from 1 again, heard directly its number is taken.
+        // Routes learned under the old numbers go, the direct link stays.
+        let mut rebooted = BmNetworkEngine::new(Some(3))
+            .with_beacon_interval(Some(1000))
+            .with_routing_mode(BmRoutingMode::Proactive);
+        rebooted.run_engine(0);
+        rebooted.run_engine(1000);
+        deliver(&mut rebooted, &mut listener, 1000, &mut |_| false);
+        let node = listener.table.find_node_by_id(Some(3)).unwrap();
+        assert_eq!(node.get_dest_seq(), Some(1));
+        assert!(node.get_route_via(Some(4)).is_none());
+        assert!(node.is_neighbor());
+
+        // Once its routes expire the node and its number are forgotten
+        listener.run_engine(1000 + BM_ROUTE_TIMEOUT_MS + 1);
+        assert!(listener.table.find_node_by_id(Some(3)).is_none());
+    }
+
+    #[test]
+    fn test_beacons_naming_more_nodes_than_the_table_holds() {
+        let mut listener = BmNetworkEngine::new(Some(9));
+        let beacons = BM_MAX_NET_DEVICES / BM_NEIGHBOR_BEACON_ENTRIES + 5;
+        for beacon in 0..beacons as u32 {
+            let mut neighbor_table = BmNeighborTable::new(0);
+            for entry in 0..BM_NEIGHBOR_BEACON_ENTRIES as u32 {
+                let id = Some(1000 + beacon * BM_NEIGHBOR_BEACON_ENTRIES as u32 + entry);
+                neighbor_table.push(BmNeighborEntry { id, rssi: -60, distance: 0, seq: 0 });
+            }
+            let mut packet = BmNetworkPacket::new(
+                BmPacketTypes::BcastNeighborTable, Some(2), None, None, 1, false, Some(neighbor_table.to_payload())
+            ).with_seq(beacon as u16);
+            let mut bytes = packet.to_bytes().unwrap();
+            listener.process_packet(bytes.len(), &mut bytes, 0, -60);
+        }
+
+        // Nodes that do not fit are refused, the sender stays
+        assert_eq!(listener.table.get_num_nodes(), BM_MAX_NET_DEVICES);
+        assert!(listener.table.is_neighbor(Some(2)));
+
+        // Expired nodes are dropped, making room again
+        listener.run_engine(BM_ROUTE_TIMEOUT_MS + 1);
+        assert_eq!(listener.table.get_num_nodes(), 0);
+        listener.table.update_node_route(Some(3), Some(3), 0, BM_ROUTE_TIMEOUT_MS + 1, -50);
+        assert!(listener.table.is_neighbor(Some(3)));
+    }
+
+    #[test]
+    fn test_hybrid_mode_advertises_and_discovers() {
+        let mut sender = BmNetworkEngine::new(Some(1))
+            .with_beacon_interval(Some(1000))
+            .with_routing_mode(BmRoutingMode::Hybrid);
+        sender.table.update_node_route(Some(2), Some(2), 0, 0, -40);
+        sender.table.update_node_route(Some(4), Some(2), 1, 0, -40);
+
+        // Routes of any distance are advertised, with a new sequence number every beacon
+        sender.run_engine(0);
+        sender.run_engine(1000);
+        let beacon = sender.get_next_outbound_packet(1000).unwrap();
+        let neighbor_table = BmNeighborTable::from_payload(beacon.get_payload().as_deref().unwrap()).unwrap();
+        let ids: std::vec::Vec<NetworkId> = neighbor_table.get_entries().iter().map(|entry| entry.id).collect();
+        assert_eq!(ids, [Some(2), Some(4)]);
+        assert_eq!(neighbor_table.seq, 1);
+        sender.set_next_outbound_complete(1000);
+        sender.run_engine(2000);
+        let beacon = sender.get_next_outbound_packet(2000).unwrap();
+        assert_eq!(BmNeighborTable::from_payload(beacon.get_payload().as_deref().unwrap()).unwrap().seq, 2);
+        sender.set_next_outbound_complete(2000);
+
+        // Destinations the beacons did not bring are still discovered
+        let payload = BmNetworkPacketPayload::from_slice(&[1]).unwrap();
+        let id = sender.initiate_packet_transfer(Some(7), BM_DEFAULT_PORT, true, 5, payload).unwrap();
+        assert_eq!(sender.get_transfer_state(id), Some(BmTransferState::Discovering));
+
+        // Switching to reactive at runtime goes back to neighbor beacons, the sequence number stays
+        sender.set_routing_mode(BmRoutingMode::Reactive);
+        assert_eq!(sender.get_routing_mode(), BmRoutingMode::Reactive);
+        sender.run_engine(3000);
+        while let Some(packet) = sender.get_next_outbound_packet(3000) {
+            if packet.packet_type == BmPacketTypes::BcastNeighborTable {
+                let neighbor_table = BmNeighborTable::from_payload(packet.get_payload().as_deref().unwrap()).unwrap();
+                assert_eq!(neighbor_table.get_entries().len(), 1);
+                assert_eq!(neighbor_table.seq, 2);
+                return
+            }
+            sender.set_next_outbound_complete(3000);
+        }
+        panic!("no beacon sent");
+    }
+
+    #[test]
+    fn test_overheard_unicast_is_learned_only_when_signed() {
+        // Node 2 relays a packet from node 1 to node 3 through node 4
+        let mut packet = BmNetworkPacket::new(BmPacketTypes::DataPayloadAck, Some(1), Some(4), Some(3), 5, false, None)
+            .with_seq(1);
+        packet.set_source(Some(2));
+        packet.increment_hop_count();
+
+        // Without a hop MIC anyone could claim the route, it is dropped unlearned
+        let mut listener = BmNetworkEngine::new(Some(9));
+        let mut bytes = packet.to_bytes().unwrap();
+        assert!(listener.process_packet(bytes.len(), &mut bytes, 100, -70).is_none());
+        assert_eq!(listener.get_rx_diagnostics().overheard, 1);
+        assert_eq!(listener.table.get_num_nodes(), 0);
+
+        // Signed by node 2, the route through it is learned but the packet not relayed
+        let mut keyed_listener = BmNetworkEngine::new(Some(9)).with_network_key(TEST_KEY);
+        keyed_listener.table.update_node_route(Some(3), Some(3), 0, 0, -50);
+        packet.sign_hop(&TEST_KEY, 5).unwrap();
+        let mut signed_bytes = packet.to_bytes().unwrap();
+        assert!(keyed_listener.process_packet(signed_bytes.len(), &mut signed_bytes, 100, -70).is_none());
+
+        assert_eq!(keyed_listener.get_next_outbound_packet(100), None);
+        assert_eq!(keyed_listener.get_rx_diagnostics().overheard, 1);
+        assert_eq!(keyed_listener.table.get_next_hop(Some(1)), Some(2));
+        assert_eq!(keyed_listener.table.get_distance(Some(1)), Some(1));
+        assert!(keyed_listener.table.is_neighbor(Some(2)));
+    }
+
+    #[test]
+    fn test_replayed_overheard_frame_not_learned() {
+        let mut listener = BmNetworkEngine::new(Some(9)).with_network_key(TEST_KEY);
+
+        // Node 2 relays a packet from node 1 to node 3, recorded by an attacker
+        let mut packet = BmNetworkPacket::new(BmPacketTypes::DataPayloadAck, Some(1), Some(4), Some(3), 5, false, None)
+            .with_seq(1);
+        packet.set_source(Some(2));
+        packet.increment_hop_count();
+        packet.sign_hop(&TEST_KEY, 5).unwrap();
+        let recorded = packet.to_bytes().unwrap();
+        let mut bytes = recorded.clone();
+        assert!(listener.process_packet(bytes.len(), &mut bytes, 0, -70).is_none());
+        assert_eq!(listener.table.get_next_hop(Some(1)), Some(2));
+
+        // Node 2 sends on, its counters move past the recorded one, then it goes away
+        let mut direct = BmNetworkPacket::new(BmPacketTypes::DataPayloadAck, Some(2), Some(9), Some(9), 5, false, None)
+            .with_seq(2);
+        direct.sign_hop(&TEST_KEY, 100).unwrap();
+        let mut bytes = direct.to_bytes().unwrap();
+        listener.process_packet(bytes.len(), &mut bytes, 100, -70);
+        assert!(listener.table.remove_route(Some(1), Some(2)));
+        assert!(listener.table.remove_route(Some(2), Some(2)));
+
+        // The replay passes its MIC, but the route does not come back
+        let mut bytes = recorded.clone();
+        assert!(listener.process_packet(bytes.len(), &mut bytes, 1000, -70).is_none());
+        assert_eq!(listener.get_rx_diagnostics().replayed, 1);
+        assert_eq!(listener.get_rx_diagnostics().overheard, 1);
+        assert_eq!(listener.table.get_next_hop(Some(1)), None);
+        assert!(!listener.table.is_neighbor(Some(2)));
+    }
+
+    // Packet from node 1 to node 3, handed to node 2 with a LinkAck request
+    fn build_link_acked_data() -> BmNetworkPacket {
+        let mut packet = BmNetworkPacket::new(BmPacketTypes::DataPayload, Some(1), Some(2), Some(3), 5, false, None)
+            .with_seq(1);
+        packet.set_link_ack(true);
+        packet
+    }
+
+    #[test]
+    fn test_link_ack_ends_relay_retransmissions() {
+        let mut relay = BmNetworkEngine::new(Some(2)).with_link_acks(true);
+        let mut dest = BmNetworkEngine::new(Some(3));
+        relay.table.update_node_route(Some(3), Some(3), 0, 0, -50);
+
+        // The hop is acked before the relayed copy goes out
+        let mut bytes = build_link_acked_data().to_bytes().unwrap();
+        assert!(relay.process_packet(bytes.len(), &mut bytes, 0, -50).is_some());
+        let ack = relay.get_next_outbound_packet(0).unwrap();
+        assert_eq!(ack.packet_type, BmPacketTypes::LinkAck);
+        assert_eq!(ack.get_destination(), Some(1));
+        assert_eq!(BmLinkAck::from_payload(ack.get_payload().as_deref().unwrap()), Some(BmLinkAck { orig: Some(1), seq: 1 }));
+        relay.set_next_outbound_complete(0);
+
+        // A retransmission from node 1 is acked again but not relayed twice
+        let mut bytes = build_link_acked_data().to_bytes().unwrap();
+        assert!(relay.process_packet(bytes.len(), &mut bytes, 10, -50).is_none());
+        assert_eq!(relay.get_rx_diagnostics().duplicate_link, 1);
+        assert_eq!(relay.get_next_outbound_packet(10).unwrap().packet_type, BmPacketTypes::LinkAck);
+        relay.set_next_outbound_complete(10);
+
+        // The relayed copy asks node 3 for a LinkAck and is kept until it arrives. Node 3
+        // acks without link acks of its own.
+        let forwarded = relay.get_next_outbound_packet(10).unwrap();
+        assert!(forwarded.is_link_ack_requested());
+        deliver(&mut relay, &mut dest, 10, &mut |_| false);
+        assert_eq!(relay.outbound.len(), 1);
+        deliver(&mut dest, &mut relay, 20, &mut |_| false);
+        assert!(relay.outbound.is_empty());
+        relay.run_engine(100_000);
+        assert_eq!(relay.get_next_outbound_packet(100_000), None);
+    }
+
+    #[test]
+    fn test_silent_next_hop_retransmits_then_reroutes() {
+        let mut relay = BmNetworkEngine::new(Some(2)).with_link_acks(true);
+        relay.table.update_node_route(Some(3), Some(3), 0, 0, -50);
+        relay.table.update_node_route(Some(3), Some(4), 1, 0, -50);
+
+        let mut bytes = build_link_acked_data().to_bytes().unwrap();
+        assert!(relay.process_packet(bytes.len(), &mut bytes, 0, -50).is_some());
+        relay.get_next_outbound_packet(0).unwrap();
+        relay.set_next_outbound_complete(0);
+
+        // Sent once and retransmitted BM_LINK_RETRY_COUNT times on the best route
+        let mut millis = 0;
+        for _ in 0..=BM_LINK_RETRY_COUNT {
+            relay.run_engine(millis);
+            let packet = relay.get_next_outbound_packet(millis).unwrap();
+            assert_eq!(packet.get_next_hop(), Some(3));
+            relay.set_next_outbound_complete(millis);
+            assert_eq!(relay.get_next_outbound_packet(millis), None);
+            millis += 20_000;
+        }
+
+        // The silent route counts a failure without being refreshed, and the alternate
+        // route takes the packet, never the node it came from
+        relay.run_engine(millis);
+        let node = relay.table.find_node_by_id(Some(3)).unwrap();
+        assert_eq!(node.get_route_via(Some(3)).unwrap().get_failures(), 1);
+        assert_eq!(node.get_route_via(Some(3)).unwrap().get_timestamp_millis(), 0);
+        assert_eq!(node.get_route_via(Some(4)).unwrap().get_failures(), 0);
+        let packet = relay.get_next_outbound_packet(millis).unwrap();
+        assert_eq!(packet.get_next_hop(), Some(4));
+        relay.set_next_outbound_complete(millis);
+
+        // Once the alternate stays silent as well, the originator gets a route error
+        for _ in 0..BM_LINK_RETRY_COUNT {
+            millis += 20_000;
+            relay.run_engine(millis);
+            relay.get_next_outbound_packet(millis).unwrap();
+            relay.set_next_outbound_complete(millis);
+        }
+        millis += 20_000;
+        relay.run_engine(millis);
+        let error = relay.get_next_outbound_packet(millis).unwrap();
+        assert_eq!(error.packet_type, BmPacketTypes::RouteDiscoveryError);
+        assert_eq!(error.get_destination(), Some(1));
+        assert_eq!(relay.outbound.len(), 1);
+    }
+
+    // Queues the next packet of `from`, returns its frame and completes it at millis
+    fn transmit(from: &mut BmNetworkEngine, millis: i64) -> BmNetworkOtaPacket {
+        let bytes = from.get_next_outbound_packet(millis).unwrap().to_bytes().unwrap();
+        from.set_next_outbound_complete(millis);
+        bytes
+    }
+
+    #[test]
+    fn test_passive_ack_by_overheard_forward() {
+        // 1 - 2 - 3
+        let mut nodes: std::vec::Vec<BmNetworkEngine> = (1..=3).map(|id| BmNetworkEngine::new(Some(id)).with_passive_acks(true)).collect();
+        nodes[0].table.update_node_route(Some(3), Some(2), 1, 0, -50);
+        nodes[1].table.update_node_route(Some(3), Some(3), 0, 0, -50);
+        nodes[0].initiate_packet_transfer(Some(3), 1, true, 5, BmNetworkPacketPayload::new()).unwrap();
+        nodes[0].run_engine(0);
+
+        // The first hop is acked by listening, not with a LinkAck
+        let packet = nodes[0].get_next_outbound_packet(0).unwrap();
+        assert!(packet.passive_ack);
+        assert!(!packet.is_link_ack_requested());
+        let mut bytes = transmit(&mut nodes[0], 0);
+        nodes[1].process_packet(bytes.len(), &mut bytes, 0, -50);
+
+        // The last hop asks for a LinkAck, the destination does not forward
+        let forward = transmit(&mut nodes[1], 10);
+        let mut overheard = forward.clone();
+        assert_eq!(nodes[0].process_packet(overheard.len(), &mut overheard, 10, -50), None);
+        assert!(nodes[0].outbound[0].link_ack_deadline.is_none());
+        let mut bytes = forward.clone();
+        nodes[2].process_packet(bytes.len(), &mut bytes, 10, -50);
+        assert!(nodes[1].outbound[0].is_link_ack_requested());
+        assert!(nodes[1].outbound[0].link_ack_deadline.is_some());
+
+        // Heard the forward, so node 1 waits for the end to end ack only
+        nodes[0].run_engine(10_000);
+        assert_eq!(nodes[0].get_next_outbound_packet(10_000), None);
+    }
+
+    #[test]
+    fn test_unheard_forward_retransmits_with_link_ack() {
+        let mut orig = BmNetworkEngine::new(Some(1)).with_passive_acks(true);
+        let mut relay = BmNetworkEngine::new(Some(2));
+        orig.table.update_node_route(Some(3), Some(2), 1, 0, -50);
+        relay.table.update_node_route(Some(3), Some(3), 0, 0, -50);
+        orig.initiate_packet_transfer(Some(3), 1, true, 5, BmNetworkPacketPayload::new()).unwrap();
+        orig.run_engine(0);
+
+        // The relay forwards, but node 1 misses it
+        let mut bytes = transmit(&mut orig, 0);
+        relay.process_packet(bytes.len(), &mut bytes, 0, -50);
+        transmit(&mut relay, 0);
+        assert!(relay.outbound.is_empty());
+
+        // After the window the retransmission asks for a LinkAck. The relay acks it
+        // without relaying the packet again.
+        let millis = orig.outbound[0].link_ack_deadline.unwrap();
+        orig.run_engine(millis);
+        let packet = orig.get_next_outbound_packet(millis).unwrap();
+        assert!(packet.is_link_ack_requested());
+        assert!(!packet.passive_ack);
+        assert_eq!(packet.link_retries, 1);
+        let mut bytes = transmit(&mut orig, millis);
+        assert_eq!(relay.process_packet(bytes.len(), &mut bytes, millis, -50), None);
+        assert_eq!(relay.get_rx_diagnostics().duplicate_link, 1);
+        assert_eq!(relay.outbound.len(), 1);
+        deliver(&mut relay, &mut orig, millis, &mut |_| false);
+        assert!(orig.outbound[0].link_ack_deadline.is_none());
+        assert_eq!(orig.outbound[0].link_retries, 0);
+    }
+
+    #[test]
+    fn test_keyed_passive_ack_checks_forward() {
+        let mut orig = BmNetworkEngine::new(Some(1)).with_network_key(TEST_KEY).with_passive_acks(true);
+        let mut relay = BmNetworkEngine::new(Some(2)).with_network_key(TEST_KEY);
+        orig.table.update_node_route(Some(3), Some(2), 1, 0, -50);
+        relay.table.update_node_route(Some(3), Some(3), 0, 0, -50);
+        orig.initiate_echo_request(Some(3), 5, 0).unwrap();
+        orig.run_engine(0);
+
+        let mut bytes = transmit(&mut orig, 0);
+        relay.process_packet(bytes.len(), &mut bytes, 0, -50);
+        let forward = transmit(&mut relay, 0);
+
+        // A forged forward is ignored, the signed one acks the hop
+        let mut forged = forward.clone();
+        let last = forged.len() - 1;
+        forged[last] ^= 0x01;
+        orig.process_packet(forged.len(), &mut forged, 0, -50);
+        assert!(orig.outbound[0].link_ack_deadline.is_some());
+        let mut bytes = forward.clone();
+        orig.process_packet(bytes.len(), &mut bytes, 0, -50);
+        assert!(orig.outbound[0].link_ack_deadline.is_none());
+    }
+
+    #[test]
+    fn test_keyed_relay_acks_replayed_retransmission() {
+        let mut relay = BmNetworkEngine::new(Some(2)).with_network_key(TEST_KEY);
+        relay.table.update_node_route(Some(3), Some(3), 0, 0, -50);
+
+        let mut packet = BmNetworkPacket::new(BmPacketTypes::EchoRequest, Some(1), Some(2), Some(3), 5, true, None)
+            .with_seq(1);
+        packet.set_link_ack(true);
+        packet.sign_hop(&TEST_KEY, 10).unwrap();
+        let frame = packet.to_bytes().unwrap();
+
+        let mut bytes = frame.clone();
+        assert!(relay.process_packet(bytes.len(), &mut bytes, 0, -50).is_some());
+        assert_eq!(relay.get_next_outbound_packet(0).unwrap().packet_type, BmPacketTypes::LinkAck);
+        relay.set_next_outbound_complete(0);
+        relay.set_next_outbound_complete(0);
+
+        // The retransmission is a replay, but authentic, so our lost LinkAck is sent again
+        let mut bytes = frame.clone();
+        assert!(relay.process_packet(bytes.len(), &mut bytes, 10, -50).is_none());
+        assert_eq!(relay.get_rx_diagnostics().replayed, 1);
+        let ack = relay.get_next_outbound_packet(10).unwrap();
+        assert_eq!(ack.packet_type, BmPacketTypes::LinkAck);
+        assert!(ack.is_authenticated());
+        relay.set_next_outbound_complete(10);
+
+        // Once the sender stopped retransmitting, a replayed copy is not acked
+        let millis = relay.get_link_seen_window() + 1;
+        let mut bytes = frame.clone();
+        assert!(relay.process_packet(bytes.len(), &mut bytes, millis, -50).is_none());
+        assert_eq!(relay.get_rx_diagnostics().replayed, 2);
+        assert_eq!(relay.get_next_outbound_packet(millis), None);
+    }
+
+    #[test]
+    fn test_destination_acks_retransmission_once_delivered() {
+        let mut dest = BmNetworkEngine::new(Some(2));
+        let mut packet = BmNetworkPacket::new(BmPacketTypes::DataPayload, Some(1), Some(2), Some(2), 5, true, None)
+            .with_seq(1);
+        packet.set_link_ack(true);
+
+        // Delivered once, with a LinkAck and the end to end ack
+        let mut bytes = packet.to_bytes().unwrap();
+        assert!(dest.process_packet(bytes.len(), &mut bytes, 0, -50).is_some());
+        assert_eq!(dest.get_next_outbound_packet(0).unwrap().packet_type, BmPacketTypes::LinkAck);
+        dest.set_next_outbound_complete(0);
+        assert_eq!(dest.get_next_outbound_packet(0).unwrap().packet_type, BmPacketTypes::DataPayloadAck);
+        dest.set_next_outbound_complete(0);
+
+        // The retransmission after a lost LinkAck is acked again, but not delivered twice
+        let mut bytes = packet.to_bytes().unwrap();
+        assert!(dest.process_packet(bytes.len(), &mut bytes, 10, -50).is_none());
+        assert_eq!(dest.get_rx_diagnostics().duplicate_link, 1);
+        assert_eq!(dest.get_next_outbound_packet(10).unwrap().packet_type, BmPacketTypes::LinkAck);
+        dest.set_next_outbound_complete(10);
+        assert_eq!(dest.get_next_outbound_packet(10), None);
+    }
+
+    #[test]
+    fn test_fragmented_message_resends_only_missing() {
+        let mut sender = BmNetworkEngine::new(Some(1)).with_network_key(TEST_KEY);
+        let mut receiver = BmNetworkEngine::new(Some(2)).with_network_key(TEST_KEY);
+        sender.table.update_node_route(Some(2), Some(2), 0, 0, -50);
+
+        let message: std::vec::Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        assert!(sender.initiate_message_transfer(Some(2), BM_DEFAULT_PORT, true, 5, &message).is_ok());
+
+        // First round loses fragment 1
+        let mut sent: std::vec::Vec<u8> = std::vec::Vec::new();
+        let mut record = |packet: &mut BmNetworkPacket, lose: Option<u8>| {
+            let mut copy = packet.clone();
+            copy.decrypt_payload(&TEST_KEY).unwrap();
+            let index = BmFragmentHdr::from_payload(copy.get_payload().as_ref().unwrap()).unwrap().0.index;
+            sent.push(index);
+            Some(index) == lose
+        };
+        sender.run_engine(0);
+        deliver(&mut sender, &mut receiver, 0, &mut |packet| record(packet, Some(1)));
+        assert_eq!(sender.run_engine(0), BmEngineStatus::WaitingForAck);
+        assert_eq!(receiver.get_inbound_message_count(), 0);
+
+        // Fragment ack reports the gap, only fragment 1 goes out again
+        deliver(&mut receiver, &mut sender, 10, &mut |_| false);
+        sender.run_engine(20);
+        deliver(&mut sender, &mut receiver, 20, &mut |packet| record(packet, None));
+        assert_eq!(sent, [0, 1, 2, 3, 4, 5, 1]);
+
+        deliver(&mut receiver, &mut sender, 30, &mut |_| false);
+        assert_eq!(sender.run_engine(40), BmEngineStatus::AckReceieved);
+
+        // One complete message reaches the application
+        assert_eq!(receiver.get_inbound_message_count(), 1);
+        let inbound = receiver.get_inbound_message().unwrap();
+        assert_eq!(inbound.get_originator(), Some(1));
+        assert_eq!(inbound.get_payload(), message.as_slice());
+    }
+
+    #[test]
+    fn test_echo_request_over_relay() {
+        let mut sender = BmNetworkEngine::new(Some(1)).with_network_key(TEST_KEY);
+        let mut relay = BmNetworkEngine::new(Some(2)).with_network_key(TEST_KEY);
+        let mut target = BmNetworkEngine::new(Some(3)).with_network_key(TEST_KEY);
+        sender.table.update_node_route(Some(3), Some(2), 1, 0, -50);
+        relay.table.update_node_route(Some(3), Some(3), 0, 0, -50);
+
+        assert!(sender.initiate_echo_request(Some(3), 5, 0).is_ok());
+        assert_eq!(sender.initiate_echo_request(Some(3), 5, 0), Err(BmError::Busy));
+
+        // Request is stamped when it is sent, answered automatically by the target
+        sender.run_engine(100);
+        deliver(&mut sender, &mut relay, 100, &mut |_| false);
+        deliver(&mut relay, &mut target, 150, &mut |_| false);
+        deliver(&mut target, &mut relay, 200, &mut |_| false);
+        assert!(sender.get_echo_result().is_none());
+        deliver(&mut relay, &mut sender, 250, &mut |_| false);
+        assert_eq!(sender.run_engine(250), BmEngineStatus::AckReceieved);
+
+        let result = sender.get_echo_result().unwrap();
+        assert_eq!(result.dest, Some(3));
+        assert_eq!(result.rtt_millis, 150);
+        assert_eq!(result.request_hop_count, 1);
+        assert_eq!(result.reply_hop_count, 1);
+        assert_eq!((result.request_rssi, result.reply_rssi), (-50, -50));
+        assert!(sender.get_echo_result().is_none());
+
+        // Echo traffic is never handed to the application
+        assert_eq!(target.get_inbound_message_count(), 0);
+    }
+
+    #[test]
+    fn test_trace_request_records_relays() {
+        let mut sender = BmNetworkEngine::new(Some(1)).with_network_key(TEST_KEY);
+        let mut relay = BmNetworkEngine::new(Some(2)).with_network_key(TEST_KEY);
+        let mut target = BmNetworkEngine::new(Some(3)).with_network_key(TEST_KEY);
+        sender.table.update_node_route(Some(3), Some(2), 1, 0, -50);
+        relay.table.update_node_route(Some(3), Some(3), 0, 0, -50);
+
+        assert!(sender.initiate_trace_request(Some(3), 5).is_ok());
+        sender.run_engine(0);
+        deliver(&mut sender, &mut relay, 0, &mut |_| false);
+        deliver(&mut relay, &mut target, 0, &mut |_| false);
+        deliver(&mut target, &mut relay, 0, &mut |_| false);
+        deliver(&mut relay, &mut sender, 0, &mut |_| false);
+        assert_eq!(sender.run_engine(0), BmEngineStatus::AckReceieved);
+
+        // Relay first, destination last
+        let result = sender.get_trace_result().unwrap();
+        assert_eq!(result.dest, Some(3));
+        let hops: std::vec::Vec<NetworkId> = result.path.get_hops().iter().map(|hop| hop.id).collect();
+        assert_eq!(hops, [Some(2), Some(3)]);
+        assert!(result.path.get_hops().iter().all(|hop| hop.rssi == -50));
+        assert!(sender.get_trace_result().is_none());
+    }
+
+    static PORT_MESSAGES: AtomicU32 = AtomicU32::new(0);
+
+    struct TestPortHandler;
+
+    impl BmPortHandler for TestPortHandler {
+        fn receive(&mut self, message: &BmNetworkMessage) {
+            assert_eq!(message.get_payload(), b"telemetry");
+            PORT_MESSAGES.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_messages_delivered_by_port() {
+        let mut sender = BmNetworkEngine::new(Some(1)).with_network_key(TEST_KEY);
+        let mut receiver = BmNetworkEngine::new(Some(2)).with_network_key(TEST_KEY);
+        sender.table.update_node_route(Some(2), Some(2), 0, 0, -50);
+
+        let handler = std::boxed::Box::leak(std::boxed::Box::new(TestPortHandler));
+        assert_eq!(receiver.register_port_handler(7, handler), BmError::None);
+
+        // One message to the handled port, two to ports without a handler
+        for (port, data) in [(7, &b"telemetry"[..]), (BM_DEFAULT_PORT, b"hello"), (9, b"status")] {
+            assert!(sender.initiate_message_transfer(Some(2), port, false, 5, data).is_ok());
+            sender.run_engine(0);
+            deliver(&mut sender, &mut receiver, 0, &mut |_| false);
+            sender.run_engine(0);
+            assert_eq!(sender.run_engine(0), BmEngineStatus::Idle);
+        }
+
+        assert_eq!(PORT_MESSAGES.load(Ordering::Relaxed), 1);
+        assert_eq!(receiver.get_inbound_message_count(), 2);
+        assert_eq!(receiver.get_inbound_message_count_on_port(7), 0);
+
+        let message = receiver.get_inbound_message_on_port(BM_DEFAULT_PORT).unwrap();
+        assert_eq!(message.get_payload(), b"hello");
+        assert!(receiver.get_inbound_message_on_port(BM_DEFAULT_PORT).is_none());
+        assert_eq!(receiver.get_inbound_message().unwrap().get_port(), 9);
+
+        assert!(receiver.unregister_port_handler(7).is_some());
+        assert!(receiver.unregister_port_handler(7).is_none());
+    }
+
+    #[test]
+    fn test_engine_events() {
+        let mut sender = BmNetworkEngine::new(Some(1));
+        let mut receiver = BmNetworkEngine::new(Some(2));
+        sender.table.update_node_route(Some(2), Some(2), 0, 0, -50);
+
+        let payload = BmNetworkPacketPayload::from_slice(b"hi").unwrap();
+        let id = sender.initiate_packet_transfer(Some(2), BM_DEFAULT_PORT, true, 5, payload).unwrap();
+        sender.run_engine(100);
+        deliver(&mut sender, &mut receiver, 100, &mut |_| false);
+
+        // Receiver hears the sender directly and gets the message
+        assert_eq!(receiver.get_next_event(), Some(BmEngineEvent::RouteFound { dest: Some(1) }));
+        assert_eq!(receiver.get_next_event(), Some(BmEngineEvent::NeighborFound { id: Some(1) }));
+        assert_eq!(receiver.get_next_event(), Some(BmEngineEvent::MessageReceived { orig: Some(1), port: BM_DEFAULT_PORT }));
+        assert_eq!(receiver.get_next_event(), None);
+
+        // The ack completes the transfer. The route to 2 existed, so nothing new is found.
+        deliver(&mut receiver, &mut sender, 150, &mut |_| false);
+        sender.run_engine(200);
+        sender.run_engine(200);
+        assert_eq!(sender.get_next_event(), Some(BmEngineEvent::TransferDelivered { id, dest: Some(2) }));
+        assert_eq!(sender.get_next_event(), None);
+
+        // Routes that are not refreshed time out
+        sender.run_engine(150 + BM_ROUTE_TIMEOUT_MS + 1);
+        assert_eq!(sender.get_next_event(), Some(BmEngineEvent::NeighborLost { id: Some(2) }));
+        assert_eq!(sender.get_next_event(), Some(BmEngineEvent::RouteLost { dest: Some(2) }));
+        assert_eq!(sender.get_next_event(), None);
+        assert_eq!(sender.table.get_next_hop(Some(2)), None);
+    }
+
+    // Sends a fragmented message to node 2 over a direct route, returns the sender status
+    fn send_fragmented(sender: &mut BmNetworkEngine, receiver: &mut BmNetworkEngine, message: &[u8], millis: i64) -> BmEngineStatus {
+        sender.table.update_node_route(Some(2), Some(2), 0, millis, -50);
+        assert!(sender.initiate_message_transfer(Some(2), BM_DEFAULT_PORT, true, 5, message).is_ok());
+        sender.run_engine(millis);
+        deliver(sender, receiver, millis, &mut |_| false);
+        deliver(receiver, sender, millis, &mut |_| false);
+        sender.run_engine(millis)
+    }
+
+    #[test]
+    fn test_fragmented_message_after_sender_reboot() {
+        let mut receiver = BmNetworkEngine::new(Some(2));
+        let message = [7u8; BM_MAX_PAYLOAD_SIZE + 100];
+        let mut sender = BmNetworkEngine::new(Some(1));
+        assert_eq!(send_fragmented(&mut sender, &mut receiver, &message, 0), BmEngineStatus::AckReceieved);
+        assert_eq!(receiver.get_inbound_message().unwrap().get_payload(), message.as_slice());
+
+        // Rebooted with an RNG, the message id starts elsewhere and the message is delivered
+        let rng = std::boxed::Box::leak(std::boxed::Box::new(TestRng { values: [500, 0], next: 0 }));
+        let mut sender = BmNetworkEngine::new(Some(1)).with_rng(rng);
+        assert_eq!(send_fragmented(&mut sender, &mut receiver, &message, 1000), BmEngineStatus::AckReceieved);
+        assert_eq!(receiver.get_inbound_message().unwrap().get_payload(), message.as_slice());
+
+        // Without one the first message id repeats, but the receiver forgot it by then
+        let mut sender = BmNetworkEngine::new(Some(1));
+        let millis = BM_REASSEMBLY_TIMEOUT_MS + 1;
+        assert_eq!(send_fragmented(&mut sender, &mut receiver, &message, millis), BmEngineStatus::AckReceieved);
+        assert_eq!(receiver.get_inbound_message().unwrap().get_payload(), message.as_slice());
+    }
+
+    #[test]
+    fn test_largest_message_read_from_reassembly() {
+        let mut receiver = BmNetworkEngine::new(Some(2));
+        let mut sender = BmNetworkEngine::new(Some(1));
+        let message: std::vec::Vec<u8> = (0..BM_MAX_MESSAGE_SIZE - BM_PORT_SIZE).map(|i| i as u8).collect();
+        assert_eq!(send_fragmented(&mut sender, &mut receiver, &message, 0), BmEngineStatus::AckReceieved);
+
+        // Queued, but kept in reassembly until it is read
+        assert_eq!(receiver.get_inbound_message_count_on_port(BM_DEFAULT_PORT), 1);
+        assert_eq!(receiver.reassembly.len(), 1);
+        let inbound = receiver.get_inbound_message_on_port(BM_DEFAULT_PORT).unwrap();
+        assert_eq!(inbound.get_originator(), Some(1));
+        assert_eq!(inbound.get_payload(), message.as_slice());
+        assert!(receiver.reassembly.is_empty());
+    }
+
+    #[test]
+    fn test_oversize_message_rejected() {
+        let mut bm_engine = BmNetworkEngine::new(Some(1));
+        let message = [0u8; BM_MAX_MESSAGE_SIZE + 1];
+        assert_eq!(bm_engine.initiate_message_transfer(Some(2), BM_DEFAULT_PORT, true, 5, &message), Err(BmError::PayloadTooLarge));
+        assert_eq!(bm_engine.run_engine(0), BmEngineStatus::Idle);
     }
 }
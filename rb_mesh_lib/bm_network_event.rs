@@ -0,0 +1,52 @@
+use core::fmt;
+use super::{
+    bm_network_engine::{BmTransferError, BmTransferId},
+    NetworkId,
+};
+
+// Something that happened in the engine, queued for the application to drain
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BmEngineEvent {
+    // A message addressed to us was handed to its port handler or the inbound queue
+    MessageReceived { orig: NetworkId, port: u8 },
+    // A transfer without ack request went on air
+    TransferSent { id: BmTransferId, dest: NetworkId },
+    TransferDelivered { id: BmTransferId, dest: NetworkId },
+    TransferFailed { id: BmTransferId, dest: NetworkId, error: BmTransferError },
+    // We can reach dest, it had no route before
+    RouteFound { dest: NetworkId },
+    // The last route to dest timed out
+    RouteLost { dest: NetworkId },
+    // A node was heard directly for the first time
+    NeighborFound { id: NetworkId },
+    // A node has not been heard directly for BM_ROUTE_TIMEOUT_MS
+    NeighborLost { id: NetworkId },
+}
+
+impl fmt::Display for BmEngineEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BmEngineEvent::MessageReceived { orig, port } => write!(f, "MSG,{},{}", orig.unwrap_or(0), port),
+            BmEngineEvent::TransferSent { id, dest } => write!(f, "SENT,{},{}", id, dest.unwrap_or(0)),
+            BmEngineEvent::TransferDelivered { id, dest } => write!(f, "DELIVERED,{},{}", id, dest.unwrap_or(0)),
+            BmEngineEvent::TransferFailed { id, dest, error } => write!(f, "FAILED,{},{},{}", id, dest.unwrap_or(0), error),
+            BmEngineEvent::RouteFound { dest } => write!(f, "ROUTE_FOUND,{}", dest.unwrap_or(0)),
+            BmEngineEvent::RouteLost { dest } => write!(f, "ROUTE_LOST,{}", dest.unwrap_or(0)),
+            BmEngineEvent::NeighborFound { id } => write!(f, "NEIGHBOR_FOUND,{}", id.unwrap_or(0)),
+            BmEngineEvent::NeighborLost { id } => write!(f, "NEIGHBOR_LOST,{}", id.unwrap_or(0)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_display() {
+        let event = BmEngineEvent::TransferFailed { id: 4, dest: Some(7), error: BmTransferError::NoAck };
+        assert_eq!(format!("{}", event), "FAILED,4,7,NO_ACK");
+        assert_eq!(format!("{}", BmEngineEvent::MessageReceived { orig: Some(3), port: 1 }), "MSG,3,1");
+        assert_eq!(format!("{}", BmEngineEvent::NeighborLost { id: Some(9) }), "NEIGHBOR_LOST,9");
+    }
+}
@@ -0,0 +1,599 @@
+use heapless::Vec; // fixed capacity `std::Vec`
+use core::fmt;
+use super::{
+    bm_network_configs::*,
+    bm_network_packet::bm_network_packet::BmNetworkPacketPayload,
+    bm_network_payload::{BmPayloadCodec, BmPayloadWriter},
+    NetworkId, RssiType, TimeType,
+};
+
+// Buffer holding a complete application message
+pub type BmNetworkMessagePayload = Vec<u8, BM_MAX_MESSAGE_SIZE>;
+
+// Size of the fragment ack payload: message id + received bitmap
+const FRAGMENT_ACK_SIZE: usize = 6;
+
+// Received fragments and used reassembly buffers are tracked in u32 bitmaps
+const _: () = assert!(BM_MAX_FRAGMENTS <= u32::BITS as usize);
+const _: () = assert!(BM_REASSEMBLY_BUFFERS <= u32::BITS as usize);
+
+// Application message as handed to the inbound queue. Single packets and
+// reassembled fragments look the same to the application.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct BmNetworkMessage {
+    orig: NetworkId,
+    port: u8,
+    hop_count: u8,
+    pub rx_rssi: RssiType,
+    payload: BmNetworkMessagePayload,
+}
+
+impl BmNetworkMessage {
+    // Constructor, None if data does not fit BM_MAX_MESSAGE_SIZE
+    pub fn new(orig: NetworkId, port: u8, hop_count: u8, rx_rssi: RssiType, data: &[u8]) -> Option<Self> {
+        Some(BmNetworkMessage {
+            orig,
+            port,
+            hop_count,
+            rx_rssi,
+            payload: BmNetworkMessagePayload::from_slice(data).ok()?,
+        })
+    }
+
+    // Splits the port off a message as received, None if there is no port
+    pub fn from_received(orig: NetworkId, hop_count: u8, rx_rssi: RssiType, received: &[u8]) -> Option<Self> {
+        let (&port, data) = received.split_first()?;
+        BmNetworkMessage::new(orig, port, hop_count, rx_rssi, data)
+    }
+
+    pub fn get_originator(&self) -> NetworkId {
+        self.orig
+    }
+    pub fn get_port(&self) -> u8 {
+        self.port
+    }
+    pub fn get_hop_count(&self) -> u8 {
+        self.hop_count
+    }
+    pub fn get_payload_len(&self) -> usize {
+        self.payload.len()
+    }
+    pub fn get_payload(&self) -> &[u8] {
+        &self.payload
+    }
+}
+
+impl fmt::Display for BmNetworkMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Orig:{}, Port:{}, Hops:{}, Len:{}",
+            self.orig.unwrap_or(0),
+            self.port,
+            self.hop_count,
+            self.payload.len()
+        )
+    }
+}
+
+// Entry of the inbound queue, turned into a BmNetworkMessage when the application reads it.
+// Single packet messages are copied in, reassembled messages stay in the reassembly pool
+// until they are read, so the queue never holds a full size message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BmInboundMessage {
+    pub orig: NetworkId,
+    pub port: u8,
+    pub hop_count: u8,
+    pub rx_rssi: RssiType,
+    // Message without its port, empty for reassembled messages
+    pub data: Vec<u8, BM_MAX_PAYLOAD_SIZE>,
+    // Message id of a complete message in the reassembly pool
+    pub reassembled: Option<u16>,
+}
+
+impl BmInboundMessage {
+    // Splits the port off a single packet message as received, None if there is no port
+    pub fn from_received(orig: NetworkId, hop_count: u8, rx_rssi: RssiType, received: &[u8]) -> Option<Self> {
+        let (&port, data) = received.split_first()?;
+        Some(BmInboundMessage {
+            orig,
+            port,
+            hop_count,
+            rx_rssi,
+            data: Vec::from_slice(data).ok()?,
+            reassembled: None,
+        })
+    }
+}
+
+// Receives the messages sent to one application port, registered on the engine
+pub trait BmPortHandler {
+    fn receive(&mut self, message: &BmNetworkMessage);
+}
+
+// Header at the start of every DataFragment payload
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct BmFragmentHdr {
+    // Identifies the message, unique per originator
+    pub msg_id: u16,
+    // Position of this fragment, 0 based
+    pub index: u8,
+    // Total number of fragments in the message
+    pub count: u8,
+}
+
+impl BmFragmentHdr {
+    // Splits a fragment payload into header and message data
+    pub fn from_payload(payload: &[u8]) -> Option<(BmFragmentHdr, &[u8])> {
+        if payload.len() < BM_FRAGMENT_HDR_SIZE {
+            return None
+        }
+        let hdr = BmFragmentHdr {
+            msg_id: u16::from_le_bytes([payload[0], payload[1]]),
+            index: payload[2],
+            count: payload[3],
+        };
+        Some((hdr, &payload[BM_FRAGMENT_HDR_SIZE..]))
+    }
+
+    fn to_bytes(self) -> [u8; BM_FRAGMENT_HDR_SIZE] {
+        let msg_id = self.msg_id.to_le_bytes();
+        [msg_id[0], msg_id[1], self.index, self.count]
+    }
+}
+
+// Payload of a DataFragmentAck, lists the fragments the destination holds
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct BmFragmentAck {
+    pub msg_id: u16,
+    // Bit n set when fragment n was received
+    pub received: u32,
+}
+
+impl BmPayloadCodec for BmFragmentAck {
+    const MAX_SIZE: usize = FRAGMENT_ACK_SIZE;
+
+    fn from_payload(payload: &[u8]) -> Option<BmFragmentAck> {
+        if payload.len() != FRAGMENT_ACK_SIZE {
+            return None
+        }
+        Some(BmFragmentAck {
+            msg_id: u16::from_le_bytes([payload[0], payload[1]]),
+            received: u32::from_le_bytes([payload[2], payload[3], payload[4], payload[5]]),
+        })
+    }
+
+    fn write_payload(&self, writer: &mut BmPayloadWriter) {
+        writer.put(&self.msg_id.to_le_bytes());
+        writer.put(&self.received.to_le_bytes());
+    }
+}
+
+// Bitmap with a bit set for every fragment of a message
+const fn all_fragments(count: u8) -> u32 {
+    if count as u32 >= u32::BITS { u32::MAX } else { (1 << count) - 1 }
+}
+
+// Sender side of a fragmented message
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct BmFragmentTx {
+    pub dest: NetworkId,
+    pub ttl: u8,
+    pub ack: bool,
+    pub msg_id: u16,
+    count: u8,
+    data: BmNetworkMessagePayload,
+    // Fragments the destination confirmed
+    acked: u32,
+    // Fragments still to be queued in the current round
+    pending: u32,
+    // Rounds of (re)sending started so far
+    rounds: u8,
+}
+
+impl BmFragmentTx {
+    // Constructor, None if data does not fit BM_MAX_MESSAGE_SIZE
+    pub fn new(dest: NetworkId, ttl: u8, ack: bool, msg_id: u16, port: u8, data: &[u8]) -> Option<Self> {
+        // The port leads the message, the same as on single packet transfers
+        let mut message = BmNetworkMessagePayload::new();
+        message.push(port).ok()?;
+        message.extend_from_slice(data).ok()?;
+
+        let count = message.len().div_ceil(BM_FRAGMENT_DATA_SIZE).max(1);
+        Some(BmFragmentTx {
+            dest,
+            ttl,
+            ack,
+            msg_id,
+            count: count as u8,
+            data: message,
+            acked: 0,
+            pending: 0,
+            rounds: 0,
+        })
+    }
+
+    pub fn get_fragment_count(&self) -> u8 {
+        self.count
+    }
+
+    // Queues every fragment the destination has not confirmed for sending.
+    // Returns false once BM_FRAGMENT_MAX_ROUNDS have been used.
+    pub fn start_round(&mut self) -> bool {
+        if self.rounds >= BM_FRAGMENT_MAX_ROUNDS {
+            return false
+        }
+        self.rounds += 1;
+        self.pending = all_fragments(self.count) & !self.acked;
+        true
+    }
+
+    // Takes the next fragment of the current round. The flag is set on the last one.
+    pub fn next_fragment(&mut self) -> Option<(BmNetworkPacketPayload, bool)> {
+        if self.pending == 0 {
+            return None
+        }
+        let index = self.pending.trailing_zeros() as usize;
+        self.pending &= !(1 << index);
+
+        let start = index * BM_FRAGMENT_DATA_SIZE;
+        let end = (start + BM_FRAGMENT_DATA_SIZE).min(self.data.len());
+        let hdr = BmFragmentHdr { msg_id: self.msg_id, index: index as u8, count: self.count };
+
+        let mut payload = BmNetworkPacketPayload::new();
+        // Cannot fail, header plus BM_FRAGMENT_DATA_SIZE fits the payload
+        let _ = payload.extend_from_slice(&hdr.to_bytes());
+        let _ = payload.extend_from_slice(&self.data[start..end]);
+        Some((payload, self.pending == 0))
+    }
+
+    pub fn set_acked(&mut self, received: u32) {
+        self.acked |= received & all_fragments(self.count);
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.acked == all_fragments(self.count)
+    }
+}
+
+// Message being put back together at the destination. Its fragments are kept in the
+// buffers of the pool as they arrive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct BmReassemblySlot {
+    in_use: bool,
+    orig: NetworkId,
+    msg_id: u16,
+    count: u8,
+    received: u32,
+    // Length of the message, known once the last fragment arrived
+    length: usize,
+    last_update: TimeType,
+    // Pool buffer holding each received fragment, by fragment index
+    buffers: [u8; BM_MAX_FRAGMENTS],
+}
+
+// Bitmap with a bit set for every reassembly buffer
+const ALL_BUFFERS: u32 = all_fragments(BM_REASSEMBLY_BUFFERS as u8);
+
+const FREE_SLOT: BmReassemblySlot = BmReassemblySlot {
+    in_use: false,
+    orig: None,
+    msg_id: 0,
+    count: 0,
+    received: 0,
+    length: 0,
+    last_update: 0,
+    buffers: [0; BM_MAX_FRAGMENTS],
+};
+
+// Bounded pool of messages under reassembly. The slots share BM_REASSEMBLY_BUFFERS
+// fragment buffers, so a message only takes the room of the fragments it has. Complete
+// messages stay in their slot until they are taken out.
+#[derive(Debug, Clone)]
+pub struct BmReassemblyPool {
+    slots: [BmReassemblySlot; BM_REASSEMBLY_SLOTS],
+    buffers: [[u8; BM_FRAGMENT_DATA_SIZE]; BM_REASSEMBLY_BUFFERS],
+    // Bit n set while buffer n holds a fragment
+    buffers_used: u32,
+}
+
+impl Default for BmReassemblyPool {
+    fn default() -> Self {
+        BmReassemblyPool::new()
+    }
+}
+
+impl BmReassemblyPool {
+    pub fn new() -> Self {
+        BmReassemblyPool {
+            slots: [FREE_SLOT; BM_REASSEMBLY_SLOTS],
+            buffers: [[0; BM_FRAGMENT_DATA_SIZE]; BM_REASSEMBLY_BUFFERS],
+            buffers_used: 0,
+        }
+    }
+
+    // Stores a fragment. Returns the bitmap of fragments received so far,
+    // None if the fragment is invalid or no slot or buffer is free.
+    pub fn insert(&mut self, orig: NetworkId, hdr: &BmFragmentHdr, data: &[u8], millis: TimeType) -> Option<u32> {
+        let count = hdr.count as usize;
+        let index = hdr.index as usize;
+        if count == 0 || count > BM_MAX_FRAGMENTS || index >= count {
+            return None
+        }
+
+        // Every fragment but the last is full
+        let is_last = index == count - 1;
+        let start = index * BM_FRAGMENT_DATA_SIZE;
+        if data.is_empty() || data.len() > BM_FRAGMENT_DATA_SIZE ||
+           (!is_last && data.len() != BM_FRAGMENT_DATA_SIZE) ||
+           start + data.len() > BM_MAX_MESSAGE_SIZE {
+            return None
+        }
+
+        let slot_idx = match self.find(orig, hdr.msg_id) {
+            Some(slot_idx) => slot_idx,
+            None => {
+                let slot_idx = self.slots.iter().position(|slot| !slot.in_use)?;
+                self.slots[slot_idx] = BmReassemblySlot {
+                    in_use: true,
+                    orig,
+                    msg_id: hdr.msg_id,
+                    count: hdr.count,
+                    last_update: millis,
+                    ..FREE_SLOT
+                };
+                slot_idx
+            }
+        };
+
+        let slot = self.slots[slot_idx];
+        if slot.count != hdr.count {
+            return None
+        }
+        let buffer_idx = if slot.received & (1 << index) != 0 {
+            // Sent again, our ack got lost
+            slot.buffers[index] as usize
+        }
+        else {
+            let free = !self.buffers_used & ALL_BUFFERS;
+            if free == 0 {
+                // No room, a slot without fragments is given back
+                if slot.received == 0 {
+                    self.slots[slot_idx].in_use = false;
+                }
+                return None
+            }
+            free.trailing_zeros() as usize
+        };
+
+        self.buffers[buffer_idx][..data.len()].copy_from_slice(data);
+        self.buffers_used |= 1 << buffer_idx;
+        let slot = &mut self.slots[slot_idx];
+        slot.buffers[index] = buffer_idx as u8;
+        slot.received |= 1 << index;
+        slot.last_update = millis;
+        if is_last {
+            slot.length = start + data.len();
+        }
+        Some(slot.received)
+    }
+
+    pub fn is_complete(&self, orig: NetworkId, msg_id: u16) -> bool {
+        self.find_complete(orig, msg_id).is_some()
+    }
+
+    // Port leading a complete message
+    pub fn get_port(&self, orig: NetworkId, msg_id: u16) -> Option<u8> {
+        let slot = &self.slots[self.find_complete(orig, msg_id)?];
+        Some(self.buffers[slot.buffers[0] as usize][0])
+    }
+
+    // Removes and returns the message once all fragments are in
+    pub fn take_complete(&mut self, orig: NetworkId, msg_id: u16) -> Option<BmNetworkMessagePayload> {
+        let slot_idx = self.find_complete(orig, msg_id)?;
+        let slot = self.slots[slot_idx];
+
+        let mut message = BmNetworkMessagePayload::new();
+        for index in 0..slot.count as usize {
+            let start = index * BM_FRAGMENT_DATA_SIZE;
+            let end = (start + BM_FRAGMENT_DATA_SIZE).min(slot.length);
+            let buffer = &self.buffers[slot.buffers[index] as usize];
+            // Cannot fail, insert keeps messages within BM_MAX_MESSAGE_SIZE
+            let _ = message.extend_from_slice(&buffer[..end - start]);
+        }
+        self.free_slot(slot_idx);
+        Some(message)
+    }
+
+    // Drops a message, complete or not
+    pub fn remove(&mut self, orig: NetworkId, msg_id: u16) {
+        if let Some(slot_idx) = self.find(orig, msg_id) {
+            self.free_slot(slot_idx);
+        }
+    }
+
+    // Drops partial messages that have not seen a fragment within the timeout.
+    // Complete messages are kept until they are taken.
+    pub fn expire(&mut self, millis: TimeType) {
+        for slot_idx in 0..self.slots.len() {
+            let slot = &self.slots[slot_idx];
+            if slot.in_use && slot.received != all_fragments(slot.count) &&
+               millis - slot.last_update > BM_REASSEMBLY_TIMEOUT_MS {
+                self.free_slot(slot_idx);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.iter().filter(|slot| slot.in_use).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    //-----------------------------------------------------------
+    // Private functions
+    //-----------------------------------------------------------
+
+    fn find(&self, orig: NetworkId, msg_id: u16) -> Option<usize> {
+        self.slots.iter().position(|slot| slot.in_use && slot.orig == orig && slot.msg_id == msg_id)
+    }
+
+    fn find_complete(&self, orig: NetworkId, msg_id: u16) -> Option<usize> {
+        let slot_idx = self.find(orig, msg_id)?;
+        let slot = &self.slots[slot_idx];
+        (slot.received == all_fragments(slot.count)).then_some(slot_idx)
+    }
+
+    // Gives the slot and the buffers of its fragments back to the pool
+    fn free_slot(&mut self, slot_idx: usize) {
+        let slot = &mut self.slots[slot_idx];
+        for index in 0..slot.count as usize {
+            if slot.received & (1 << index) != 0 {
+                self.buffers_used &= !(1 << slot.buffers[index]);
+            }
+        }
+        slot.in_use = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::bm_network_payload::assert_round_trip;
+
+    fn message(len: usize) -> BmNetworkMessagePayload {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn test_fragments_reassemble_out_of_order() {
+        let data = message(500);
+        let mut tx = BmFragmentTx::new(Some(2), 5, true, 7, 9, &data).unwrap();
+        assert_eq!(tx.get_fragment_count(), 3);
+        assert!(tx.start_round());
+
+        let mut fragments: std::vec::Vec<(BmNetworkPacketPayload, bool)> = std::vec::Vec::new();
+        while let Some(fragment) = tx.next_fragment() {
+            fragments.push(fragment);
+        }
+        assert_eq!(fragments.iter().map(|f| f.1).collect::<std::vec::Vec<_>>(), [false, false, true]);
+
+        let mut pool = BmReassemblyPool::new();
+        for (payload, _) in fragments.iter().rev() {
+            let (hdr, fragment_data) = BmFragmentHdr::from_payload(payload).unwrap();
+            assert!(pool.insert(Some(1), &hdr, fragment_data, 0).is_some());
+        }
+        let received = pool.take_complete(Some(1), 7).unwrap();
+        assert!(pool.is_empty());
+
+        // Port travels in front of the message
+        let message = BmNetworkMessage::from_received(Some(1), 2, -70, &received).unwrap();
+        assert_eq!(message.get_port(), 9);
+        assert_eq!(message.get_payload(), data.as_slice());
+    }
+
+    #[test]
+    fn test_fragment_ack_round_trip() {
+        assert_round_trip(&BmFragmentAck { msg_id: 0xBEEF, received: 0x8000_0001 }, FRAGMENT_ACK_SIZE);
+    }
+
+    #[test]
+    fn test_only_missing_fragments_resent() {
+        let mut tx = BmFragmentTx::new(Some(2), 5, true, 1, 9, &message(1000)).unwrap();
+        assert!(tx.start_round());
+        while tx.next_fragment().is_some() {}
+
+        // Destination reports fragments 0, 2 and 4 missing
+        let ack = BmFragmentAck::from_payload(&BmFragmentAck { msg_id: 1, received: 0b101010 }.to_payload()).unwrap();
+        tx.set_acked(ack.received);
+        assert!(!tx.is_complete());
+
+        assert!(tx.start_round());
+        let mut resent: std::vec::Vec<u8> = std::vec::Vec::new();
+        while let Some((payload, _)) = tx.next_fragment() {
+            resent.push(BmFragmentHdr::from_payload(&payload).unwrap().0.index);
+        }
+        assert_eq!(resent, [0, 2, 4]);
+
+        tx.set_acked(u32::MAX);
+        assert!(tx.is_complete());
+    }
+
+    #[test]
+    fn test_send_rounds_are_bounded() {
+        let mut tx = BmFragmentTx::new(Some(2), 5, true, 1, 9, &message(300)).unwrap();
+        for _ in 0..BM_FRAGMENT_MAX_ROUNDS {
+            assert!(tx.start_round());
+        }
+        assert!(!tx.start_round());
+    }
+
+    #[test]
+    fn test_pool_bounded_and_expires() {
+        let mut pool = BmReassemblyPool::new();
+        let data = [0u8; BM_FRAGMENT_DATA_SIZE];
+        for msg_id in 0..BM_REASSEMBLY_SLOTS as u16 {
+            let hdr = BmFragmentHdr { msg_id, index: 0, count: 2 };
+            assert_eq!(pool.insert(Some(1), &hdr, &data, 0), Some(0b01));
+        }
+
+        // No free slot for another message
+        let hdr = BmFragmentHdr { msg_id: 99, index: 0, count: 2 };
+        assert_eq!(pool.insert(Some(1), &hdr, &data, 0), None);
+
+        // Stale partial messages are dropped, making room again
+        pool.expire(BM_REASSEMBLY_TIMEOUT_MS + 1);
+        assert!(pool.is_empty());
+        assert!(pool.insert(Some(1), &hdr, &data, 0).is_some());
+    }
+
+    // Inserts every fragment of a message, returns the last bitmap
+    fn insert_message(pool: &mut BmReassemblyPool, msg_id: u16, data: &[u8]) -> Option<u32> {
+        let mut tx = BmFragmentTx::new(Some(2), 5, true, msg_id, 9, data).unwrap();
+        tx.start_round();
+        let mut received = None;
+        while let Some((payload, _)) = tx.next_fragment() {
+            let (hdr, fragment_data) = BmFragmentHdr::from_payload(&payload).unwrap();
+            received = pool.insert(Some(1), &hdr, fragment_data, 0);
+        }
+        received
+    }
+
+    #[test]
+    fn test_pool_shares_fragment_buffers() {
+        let mut pool = BmReassemblyPool::new();
+        let largest = message(BM_MAX_MESSAGE_SIZE - BM_PORT_SIZE);
+        assert_eq!(insert_message(&mut pool, 1, &largest), Some(all_fragments(BM_MAX_FRAGMENTS as u8)));
+
+        // The rest of the buffers are too few for a second large message
+        assert_eq!(insert_message(&mut pool, 2, &largest), None);
+        assert_eq!(pool.len(), 2);
+        assert!(!pool.is_complete(Some(1), 2));
+
+        // A complete message waits to be read, the partial one expires
+        pool.expire(BM_REASSEMBLY_TIMEOUT_MS + 1);
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.get_port(Some(1), 1), Some(9));
+        let received = pool.take_complete(Some(1), 1).unwrap();
+        assert_eq!(&received[BM_PORT_SIZE..], largest.as_slice());
+
+        // Its buffers are free again
+        assert!(pool.is_empty());
+        assert!(insert_message(&mut pool, 2, &largest).is_some());
+        assert!(pool.is_complete(Some(1), 2));
+        pool.remove(Some(1), 2);
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn test_pool_rejects_inconsistent_fragments() {
+        let mut pool = BmReassemblyPool::new();
+        let data = [0u8; BM_FRAGMENT_DATA_SIZE];
+
+        // Index past count, short middle fragment, count changing mid message
+        assert_eq!(pool.insert(Some(1), &BmFragmentHdr { msg_id: 1, index: 2, count: 2 }, &data, 0), None);
+        assert_eq!(pool.insert(Some(1), &BmFragmentHdr { msg_id: 1, index: 0, count: 2 }, &data[..10], 0), None);
+        assert!(pool.insert(Some(1), &BmFragmentHdr { msg_id: 1, index: 0, count: 2 }, &data, 0).is_some());
+        assert_eq!(pool.insert(Some(1), &BmFragmentHdr { msg_id: 1, index: 1, count: 3 }, &data, 0), None);
+    }
+}
@@ -0,0 +1,114 @@
+use heapless::Deque; // fixed capacity ring buffer
+use super::{
+    bm_network_configs::*,
+    bm_network_payload::{BmPayloadCodec, BmPayloadWriter},
+    NetworkId, TimeType,
+};
+
+// LinkAck payload: originator id + sequence number of the acked packet
+const LINK_ACK_SIZE: usize = 6;
+
+// Payload of a LinkAck. Sent by the next hop of a packet that asked for one, back to the
+// node it heard the packet from.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct BmLinkAck {
+    pub orig: NetworkId,
+    pub seq: u16,
+}
+
+impl BmPayloadCodec for BmLinkAck {
+    const MAX_SIZE: usize = LINK_ACK_SIZE;
+
+    fn from_payload(payload: &[u8]) -> Option<BmLinkAck> {
+        if payload.len() != LINK_ACK_SIZE {
+            return None
+        }
+        Some(BmLinkAck {
+            orig: Some(u32::from_le_bytes(payload[0..4].try_into().ok()?)),
+            seq: u16::from_le_bytes(payload[4..6].try_into().ok()?),
+        })
+    }
+
+    fn write_payload(&self, writer: &mut BmPayloadWriter) {
+        writer.put_id(self.orig);
+        writer.put(&self.seq.to_le_bytes());
+    }
+}
+
+// Unicast packets we relayed or received, by originator and sequence number. A copy heard again
+// within the retransmission window is a retransmission whose LinkAck was lost. Later copies
+// are end to end retries and are relayed again. The engine remembers the fragmented messages
+// it delivered the same way, by message id.
+#[derive(Default, Debug, Clone)]
+pub struct BmLinkSeenCache {
+    entries: Deque<(NetworkId, u16, TimeType), BM_LINK_SEEN_CACHE_SIZE>,
+}
+
+impl BmLinkSeenCache {
+    pub fn new() -> Self {
+        BmLinkSeenCache {
+            entries: Deque::new(),
+        }
+    }
+
+    // Records the packet, returns false if it was already recorded within window_millis
+    pub fn insert(&mut self, orig: NetworkId, seq: u16, millis: TimeType, window_millis: TimeType) -> bool {
+        if let Some(entry) = self.entries.iter_mut().find(|entry| entry.0 == orig && entry.1 == seq) {
+            if millis - entry.2 <= window_millis {
+                return false
+            }
+            entry.2 = millis;
+            return true
+        }
+
+        // Drop oldest entry to make room
+        if self.entries.is_full() {
+            self.entries.pop_front();
+        }
+        // Cannot fail, room was made above
+        let _ = self.entries.push_back((orig, seq, millis));
+        true
+    }
+
+    // True if the packet was recorded within window_millis. Nothing is recorded.
+    pub fn contains(&self, orig: NetworkId, seq: u16, millis: TimeType, window_millis: TimeType) -> bool {
+        self.entries.iter().any(|entry| entry.0 == orig && entry.1 == seq && millis - entry.2 <= window_millis)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::bm_network_payload::assert_round_trip;
+
+    #[test]
+    fn test_link_ack_round_trip() {
+        assert_round_trip(&BmLinkAck { orig: Some(0x1234_5678), seq: 0xABCD }, LINK_ACK_SIZE);
+    }
+
+    #[test]
+    fn test_link_seen_cache_window() {
+        let mut cache = BmLinkSeenCache::new();
+        assert!(cache.insert(Some(1), 10, 0, 100));
+
+        // Retransmissions inside the window are duplicates, later copies are not
+        assert!(!cache.insert(Some(1), 10, 100, 100));
+        assert!(cache.insert(Some(2), 10, 100, 100));
+        assert!(cache.insert(Some(1), 10, 101, 100));
+        assert!(!cache.insert(Some(1), 10, 150, 100));
+    }
+
+    #[test]
+    fn test_link_seen_cache_contains() {
+        let mut cache = BmLinkSeenCache::new();
+        assert!(!cache.contains(Some(1), 10, 0, 100));
+        cache.insert(Some(1), 10, 0, 100);
+
+        assert!(cache.contains(Some(1), 10, 100, 100));
+        assert!(!cache.contains(Some(1), 11, 100, 100));
+        assert!(!cache.contains(Some(1), 10, 101, 100));
+
+        // Lookups do not refresh the entry
+        assert!(cache.insert(Some(1), 10, 101, 100));
+    }
+}
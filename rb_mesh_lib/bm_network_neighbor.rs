@@ -0,0 +1,121 @@
+use heapless::Vec; // fixed capacity `std::Vec`
+use super::{
+    bm_network_configs::*,
+    bm_network_payload::{BmPayloadCodec, BmPayloadWriter},
+    NetworkId, RssiType,
+};
+
+// Sequence number of the sender in front of the entries
+const NEIGHBOR_HDR_SIZE: usize = 2;
+
+// Network id + link rssi + distance + destination sequence number of one entry
+const NEIGHBOR_ENTRY_SIZE: usize = 9;
+
+// One node the sender of a neighbor table can reach
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct BmNeighborEntry {
+    pub id: NetworkId,
+    // Average rssi the sender hears this node with
+    pub rssi: RssiType,
+    // Distance of the sender's route to this node, 0 for its neighbors
+    pub distance: u8,
+    // Destination sequence number the sender knows for this node, 0 when unknown
+    pub seq: u16,
+}
+
+// Payload of a BcastNeighborTable beacon. Carries a few entries at a time, successive
+// beacons go through the rest of the table. Neighbor beacons name neighbors only, route
+// advertisements any node with a route.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct BmNeighborTable {
+    // Destination sequence number of the sender itself, 0 when it does not advertise one
+    pub seq: u16,
+    entries: Vec<BmNeighborEntry, BM_ROUTE_ADVERTISE_ENTRIES>,
+}
+
+impl BmNeighborTable {
+    pub fn new(seq: u16) -> Self {
+        BmNeighborTable { seq, entries: Vec::new() }
+    }
+
+    pub fn get_entries(&self) -> &[BmNeighborEntry] {
+        &self.entries
+    }
+
+    // Returns false when the table is full
+    pub fn push(&mut self, entry: BmNeighborEntry) -> bool {
+        self.entries.push(entry).is_ok()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.entries.is_full()
+    }
+}
+
+impl BmPayloadCodec for BmNeighborTable {
+    const MAX_SIZE: usize = NEIGHBOR_HDR_SIZE + BM_ROUTE_ADVERTISE_ENTRIES * NEIGHBOR_ENTRY_SIZE;
+
+    fn from_payload(payload: &[u8]) -> Option<BmNeighborTable> {
+        if payload.len() < NEIGHBOR_HDR_SIZE ||
+           !(payload.len() - NEIGHBOR_HDR_SIZE).is_multiple_of(NEIGHBOR_ENTRY_SIZE) {
+            return None
+        }
+        let mut table = BmNeighborTable::new(u16::from_le_bytes([payload[0], payload[1]]));
+        for record in payload[NEIGHBOR_HDR_SIZE..].chunks_exact(NEIGHBOR_ENTRY_SIZE) {
+            let entry = BmNeighborEntry {
+                id: Some(u32::from_le_bytes([record[0], record[1], record[2], record[3]])),
+                rssi: RssiType::from_le_bytes([record[4], record[5]]),
+                distance: record[6],
+                seq: u16::from_le_bytes([record[7], record[8]]),
+            };
+            if !table.push(entry) {
+                return None
+            }
+        }
+        Some(table)
+    }
+
+    fn write_payload(&self, writer: &mut BmPayloadWriter) {
+        writer.put(&self.seq.to_le_bytes());
+        for entry in self.entries.iter() {
+            writer.put_id(entry.id);
+            writer.put(&entry.rssi.to_le_bytes());
+            writer.put_u8(entry.distance);
+            writer.put(&entry.seq.to_le_bytes());
+        }
+    }
+}
+
+const _: () = assert!(BM_NEIGHBOR_BEACON_ENTRIES <= BM_ROUTE_ADVERTISE_ENTRIES);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::bm_network_payload::assert_round_trip;
+
+    #[test]
+    fn test_neighbor_table_round_trip() {
+        let mut table = BmNeighborTable::new(42);
+        assert!(table.push(BmNeighborEntry { id: Some(7), rssi: -61, distance: 0, seq: 0 }));
+        assert!(table.push(BmNeighborEntry { id: Some(0x1234_5678), rssi: -120, distance: 1, seq: 0xFFFE }));
+        assert_round_trip(&table, NEIGHBOR_HDR_SIZE + 2 * NEIGHBOR_ENTRY_SIZE);
+
+        // Only the sequence number, the missing one is rejected
+        assert_round_trip(&BmNeighborTable::new(0), NEIGHBOR_HDR_SIZE);
+    }
+
+    #[test]
+    fn test_neighbor_table_full() {
+        let mut table = BmNeighborTable::new(1);
+        for id in 0..BM_ROUTE_ADVERTISE_ENTRIES as u32 {
+            assert!(table.push(BmNeighborEntry { id: Some(id), rssi: -50, distance: 0, seq: 0 }));
+        }
+        assert!(table.is_full());
+        assert!(!table.push(BmNeighborEntry::default()));
+
+        // More entries than a beacon carries are rejected
+        let mut payload = table.to_payload();
+        payload.extend_from_slice(&[0; NEIGHBOR_ENTRY_SIZE]).unwrap();
+        assert_eq!(BmNeighborTable::from_payload(&payload), None);
+    }
+}
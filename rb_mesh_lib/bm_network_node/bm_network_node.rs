@@ -2,6 +2,7 @@ use heapless::Vec; // fixed capacity `std::Vec`
 use super::super::{
     NetworkId, RssiType, TimeType,
     bm_network_configs::*,
+    bm_network_security::BmReplayWindow,
 };
 use core::fmt::{self};
 use core::option::Option::{self, Some, None};
@@ -37,6 +38,22 @@ impl BmRoute {
     pub fn get_next_hop(&mut self) -> NetworkId {
         self.next_hop
     }
+
+    pub fn get_distance(&self) -> u8 {
+        self.distance
+    }
+
+    pub fn get_timestamp_millis(&self) -> TimeType {
+        self.timestamp_millis
+    }
+
+    pub fn get_failures(&self) -> u8 {
+        self.failures
+    }
+
+    pub fn get_avg_rssi(&self) -> i32 {
+        self.avg_rssi
+    }
 }
 
 #[derive(Default, Debug, Clone)]
@@ -47,6 +64,13 @@ pub struct BmNodeEntry {
     primary_route_idx: Option<usize>,
     // Available routes
     routes: Vec<BmRoute, BM_MAX_DEVICE_ROUTES>,
+    // Latest destination sequence number advertised by the node, None until we hear one
+    dest_seq: Option<u16>,
+    // Set when a route broke or expired since dest_seq. Other nodes may still advertise
+    // the lost route under the same number, through us.
+    route_lost: bool,
+    // Frame counters already accepted from this node
+    replay_window: BmReplayWindow,
 }
 
 impl fmt::Display for BmNodeEntry {
@@ -61,9 +85,16 @@ impl BmNodeEntry {
             dest_id: dest_id,
             primary_route_idx: None,
             routes: Vec::new(),
+            dest_seq: None,
+            route_lost: false,
+            replay_window: BmReplayWindow::default(),
         }
     }
 
+    pub fn get_dest_seq(&self) -> Option<u16> {
+        self.dest_seq
+    }
+
     pub fn with_route(mut self, next_hop: NetworkId, distance: u8, millis: TimeType, rssi: RssiType) -> Self {
         if self.route_exists(next_hop) {
             self.update_route(next_hop, distance, millis, rssi);
@@ -81,6 +112,19 @@ impl BmNodeEntry {
         }
     }
 
+    // Counts a failure against the route through next_hop. The route is not refreshed,
+    // a failing route still expires when nothing else is heard through it.
+    pub fn record_failure(&mut self, next_hop: NetworkId) {
+        let Some(route) = self.routes.iter_mut().find(|route| route.next_hop == next_hop) else {
+            return
+        };
+        route.failures = route.failures.saturating_add(1);
+
+        // Its metric changed, pick the primary route again
+        self.primary_route_idx = None;
+        self.determine_primary_route();
+    }
+
     pub fn add_new_route(&mut self, next_hop: NetworkId, distance: u8, millis: TimeType, rssi: RssiType) {
         if self.routes.len() >= BM_MAX_DEVICE_ROUTES {
             defmt::error!("BmNodeEntry: route list full");
@@ -133,6 +177,121 @@ impl BmNodeEntry {
         self.determine_primary_route();
     }
 
+    // Returns false if the frame counter was already used by this node
+    pub fn check_frame_counter(&mut self, frame_counter: u32) -> bool {
+        self.replay_window.check_and_update(frame_counter)
+    }
+
+    // Same as check_frame_counter, without recording the counter
+    pub fn is_frame_counter_fresh(&self, frame_counter: u32) -> bool {
+        self.replay_window.check(frame_counter)
+    }
+
+    // Drops routes not refreshed since oldest_millis. Returns true if any was dropped.
+    // Once no route is left the sequence number is forgotten too, so a node that restarted
+    // its numbers in the meantime is not taken for stale.
+    pub fn expire_routes(&mut self, oldest_millis: TimeType) -> bool {
+        let route_count = self.routes.len();
+        self.routes.retain(|route| route.timestamp_millis >= oldest_millis);
+        if self.routes.len() == route_count {
+            return false
+        }
+
+        if self.routes.is_empty() {
+            self.dest_seq = None;
+            self.route_lost = false;
+        }
+        else {
+            self.route_lost = true;
+        }
+
+        // Indexes moved, pick the primary route again
+        self.primary_route_idx = None;
+        self.determine_primary_route();
+        true
+    }
+
+    // Drops the route through next_hop. Returns true if there was one.
+    pub fn remove_route(&mut self, next_hop: NetworkId) -> bool {
+        let route_count = self.routes.len();
+        self.routes.retain(|route| route.next_hop != next_hop);
+        if self.routes.len() == route_count {
+            return false
+        }
+        self.route_lost = true;
+
+        // Indexes moved, pick the primary route again
+        self.primary_route_idx = None;
+        self.determine_primary_route();
+        true
+    }
+
+    // Checks a route advertised with the node's sequence number, before it is updated.
+    // Older numbers are refused, and so are routes with the current number that are longer
+    // than the ones we have. After a route was lost, new routes with the current number are
+    // refused as well, they may be our lost route coming back through a neighbor. Only the
+    // direct link and routes we still have are refreshed until a newer number arrives.
+    // A newer number drops the routes learned before it, except the direct link and the
+    // route through next_hop, so no route can lead back through us.
+    pub fn accept_seq(&mut self, next_hop: NetworkId, distance: u8, seq: u16) -> bool {
+        match self.dest_seq.map(|known| seq.wrapping_sub(known) as i16) {
+            Some(age) if age < 0 => false,
+            Some(0) if self.route_lost => {
+                next_hop == self.dest_id || self.route_exists(next_hop)
+            }
+            Some(0) => {
+                self.route_exists(next_hop) || self.routes.iter().all(|route| distance <= route.distance)
+            }
+            _ => {
+                self.dest_seq = Some(seq);
+                self.route_lost = false;
+                let dest_id = self.dest_id;
+                self.routes.retain(|route| route.next_hop == next_hop || route.next_hop == dest_id);
+
+                // Indexes moved, pick the primary route again
+                self.primary_route_idx = None;
+                self.determine_primary_route();
+                true
+            }
+        }
+    }
+
+    // The node itself advertised an older number than we know, it restarted its numbers.
+    // Takes the new number and drops the routes learned under the old ones, except the
+    // direct link.
+    pub fn restart_seq(&mut self, seq: u16) {
+        self.dest_seq = Some(seq);
+        self.route_lost = false;
+        let dest_id = self.dest_id;
+        self.routes.retain(|route| route.next_hop == dest_id);
+
+        // Indexes moved, pick the primary route again
+        self.primary_route_idx = None;
+        self.determine_primary_route();
+    }
+
+    pub fn has_routes(&self) -> bool {
+        !self.routes.is_empty()
+    }
+
+    // True when we heard the node directly
+    pub fn is_neighbor(&self) -> bool {
+        self.routes.iter().any(|route| route.next_hop == self.dest_id)
+    }
+
+    // Route through a single next hop, when there is one
+    pub fn get_route_via(&self, next_hop: NetworkId) -> Option<BmRoute> {
+        self.routes.iter().find(|route| route.next_hop == next_hop).cloned()
+    }
+
+    // Next hop of the best route that does not go through any of the excluded nodes
+    pub fn get_next_hop_excluding(&self, excluded: &[NetworkId]) -> NetworkId {
+        self.routes.iter()
+            .filter(|route| !excluded.contains(&route.next_hop))
+            .min_by_key(|route| calc_route_metric(route))
+            .and_then(|route| route.next_hop)
+    }
+
     pub fn get_best_route(&mut self) -> Option<BmRoute> {
         if let Some(route_idx) = self.primary_route_idx {
             return Some(self.routes[route_idx].clone())
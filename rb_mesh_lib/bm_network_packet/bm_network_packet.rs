@@ -3,8 +3,9 @@ use bitfield_struct::bitfield;
 use crate::RssiType;
 
 use super::super::{
-    NetworkId, 
+    NetworkId, BmNetworkKey,
     bm_network_configs::*,
+    bm_network_security::{self, BmSecurityError},
 };
 use core::fmt::{self};
 
@@ -14,13 +15,32 @@ pub type BmNetworkOtaPacket = Vec<u8, BM_MAX_OTA_SIZE>;
 pub type BmNetworkPacketPayload = Vec<u8, BM_MAX_PAYLOAD_SIZE>;
 
 // Max TTL and hop count value
-const MAX_TTL_HOP_CNT: u8 = 7;
+pub(super) const MAX_TTL_HOP_CNT: u8 = 7;
+
+// OTA protocol version, carried in the upper nibble of the first header byte.
+// Frames from before versioning started with the packet type byte, whose upper
+// nibble is 0 or 1 for every defined type. Versions start at 2 to tell them apart.
+pub const BM_PROTOCOL_VERSION: u8 = 4;
+
+// OTA header byte offsets. Addresses follow the type byte in the order dest, src,
+// next_hop, orig, then info and seq. All multi-byte fields are little endian.
+const HDR_CTRL_OFFSET: usize = 0;
+const HDR_TYPE_OFFSET: usize = 1;
+const HDR_DEST_OFFSET: usize = 2;
+
+// Address field sizes of the full and compact header
+const ADDRESS_SIZE: usize = 4;
+const SHORT_ADDRESS_SIZE: usize = 2;
+
+// Ctrl + type + dest + orig + seq + info, the header fields relays never change
+const SECURITY_AAD_SIZE: usize = 13;
 
 #[repr(u8)]
 #[derive(Default, Clone, Debug, PartialEq)]
 pub enum BmPacketTypes {
     #[default]
     BcastNeighborTable = 0,
+    LinkAck = 1,
 
     RouteDiscoveryRequest = 10,
     RouteDiscoveryResponse = 11,
@@ -28,6 +48,13 @@ pub enum BmPacketTypes {
 
     DataPayload = 20,
     DataPayloadAck = 21,
+    DataFragment = 22,
+    DataFragmentAck = 23,
+
+    EchoRequest = 30,
+    EchoReply = 31,
+    TraceRequest = 32,
+    TraceReply = 33,
 }
 
 impl fmt::Display for BmPacketTypes {
@@ -36,6 +63,9 @@ impl fmt::Display for BmPacketTypes {
             BmPacketTypes::BcastNeighborTable => {
                 write!(f, "BcastNeighborTable")
             }
+            BmPacketTypes::LinkAck => {
+                write!(f, "LinkAck")
+            }
             BmPacketTypes::RouteDiscoveryRequest => {
                 write!(f, "RouteDiscoveryRequest")
             }
@@ -48,26 +78,109 @@ impl fmt::Display for BmPacketTypes {
             BmPacketTypes::DataPayloadAck => {
                 write!(f, "DataPayloadAck")
             }
+            BmPacketTypes::DataFragment => {
+                write!(f, "DataFragment")
+            }
+            BmPacketTypes::DataFragmentAck => {
+                write!(f, "DataFragmentAck")
+            }
+            BmPacketTypes::EchoRequest => {
+                write!(f, "EchoRequest")
+            }
+            BmPacketTypes::EchoReply => {
+                write!(f, "EchoReply")
+            }
+            BmPacketTypes::TraceRequest => {
+                write!(f, "TraceRequest")
+            }
+            BmPacketTypes::TraceReply => {
+                write!(f, "TraceReply")
+            }
             _ => { write!(f, "Unknown") }
         }
     }
 }
 
 impl BmPacketTypes {
-    const fn from_bits(value: u8) -> Self {
+    const fn from_bits(value: u8) -> Option<Self> {
         match value {
-            0 => Self::BcastNeighborTable,
+            0 => Some(Self::BcastNeighborTable),
+            1 => Some(Self::LinkAck),
             
-            10 => Self::RouteDiscoveryRequest,
-            11 => Self::RouteDiscoveryResponse,
-            12 => Self::RouteDiscoveryError,
+            10 => Some(Self::RouteDiscoveryRequest),
+            11 => Some(Self::RouteDiscoveryResponse),
+            12 => Some(Self::RouteDiscoveryError),
 
-            20 => Self::DataPayload,
-            21 => Self::DataPayloadAck,
+            20 => Some(Self::DataPayload),
+            21 => Some(Self::DataPayloadAck),
+            22 => Some(Self::DataFragment),
+            23 => Some(Self::DataFragmentAck),
 
-            _ => Self::BcastNeighborTable,
+            30 => Some(Self::EchoRequest),
+            31 => Some(Self::EchoReply),
+            32 => Some(Self::TraceRequest),
+            33 => Some(Self::TraceReply),
+
+            _ => None,
         }
     }
+
+    // Packet types that are rebroadcast by every node rather than routed
+    pub const fn is_flood(&self) -> bool {
+        matches!(self, Self::BcastNeighborTable | Self::RouteDiscoveryRequest)
+    }
+
+    // Packet types carrying application data, encrypted when a network key is set
+    pub const fn is_data(&self) -> bool {
+        matches!(self, Self::DataPayload | Self::DataFragment)
+    }
+}
+
+// Reasons a received frame could not be decoded into a BmNetworkPacket
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BmPacketDecodeError {
+    // Frame is shorter than the OTA header
+    TooShort,
+    // Packet type byte does not match any BmPacketTypes
+    UnknownType(u8),
+    // Payload length exceeds BM_MAX_PAYLOAD_SIZE
+    OversizePayload(usize),
+    // Header info byte is inconsistent, i.e. hop count past TTL
+    BadInfoBits(u8),
+    // Frame was built for a different protocol version
+    VersionMismatch(u8),
+}
+
+impl defmt::Format for BmPacketDecodeError {
+    fn format(&self, fmt: defmt::Formatter) {
+        match self {
+            BmPacketDecodeError::TooShort => defmt::write!(fmt, "TooShort"),
+            BmPacketDecodeError::UnknownType(value) => defmt::write!(fmt, "UnknownType({})", value),
+            BmPacketDecodeError::OversizePayload(len) => defmt::write!(fmt, "OversizePayload({})", len),
+            BmPacketDecodeError::BadInfoBits(value) => defmt::write!(fmt, "BadInfoBits({:#X})", value),
+            BmPacketDecodeError::VersionMismatch(version) => defmt::write!(fmt, "VersionMismatch({})", version),
+        }
+    }
+}
+
+#[bitfield(u8)]
+#[derive(PartialEq, Eq)]
+pub struct BmNetworkHdrCtrl {
+    // Flag indicating a hop MIC trailer follows the payload
+    #[bits(1)]
+    pub authenticated: bool,
+    // Flag indicating 16 bit short addresses, see BM_COMPACT_PACKET_HDR_SIZE
+    #[bits(1)]
+    pub compact: bool,
+    // Flag asking the next hop for a LinkAck
+    #[bits(1)]
+    pub link_ack: bool,
+    // Reserved header flag, always zero in this version
+    #[bits(1)]
+    __: u8,
+    // OTA protocol version, see BM_PROTOCOL_VERSION
+    #[bits(4)]
+    pub version: u8,
 }
 
 #[bitfield(u8)]
@@ -95,6 +208,8 @@ pub struct BmNetworkRoutingHdr {
     orig: NetworkId,
     dest: NetworkId,
     info: BmNetworkHdrInfo,
+    // Per-originator sequence number, never changed by relays
+    seq: u16,
 }
 
 impl BmNetworkRoutingHdr {
@@ -110,6 +225,7 @@ impl BmNetworkRoutingHdr {
                 .with_hop_count(0)
                 .with_required_ack(ack)
                 .with_encrypted(false),
+            seq: 0,
         }
     }
 
@@ -133,6 +249,11 @@ impl BmNetworkRoutingHdr {
         self
     }
 
+    pub const fn with_seq(mut self, new_seq: u16) -> Self {
+        self.seq = new_seq;
+        self
+    }
+
     pub fn set_ttl(&mut self, new_ttl: u8) {
         self.info.set_ttl(new_ttl);
     }
@@ -150,6 +271,14 @@ pub enum TransmitState {
     Complete,
 }
 
+// Hop MIC trailer. Signed by the transmitting node over the whole frame, using its
+// own frame counter, so relays re-sign after rewriting the header.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct BmHopMic {
+    pub frame_counter: u32,
+    pub mic: [u8; BM_MIC_SIZE],
+}
+
 #[derive(Default, Debug, Clone, PartialEq)]
 pub struct BmNetworkPacket {
     // Packet enumeration
@@ -158,13 +287,31 @@ pub struct BmNetworkPacket {
     routing_hdr: BmNetworkRoutingHdr,
     // Payload buffer, optional as only data payload will use this
     payload: Option<BmNetworkPacketPayload>,
+    // Hop MIC trailer, cleared whenever the header is modified
+    hop_mic: Option<BmHopMic>,
+    // Prefer the compact header. Only used when every address fits a short address.
+    compact_hdr: bool,
+    // Ask the next hop for a LinkAck
+    link_ack: bool,
 
     // Metadata (Note: Does not go OTA)
     pub tx_state: TransmitState,
     pub tx_complete_timestamp: Option<i64>,
+    // Earliest time the packet may go on air, None for right away
+    pub tx_not_before: Option<i64>,
     pub tx_count: u8,
     pub wait_for_reply: bool,
     pub rx_rssi: RssiType,
+    // When the LinkAck of the next hop is overdue, None when not waiting for one
+    pub link_ack_deadline: Option<i64>,
+    // Retransmissions to the current next hop
+    pub link_retries: u8,
+    // Alternate next hops tried after the first one stayed silent
+    pub link_reroutes: u8,
+    // Node we received a relayed packet from, None for our own
+    pub prev_hop: NetworkId,
+    // Waiting to hear the next hop forward the packet, instead of a LinkAck
+    pub passive_ack: bool,
 }
 
 impl fmt::Display for BmNetworkPacket {
@@ -189,11 +336,20 @@ impl BmNetworkPacket {
                 .with_orig(orig)
                 .with_dest(dest),
             payload: new_payload,
+            hop_mic: None,
+            compact_hdr: false,
+            link_ack: false,
             tx_state: TransmitState::Waiting,
             tx_complete_timestamp: None,
+            tx_not_before: None,
             tx_count: 0,
             wait_for_reply: false,
             rx_rssi: 0,
+            link_ack_deadline: None,
+            link_retries: 0,
+            link_reroutes: 0,
+            prev_hop: None,
+            passive_ack: false,
         }
     }
 
@@ -202,6 +358,16 @@ impl BmNetworkPacket {
         self
     }
 
+    pub const fn with_seq(mut self, new_seq: u16) -> Self {
+        self.routing_hdr = self.routing_hdr.with_seq(new_seq);
+        self
+    }
+
+    pub const fn with_compact_header(mut self, compact: bool) -> Self {
+        self.compact_hdr = compact;
+        self
+    }
+
     pub const fn with_ok_to_transmit(mut self) -> Self {
         self.tx_state = TransmitState::Ok;
         self
@@ -223,12 +389,14 @@ impl BmNetworkPacket {
     }
     pub fn set_source(&mut self, new_src: NetworkId) {
         self.routing_hdr.src = new_src;
+        self.hop_mic = None;
     }
     pub fn get_next_hop(&mut self) -> NetworkId {
         self.routing_hdr.next_hop
     }
     pub fn set_next_hop(&mut self, new_next_hop: NetworkId) {
         self.routing_hdr.next_hop = new_next_hop;
+        self.hop_mic = None;
     }
     pub fn get_originator(&mut self) -> NetworkId {
         self.routing_hdr.orig
@@ -236,6 +404,9 @@ impl BmNetworkPacket {
     pub fn get_destination(&mut self) -> NetworkId {
         self.routing_hdr.dest
     }
+    pub fn get_seq(&mut self) -> u16 {
+        self.routing_hdr.seq
+    }
     pub fn get_hop_count(&mut self) -> u8 {
         self.routing_hdr.info.hop_count()
     }
@@ -244,6 +415,7 @@ impl BmNetworkPacket {
     }
     pub fn set_info(&mut self, new_info: BmNetworkHdrInfo) {
         self.routing_hdr.info = new_info;
+        self.hop_mic = None;
     }
     pub fn increment_hop_count(&mut self) {
         let hop_cnt = self.routing_hdr.info.hop_count();
@@ -251,7 +423,36 @@ impl BmNetworkPacket {
         if hop_cnt + 1 <= MAX_TTL_HOP_CNT {
             self.routing_hdr.info.set_hop_count(hop_cnt + 1);
         }
-        
+        self.hop_mic = None;
+    }
+    pub fn is_authenticated(&self) -> bool {
+        self.hop_mic.is_some()
+    }
+    pub fn set_compact_header(&mut self, compact: bool) {
+        self.compact_hdr = compact;
+        self.hop_mic = None;
+    }
+    // Asking for a LinkAck starts over on the current next hop
+    pub fn set_link_ack(&mut self, link_ack: bool) {
+        self.link_ack = link_ack;
+        self.link_ack_deadline = None;
+        self.link_retries = 0;
+        self.hop_mic = None;
+    }
+    pub fn is_link_ack_requested(&self) -> bool {
+        self.link_ack
+    }
+    // True when the next hop acks the packet, with a LinkAck or by forwarding it
+    pub fn is_hop_ack_expected(&self) -> bool {
+        self.link_ack || self.passive_ack
+    }
+    // True when the packet goes on air with the compact header
+    pub fn is_compact_header(&self) -> bool {
+        self.compact_hdr &&
+        short_address(self.routing_hdr.dest).is_some() &&
+        short_address(self.routing_hdr.src).is_some() &&
+        short_address(self.routing_hdr.orig).is_some() &&
+        (self.packet_type.is_flood() || short_address(self.routing_hdr.next_hop).is_some())
     }
     pub fn get_payload_len(&mut self) -> usize {
         if let Some(packet_payload) = &self.payload {
@@ -262,12 +463,21 @@ impl BmNetworkPacket {
     pub fn get_payload(&mut self) -> &Option<BmNetworkPacketPayload> {
         &self.payload
     }
+    pub fn set_payload(&mut self, new_payload: Option<BmNetworkPacketPayload>) {
+        self.payload = new_payload;
+        self.hop_mic = None;
+    }
     pub fn set_ok_to_transmit(&mut self) {
         self.tx_state = TransmitState::Ok;
     }
     pub fn is_ok_to_transmit(&mut self) -> bool {
         self.tx_state == TransmitState::Ok
     }
+    // True once the packet may go on air
+    pub fn is_due(&self, millis: i64) -> bool {
+        self.tx_not_before.is_none_or(|not_before| millis >= not_before)
+    }
+
     pub fn set_wait_for_reply(&mut self) {
         self.wait_for_reply = true;
     }
@@ -275,69 +485,356 @@ impl BmNetworkPacket {
         self.wait_for_reply
     }
 
-    // Mutation functions
-    pub fn from(length: usize, buffer: &mut [u8]) -> Option<BmNetworkPacket> {
-        // Ensure packet is long enough to contain the header
-        if length < BM_PACKET_HDR_SIZE {
-            defmt::warn!("BmNetworkPacket: len too small");
+    // Encrypts the payload with the network key. The MIC also covers every header
+    // field relays do not modify, so the header cannot be altered in flight.
+    pub fn encrypt_payload(&mut self, key: &BmNetworkKey, frame_counter: u32) -> Result<(), BmSecurityError> {
+        let plaintext_len = self.get_payload_len();
+        if plaintext_len + BM_SECURITY_OVERHEAD > BM_MAX_PAYLOAD_SIZE {
+            return Err(BmSecurityError::PayloadTooLarge)
+        }
+
+        // Flag is part of the associated data, so set it first
+        self.routing_hdr.info.set_encrypted(true);
+        let aad = self.security_aad();
+
+        // Secured payload: frame counter, ciphertext, MIC
+        let mut secured: BmNetworkPacketPayload = Vec::new();
+        secured.extend_from_slice(&frame_counter.to_le_bytes()).map_err(|_| BmSecurityError::PayloadTooLarge)?;
+        if let Some(plaintext) = self.payload.as_ref() {
+            secured.extend_from_slice(plaintext).map_err(|_| BmSecurityError::PayloadTooLarge)?;
+        }
+        let mic = bm_network_security::seal(
+            key, self.routing_hdr.orig, frame_counter, &aad, &mut secured[BM_FRAME_COUNTER_SIZE..])?;
+        secured.extend_from_slice(&mic).map_err(|_| BmSecurityError::PayloadTooLarge)?;
+
+        self.payload = Some(secured);
+        Ok(())
+    }
+
+    // Authenticates and decrypts the payload in place. Returns the frame counter it was sent with.
+    pub fn decrypt_payload(&mut self, key: &BmNetworkKey) -> Result<u32, BmSecurityError> {
+        let frame_counter = self.get_frame_counter().ok_or(BmSecurityError::Malformed)?;
+        let aad = self.security_aad();
+
+        let secured = self.payload.as_ref().ok_or(BmSecurityError::Malformed)?;
+        let mic_offset = secured.len() - BM_MIC_SIZE;
+        let mut plaintext = BmNetworkPacketPayload::from_slice(&secured[BM_FRAME_COUNTER_SIZE..mic_offset])
+            .map_err(|_| BmSecurityError::Malformed)?;
+        bm_network_security::open(
+            key, self.routing_hdr.orig, frame_counter, &aad, &mut plaintext, &secured[mic_offset..])?;
+
+        self.payload = if plaintext.is_empty() { None } else { Some(plaintext) };
+        self.routing_hdr.info.set_encrypted(false);
+        Ok(frame_counter)
+    }
+
+    // Signs the frame as it will go on air. Must be called after the last header change.
+    pub fn sign_hop(&mut self, key: &BmNetworkKey, frame_counter: u32) -> Result<(), BmSecurityError> {
+        let frame = self.encode_frame(true).ok_or(BmSecurityError::PayloadTooLarge)?;
+        let mic = bm_network_security::seal(key, self.routing_hdr.src, frame_counter, &frame, &mut [])?;
+        self.hop_mic = Some(BmHopMic { frame_counter, mic });
+        Ok(())
+    }
+
+    // Checks the hop MIC against the network key. Returns the transmitter's frame counter.
+    pub fn verify_hop(&self, key: &BmNetworkKey) -> Result<u32, BmSecurityError> {
+        let hop_mic = self.hop_mic.as_ref().ok_or(BmSecurityError::Missing)?;
+        let frame = self.encode_frame(true).ok_or(BmSecurityError::Malformed)?;
+        bm_network_security::open(key, self.routing_hdr.src, hop_mic.frame_counter, &frame, &mut [], &hop_mic.mic)?;
+        Ok(hop_mic.frame_counter)
+    }
+
+    // Frame counter of an encrypted payload, None for plaintext packets
+    pub fn get_frame_counter(&self) -> Option<u32> {
+        if !self.routing_hdr.info.encrypted() {
             return None
         }
+        match self.payload.as_ref() {
+            Some(secured) if secured.len() >= BM_SECURITY_OVERHEAD => Some(read_u32_le(secured, 0)),
+            _ => None,
+        }
+    }
 
-        defmt::info!("from: buffer={}", buffer[0..length]);
+    // Mutation functions
+    pub fn from(length: usize, buffer: &[u8]) -> Result<BmNetworkPacket, BmPacketDecodeError> {
+        let mut packet = BmNetworkPacket::default();
+        packet.read_from(length, buffer)?;
+        Ok(packet)
+    }
 
-        // Create vec from payload bytes
-        let mut payload_vec: BmNetworkPacketPayload = Vec::new();
-        let mut payload: Option<BmNetworkPacketPayload> = None;
-        if length > BM_PACKET_HDR_SIZE {
-            // Todo - figure out better way to get u8 to vec with const
-            payload_vec.extend_from_slice(buffer[BM_PACKET_HDR_SIZE..length].try_into().unwrap()).unwrap();
-            payload = Some(payload_vec);
-        }       
-
-        // Todo parse packet into pieces below
-        Some(BmNetworkPacket {
-            packet_type: BmPacketTypes::from_bits(buffer[0]),
-                routing_hdr: BmNetworkRoutingHdr {
-                    dest: Some(u32::from_ne_bytes(buffer[1..5].try_into().unwrap())),
-                    src: Some(u32::from_ne_bytes(buffer[5..9].try_into().unwrap())),
-                    next_hop: Some(u32::from_ne_bytes(buffer[9..13].try_into().unwrap())),
-                    orig: Some(u32::from_ne_bytes(buffer[13..17].try_into().unwrap())),
-                    info: BmNetworkHdrInfo(buffer[17]),
-                },
-                payload,
-                // Init metadata
-                tx_state: TransmitState::Waiting,
-                tx_complete_timestamp: None,
-                tx_count: 0,
-                wait_for_reply: false,
-                rx_rssi: 0,
-            }
-        )
+    // Decodes a frame over this packet, so it can be filled in place, e.g. in a queue slot.
+    // Metadata is reset. The packet is left unchanged when the frame is rejected.
+    pub fn read_from(&mut self, length: usize, buffer: &[u8]) -> Result<(), BmPacketDecodeError> {
+        let layout = BmFrameLayout::parse(length, buffer)?;
+
+        self.packet_type = layout.packet_type.clone();
+        self.routing_hdr = BmNetworkRoutingHdr {
+            dest: layout.read_address(buffer, layout.dest_offset()),
+            src: layout.read_address(buffer, layout.src_offset()),
+            next_hop: layout.next_hop_offset.and_then(|offset| layout.read_address(buffer, offset)),
+            orig: layout.read_address(buffer, layout.orig_offset),
+            info: layout.info(buffer),
+            seq: layout.seq(buffer),
+        };
+
+        // Copy payload bytes into the existing buffer, size was checked by parse
+        if layout.frame_end > layout.hdr_size {
+            let payload = self.payload.get_or_insert_with(BmNetworkPacketPayload::new);
+            payload.clear();
+            // Cannot fail, parse limits the payload to BM_MAX_PAYLOAD_SIZE
+            let _ = payload.extend_from_slice(&buffer[layout.hdr_size..layout.frame_end]);
+        }
+        else {
+            self.payload = None;
+        }
+
+        self.hop_mic = layout.hop_mic(buffer);
+        self.compact_hdr = layout.ctrl.compact();
+        self.link_ack = layout.ctrl.link_ack();
+
+        // Init metadata
+        self.tx_state = TransmitState::Waiting;
+        self.tx_complete_timestamp = None;
+        self.tx_not_before = None;
+        self.tx_count = 0;
+        self.wait_for_reply = false;
+        self.rx_rssi = 0;
+        self.link_ack_deadline = None;
+        self.link_retries = 0;
+        self.link_reroutes = 0;
+        self.prev_hop = None;
+        self.passive_ack = false;
+        Ok(())
     }
 
     pub fn to_bytes(&mut self) -> Option<BmNetworkOtaPacket> {
+        let mut out_buffer = self.encode_frame(self.hop_mic.is_some())?;
+
+        // If signed, append hop MIC trailer
+        if let Some(hop_mic) = self.hop_mic.as_ref() {
+            if out_buffer.extend_from_slice(&hop_mic.frame_counter.to_le_bytes()).is_err() { return None; }
+            if out_buffer.extend_from_slice(&hop_mic.mic).is_err() { return None; }
+        }
+
+        defmt::info!("to: buffer={}", out_buffer[0..out_buffer.len()]);     
+
+        // Return the length of bytes to send
+        Some(out_buffer)
+    }
+
+    //-----------------------------------------------------------
+    // Private functions
+    //----------------------------------------------------------- 
+
+    // Header and payload bytes, everything but the hop MIC trailer
+    fn encode_frame(&self, authenticated: bool) -> Option<BmNetworkOtaPacket> {
         let mut out_buffer: BmNetworkOtaPacket = Vec::new();
+        let compact = self.is_compact_header();
+        let ctrl = BmNetworkHdrCtrl::new()
+            .with_authenticated(authenticated)
+            .with_compact(compact)
+            .with_link_ack(self.link_ack)
+            .with_version(BM_PROTOCOL_VERSION);
 
         // Copy packet to vector buffer
+        if out_buffer.push(ctrl.into()).is_err() { return None; }
         if out_buffer.push(self.packet_type.clone() as u8).is_err() { return None; }
-        if out_buffer.extend_from_slice(&self.routing_hdr.dest.unwrap_or(0).to_ne_bytes()).is_err() { return None; }
-        if out_buffer.extend_from_slice(&self.routing_hdr.src.unwrap_or(0).to_ne_bytes()).is_err() { return None; }
-        if out_buffer.extend_from_slice(&self.routing_hdr.next_hop.unwrap_or(0).to_ne_bytes()).is_err() { return None; }
-        if out_buffer.extend_from_slice(&self.routing_hdr.orig.unwrap_or(0).to_ne_bytes()).is_err() { return None; }
+        if compact {
+            // Addresses were checked to fit by is_compact_header
+            let short = |id: NetworkId| (id.unwrap_or(0) as u16).to_le_bytes();
+            if out_buffer.extend_from_slice(&short(self.routing_hdr.dest)).is_err() { return None; }
+            if out_buffer.extend_from_slice(&short(self.routing_hdr.src)).is_err() { return None; }
+            if !self.packet_type.is_flood() &&
+               out_buffer.extend_from_slice(&short(self.routing_hdr.next_hop)).is_err() { return None; }
+            if out_buffer.extend_from_slice(&short(self.routing_hdr.orig)).is_err() { return None; }
+        }
+        else {
+            if out_buffer.extend_from_slice(&self.routing_hdr.dest.unwrap_or(0).to_le_bytes()).is_err() { return None; }
+            if out_buffer.extend_from_slice(&self.routing_hdr.src.unwrap_or(0).to_le_bytes()).is_err() { return None; }
+            if out_buffer.extend_from_slice(&self.routing_hdr.next_hop.unwrap_or(0).to_le_bytes()).is_err() { return None; }
+            if out_buffer.extend_from_slice(&self.routing_hdr.orig.unwrap_or(0).to_le_bytes()).is_err() { return None; }
+        }
         if out_buffer.push(self.routing_hdr.info.into()).is_err() { return None; }
+        if out_buffer.extend_from_slice(&self.routing_hdr.seq.to_le_bytes()).is_err() { return None; }
 
-        // If there is a payload, oush bytes
+        // If there is a payload, push bytes
         if let Some(payload) = self.payload.as_ref() {        
-            for &payload_byte in payload.iter() {
-                out_buffer.push(payload_byte).unwrap();
+            if out_buffer.extend_from_slice(payload).is_err() { return None; }
+        }
+
+        Some(out_buffer)
+    }
+
+    fn security_aad(&self) -> [u8; SECURITY_AAD_SIZE] {
+        security_aad(&self.packet_type, self.routing_hdr.dest, self.routing_hdr.orig, self.routing_hdr.seq, self.routing_hdr.info)
+    }
+}
+
+// Field positions of a received frame, shared by BmNetworkPacket::from and BmPacketView
+#[derive(Debug, Clone, PartialEq)]
+pub(super) struct BmFrameLayout {
+    pub(super) ctrl: BmNetworkHdrCtrl,
+    pub(super) packet_type: BmPacketTypes,
+    // 4 byte addresses, or 2 with the compact header
+    pub(super) address_size: usize,
+    // Compact broadcasts carry no next hop
+    pub(super) next_hop_offset: Option<usize>,
+    pub(super) orig_offset: usize,
+    pub(super) info_offset: usize,
+    pub(super) hdr_size: usize,
+    // End of the payload, the hop MIC trailer follows if authenticated
+    pub(super) frame_end: usize,
+}
+
+impl BmFrameLayout {
+    // Validates the frame and locates its fields
+    pub(super) fn parse(length: usize, buffer: &[u8]) -> Result<BmFrameLayout, BmPacketDecodeError> {
+        // Ensure packet is long enough to contain the smallest header
+        if length < BM_COMPACT_PACKET_HDR_SIZE - SHORT_ADDRESS_SIZE || length > buffer.len() {
+            defmt::warn!("BmNetworkPacket: len too small");
+            return Err(BmPacketDecodeError::TooShort)
+        }
+
+        defmt::info!("from: buffer={}", buffer[0..length]);
+
+        // Reject frames built for a different protocol version rather than misparse them
+        let ctrl = BmNetworkHdrCtrl(buffer[HDR_CTRL_OFFSET]);
+        if ctrl.version() != BM_PROTOCOL_VERSION {
+            defmt::warn!("BmNetworkPacket: unsupported version={}", ctrl.version());
+            return Err(BmPacketDecodeError::VersionMismatch(ctrl.version()))
+        }
+
+        let packet_type = BmPacketTypes::from_bits(buffer[HDR_TYPE_OFFSET])
+            .ok_or(BmPacketDecodeError::UnknownType(buffer[HDR_TYPE_OFFSET]))?;
+
+        // Header length depends on the address size and packet type
+        let hdr_size = header_size(ctrl.compact(), &packet_type);
+        if length < hdr_size {
+            return Err(BmPacketDecodeError::TooShort)
+        }
+
+        // Hop MIC trailer sits at the end
+        let mut frame_end = length;
+        if ctrl.authenticated() {
+            if length < hdr_size + BM_SECURITY_OVERHEAD {
+                return Err(BmPacketDecodeError::TooShort)
             }
+            frame_end -= BM_SECURITY_OVERHEAD;
+        }
+        if frame_end - hdr_size > BM_MAX_PAYLOAD_SIZE {
+            return Err(BmPacketDecodeError::OversizePayload(frame_end - hdr_size))
         }
 
-        defmt::info!("to: buffer={}", out_buffer[0..out_buffer.len()]);     
+        // Addresses in header order: dest, src, next_hop, orig
+        let address_size = if ctrl.compact() { SHORT_ADDRESS_SIZE } else { ADDRESS_SIZE };
+        let has_next_hop = !(ctrl.compact() && packet_type.is_flood());
+        let next_hop_offset = HDR_DEST_OFFSET + 2 * address_size;
+        let orig_offset = if has_next_hop { next_hop_offset + address_size } else { next_hop_offset };
 
-        // Return the length of bytes to send
-        Some(out_buffer)
+        let layout = BmFrameLayout {
+            ctrl,
+            packet_type,
+            address_size,
+            next_hop_offset: if has_next_hop { Some(next_hop_offset) } else { None },
+            orig_offset,
+            info_offset: orig_offset + address_size,
+            hdr_size,
+            frame_end,
+        };
+
+        // A relay never forwards a packet past its TTL
+        let info = layout.info(buffer);
+        if info.hop_count() > info.ttl() {
+            return Err(BmPacketDecodeError::BadInfoBits(info.into()))
+        }
+        Ok(layout)
+    }
+
+    pub(super) const fn dest_offset(&self) -> usize {
+        HDR_DEST_OFFSET
     }
+
+    pub(super) const fn src_offset(&self) -> usize {
+        HDR_DEST_OFFSET + self.address_size
+    }
+
+    pub(super) fn read_address(&self, buffer: &[u8], offset: usize) -> NetworkId {
+        if self.ctrl.compact() {
+            Some(read_u16_le(buffer, offset) as u32)
+        }
+        else {
+            Some(read_u32_le(buffer, offset))
+        }
+    }
+
+    // Writes an address in place, false if it does not fit the header format
+    pub(super) fn write_address(&self, buffer: &mut [u8], offset: usize, id: NetworkId) -> bool {
+        if self.ctrl.compact() {
+            let Some(short) = short_address(id) else {
+                return false
+            };
+            buffer[offset..offset + SHORT_ADDRESS_SIZE].copy_from_slice(&short.to_le_bytes());
+        }
+        else {
+            buffer[offset..offset + ADDRESS_SIZE].copy_from_slice(&id.unwrap_or(0).to_le_bytes());
+        }
+        true
+    }
+
+    pub(super) fn info(&self, buffer: &[u8]) -> BmNetworkHdrInfo {
+        BmNetworkHdrInfo(buffer[self.info_offset])
+    }
+
+    pub(super) fn seq(&self, buffer: &[u8]) -> u16 {
+        read_u16_le(buffer, self.info_offset + 1)
+    }
+
+    pub(super) fn hop_mic(&self, buffer: &[u8]) -> Option<BmHopMic> {
+        if !self.ctrl.authenticated() {
+            return None
+        }
+        let mut mic = [0u8; BM_MIC_SIZE];
+        let mic_offset = self.frame_end + BM_FRAME_COUNTER_SIZE;
+        mic.copy_from_slice(&buffer[mic_offset..mic_offset + BM_MIC_SIZE]);
+        Some(BmHopMic { frame_counter: read_u32_le(buffer, self.frame_end), mic })
+    }
+}
+
+// Header bytes bound to the payload MIC. Source, next hop and hop count are
+// rewritten by every relay and are left out.
+pub(super) fn security_aad(packet_type: &BmPacketTypes, dest: NetworkId, orig: NetworkId, seq: u16, info: BmNetworkHdrInfo) -> [u8; SECURITY_AAD_SIZE] {
+    let mut aad = [0u8; SECURITY_AAD_SIZE];
+    aad[0] = BmNetworkHdrCtrl::new().with_version(BM_PROTOCOL_VERSION).into();
+    aad[1] = packet_type.clone() as u8;
+    aad[2..6].copy_from_slice(&dest.unwrap_or(0).to_le_bytes());
+    aad[6..10].copy_from_slice(&orig.unwrap_or(0).to_le_bytes());
+    aad[10..12].copy_from_slice(&seq.to_le_bytes());
+    aad[12] = info.with_hop_count(0).into();
+    aad
+}
+
+// Short address of a network id. Short addresses are assigned by giving nodes ids
+// below 0x10000, so both header formats resolve to the same NetworkId.
+pub fn short_address(id: NetworkId) -> Option<u16> {
+    u16::try_from(id.unwrap_or(0)).ok()
+}
+
+// Header length for the given format. Compact broadcasts drop the next hop.
+fn header_size(compact: bool, packet_type: &BmPacketTypes) -> usize {
+    match (compact, packet_type.is_flood()) {
+        (false, _) => BM_PACKET_HDR_SIZE,
+        (true, false) => BM_COMPACT_PACKET_HDR_SIZE,
+        (true, true) => BM_COMPACT_PACKET_HDR_SIZE - SHORT_ADDRESS_SIZE,
+    }
+}
+
+// Read little endian values out of a header buffer. Caller guarantees the length.
+pub(super) fn read_u32_le(buffer: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buffer[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u16_le(buffer: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buffer[offset..offset + 2].try_into().unwrap())
 }
 
 #[cfg(test)]
@@ -346,14 +843,17 @@ mod tests {
 
     #[test]
     fn test_packet_type_conversions() {
-        assert_eq!(BmPacketTypes::from_bits(0), BmPacketTypes::BcastNeighborTable);
-        assert_eq!(BmPacketTypes::from_bits(10), BmPacketTypes::RouteDiscoveryRequest);
-        assert_eq!(BmPacketTypes::from_bits(11), BmPacketTypes::RouteDiscoveryResponse);
-        assert_eq!(BmPacketTypes::from_bits(12), BmPacketTypes::RouteDiscoveryError);
-        assert_eq!(BmPacketTypes::from_bits(20), BmPacketTypes::DataPayload);
-        assert_eq!(BmPacketTypes::from_bits(21), BmPacketTypes::DataPayloadAck);
-        // Fallback for unknown bit patterns
-        assert_eq!(BmPacketTypes::from_bits(99), BmPacketTypes::BcastNeighborTable);
+        assert_eq!(BmPacketTypes::from_bits(0), Some(BmPacketTypes::BcastNeighborTable));
+        assert_eq!(BmPacketTypes::from_bits(1), Some(BmPacketTypes::LinkAck));
+        assert_eq!(BmPacketTypes::from_bits(10), Some(BmPacketTypes::RouteDiscoveryRequest));
+        assert_eq!(BmPacketTypes::from_bits(11), Some(BmPacketTypes::RouteDiscoveryResponse));
+        assert_eq!(BmPacketTypes::from_bits(12), Some(BmPacketTypes::RouteDiscoveryError));
+        assert_eq!(BmPacketTypes::from_bits(20), Some(BmPacketTypes::DataPayload));
+        assert_eq!(BmPacketTypes::from_bits(21), Some(BmPacketTypes::DataPayloadAck));
+        assert_eq!(BmPacketTypes::from_bits(22), Some(BmPacketTypes::DataFragment));
+        assert_eq!(BmPacketTypes::from_bits(23), Some(BmPacketTypes::DataFragmentAck));
+        // Unknown bit patterns are not mapped to a type
+        assert_eq!(BmPacketTypes::from_bits(99), None);
     }
 
     #[test]
@@ -447,21 +947,23 @@ mod tests {
             5,
             true,
             Some(payload.clone()),
-        );
+        )
+        .with_seq(0xBEEF);
 
         // Serialize to bytes (OTA format)
-        let mut bytes = original_pkt.to_bytes().expect("Serialization failed");
+        let bytes = original_pkt.to_bytes().expect("Serialization failed");
         assert!(bytes.len() >= BM_PACKET_HDR_SIZE);
 
         // Deserialize from buffer
         let len = bytes.len();
-        let parsed_pkt = BmNetworkPacket::from(len, &mut bytes).expect("Deserialization failed");
+        let parsed_pkt = BmNetworkPacket::from(len, &bytes).expect("Deserialization failed");
 
         assert_eq!(parsed_pkt.packet_type, BmPacketTypes::DataPayload);
         assert_eq!(parsed_pkt.routing_hdr.dest, dest);
         assert_eq!(parsed_pkt.routing_hdr.src, src);
         assert_eq!(parsed_pkt.routing_hdr.next_hop, next_hop);
         assert_eq!(parsed_pkt.routing_hdr.orig, orig);
+        assert_eq!(parsed_pkt.routing_hdr.seq, 0xBEEF);
 
         // Verify Header Info bits survived roundtrip
         assert_eq!(parsed_pkt.routing_hdr.info.ttl(), 5);
@@ -473,8 +975,278 @@ mod tests {
 
     #[test]
     fn test_from_bytes_buffer_too_small() {
-        let mut short_buffer = [0u8; 5]; // Smaller than BM_PACKET_HDR_SIZE
-        let parsed = BmNetworkPacket::from(short_buffer.len(), &mut short_buffer);
-        assert!(parsed.is_none());
+        let short_buffer = [0u8; 5]; // Smaller than BM_PACKET_HDR_SIZE
+        let parsed = BmNetworkPacket::from(short_buffer.len(), &short_buffer);
+        assert_eq!(parsed, Err(BmPacketDecodeError::TooShort));
+
+        // Declared length longer than the buffer itself
+        let parsed = BmNetworkPacket::from(GOLDEN_DATA_FRAME.len() + 1, &GOLDEN_DATA_FRAME);
+        assert_eq!(parsed, Err(BmPacketDecodeError::TooShort));
+    }
+
+    // Golden frame: DataPayload 0x11223344 -> 0x99AABBCC via 0x55667788, ttl 5, ack, seq 0x0102,
+    // payload DEADBEEF
+    const GOLDEN_DATA_FRAME: [u8; 25] = [
+        0x40,                   // version 4, no flags
+        0x14,                   // DataPayload
+        0xCC, 0xBB, 0xAA, 0x99, // dest
+        0x44, 0x33, 0x22, 0x11, // src
+        0x88, 0x77, 0x66, 0x55, // next hop
+        0x44, 0x33, 0x22, 0x11, // orig
+        0x45,                   // ttl 5, hop 0, ack
+        0x02, 0x01,             // seq
+        0xDE, 0xAD, 0xBE, 0xEF, // payload
+    ];
+
+    #[test]
+    fn test_golden_vector_encode() {
+        let mut payload: BmNetworkPacketPayload = Vec::new();
+        payload.extend_from_slice(&[0xDE, 0xAD, 0xBE, 0xEF]).unwrap();
+
+        let mut pkt = BmNetworkPacket::new(
+            BmPacketTypes::DataPayload,
+            Some(0x11223344),
+            Some(0x55667788),
+            Some(0x99AABBCC),
+            5,
+            true,
+            Some(payload),
+        )
+        .with_seq(0x0102);
+
+        let bytes = pkt.to_bytes().expect("Serialization failed");
+        assert_eq!(bytes.len(), BM_PACKET_HDR_SIZE + 4);
+        assert_eq!(bytes.as_slice(), &GOLDEN_DATA_FRAME);
+    }
+
+    #[test]
+    fn test_golden_vector_decode() {
+        let mut pkt = BmNetworkPacket::from(GOLDEN_DATA_FRAME.len(), &GOLDEN_DATA_FRAME).expect("Deserialization failed");
+
+        assert_eq!(pkt.packet_type, BmPacketTypes::DataPayload);
+        assert_eq!(pkt.get_destination(), Some(0x99AABBCC));
+        assert_eq!(pkt.get_source(), Some(0x11223344));
+        assert_eq!(pkt.get_next_hop(), Some(0x55667788));
+        assert_eq!(pkt.get_originator(), Some(0x11223344));
+        assert_eq!(pkt.get_info().ttl(), 5);
+        assert_eq!(pkt.get_hop_count(), 0);
+        assert!(pkt.get_info().required_ack());
+        assert_eq!(pkt.get_seq(), 0x0102);
+        assert_eq!(pkt.get_payload().as_ref().unwrap().as_slice(), &[0xDE, 0xAD, 0xBE, 0xEF]);
+    }
+
+    #[test]
+    fn test_from_bytes_rejects_other_versions() {
+        // Pre-versioned frame layout, starting with the packet type byte
+        let mut legacy = [0u8; BM_PACKET_HDR_SIZE];
+        legacy[0] = BmPacketTypes::DataPayload as u8;
+        assert_eq!(BmNetworkPacket::from(legacy.len(), &legacy), Err(BmPacketDecodeError::VersionMismatch(1)));
+
+        // Frame from a newer protocol version
+        let mut future = GOLDEN_DATA_FRAME;
+        future[0] = (BM_PROTOCOL_VERSION + 1) << 4;
+        assert_eq!(
+            BmNetworkPacket::from(future.len(), &future),
+            Err(BmPacketDecodeError::VersionMismatch(BM_PROTOCOL_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn test_from_bytes_rejects_unknown_type() {
+        let mut frame = GOLDEN_DATA_FRAME;
+        frame[1] = 99;
+        assert_eq!(BmNetworkPacket::from(frame.len(), &frame), Err(BmPacketDecodeError::UnknownType(99)));
+    }
+
+    #[test]
+    fn test_from_bytes_rejects_bad_info_bits() {
+        // Hop count of 6 with a TTL of 5
+        let mut frame = GOLDEN_DATA_FRAME;
+        frame[BM_PACKET_HDR_SIZE - 3] = BmNetworkHdrInfo::new().with_ttl(5).with_hop_count(6).into();
+        assert_eq!(BmNetworkPacket::from(frame.len(), &frame), Err(BmPacketDecodeError::BadInfoBits(0x35)));
+    }
+
+    #[test]
+    fn test_from_bytes_rejects_oversize_payload() {
+        // Radio buffers can hold up to 255 bytes, more than hdr + max payload
+        let mut frame = [0u8; 255];
+        frame[..BM_PACKET_HDR_SIZE].copy_from_slice(&GOLDEN_DATA_FRAME[..BM_PACKET_HDR_SIZE]);
+        assert_eq!(
+            BmNetworkPacket::from(frame.len(), &frame),
+            Err(BmPacketDecodeError::OversizePayload(255 - BM_PACKET_HDR_SIZE))
+        );
+    }
+
+    const TEST_KEY: BmNetworkKey = [0x2B; 16];
+
+    fn build_data_packet() -> BmNetworkPacket {
+        let mut payload: BmNetworkPacketPayload = Vec::new();
+        payload.extend_from_slice(b"hello").unwrap();
+        BmNetworkPacket::new(BmPacketTypes::DataPayload, Some(1), Some(2), Some(3), 5, true, Some(payload))
+            .with_seq(42)
+    }
+
+    #[test]
+    fn test_encrypted_payload_roundtrip() {
+        let mut pkt = build_data_packet();
+        pkt.encrypt_payload(&TEST_KEY, 9).unwrap();
+
+        assert!(pkt.get_info().encrypted());
+        assert_eq!(pkt.get_frame_counter(), Some(9));
+        assert_eq!(pkt.get_payload_len(), 5 + BM_SECURITY_OVERHEAD);
+        assert!(!pkt.get_payload().as_ref().unwrap().windows(5).any(|w| w == b"hello"));
+
+        // Relays rewrite source, next hop and hop count on the way
+        let bytes = pkt.to_bytes().unwrap();
+        let mut relayed = BmNetworkPacket::from(bytes.len(), &bytes).unwrap();
+        relayed.set_source(Some(2));
+        relayed.set_next_hop(Some(3));
+        relayed.increment_hop_count();
+
+        assert_eq!(relayed.decrypt_payload(&TEST_KEY), Ok(9));
+        assert!(!relayed.get_info().encrypted());
+        assert_eq!(relayed.get_payload().as_ref().unwrap().as_slice(), b"hello");
+    }
+
+    #[test]
+    fn test_encrypted_payload_rejects_tampering() {
+        let mut pkt = build_data_packet();
+        pkt.encrypt_payload(&TEST_KEY, 9).unwrap();
+
+        // Redirected to another destination
+        let mut redirected = pkt.clone();
+        redirected.routing_hdr.dest = Some(4);
+        assert_eq!(redirected.decrypt_payload(&TEST_KEY), Err(BmSecurityError::AuthFailed));
+
+        // Flipped ciphertext bit
+        let mut flipped = pkt.clone();
+        flipped.payload.as_mut().unwrap()[BM_FRAME_COUNTER_SIZE] ^= 0x01;
+        assert_eq!(flipped.decrypt_payload(&TEST_KEY), Err(BmSecurityError::AuthFailed));
+
+        // Wrong key
+        assert_eq!(pkt.clone().decrypt_payload(&[0u8; 16]), Err(BmSecurityError::AuthFailed));
+
+        // Plaintext packet has nothing to decrypt
+        assert_eq!(build_data_packet().decrypt_payload(&TEST_KEY), Err(BmSecurityError::Malformed));
+    }
+
+    #[test]
+    fn test_encrypt_rejects_payload_without_room_for_overhead() {
+        let mut payload: BmNetworkPacketPayload = Vec::new();
+        payload.resize(BM_MAX_PAYLOAD_SIZE - BM_SECURITY_OVERHEAD + 1, 0).unwrap();
+        let mut pkt = BmNetworkPacket::new(BmPacketTypes::DataPayload, Some(1), Some(2), Some(3), 5, true, Some(payload));
+
+        assert_eq!(pkt.encrypt_payload(&TEST_KEY, 1), Err(BmSecurityError::PayloadTooLarge));
+        assert!(!pkt.get_info().encrypted());
+    }
+
+    #[test]
+    fn test_hop_mic_trailer_roundtrip() {
+        let mut pkt = BmNetworkPacket::new(BmPacketTypes::RouteDiscoveryRequest, Some(1), None, Some(3), 5, false, None)
+            .with_seq(7);
+        pkt.sign_hop(&TEST_KEY, 0x01020304).unwrap();
+
+        let bytes = pkt.to_bytes().unwrap();
+        assert_eq!(bytes.len(), BM_PACKET_HDR_SIZE + BM_SECURITY_OVERHEAD);
+        assert_eq!(bytes[HDR_CTRL_OFFSET], 0x41);
+        assert_eq!(&bytes[BM_PACKET_HDR_SIZE..BM_PACKET_HDR_SIZE + BM_FRAME_COUNTER_SIZE], &[0x04, 0x03, 0x02, 0x01]);
+
+        let mut received = BmNetworkPacket::from(bytes.len(), &bytes).unwrap();
+        assert_eq!(received.get_payload_len(), 0);
+        assert_eq!(received.verify_hop(&TEST_KEY), Ok(0x01020304));
+        assert_eq!(received.verify_hop(&[0u8; 16]), Err(BmSecurityError::AuthFailed));
+
+        // Any header change invalidates the MIC until re-signed
+        received.increment_hop_count();
+        assert!(!received.is_authenticated());
+        assert_eq!(received.verify_hop(&TEST_KEY), Err(BmSecurityError::Missing));
+
+        // A frame with the flag set but no room for the trailer
+        let mut truncated = bytes.clone();
+        truncated.truncate(BM_PACKET_HDR_SIZE + 4);
+        assert_eq!(BmNetworkPacket::from(truncated.len(), &truncated), Err(BmPacketDecodeError::TooShort));
+    }
+
+    #[test]
+    fn test_compact_header_roundtrip() {
+        let mut payload: BmNetworkPacketPayload = Vec::new();
+        payload.extend_from_slice(&[0xDE, 0xAD, 0xBE, 0xEF]).unwrap();
+        let mut pkt = BmNetworkPacket::new(BmPacketTypes::DataPayload, Some(0x1234), Some(0x5678), Some(0x9ABC), 5, true, Some(payload.clone()))
+            .with_seq(0x0102)
+            .with_compact_header(true);
+
+        let bytes = pkt.to_bytes().unwrap();
+        assert_eq!(bytes.len(), BM_COMPACT_PACKET_HDR_SIZE + 4);
+        assert_eq!(&bytes[..BM_COMPACT_PACKET_HDR_SIZE], &[
+            0x42, 0x14, 0xBC, 0x9A, 0x34, 0x12, 0x78, 0x56, 0x34, 0x12, 0x45, 0x02, 0x01]);
+
+        let mut parsed = BmNetworkPacket::from(bytes.len(), &bytes).unwrap();
+        assert!(parsed.is_compact_header());
+        assert_eq!(parsed.get_destination(), Some(0x9ABC));
+        assert_eq!(parsed.get_source(), Some(0x1234));
+        assert_eq!(parsed.get_next_hop(), Some(0x5678));
+        assert_eq!(parsed.get_originator(), Some(0x1234));
+        assert_eq!(parsed.get_seq(), 0x0102);
+        assert_eq!(parsed.payload, Some(payload));
+    }
+
+    #[test]
+    fn test_compact_broadcast_drops_next_hop() {
+        let mut pkt = BmNetworkPacket::new(BmPacketTypes::RouteDiscoveryRequest, Some(1), Some(7), Some(3), 5, false, None)
+            .with_compact_header(true);
+
+        let bytes = pkt.to_bytes().unwrap();
+        assert_eq!(bytes.len(), BM_COMPACT_PACKET_HDR_SIZE - SHORT_ADDRESS_SIZE);
+
+        let mut parsed = BmNetworkPacket::from(bytes.len(), &bytes).unwrap();
+        assert_eq!(parsed.get_next_hop(), None);
+        assert_eq!(parsed.get_destination(), Some(3));
+
+        // Truncated compact frame
+        assert_eq!(BmNetworkPacket::from(bytes.len() - 1, &bytes), Err(BmPacketDecodeError::TooShort));
+    }
+
+    #[test]
+    fn test_link_ack_flag_roundtrip() {
+        let mut pkt = BmNetworkPacket::new(BmPacketTypes::DataPayload, Some(1), Some(2), Some(3), 5, false, None);
+        pkt.set_link_ack(true);
+
+        // Carried in the ctrl byte, next to the compact flag
+        let bytes = pkt.to_bytes().unwrap();
+        assert_eq!(bytes[0], 0x44);
+
+        let parsed = BmNetworkPacket::from(bytes.len(), &bytes).unwrap();
+        assert!(parsed.is_link_ack_requested());
+        pkt.set_link_ack(false);
+        let bytes = pkt.to_bytes().unwrap();
+        assert!(!BmNetworkPacket::from(bytes.len(), &bytes).unwrap().is_link_ack_requested());
+    }
+
+    #[test]
+    fn test_compact_header_falls_back_for_long_addresses() {
+        let mut pkt = BmNetworkPacket::new(BmPacketTypes::DataPayload, Some(0x10000), Some(2), Some(3), 5, false, None)
+            .with_compact_header(true);
+        assert!(!pkt.is_compact_header());
+        assert_eq!(pkt.to_bytes().unwrap().len(), BM_PACKET_HDR_SIZE);
+    }
+
+    #[test]
+    fn test_compact_header_signed_and_encrypted() {
+        let mut payload: BmNetworkPacketPayload = Vec::new();
+        payload.extend_from_slice(b"data").unwrap();
+        let mut pkt = BmNetworkPacket::new(BmPacketTypes::DataPayload, Some(1), Some(2), Some(3), 5, false, Some(payload))
+            .with_compact_header(true);
+        pkt.encrypt_payload(&TEST_KEY, 9).unwrap();
+        let bytes = pkt.to_bytes().unwrap();
+        let mut parsed = BmNetworkPacket::from(bytes.len(), &bytes).unwrap();
+        assert_eq!(parsed.decrypt_payload(&TEST_KEY), Ok(9));
+
+        let mut ack = BmNetworkPacket::new(BmPacketTypes::DataPayloadAck, Some(3), Some(2), Some(1), 5, false, None)
+            .with_compact_header(true);
+        ack.sign_hop(&TEST_KEY, 4).unwrap();
+        let bytes = ack.to_bytes().unwrap();
+        assert_eq!(bytes.len(), BM_COMPACT_PACKET_HDR_SIZE + BM_SECURITY_OVERHEAD);
+        let parsed = BmNetworkPacket::from(bytes.len(), &bytes).unwrap();
+        assert_eq!(parsed.verify_hop(&TEST_KEY), Ok(4));
     }
 }
@@ -0,0 +1,254 @@
+use super::super::{
+    NetworkId, BmNetworkKey,
+    bm_network_configs::*,
+    bm_network_security::{self, BmSecurityError},
+};
+use super::bm_network_packet::{
+    self, BmFrameLayout, BmNetworkHdrInfo, BmNetworkPacket, BmPacketDecodeError, BmPacketTypes,
+    MAX_TTL_HOP_CNT,
+};
+
+// Borrowed view of a received frame. Header fields are read in place from the receive
+// buffer, and relays rewrite source, next hop and hop count without copying the packet.
+pub struct BmPacketView<'a> {
+    buffer: &'a mut [u8],
+    // Frame length, shrinks when the hop MIC trailer is dropped
+    length: usize,
+    layout: BmFrameLayout,
+}
+
+impl<'a> BmPacketView<'a> {
+    // Constructor, validates the frame the same way BmNetworkPacket::from does
+    pub fn new(length: usize, buffer: &'a mut [u8]) -> Result<Self, BmPacketDecodeError> {
+        let layout = BmFrameLayout::parse(length, buffer)?;
+        Ok(BmPacketView { buffer, length, layout })
+    }
+
+    // Public accessor functions
+    pub fn get_packet_type(&self) -> BmPacketTypes {
+        self.layout.packet_type.clone()
+    }
+    pub fn get_source(&self) -> NetworkId {
+        self.layout.read_address(self.buffer, self.layout.src_offset())
+    }
+    pub fn get_next_hop(&self) -> NetworkId {
+        self.layout.next_hop_offset.and_then(|offset| self.layout.read_address(self.buffer, offset))
+    }
+    pub fn get_originator(&self) -> NetworkId {
+        self.layout.read_address(self.buffer, self.layout.orig_offset)
+    }
+    pub fn get_destination(&self) -> NetworkId {
+        self.layout.read_address(self.buffer, self.layout.dest_offset())
+    }
+    pub fn get_seq(&self) -> u16 {
+        self.layout.seq(self.buffer)
+    }
+    pub fn get_info(&self) -> BmNetworkHdrInfo {
+        self.layout.info(self.buffer)
+    }
+    pub fn get_hop_count(&self) -> u8 {
+        self.get_info().hop_count()
+    }
+    pub fn get_payload(&self) -> &[u8] {
+        &self.buffer[self.layout.hdr_size..self.layout.frame_end]
+    }
+    pub fn is_authenticated(&self) -> bool {
+        self.layout.ctrl.authenticated()
+    }
+    pub fn is_compact_header(&self) -> bool {
+        self.layout.ctrl.compact()
+    }
+    pub fn is_link_ack_requested(&self) -> bool {
+        self.layout.ctrl.link_ack()
+    }
+    // Frame bytes as they go on air
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer[..self.length]
+    }
+
+    // In place mutation for relays. Setters return false when the new id does not fit
+    // a compact header, the frame is left unchanged then.
+    pub fn set_source(&mut self, new_src: NetworkId) -> bool {
+        if !fits_header(&self.layout, new_src) {
+            return false
+        }
+        self.clear_hop_mic();
+        self.layout.write_address(self.buffer, self.layout.src_offset(), new_src)
+    }
+    pub fn set_next_hop(&mut self, new_next_hop: NetworkId) -> bool {
+        let Some(offset) = self.layout.next_hop_offset else {
+            return false
+        };
+        if !fits_header(&self.layout, new_next_hop) {
+            return false
+        }
+        self.clear_hop_mic();
+        self.layout.write_address(self.buffer, offset, new_next_hop)
+    }
+    pub fn increment_hop_count(&mut self) {
+        let info = self.get_info();
+        if info.hop_count() < MAX_TTL_HOP_CNT {
+            self.buffer[self.layout.info_offset] = info.with_hop_count(info.hop_count() + 1).into();
+        }
+        self.clear_hop_mic();
+    }
+
+    // Checks the hop MIC over the frame as received. Returns the transmitter's frame counter.
+    pub fn verify_hop(&self, key: &BmNetworkKey) -> Result<u32, BmSecurityError> {
+        let hop_mic = self.layout.hop_mic(self.buffer).ok_or(BmSecurityError::Missing)?;
+        bm_network_security::open(
+            key, self.get_source(), hop_mic.frame_counter, &self.buffer[..self.layout.frame_end], &mut [], &hop_mic.mic)?;
+        Ok(hop_mic.frame_counter)
+    }
+
+    // Checks the payload MIC without touching the frame, so it can be relayed as received.
+    // CCM only authenticates the plaintext, so it is decrypted into a scratch buffer.
+    pub fn verify_payload(&self, key: &BmNetworkKey) -> Result<u32, BmSecurityError> {
+        let secured = self.get_payload();
+        if !self.get_info().encrypted() || secured.len() < BM_SECURITY_OVERHEAD {
+            return Err(BmSecurityError::Malformed)
+        }
+        let frame_counter = bm_network_packet::read_u32_le(secured, 0);
+        let mic_offset = secured.len() - BM_MIC_SIZE;
+
+        let mut scratch = [0u8; BM_MAX_PAYLOAD_SIZE];
+        let plaintext = &mut scratch[..mic_offset - BM_FRAME_COUNTER_SIZE];
+        plaintext.copy_from_slice(&secured[BM_FRAME_COUNTER_SIZE..mic_offset]);
+
+        let aad = bm_network_packet::security_aad(
+            &self.layout.packet_type, self.get_destination(), self.get_originator(), self.get_seq(), self.get_info());
+        bm_network_security::open(key, self.get_originator(), frame_counter, &aad, plaintext, &secured[mic_offset..])?;
+        Ok(frame_counter)
+    }
+
+    // Copies the frame into owned storage, for packets delivered locally or queued
+    pub fn to_packet(&self) -> Result<BmNetworkPacket, BmPacketDecodeError> {
+        BmNetworkPacket::from(self.length, self.buffer)
+    }
+
+    // Copies the frame over an existing packet, e.g. a free outbound queue slot
+    pub fn copy_to(&self, packet: &mut BmNetworkPacket) -> Result<(), BmPacketDecodeError> {
+        packet.read_from(self.length, self.buffer)
+    }
+
+    //-----------------------------------------------------------
+    // Private functions
+    //-----------------------------------------------------------
+
+    // A modified header invalidates the hop MIC, drop the trailer
+    fn clear_hop_mic(&mut self) {
+        if self.layout.ctrl.authenticated() {
+            self.layout.ctrl.set_authenticated(false);
+            self.buffer[0] = self.layout.ctrl.into();
+            self.length = self.layout.frame_end;
+        }
+    }
+}
+
+// Compact headers only hold short addresses
+fn fits_header(layout: &BmFrameLayout, id: NetworkId) -> bool {
+    !layout.ctrl.compact() || bm_network_packet::short_address(id).is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::bm_network_packet::BmNetworkPacketPayload;
+
+    const TEST_KEY: BmNetworkKey = [0x2B; 16];
+
+    #[test]
+    fn test_view_reads_fields_in_place() {
+        let mut payload = BmNetworkPacketPayload::new();
+        payload.extend_from_slice(&[0xDE, 0xAD]).unwrap();
+        let mut pkt = BmNetworkPacket::new(BmPacketTypes::DataPayload, Some(10), Some(20), Some(30), 5, true, Some(payload))
+            .with_seq(7);
+        let mut bytes = pkt.to_bytes().unwrap();
+        let length = bytes.len();
+
+        let view = BmPacketView::new(length, &mut bytes).unwrap();
+        assert_eq!(view.get_packet_type(), BmPacketTypes::DataPayload);
+        assert_eq!(view.get_originator(), Some(10));
+        assert_eq!(view.get_source(), Some(10));
+        assert_eq!(view.get_next_hop(), Some(20));
+        assert_eq!(view.get_destination(), Some(30));
+        assert_eq!(view.get_seq(), 7);
+        assert!(view.get_info().required_ack());
+        assert_eq!(view.get_payload(), &[0xDE, 0xAD]);
+        assert_eq!(view.to_packet().unwrap(), BmNetworkPacket::from(length, view.as_bytes()).unwrap());
+    }
+
+    #[test]
+    fn test_view_relay_rewrites_header_in_place() {
+        let mut pkt = BmNetworkPacket::new(BmPacketTypes::RouteDiscoveryResponse, Some(3), Some(2), Some(1), 5, false, None);
+        pkt.sign_hop(&TEST_KEY, 1).unwrap();
+        let mut bytes = pkt.to_bytes().unwrap();
+        let length = bytes.len();
+
+        let mut view = BmPacketView::new(length, &mut bytes).unwrap();
+        assert_eq!(view.verify_hop(&TEST_KEY), Ok(1));
+        assert!(view.set_source(Some(2)));
+        assert!(view.set_next_hop(Some(1)));
+        view.increment_hop_count();
+
+        // Header changed, so the old trailer is gone
+        assert!(!view.is_authenticated());
+        assert_eq!(view.as_bytes().len(), length - BM_SECURITY_OVERHEAD);
+
+        let mut relayed = view.to_packet().unwrap();
+        assert_eq!(relayed.get_source(), Some(2));
+        assert_eq!(relayed.get_next_hop(), Some(1));
+        assert_eq!(relayed.get_originator(), Some(3));
+        assert_eq!(relayed.get_hop_count(), 1);
+    }
+
+    #[test]
+    fn test_view_compact_rejects_long_ids() {
+        let mut pkt = BmNetworkPacket::new(BmPacketTypes::RouteDiscoveryRequest, Some(1), None, Some(3), 5, false, None)
+            .with_compact_header(true);
+        let mut bytes = pkt.to_bytes().unwrap();
+        let length = bytes.len();
+
+        let mut view = BmPacketView::new(length, &mut bytes).unwrap();
+        assert!(!view.set_source(Some(0x10000)));
+        assert_eq!(view.get_source(), Some(1));
+        // Compact broadcasts have no next hop field
+        assert!(!view.set_next_hop(Some(2)));
+        assert!(view.set_source(Some(2)));
+        assert_eq!(view.get_source(), Some(2));
+    }
+
+    #[test]
+    fn test_view_verifies_payload_without_decrypting_frame() {
+        let mut payload = BmNetworkPacketPayload::new();
+        payload.extend_from_slice(b"secret").unwrap();
+        let mut pkt = BmNetworkPacket::new(BmPacketTypes::DataPayload, Some(1), Some(2), Some(3), 5, false, Some(payload));
+        pkt.encrypt_payload(&TEST_KEY, 42).unwrap();
+        let mut bytes = pkt.to_bytes().unwrap();
+        let original = bytes.clone();
+        let length = bytes.len();
+
+        let view = BmPacketView::new(length, &mut bytes).unwrap();
+        assert_eq!(view.verify_payload(&TEST_KEY), Ok(42));
+        assert_eq!(view.verify_payload(&[0u8; 16]), Err(BmSecurityError::AuthFailed));
+        assert_eq!(view.as_bytes(), original.as_slice());
+    }
+
+    #[test]
+    fn test_view_copies_into_existing_packet() {
+        let mut payload = BmNetworkPacketPayload::new();
+        payload.extend_from_slice(&[0xDE, 0xAD]).unwrap();
+        let mut pkt = BmNetworkPacket::new(BmPacketTypes::DataPayload, Some(10), Some(20), Some(30), 5, true, Some(payload))
+            .with_seq(7);
+        let mut bytes = pkt.to_bytes().unwrap();
+        let length = bytes.len();
+        let view = BmPacketView::new(length, &mut bytes).unwrap();
+
+        // A used slot is overwritten, metadata included
+        let mut slot = BmNetworkPacket::new(BmPacketTypes::TraceRequest, Some(1), None, Some(2), 3, false, None);
+        slot.tx_count = 2;
+        slot.prev_hop = Some(4);
+        view.copy_to(&mut slot).unwrap();
+        assert_eq!(slot, view.to_packet().unwrap());
+    }
+}
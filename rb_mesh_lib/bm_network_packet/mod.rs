@@ -1 +1,2 @@
-pub mod bm_network_packet;
\ No newline at end of file
+pub mod bm_network_packet;
+pub mod bm_packet_view;
@@ -0,0 +1,82 @@
+use super::{
+    bm_network_configs::*,
+    bm_network_packet::bm_network_packet::BmNetworkPacketPayload,
+    NetworkId,
+};
+
+// Payloads of the engine's own packet types, encoded little endian. MAX_SIZE is the
+// longest encoding of the type and is checked against BM_MAX_PAYLOAD_SIZE when the type
+// is built, so writing a payload cannot run out of room.
+pub trait BmPayloadCodec: Sized {
+    const MAX_SIZE: usize;
+    const FITS_PAYLOAD: () = assert!(Self::MAX_SIZE <= BM_MAX_PAYLOAD_SIZE);
+
+    // None when the payload is malformed
+    fn from_payload(payload: &[u8]) -> Option<Self>;
+
+    // Writes at most MAX_SIZE bytes
+    fn write_payload(&self, writer: &mut BmPayloadWriter);
+
+    fn to_payload(&self) -> BmNetworkPacketPayload {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::FITS_PAYLOAD;
+        let mut writer = BmPayloadWriter::default();
+        self.write_payload(&mut writer);
+        debug_assert!(writer.payload.len() <= Self::MAX_SIZE);
+        writer.payload
+    }
+}
+
+// Builds a payload of at most MAX_SIZE bytes for BmPayloadCodec::to_payload
+#[derive(Default)]
+pub struct BmPayloadWriter {
+    payload: BmNetworkPacketPayload,
+}
+
+impl BmPayloadWriter {
+    pub fn put(&mut self, bytes: &[u8]) {
+        // Cannot fail, MAX_SIZE of every codec fits BM_MAX_PAYLOAD_SIZE
+        let _ = self.payload.extend_from_slice(bytes);
+    }
+
+    pub fn put_u8(&mut self, value: u8) {
+        self.put(&[value]);
+    }
+
+    // Unknown ids are sent as 0
+    pub fn put_id(&mut self, id: NetworkId) {
+        self.put(&id.unwrap_or(0).to_le_bytes());
+    }
+}
+
+// Encodes value, checks the size of the encoding and that it decodes back to value, and
+// that the payload one byte short or one byte long is rejected
+#[cfg(test)]
+pub fn assert_round_trip<T: BmPayloadCodec + PartialEq + core::fmt::Debug>(value: &T, size: usize) {
+    let payload = value.to_payload();
+    assert_eq!(payload.len(), size);
+    assert!(size <= T::MAX_SIZE);
+    assert_eq!(T::from_payload(&payload).as_ref(), Some(value));
+
+    if size > 0 {
+        assert_eq!(T::from_payload(&payload[..size - 1]), None);
+    }
+    let mut long = payload.clone();
+    long.push(0).unwrap();
+    assert_eq!(T::from_payload(&long), None);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_writer_encodes_little_endian() {
+        let mut writer = BmPayloadWriter::default();
+        writer.put_id(Some(0x1234_5678));
+        writer.put_id(None);
+        writer.put_u8(7);
+        writer.put(&0xABCDu16.to_le_bytes());
+        assert_eq!(&writer.payload[..], &[0x78, 0x56, 0x34, 0x12, 0, 0, 0, 0, 7, 0xCD, 0xAB]);
+    }
+}
@@ -0,0 +1,5 @@
+// Source of random numbers for the engine. Firmware backs it with the radio's hardware RNG,
+// host tests with a fixed sequence.
+pub trait BmRng {
+    fn next_u32(&mut self) -> u32;
+}
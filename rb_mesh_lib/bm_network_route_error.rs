@@ -0,0 +1,43 @@
+use super::{
+    bm_network_payload::{BmPayloadCodec, BmPayloadWriter},
+    NetworkId,
+};
+
+// RouteDiscoveryError payload: unreachable destination id
+const ROUTE_ERROR_SIZE: usize = 4;
+
+// Payload of a RouteDiscoveryError. Sent by a relay that has no route for a packet,
+// back to the originator of that packet.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct BmRouteError {
+    // Destination the relay could not reach
+    pub unreachable: NetworkId,
+}
+
+impl BmPayloadCodec for BmRouteError {
+    const MAX_SIZE: usize = ROUTE_ERROR_SIZE;
+
+    fn from_payload(payload: &[u8]) -> Option<BmRouteError> {
+        if payload.len() != ROUTE_ERROR_SIZE {
+            return None
+        }
+        Some(BmRouteError {
+            unreachable: Some(u32::from_le_bytes(payload.try_into().ok()?)),
+        })
+    }
+
+    fn write_payload(&self, writer: &mut BmPayloadWriter) {
+        writer.put_id(self.unreachable);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::bm_network_payload::assert_round_trip;
+
+    #[test]
+    fn test_route_error_round_trip() {
+        assert_round_trip(&BmRouteError { unreachable: Some(0x1234_5678) }, ROUTE_ERROR_SIZE);
+    }
+}
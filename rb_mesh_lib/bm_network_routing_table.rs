@@ -2,7 +2,7 @@ use heapless::Vec; // fixed capacity `std::Vec`
 use super::{
     bm_network_configs::*,
     NetworkId, TimeType, RssiType,
-    bm_network_node::bm_network_node::BmNodeEntry,
+    bm_network_node::bm_network_node::{BmNodeEntry, BmRoute},
 };
 use core::option::Option::{self, Some, None};
 
@@ -23,7 +23,7 @@ impl BmNetworkRoutingTable {
         }
     }
 
-    pub fn get_local_network_id(&mut self) -> NetworkId {
+    pub fn get_local_network_id(&self) -> NetworkId {
         self.network_id
     }
 
@@ -49,8 +49,27 @@ impl BmNetworkRoutingTable {
             
             defmt::info!("rb_stack: node node={}", defmt::Display2Format(&new_node_entry));
 
-            self.add_node( new_node_entry );
+            if !self.add_node(new_node_entry) {
+                defmt::warn!("rb_stack: node table full");
+            }
+        }
+    }
+
+    // Adds or updates a route advertised with the destination's sequence number. Returns
+    // false when the sequence number makes the route stale or a loop risk.
+    pub fn update_node_route_seq(&mut self, orig_id: NetworkId, next_hop: NetworkId, distance: u8, seq: u16, millis: TimeType, rssi: RssiType) -> bool {
+        if let Some(node_entry) = self.find_node_by_id(orig_id) {
+            if !node_entry.accept_seq(next_hop, distance, seq) {
+                return false
+            }
+        }
+        self.update_node_route(orig_id, next_hop, distance, millis, rssi);
+
+        // New nodes take the sequence number once they exist
+        if let Some(node_entry) = self.find_node_by_id(orig_id) {
+            node_entry.accept_seq(next_hop, distance, seq);
         }
+        true
     }
 
     pub fn set_node_error(&mut self, dest_id: NetworkId, millis: TimeType) {
@@ -63,6 +82,34 @@ impl BmNetworkRoutingTable {
         }
     }
 
+    // Counts a failure against the route to dest_id through next_hop only
+    pub fn set_route_failure(&mut self, dest_id: NetworkId, next_hop: NetworkId) {
+        if let Some(node_entry) = self.find_node_by_id(dest_id) {
+            node_entry.record_failure(next_hop);
+        }
+    }
+
+    // Checks a frame counter against the replay window of the node that used it.
+    // Nodes we have not heard from yet are added without a route. When they do not fit
+    // the table their counters cannot be tracked and are refused.
+    pub fn check_frame_counter(&mut self, net_id: NetworkId, frame_counter: u32) -> bool {
+        if self.find_node_by_id(net_id).is_none() && !self.add_node(BmNodeEntry::new(net_id)) {
+            defmt::warn!("rb_stack: node table full");
+            return false
+        }
+
+        match self.find_node_by_id(net_id) {
+            Some(node_entry) => node_entry.check_frame_counter(frame_counter),
+            None => false,
+        }
+    }
+
+    // Checks a frame counter without recording it. Counters of nodes we have not heard
+    // from yet are fresh.
+    pub fn is_frame_counter_fresh(&mut self, net_id: NetworkId, frame_counter: u32) -> bool {
+        self.find_node_by_id(net_id).is_none_or(|node_entry| node_entry.is_frame_counter_fresh(frame_counter))
+    }
+
     pub fn get_next_hop(&mut self, dest_id: NetworkId) -> NetworkId {
         // Search through node list for dest node
         if let Some(node_entry) = self.find_node_by_id(dest_id) {
@@ -75,8 +122,45 @@ impl BmNetworkRoutingTable {
         None
     }
 
-    pub fn add_node(&mut self, new_node: BmNodeEntry) {
-        self.nodes.push(new_node).unwrap();
+    // Next hop of the best other route to dest_id, avoiding the excluded nodes
+    pub fn get_alternate_next_hop(&mut self, dest_id: NetworkId, excluded: &[NetworkId]) -> NetworkId {
+        self.find_node_by_id(dest_id)?.get_next_hop_excluding(excluded)
+    }
+
+    // Drops the route to dest_id through next_hop, other routes to dest_id are kept
+    pub fn remove_route(&mut self, dest_id: NetworkId, next_hop: NetworkId) -> bool {
+        self.find_node_by_id(dest_id).is_some_and(|node_entry| node_entry.remove_route(next_hop))
+    }
+
+    // True when we heard net_id directly
+    pub fn is_neighbor(&mut self, net_id: NetworkId) -> bool {
+        self.find_node_by_id(net_id).is_some_and(|node_entry| node_entry.is_neighbor())
+    }
+
+    // Hops after the first one on the best route to dest_id
+    pub fn get_distance(&mut self, dest_id: NetworkId) -> Option<u8> {
+        let route = self.get_best_route(dest_id)?;
+        Some(route.get_distance())
+    }
+
+    pub fn get_best_route(&mut self, dest_id: NetworkId) -> Option<BmRoute> {
+        self.find_node_by_id(dest_id)?.get_best_route()
+    }
+
+    // Adds a node. A full table makes room by dropping a node without routes. Returns
+    // false when there is none, the new node is not added then.
+    pub fn add_node(&mut self, new_node: BmNodeEntry) -> bool {
+        if self.nodes.is_full() {
+            if let Some(index) = self.nodes.iter().position(|node| !node.has_routes()) {
+                self.nodes.remove(index);
+            }
+        }
+        self.nodes.push(new_node).is_ok()
+    }
+
+    // Drops the node at index, e.g. once all its routes expired
+    pub fn remove_node_by_idx(&mut self, index: usize) -> Option<BmNodeEntry> {
+        (index < self.nodes.len()).then(|| self.nodes.remove(index))
     }
 
     pub fn get_num_nodes(&mut self) -> usize {
@@ -117,7 +201,7 @@ mod tests {
 
         // Add a node entry manually
         let new_node = BmNodeEntry::new(node_id);
-        table.add_node(new_node);
+        assert!(table.add_node(new_node));
 
         // Verify count and lookup
         assert_eq!(table.get_num_nodes(), 1);
@@ -155,8 +239,8 @@ mod tests {
     fn test_get_node_by_idx() {
         let mut table = BmNetworkRoutingTable::new(Some(1));
 
-        table.add_node(BmNodeEntry::new(Some(100)));
-        table.add_node(BmNodeEntry::new(Some(200)));
+        assert!(table.add_node(BmNodeEntry::new(Some(100))));
+        assert!(table.add_node(BmNodeEntry::new(Some(200))));
 
         assert_eq!(table.get_num_nodes(), 2);
 
@@ -168,6 +252,24 @@ mod tests {
         assert!(table.get_node_by_idx(2).is_none());
     }
 
+    #[test]
+    fn test_full_table_refuses_nodes() {
+        let mut table = BmNetworkRoutingTable::new(Some(1));
+        for id in 0..BM_MAX_NET_DEVICES as u32 + 20 {
+            table.update_node_route(Some(1000 + id), Some(2), 1, 0, -60);
+        }
+        assert_eq!(table.get_num_nodes(), BM_MAX_NET_DEVICES);
+        assert_eq!(table.get_next_hop(Some(1000)), Some(2));
+        assert_eq!(table.get_next_hop(Some(1000 + BM_MAX_NET_DEVICES as u32)), None);
+        assert!(!table.check_frame_counter(Some(5), 1));
+
+        // A node without routes makes room
+        assert!(table.remove_route(Some(1000), Some(2)));
+        assert!(table.check_frame_counter(Some(5), 1));
+        assert!(table.find_node_by_id(Some(1000)).is_none());
+        assert_eq!(table.get_num_nodes(), BM_MAX_NET_DEVICES);
+    }
+
     #[test]
     fn test_set_node_error() {
         let mut table = BmNetworkRoutingTable::new(Some(1));
@@ -182,4 +284,96 @@ mod tests {
         // Setting error on non-existent node (should log defmt error and handle gracefully)
         table.set_node_error(Some(999), 2000);
     }
+
+    #[test]
+    fn test_set_route_failure() {
+        let mut table = BmNetworkRoutingTable::new(Some(1));
+        table.update_node_route(Some(9), Some(2), 1, 1000, -60);
+        table.update_node_route(Some(9), Some(3), 1, 1000, -60);
+        let primary = table.get_next_hop(Some(9));
+        let other = if primary == Some(2) { Some(3) } else { Some(2) };
+
+        // Only the failed route counts it, and it is not refreshed
+        table.set_route_failure(Some(9), primary);
+        let node = table.find_node_by_id(Some(9)).unwrap();
+        let failed = node.get_route_via(primary).unwrap();
+        assert_eq!(failed.get_failures(), 1);
+        assert_eq!(failed.get_timestamp_millis(), 1000);
+        assert_eq!(node.get_route_via(other).unwrap().get_failures(), 0);
+
+        // The other route takes over
+        assert_eq!(table.get_next_hop(Some(9)), other);
+    }
+
+    #[test]
+    fn test_remove_route() {
+        let mut table = BmNetworkRoutingTable::new(Some(1));
+        table.update_node_route(Some(9), Some(2), 1, 1000, -60);
+        table.update_node_route(Some(9), Some(3), 2, 1000, -80);
+        assert_eq!(table.get_next_hop(Some(9)), Some(2));
+
+        // The other route takes over
+        assert!(table.remove_route(Some(9), Some(2)));
+        assert_eq!(table.get_next_hop(Some(9)), Some(3));
+        assert!(!table.remove_route(Some(9), Some(2)));
+
+        assert!(table.remove_route(Some(9), Some(3)));
+        assert_eq!(table.get_next_hop(Some(9)), None);
+        assert!(!table.remove_route(Some(99), Some(3)));
+    }
+
+    #[test]
+    fn test_get_alternate_next_hop() {
+        let mut table = BmNetworkRoutingTable::new(Some(1));
+        table.update_node_route(Some(9), Some(2), 1, 1000, -60);
+        table.update_node_route(Some(9), Some(3), 2, 1000, -80);
+        table.update_node_route(Some(9), Some(4), 2, 1000, -70);
+
+        assert_eq!(table.get_alternate_next_hop(Some(9), &[Some(2)]), Some(4));
+        assert_eq!(table.get_alternate_next_hop(Some(9), &[Some(2), Some(4)]), Some(3));
+        assert_eq!(table.get_alternate_next_hop(Some(9), &[Some(2), Some(3), Some(4)]), None);
+        assert_eq!(table.get_alternate_next_hop(Some(99), &[]), None);
+    }
+
+    #[test]
+    fn test_update_node_route_seq() {
+        let mut table = BmNetworkRoutingTable::new(Some(1));
+        assert!(table.update_node_route_seq(Some(9), Some(2), 2, 10, 1000, -60));
+        assert_eq!(table.find_node_by_id(Some(9)).unwrap().get_dest_seq(), Some(10));
+
+        // Same number: longer routes are refused, shorter ones join the list
+        assert!(!table.update_node_route_seq(Some(9), Some(3), 3, 10, 1000, -60));
+        assert!(table.update_node_route_seq(Some(9), Some(4), 1, 10, 1000, -60));
+        assert_eq!(table.get_next_hop(Some(9)), Some(4));
+        assert!(table.find_node_by_id(Some(9)).unwrap().get_route_via(Some(2)).is_some());
+
+        // Older numbers are refused
+        assert!(!table.update_node_route_seq(Some(9), Some(3), 0, 9, 1000, -60));
+
+        // A newer number keeps only the route it came with
+        assert!(table.update_node_route_seq(Some(9), Some(3), 4, 11, 2000, -60));
+        assert_eq!(table.get_next_hop(Some(9)), Some(3));
+        assert!(table.find_node_by_id(Some(9)).unwrap().get_route_via(Some(2)).is_none());
+
+        // Sequence numbers wrap
+        assert!(table.update_node_route_seq(Some(8), Some(2), 1, 0xFFFF, 1000, -60));
+        assert!(table.update_node_route_seq(Some(8), Some(2), 1, 1, 1000, -60));
+        assert!(!table.update_node_route_seq(Some(8), Some(2), 1, 0xFFFE, 1000, -60));
+    }
+
+    #[test]
+    fn test_check_frame_counter_per_node() {
+        let mut table = BmNetworkRoutingTable::new(Some(1));
+
+        // Unknown node is added, but has no route yet
+        assert!(table.check_frame_counter(Some(2), 5));
+        assert_eq!(table.get_num_nodes(), 1);
+        assert_eq!(table.get_next_hop(Some(2)), None);
+
+        assert!(!table.check_frame_counter(Some(2), 5));
+        assert!(table.check_frame_counter(Some(2), 6));
+
+        // Counters are tracked per node
+        assert!(table.check_frame_counter(Some(3), 5));
+    }
 }
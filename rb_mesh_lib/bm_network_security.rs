@@ -0,0 +1,215 @@
+use aes::Aes128;
+use ccm::{
+    aead::{generic_array::GenericArray, AeadInPlace, KeyInit},
+    consts::U8,
+    Ccm,
+};
+use super::{
+    bm_network_configs::*,
+    BmNetworkKey, NetworkId,
+};
+
+// AES-128-CCM with an 8 byte MIC and an 8 byte nonce (originator id + frame counter)
+type BmCcm = Ccm<Aes128, U8, U8>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BmSecurityError {
+    // Plaintext plus security overhead does not fit in a payload
+    PayloadTooLarge,
+    // Secured payload is too short to hold frame counter and MIC
+    Malformed,
+    // MIC did not match, frame was forged or corrupted
+    AuthFailed,
+    // Frame carries no MIC at all
+    Missing,
+    // Frame counter was already used or is too old
+    Replayed,
+}
+
+impl defmt::Format for BmSecurityError {
+    fn format(&self, fmt: defmt::Formatter) {
+        match self {
+            BmSecurityError::PayloadTooLarge => defmt::write!(fmt, "PayloadTooLarge"),
+            BmSecurityError::Malformed => defmt::write!(fmt, "Malformed"),
+            BmSecurityError::AuthFailed => defmt::write!(fmt, "AuthFailed"),
+            BmSecurityError::Missing => defmt::write!(fmt, "Missing"),
+            BmSecurityError::Replayed => defmt::write!(fmt, "Replayed"),
+        }
+    }
+}
+
+// Number of counters below the highest one that are still accepted out of order
+const REPLAY_WINDOW_SIZE: u32 = u32::BITS;
+
+// Persistence hook for the local frame counter. Implementations write to flash or
+// backup registers so counters, and therefore nonces, are never reused after a reboot.
+pub trait BmFrameCounterStore {
+    // Returns the last stored value, None if nothing was ever stored
+    fn load(&mut self) -> Option<u32>;
+    // Stores the highest frame counter that may have been used
+    fn store(&mut self, frame_counter: u32);
+}
+
+// Sliding window of frame counters accepted from one node
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct BmReplayWindow {
+    // Highest frame counter accepted so far
+    highest: Option<u32>,
+    // Bit n is set when counter (highest - n) has been accepted
+    seen_mask: u32,
+}
+
+impl BmReplayWindow {
+    // Same as check_and_update, but leaves the window as it is
+    pub fn check(&self, frame_counter: u32) -> bool {
+        match self.highest {
+            Some(highest) if frame_counter <= highest => {
+                let age = highest - frame_counter;
+                age < REPLAY_WINDOW_SIZE && self.seen_mask & (1 << age) == 0
+            }
+            _ => true,
+        }
+    }
+
+    // Accepts counters above the highest seen, or unseen ones still inside the window.
+    // Returns false for replays, the window is only updated on success.
+    pub fn check_and_update(&mut self, frame_counter: u32) -> bool {
+        if !self.check(frame_counter) {
+            return false
+        }
+        let Some(highest) = self.highest else {
+            self.highest = Some(frame_counter);
+            self.seen_mask = 1;
+            return true
+        };
+
+        if frame_counter > highest {
+            let shift = frame_counter - highest;
+            self.seen_mask = if shift >= REPLAY_WINDOW_SIZE { 0 } else { self.seen_mask << shift };
+            self.seen_mask |= 1;
+            self.highest = Some(frame_counter);
+            return true
+        }
+
+        self.seen_mask |= 1 << (highest - frame_counter);
+        true
+    }
+}
+
+// Encrypts buffer in place and returns the MIC over buffer and aad.
+pub fn seal(key: &BmNetworkKey, orig: NetworkId, frame_counter: u32, aad: &[u8], buffer: &mut [u8]) -> Result<[u8; BM_MIC_SIZE], BmSecurityError> {
+    let cipher = BmCcm::new(GenericArray::from_slice(key));
+    let nonce = build_nonce(orig, frame_counter);
+
+    let tag = cipher
+        .encrypt_in_place_detached(GenericArray::from_slice(&nonce), aad, buffer)
+        .map_err(|_| BmSecurityError::PayloadTooLarge)?;
+
+    let mut mic = [0u8; BM_MIC_SIZE];
+    mic.copy_from_slice(&tag);
+    Ok(mic)
+}
+
+// Checks the MIC and decrypts buffer in place. Buffer is wiped on failure.
+pub fn open(key: &BmNetworkKey, orig: NetworkId, frame_counter: u32, aad: &[u8], buffer: &mut [u8], mic: &[u8]) -> Result<(), BmSecurityError> {
+    if mic.len() != BM_MIC_SIZE {
+        return Err(BmSecurityError::Malformed)
+    }
+
+    let cipher = BmCcm::new(GenericArray::from_slice(key));
+    let nonce = build_nonce(orig, frame_counter);
+
+    cipher
+        .decrypt_in_place_detached(GenericArray::from_slice(&nonce), aad, buffer, GenericArray::from_slice(mic))
+        .map_err(|_| BmSecurityError::AuthFailed)
+}
+
+//-----------------------------------------------------------
+// Private functions
+//-----------------------------------------------------------
+
+// Nonce must never repeat for a key, so it is built from the originator id and
+// that originator's frame counter.
+fn build_nonce(orig: NetworkId, frame_counter: u32) -> [u8; 8] {
+    let mut nonce = [0u8; 8];
+    nonce[0..4].copy_from_slice(&orig.unwrap_or(0).to_le_bytes());
+    nonce[4..8].copy_from_slice(&frame_counter.to_le_bytes());
+    nonce
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_KEY: BmNetworkKey = [0x2B; 16];
+
+    #[test]
+    fn test_seal_and_open_roundtrip() {
+        let aad = [1, 2, 3];
+        let mut buffer = *b"hello mesh";
+
+        let mic = seal(&TEST_KEY, Some(7), 1, &aad, &mut buffer).unwrap();
+        assert_ne!(&buffer, b"hello mesh");
+
+        open(&TEST_KEY, Some(7), 1, &aad, &mut buffer, &mic).unwrap();
+        assert_eq!(&buffer, b"hello mesh");
+    }
+
+    #[test]
+    fn test_open_rejects_wrong_context() {
+        let aad = [1, 2, 3];
+        let mut sealed = *b"hello mesh";
+        let mic = seal(&TEST_KEY, Some(7), 1, &aad, &mut sealed).unwrap();
+
+        // Different associated data
+        let mut buffer = sealed;
+        assert_eq!(open(&TEST_KEY, Some(7), 1, &[1, 2, 4], &mut buffer, &mic), Err(BmSecurityError::AuthFailed));
+        // Failed opens never leave unauthenticated plaintext behind
+        assert_eq!(buffer, [0u8; 10]);
+
+        // Different frame counter or originator, i.e. different nonce
+        let mut buffer = sealed;
+        assert_eq!(open(&TEST_KEY, Some(7), 2, &aad, &mut buffer, &mic), Err(BmSecurityError::AuthFailed));
+        let mut buffer = sealed;
+        assert_eq!(open(&TEST_KEY, Some(8), 1, &aad, &mut buffer, &mic), Err(BmSecurityError::AuthFailed));
+
+        // Different key
+        let mut buffer = sealed;
+        assert_eq!(open(&[0u8; 16], Some(7), 1, &aad, &mut buffer, &mic), Err(BmSecurityError::AuthFailed));
+    }
+
+    #[test]
+    fn test_replay_window_rejects_duplicates_and_old_counters() {
+        let mut window = BmReplayWindow::default();
+
+        assert!(window.check_and_update(100));
+        assert!(!window.check_and_update(100));
+        assert!(window.check_and_update(101));
+
+        // Out of order but inside the window, accepted once
+        assert!(window.check_and_update(99));
+        assert!(!window.check_and_update(99));
+
+        // Jump forward, counters that fell out of the window are rejected
+        assert!(window.check_and_update(200));
+        assert!(!window.check_and_update(101));
+        assert!(!window.check_and_update(200 - REPLAY_WINDOW_SIZE));
+        assert!(window.check_and_update(200 - REPLAY_WINDOW_SIZE + 1));
+    }
+
+    #[test]
+    fn test_replay_window_check_leaves_window() {
+        let mut window = BmReplayWindow::default();
+        assert!(window.check(100));
+        assert!(window.check_and_update(100));
+        assert!(!window.check(100));
+
+        // Checked twice, still accepted once
+        assert!(window.check(99));
+        assert!(window.check(99));
+        assert!(window.check_and_update(99));
+        assert!(!window.check(99));
+        assert!(!window.check(100 - REPLAY_WINDOW_SIZE));
+        assert!(window.check(101));
+    }
+}
@@ -0,0 +1,81 @@
+use heapless::Deque; // fixed capacity ring buffer
+use super::{
+    bm_network_configs::*,
+    NetworkId,
+};
+
+// Bounded cache of (originator, sequence number) pairs the node has already handled.
+// Oldest entries fall out once the cache is full.
+#[derive(Default, Debug, Clone)]
+pub struct BmSeenCache {
+    entries: Deque<(NetworkId, u16), BM_SEEN_CACHE_SIZE>,
+}
+
+impl BmSeenCache {
+    pub fn new() -> Self {
+        BmSeenCache {
+            entries: Deque::new(),
+        }
+    }
+
+    pub fn contains(&self, orig: NetworkId, seq: u16) -> bool {
+        self.entries.iter().any(|&entry| entry == (orig, seq))
+    }
+
+    // Records the pair, returns false if it was already in the cache.
+    pub fn insert(&mut self, orig: NetworkId, seq: u16) -> bool {
+        if self.contains(orig, seq) {
+            return false
+        }
+
+        // Drop oldest entry to make room
+        if self.entries.is_full() {
+            self.entries.pop_front();
+        }
+        // Cannot fail, room was made above
+        let _ = self.entries.push_back((orig, seq));
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_rejects_duplicates() {
+        let mut cache = BmSeenCache::new();
+
+        assert!(cache.insert(Some(1), 10));
+        assert!(!cache.insert(Some(1), 10));
+
+        // Same seq from a different originator is a different flood
+        assert!(cache.insert(Some(2), 10));
+        assert!(cache.insert(Some(1), 11));
+        assert_eq!(cache.len(), 3);
+    }
+
+    #[test]
+    fn test_oldest_entry_evicted_when_full() {
+        let mut cache = BmSeenCache::new();
+
+        for seq in 0..BM_SEEN_CACHE_SIZE as u16 {
+            assert!(cache.insert(Some(1), seq));
+        }
+        assert_eq!(cache.len(), BM_SEEN_CACHE_SIZE);
+
+        // One more pushes out seq 0
+        assert!(cache.insert(Some(1), BM_SEEN_CACHE_SIZE as u16));
+        assert_eq!(cache.len(), BM_SEEN_CACHE_SIZE);
+        assert!(!cache.contains(Some(1), 0));
+        assert!(cache.contains(Some(1), 1));
+    }
+}
@@ -0,0 +1,119 @@
+use heapless::Vec; // fixed capacity `std::Vec`
+use core::fmt;
+use super::{
+    bm_network_configs::*,
+    bm_network_payload::{BmPayloadCodec, BmPayloadWriter},
+    NetworkId, RssiType,
+};
+
+// Network id + rssi of one hop record
+const TRACE_HOP_SIZE: usize = 6;
+
+// One node a trace request passed through
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct BmTraceHop {
+    pub id: NetworkId,
+    // Rssi the request was received with at this node
+    pub rssi: RssiType,
+}
+
+// Hop records carried in the payload of TraceRequest and TraceReply packets,
+// in the order the request went through them
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct BmTracePath {
+    hops: Vec<BmTraceHop, BM_MAX_TRACE_HOPS>,
+}
+
+impl BmTracePath {
+    pub fn new() -> Self {
+        BmTracePath { hops: Vec::new() }
+    }
+
+    // Appends a hop, false when the path is full
+    pub fn push(&mut self, id: NetworkId, rssi: RssiType) -> bool {
+        self.hops.push(BmTraceHop { id, rssi }).is_ok()
+    }
+
+    pub fn get_hops(&self) -> &[BmTraceHop] {
+        &self.hops
+    }
+}
+
+impl BmPayloadCodec for BmTracePath {
+    const MAX_SIZE: usize = BM_MAX_TRACE_HOPS * TRACE_HOP_SIZE;
+
+    // Empty payloads are an empty path
+    fn from_payload(payload: &[u8]) -> Option<BmTracePath> {
+        if !payload.len().is_multiple_of(TRACE_HOP_SIZE) {
+            return None
+        }
+        let mut path = BmTracePath::new();
+        for record in payload.chunks_exact(TRACE_HOP_SIZE) {
+            let id = u32::from_le_bytes([record[0], record[1], record[2], record[3]]);
+            let rssi = RssiType::from_le_bytes([record[4], record[5]]);
+            if !path.push(Some(id), rssi) {
+                return None
+            }
+        }
+        Some(path)
+    }
+
+    fn write_payload(&self, writer: &mut BmPayloadWriter) {
+        for hop in self.hops.iter() {
+            writer.put_id(hop.id);
+            writer.put(&hop.rssi.to_le_bytes());
+        }
+    }
+}
+
+// Path to a destination as reported back to the node that sent the trace request.
+// The last hop is the destination itself.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct BmTraceResult {
+    pub dest: NetworkId,
+    pub path: BmTracePath,
+}
+
+impl fmt::Display for BmTraceResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{}", self.dest.unwrap_or(0), self.path.get_hops().len())?;
+        for hop in self.path.get_hops() {
+            write!(f, ",{}:{}", hop.id.unwrap_or(0), hop.rssi)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::bm_network_payload::assert_round_trip;
+
+    #[test]
+    fn test_trace_path_round_trip() {
+        let mut path = BmTracePath::new();
+        assert!(path.push(Some(2), -60));
+        assert!(path.push(Some(0x12345678), -101));
+        assert_round_trip(&path, 2 * TRACE_HOP_SIZE);
+
+        // Empty payloads are an empty path
+        assert_round_trip(&BmTracePath::new(), 0);
+
+        let result = BmTraceResult { dest: Some(0x12345678), path };
+        assert_eq!(format!("{}", result), "305419896,2,2:-60,305419896:-101");
+    }
+
+    #[test]
+    fn test_trace_path_full() {
+        let mut path = BmTracePath::new();
+        for id in 0..BM_MAX_TRACE_HOPS as u32 {
+            assert!(path.push(Some(id), -50));
+        }
+        assert!(!path.push(Some(99), -50));
+
+        // Longer paths than we can hold are not accepted from the air either
+        let mut payload = path.to_payload();
+        payload.extend_from_slice(&[0; TRACE_HOP_SIZE]).unwrap();
+        assert_eq!(BmTracePath::from_payload(&payload), None);
+    }
+}
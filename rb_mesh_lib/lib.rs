@@ -9,19 +9,39 @@ pub type RssiType = i16;
 // Date Time timestamp
 pub type TimeType = i64;
 
+// AES-128 network key shared by all nodes of a mesh
+pub type BmNetworkKey = [u8; 16];
+
 #[derive(Default, PartialEq, Debug)]
 pub enum BmError {
     #[default]
     None,
     Busy,
     QueueFull,
+    PayloadTooLarge,
+    UnknownTransfer,
+    // Outcomes of finished transfers are waiting to be read with get_transfer_record
+    RecordsFull,
 }
 
+pub mod bm_network_airtime;
 pub mod bm_network_configs;
+pub mod bm_network_discovery;
+pub mod bm_network_echo;
 pub mod bm_network_engine;
+pub mod bm_network_event;
+pub mod bm_network_fragment;
+pub mod bm_network_link;
 pub mod bm_network_routing_table;
+pub mod bm_network_neighbor;
 pub mod bm_network_node;
 pub mod bm_network_packet;
+pub mod bm_network_payload;
+pub mod bm_network_rng;
+pub mod bm_network_route_error;
+pub mod bm_network_security;
+pub mod bm_network_seen_cache;
+pub mod bm_network_trace;
 
 // Include stubs whenever building for host OS (Linux/WSL) so integration tests link cleanly:
 #[cfg(not(target_os = "none"))]
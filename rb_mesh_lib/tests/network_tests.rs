@@ -1,9 +1,10 @@
 use bm_network::{
-    bm_network_engine::{BmEngineStatus, BmNetworkEngine},
+    bm_network_configs::BM_DEFAULT_PORT,
+    bm_network_engine::{BmEngineStatus, BmNetworkEngine, BmTransferState},
     bm_network_packet::bm_network_packet::{
-        BmNetworkPacketPayload, BmPacketTypes,
+        BmNetworkOtaPacket, BmNetworkPacket, BmNetworkPacketPayload, BmPacketTypes,
     },
-    BmError,
+    NetworkId,
 };
 use std::println;
 
@@ -12,6 +13,13 @@ fn _defmt_timestamp() -> u64 {
     0
 }
 
+// Build the OTA bytes of a packet sent directly from `orig` to `dest` with TTL 5.
+fn build_frame(packet_type: BmPacketTypes, orig: NetworkId, dest: NetworkId) -> BmNetworkOtaPacket {
+    BmNetworkPacket::new(packet_type, orig, orig, dest, 5, false, None)
+        .to_bytes()
+        .expect("Serialization failed")
+}
+
 /// # Single-Node Route Discovery & Packet Completion Cycle
 ///
 /// **Scenario:** End-to-end state machine transitions and packet queue updates for a single node 
@@ -39,15 +47,17 @@ fn test_route_discovery_and_packet_completion_flow() {
     // Step 1: Initiate Transfer to unknown route (Node 2)
     // ------------------------------------------------------------------------
     println!("\n--- Step 1: Initiating transfer to Node 2 (No route exists) ---");
-    let err = engine.initiate_packet_transfer(node2_id, true, 5, payload);
-    assert_eq!(err, BmError::None);
+    let transfer_id = engine
+        .initiate_packet_transfer(node2_id, BM_DEFAULT_PORT, true, 5, payload)
+        .expect("Expected transfer to start");
 
     let status = engine.run_engine(0);
     println!("[STATE] Engine Status: {:?}", status);
     assert_eq!(status, BmEngineStatus::PerformingNetworkDiscovery);
+    assert_eq!(engine.get_transfer_state(transfer_id), Some(BmTransferState::Discovering));
 
     // Verify a RouteDiscoveryRequest is ready to transmit
-    let discovery_pkt = engine.get_next_outbound_packet().expect("Expected outbound discovery packet");
+    let discovery_pkt = engine.get_next_outbound_packet(0).expect("Expected outbound discovery packet");
     
     // Evaluate dest before referencing packet_type immutably
     let dest = discovery_pkt.get_destination();
@@ -72,15 +82,9 @@ fn test_route_discovery_and_packet_completion_flow() {
     // ------------------------------------------------------------------------
     println!("\n--- Step 3: Simulating Rx RouteDiscoveryResponse from Node 2 ---");
     
-    let mut disc_resp_bytes = [0u8; 18];
-    disc_resp_bytes[0] = BmPacketTypes::RouteDiscoveryResponse as u8;
-    disc_resp_bytes[1..5].copy_from_slice(&1u32.to_ne_bytes());
-    disc_resp_bytes[5..9].copy_from_slice(&2u32.to_ne_bytes());
-    disc_resp_bytes[9..13].copy_from_slice(&2u32.to_ne_bytes());
-    disc_resp_bytes[13..17].copy_from_slice(&2u32.to_ne_bytes());
-    disc_resp_bytes[17] = 0x05; // TTL 5
-
-    let processed = engine.process_packet(18, &mut disc_resp_bytes, 200, -60);
+    let mut disc_resp_bytes = build_frame(BmPacketTypes::RouteDiscoveryResponse, node2_id, node1_id);
+
+    let processed = engine.process_packet(disc_resp_bytes.len(), &mut disc_resp_bytes, 200, -60);
     assert!(processed.is_some());
     println!("[RX] Processed Discovery Response from Node 2.");
 
@@ -100,7 +104,7 @@ fn test_route_discovery_and_packet_completion_flow() {
     println!("[STATE] Engine Status: {:?}", status);
     assert_eq!(status, BmEngineStatus::SendingPayload);
 
-    let data_pkt = engine.get_next_outbound_packet().expect("Expected outbound data packet");
+    let data_pkt = engine.get_next_outbound_packet(300).expect("Expected outbound data packet");
     
     // Evaluate next_hop before referencing packet_type immutably
     let next_hop_val = data_pkt.get_next_hop();
@@ -119,20 +123,15 @@ fn test_route_discovery_and_packet_completion_flow() {
     let status = engine.run_engine(400);
     println!("[STATE] Engine Status: {:?}", status);
     assert_eq!(status, BmEngineStatus::WaitingForAck);
+    assert_eq!(engine.get_transfer_state(transfer_id), Some(BmTransferState::InFlight));
 
     // ------------------------------------------------------------------------
     // Step 6: Simulate receiving DataPayloadAck from Node 2
     // ------------------------------------------------------------------------
     println!("\n--- Step 6: Simulating Rx DataPayloadAck from Node 2 ---");
-    let mut ack_bytes = [0u8; 18];
-    ack_bytes[0] = BmPacketTypes::DataPayloadAck as u8;
-    ack_bytes[1..5].copy_from_slice(&1u32.to_ne_bytes());
-    ack_bytes[5..9].copy_from_slice(&2u32.to_ne_bytes());
-    ack_bytes[9..13].copy_from_slice(&2u32.to_ne_bytes());
-    ack_bytes[13..17].copy_from_slice(&2u32.to_ne_bytes());
-    ack_bytes[17] = 0x05;
-
-    let ack_processed = engine.process_packet(18, &mut ack_bytes, 450, -55);
+    let mut ack_bytes = build_frame(BmPacketTypes::DataPayloadAck, node2_id, node1_id);
+
+    let ack_processed = engine.process_packet(ack_bytes.len(), &mut ack_bytes, 450, -55);
     assert!(ack_processed.is_some());
     println!("[RX] Processed DataPayloadAck from Node 2.");
 
@@ -148,6 +147,14 @@ fn test_route_discovery_and_packet_completion_flow() {
     println!("[STATE] Engine Status: {:?}", status3);
     assert_eq!(status3, BmEngineStatus::Idle);
 
+    // Outcome is kept until the application reads it
+    assert_eq!(engine.get_transfer_state(transfer_id), Some(BmTransferState::Delivered));
+    let record = engine.get_transfer_record().expect("Expected transfer record");
+    println!("[RECORD] Transfer {} to {:?}: {}", record.id, record.dest, record.state);
+    assert_eq!((record.id, record.dest, record.state), (transfer_id, node2_id, BmTransferState::Delivered));
+    assert_eq!(engine.get_transfer_record(), None);
+    assert_eq!(engine.get_transfer_state(transfer_id), None);
+
     println!("\n=== END: Test Passed Successfully! ===");
 }
 
@@ -187,8 +194,8 @@ fn test_packet_routing() {
     // ------------------------------------------------------------------------
     println!("\n--- Step 1: Node 1 initiates discovery for Node 3 ---");
     let payload = BmNetworkPacketPayload::default();
-    let err = engine_node1.initiate_packet_transfer(node3_id, true, 5, payload);
-    assert_eq!(err, BmError::None);
+    let err = engine_node1.initiate_packet_transfer(node3_id, BM_DEFAULT_PORT, true, 5, payload);
+    assert!(err.is_ok());
 
     assert_eq!(
         engine_node1.run_engine(0),
@@ -197,7 +204,7 @@ fn test_packet_routing() {
 
     // Get Node 1's broadcast discovery request
     let node1_disc_pkt = engine_node1
-        .get_next_outbound_packet()
+        .get_next_outbound_packet(0)
         .expect("Node 1 should have an outbound discovery request");
     
     assert_eq!(node1_disc_pkt.packet_type, BmPacketTypes::RouteDiscoveryRequest);
@@ -215,7 +222,7 @@ fn test_packet_routing() {
 
     // Pop the forwarded packet using get_next_outbound_packet
     let node2_fwd_pkt = engine_node2
-        .get_next_outbound_packet()
+        .get_next_outbound_packet(100)
         .expect("Node 2 should have queued a forwarded packet");
 
     // Check that Node 2 updated headers (hop count incremented, src set to Node 2)
@@ -240,7 +247,7 @@ fn test_packet_routing() {
 
     // Verify Node 3 created an outbound RouteDiscoveryResponse packet
     let node3_resp_pkt = engine_node3
-        .get_next_outbound_packet()
+        .get_next_outbound_packet(200)
         .expect("Node 3 should have an outbound discovery response");
 
     assert_eq!(node3_resp_pkt.packet_type, BmPacketTypes::RouteDiscoveryResponse);
@@ -259,7 +266,7 @@ fn test_packet_routing() {
     assert!(processed_at_node2_resp.is_some());
 
     let node2_fwd_resp = engine_node2
-        .get_next_outbound_packet()
+        .get_next_outbound_packet(300)
         .expect("Node 2 should have queued forwarded response");
     assert_eq!(node2_fwd_resp.get_next_hop(), node1_id);
 
@@ -281,7 +288,7 @@ fn test_packet_routing() {
     assert_eq!(engine_node1.run_engine(500), BmEngineStatus::SendingPayload);
 
     let data_pkt = engine_node1
-        .get_next_outbound_packet()
+        .get_next_outbound_packet(500)
         .expect("Node 1 should have a queued DataPayload packet");
 
     assert_eq!(data_pkt.packet_type, BmPacketTypes::DataPayload);
@@ -299,10 +306,117 @@ fn test_packet_routing() {
     let _ = engine_node2.process_packet(len, &mut data_bytes, 600, -60);
 
     let fwd_data_pkt = engine_node2
-        .get_next_outbound_packet()
+        .get_next_outbound_packet(600)
         .expect("Node 2 should have queued forwarded DataPayload");
     assert_eq!(fwd_data_pkt.packet_type, BmPacketTypes::DataPayload);
     assert_eq!(fwd_data_pkt.get_destination(), node3_id);
 
     println!("\n=== END: Multi-Hop Routing Test Passed! ===");
 }
+
+// Sends the next outbound packet of `from` and hands its OTA bytes to `to`. Returns the type of the packet sent.
+fn transmit(from: &mut BmNetworkEngine, to: &mut BmNetworkEngine, millis: i64) -> BmPacketTypes {
+    let packet = from.get_next_outbound_packet(millis).expect("Expected an outbound packet");
+    let packet_type = packet.packet_type.clone();
+    let mut bytes = packet.to_bytes().expect("Serialization failed");
+    from.set_next_outbound_complete(millis);
+    to.process_packet(bytes.len(), &mut bytes, millis, -60);
+    packet_type
+}
+
+/// # Intermediate Route Discovery Reply Test
+///
+/// **Scenario:** Relay replies to route discovery across the topology `Node 1 <-> Node 2 <-> Node 3`, with `Node 4` and `Node 5` as other neighbors of Node 2.
+///
+/// **Workflow Tested:**
+/// 1. **Sequenced Route:** Node 4 discovers Node 3 through Node 2. Node 3 answers with its destination sequence number, which Node 2 keeps with its route.
+/// 2. **Relay Reply:** Node 1 discovers Node 3. Node 2 answers for Node 3 instead of relaying the request, and Node 1 routes through Node 2.
+/// 3. **Stale Route:** Node 5 asks with a newer sequence number than Node 2 holds. Node 2 relays the request and Node 3 answers itself.
+#[test]
+fn test_intermediate_route_reply() {
+    println!("\n=== START: Intermediate Route Discovery Reply Test ===");
+
+    let node1_id = Some(1);
+    let node2_id = Some(2);
+    let node3_id = Some(3);
+    let node4_id = Some(4);
+    let node5_id = Some(5);
+
+    let mut engine_node1 = BmNetworkEngine::new(node1_id);
+    let mut engine_node2 = BmNetworkEngine::new(node2_id);
+    let mut engine_node3 = BmNetworkEngine::new(node3_id);
+    let mut engine_node4 = BmNetworkEngine::new(node4_id);
+
+    // ------------------------------------------------------------------------
+    // Step 1: Node 4 discovers Node 3, Node 2 learns the route with its sequence number
+    // ------------------------------------------------------------------------
+    println!("\n--- Step 1: Node 4 discovers Node 3 through Node 2 ---");
+    let payload = BmNetworkPacketPayload::default();
+    assert!(engine_node4.initiate_packet_transfer(node3_id, BM_DEFAULT_PORT, true, 5, payload).is_ok());
+    engine_node4.run_engine(0);
+
+    assert_eq!(transmit(&mut engine_node4, &mut engine_node2, 0), BmPacketTypes::RouteDiscoveryRequest);
+    assert_eq!(transmit(&mut engine_node2, &mut engine_node3, 100), BmPacketTypes::RouteDiscoveryRequest);
+    assert_eq!(transmit(&mut engine_node3, &mut engine_node2, 200), BmPacketTypes::RouteDiscoveryResponse);
+
+    let node3_seq = engine_node2.table.find_node_by_id(node3_id)
+        .and_then(|node| node.get_dest_seq())
+        .expect("Node 2 should know Node 3's sequence number");
+    assert_eq!(engine_node2.table.get_next_hop(node3_id), node3_id);
+
+    // ------------------------------------------------------------------------
+    // Step 2: Node 2 answers Node 1's discovery for Node 3
+    // ------------------------------------------------------------------------
+    println!("\n--- Step 2: Node 1 discovers Node 3, Node 2 replies ---");
+    let payload = BmNetworkPacketPayload::default();
+    assert!(engine_node1.initiate_packet_transfer(node3_id, BM_DEFAULT_PORT, true, 5, payload).is_ok());
+    engine_node1.run_engine(1000);
+    assert_eq!(transmit(&mut engine_node1, &mut engine_node2, 1000), BmPacketTypes::RouteDiscoveryRequest);
+
+    // The forwarded response for Node 4 goes first, then the reply for Node 1
+    let forwarded = engine_node2.get_next_outbound_packet(1100).expect("Node 2 should forward Node 4's response");
+    assert_eq!(forwarded.get_destination(), node4_id);
+    engine_node2.set_next_outbound_complete(1100);
+
+    let reply = engine_node2.get_next_outbound_packet(1100).expect("Node 2 should reply for Node 3");
+    assert_eq!(reply.packet_type, BmPacketTypes::RouteDiscoveryResponse);
+    assert_eq!(reply.get_originator(), node2_id);
+    assert_eq!(reply.get_destination(), node1_id);
+    assert!(!reply.get_info().required_ack());
+
+    // The request stops at Node 2
+    assert_eq!(transmit(&mut engine_node2, &mut engine_node1, 1100), BmPacketTypes::RouteDiscoveryResponse);
+    assert!(engine_node2.get_next_outbound_packet(1100).is_none());
+    assert_eq!(engine_node1.table.get_next_hop(node3_id), node2_id);
+    assert_eq!(engine_node1.table.get_distance(node3_id), Some(1));
+    assert_eq!(engine_node1.table.find_node_by_id(node3_id).and_then(|node| node.get_dest_seq()), Some(node3_seq));
+    assert_eq!(engine_node1.run_engine(1200), BmEngineStatus::RouteFound);
+
+    // ------------------------------------------------------------------------
+    // Step 3: A request for a newer route than Node 2 holds is relayed
+    // ------------------------------------------------------------------------
+    println!("\n--- Step 3: Node 5 asks for a newer route, Node 2 relays ---");
+    // Node 5 heard of a newer sequence number than Node 2, its route is gone since
+    let mut engine_node5 = BmNetworkEngine::new(node5_id);
+    engine_node5.table.update_node_route_seq(node3_id, node2_id, 1, node3_seq.wrapping_add(1), 2000, -60);
+    engine_node5.table.remove_route(node3_id, node2_id);
+
+    let payload = BmNetworkPacketPayload::default();
+    assert!(engine_node5.initiate_packet_transfer(node3_id, BM_DEFAULT_PORT, true, 5, payload).is_ok());
+    engine_node5.run_engine(2000);
+    assert_eq!(transmit(&mut engine_node5, &mut engine_node2, 2000), BmPacketTypes::RouteDiscoveryRequest);
+
+    assert_eq!(transmit(&mut engine_node2, &mut engine_node3, 2100), BmPacketTypes::RouteDiscoveryRequest);
+    assert_eq!(transmit(&mut engine_node3, &mut engine_node2, 2200), BmPacketTypes::RouteDiscoveryResponse);
+    assert_eq!(transmit(&mut engine_node2, &mut engine_node5, 2300), BmPacketTypes::RouteDiscoveryResponse);
+
+    // Node 3 answered with a number at least as new as the one asked for
+    let new_seq = engine_node5.table.find_node_by_id(node3_id)
+        .and_then(|node| node.get_dest_seq())
+        .expect("Node 5 should know Node 3's sequence number");
+    assert!(new_seq.wrapping_sub(node3_seq) as i16 > 0);
+    assert_eq!(engine_node5.table.get_next_hop(node3_id), node2_id);
+    assert_eq!(engine_node5.run_engine(2400), BmEngineStatus::RouteFound);
+
+    println!("\n=== END: Intermediate Route Reply Test Passed! ===");
+}